@@ -0,0 +1,95 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use midi_sample_qzt::bench;
+use midi_sample_qzt::pitch::{PitchEnvelope, PitchEnvelopeDescr};
+use midi_sample_qzt::resample::Quality;
+use midi_sample_qzt::smooth::{Curve, Ramp};
+use std::hint::black_box;
+
+/// The sample rate the benchmarks pretend to run at
+const SAMPLE_RATE: usize = 48_000;
+
+/// The frames in each Jack cycle mixed
+const FRAMES: usize = 256;
+
+/// Mixing a cycle, with every voice looping at a rate that is not one
+/// and the master gain ramping
+fn mix(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mix");
+    for voices in [1, 16, 64, 256] {
+        let mut engine = bench::engine(voices);
+        group.bench_function(BenchmarkId::new("voices", voices), |b| {
+            b.iter(|| engine.process(&[], FRAMES))
+        });
+    }
+    group.finish();
+}
+
+/// Reading a cycle of a sample a little fast, a frame at a time
+fn resample(c: &mut Criterion) {
+    let data: Vec<f32> = (0..SAMPLE_RATE)
+        .map(|frame| (frame as f32 * 0.01).sin())
+        .collect();
+    let mut group = c.benchmark_group("resample");
+    for quality in [Quality::Linear, Quality::Cubic, Quality::Sinc] {
+        group.bench_function(format!("{quality:?}"), |b| {
+            b.iter(|| {
+                let mut position = 0.0;
+                for _ in 0..FRAMES {
+                    let frame = position as usize;
+                    black_box(quality.interpolate(
+                        black_box(&data),
+                        frame,
+                        position - frame as f32,
+                    ));
+                    position += 1.01;
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Gain ramps, as fades and voices' attacks and releases use, and
+/// pitch envelopes, falling exponentially or along a curve
+fn envelope(c: &mut Criterion) {
+    let mut group = c.benchmark_group("envelope");
+    for curve in [
+        Curve::Linear,
+        Curve::EqualPower,
+        Curve::Exponential,
+        Curve::Smooth,
+    ] {
+        let mut ramp = Ramp::new(0.0);
+        ramp.set_curve(curve);
+        let mut target = 1.0;
+        group.bench_function(format!("ramp/{curve:?}"), |b| {
+            b.iter(|| {
+                ramp.set(target, FRAMES);
+                for _ in 0..FRAMES {
+                    black_box(ramp.tick());
+                }
+                target = 1.0 - target;
+            })
+        });
+    }
+    for (fall, curve) in [("exponential", None), ("curve", Some(Curve::Smooth))]
+    {
+        let descr = PitchEnvelopeDescr {
+            semitones: 12.0,
+            decay: 100.0,
+            curve,
+        };
+        group.bench_function(format!("pitch/{fall}"), |b| {
+            b.iter(|| {
+                let mut envelope = PitchEnvelope::new(&descr, SAMPLE_RATE);
+                for _ in 0..FRAMES {
+                    black_box(envelope.tick());
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, mix, resample, envelope);
+criterion_main!(benches);
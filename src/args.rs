@@ -0,0 +1,321 @@
+use std::env;
+use std::path::PathBuf;
+
+pub const USAGE: &str =
+    "Usage: midi_sample_qzt [config.json | bundle.qzt] [options]
+       midi_sample_qzt --demo [options]
+       midi_sample_qzt pack <config.json> <bundle.qzt>
+       midi_sample_qzt unpack <bundle.qzt> <directory>
+       midi_sample_qzt hash <file>...
+       midi_sample_qzt export-kit <config.json> <directory>
+       midi_sample_qzt analyze <config.json>
+       midi_sample_qzt lint <config.json> [--json]
+       midi_sample_qzt stats <config.json>
+       midi_sample_qzt calibrate-velocity <config.json>
+       midi_sample_qzt --bench-synthetic <voices>
+Options:
+  --demo
+        Play the built in demo kit, with no configuration, to check
+        the audio and MIDI connections
+  --config <file>
+        The configuration file or bundle.  Without it or a file
+        before the options, the default configuration file
+  --audition <note> <file_a> <file_b>
+        Alternate between two files on a note, reporting which played
+  --trigger-log <file>
+        Record every trigger to a CSV file, or JSON if <file> ends
+        in .json or .jsonl
+  --capture-midi <file>
+        Write the notes played to a Standard MIDI File on exit
+  --report <file>
+        Write the session report printed on exit to a JSON file
+  --profile <name>
+        Use the settings of a profile in the configuration
+  --auto-gain
+        Turn each sample up or down to the median loudness, as far
+        as its peak allows
+  --mlock
+        Lock the samples into memory so they are never paged out
+  --keyboard
+        Play notes from the computer keyboard
+  --console
+        Take commands on stdin: trigger, load, gain, status, quit,
+        and the control commands
+  --start-jack
+        Start a Jack server, as ~/.jackdrc sets it up, if none is
+        running
+  --wait-for-jack
+        Wait for a Jack server to start if none is running
+  --test-tone
+        Beep on each output channel in turn, instead of loading the
+        samples, to check the speakers
+  --latency <note>
+        Click on the output when <note> arrives and time how long
+        it takes to come back in on the input, through a loopback
+        cable, instead of loading the samples
+  --calibrate-latency
+        Click on each note and time it from the pad being hit, heard
+        on the input by a microphone, then write a delay for each
+        note into the configuration to bring the pads into time";
+
+/// Two candidate files played in turn on one note, to help choose
+/// between takes
+#[derive(Debug)]
+pub struct Audition {
+    pub note: u8,
+    pub paths: [String; 2],
+}
+
+/// Commands that do a job and exit rather than run the sampler
+#[derive(Debug)]
+pub enum Subcommand {
+    /// Pack a configuration and its samples into a bundle
+    Pack { config: PathBuf, bundle: PathBuf },
+
+    /// Unpack a bundle into a directory
+    Unpack { bundle: PathBuf, dir: PathBuf },
+
+    /// Print the SHA-256 digests of files, for the configuration
+    Hash { paths: Vec<PathBuf> },
+
+    /// Render each sample, processed, to a WAV file in a directory
+    ExportKit { config: PathBuf, dir: PathBuf },
+
+    /// Find the tempo of each sample and write it into the
+    /// configuration
+    Analyze { config: PathBuf },
+
+    /// Check a configuration for mistakes, printing them as text or
+    /// JSON
+    Lint { config: PathBuf, json: bool },
+
+    /// Print the memory, length, sample rate and channels of each
+    /// sample, and the totals for each bank
+    Stats { config: PathBuf },
+
+    /// Fit a velocity curve to the controller's pads and write it into
+    /// the configuration
+    CalibrateVelocity { config: PathBuf },
+
+    /// Time mixing a number of voices offline
+    BenchSynthetic { voices: usize },
+}
+
+/// The command line
+#[derive(Debug)]
+pub struct Args {
+    pub config: String,
+    pub demo: bool,
+    pub audition: Option<Audition>,
+    pub trigger_log: Option<PathBuf>,
+    pub capture_midi: Option<PathBuf>,
+    pub report: Option<PathBuf>,
+    pub profile: Option<String>,
+    pub auto_gain: bool,
+    pub mlock: bool,
+    pub keyboard: bool,
+    pub console: bool,
+    pub start_jack: bool,
+    pub wait_for_jack: bool,
+    pub test_tone: bool,
+    pub latency: Option<u8>,
+    pub calibrate_latency: bool,
+}
+
+/// The subcommand, if the command line starts with one
+pub fn subcommand() -> Option<Result<Subcommand, String>> {
+    let mut args = env::args().skip(1);
+    let subcommand = args.next()?;
+    let mut path = || next(&mut args, &subcommand).map(PathBuf::from);
+    Some(match subcommand.as_str() {
+        "pack" => path().and_then(|config| {
+            Ok(Subcommand::Pack {
+                config,
+                bundle: path()?,
+            })
+        }),
+        "unpack" => path().and_then(|bundle| {
+            Ok(Subcommand::Unpack {
+                bundle,
+                dir: path()?,
+            })
+        }),
+        "export-kit" => path().and_then(|config| {
+            Ok(Subcommand::ExportKit {
+                config,
+                dir: path()?,
+            })
+        }),
+        "analyze" => path().map(|config| Subcommand::Analyze { config }),
+        "lint" => path().and_then(|config| match args.next().as_deref() {
+            None => Ok(Subcommand::Lint {
+                config,
+                json: false,
+            }),
+            Some("--json") => Ok(Subcommand::Lint { config, json: true }),
+            Some(arg) => Err(format!("lint: Unexpected {arg}")),
+        }),
+        "stats" => path().map(|config| Subcommand::Stats { config }),
+        "calibrate-velocity" => {
+            path().map(|config| Subcommand::CalibrateVelocity { config })
+        },
+        "hash" => match args.map(PathBuf::from).collect::<Vec<_>>() {
+            paths if paths.is_empty() => Err("hash: No files".to_string()),
+            paths => Ok(Subcommand::Hash { paths }),
+        },
+        "--bench-synthetic" => {
+            next(&mut args, &subcommand).and_then(|n| {
+                match n.parse::<usize>() {
+                    Ok(voices) if voices > 0 => {
+                        Ok(Subcommand::BenchSynthetic { voices })
+                    },
+                    _ => Err(format!("Bad number of voices: {n}")),
+                }
+            })
+        },
+        _ => return None,
+    })
+}
+
+/// Process the command line arguments
+pub fn parse() -> Result<Args, String> {
+    let mut args = env::args().skip(1);
+    let mut config: Option<String> = None;
+    let mut demo = false;
+    let mut audition: Option<Audition> = None;
+    let mut trigger_log: Option<PathBuf> = None;
+    let mut capture_midi: Option<PathBuf> = None;
+    let mut report: Option<PathBuf> = None;
+    let mut profile: Option<String> = None;
+    let mut auto_gain = false;
+    let mut mlock = false;
+    let mut keyboard = false;
+    let mut console = false;
+    let mut start_jack = false;
+    let mut wait_for_jack = false;
+    let mut test_tone = false;
+    let mut latency: Option<u8> = None;
+    let mut calibrate_latency = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--audition" => {
+                let note = next(&mut args, &arg)?;
+                let note = note
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|n| *n < 128)
+                    .ok_or(format!("Bad note: {note}"))?;
+                let a = next(&mut args, &arg)?;
+                let b = next(&mut args, &arg)?;
+                audition = Some(Audition {
+                    note,
+                    paths: [a, b],
+                });
+            },
+            "--trigger-log" => {
+                trigger_log = Some(PathBuf::from(next(&mut args, &arg)?))
+            },
+            "--capture-midi" => {
+                capture_midi = Some(PathBuf::from(next(&mut args, &arg)?))
+            },
+            "--report" => report = Some(PathBuf::from(next(&mut args, &arg)?)),
+            "--demo" => demo = true,
+            "--config" if config.is_none() => {
+                config = Some(next(&mut args, &arg)?)
+            },
+            "--config" => return Err("Two configuration files".to_string()),
+            "--profile" => profile = Some(next(&mut args, &arg)?),
+            "--auto-gain" => auto_gain = true,
+            "--mlock" => mlock = true,
+            "--keyboard" => keyboard = true,
+            "--console" => console = true,
+            "--start-jack" => start_jack = true,
+            "--wait-for-jack" => wait_for_jack = true,
+            "--test-tone" => test_tone = true,
+            "--calibrate-latency" => calibrate_latency = true,
+            "--latency" => {
+                let note = next(&mut args, &arg)?;
+                latency = Some(
+                    note.parse::<u8>()
+                        .ok()
+                        .filter(|n| *n < 128)
+                        .ok_or(format!("Bad note: {note}"))?,
+                );
+            },
+            _ if arg.starts_with("--") => {
+                return Err(format!("Unknown option: {arg}"))
+            },
+            _ if config.is_none() => config = Some(arg),
+            _ => return Err(format!("Unexpected argument: {arg}")),
+        }
+    }
+    if keyboard && console {
+        return Err("--keyboard and --console both read the terminal".into());
+    }
+    let config = match config {
+        Some(_) if demo => {
+            return Err("--demo plays without a configuration".to_string())
+        },
+        Some(config) => config,
+        None if demo => String::new(),
+        None => {
+            let path = default_config()
+                .ok_or("No configuration file, and nowhere to look for one")?;
+            if !path.exists() {
+                return Err(format!(
+                    "No configuration file, and none at {}",
+                    path.display()
+                ));
+            }
+            path.to_string_lossy().into_owned()
+        },
+    };
+    if calibrate_latency && (demo || config.ends_with(".qzt")) {
+        return Err(
+            "--calibrate-latency writes into a configuration file, not \
+             the demo kit or a bundle"
+                .into(),
+        );
+    }
+    Ok(Args {
+        config,
+        demo,
+        audition,
+        trigger_log,
+        capture_midi,
+        report,
+        profile,
+        auto_gain,
+        mlock,
+        keyboard,
+        console,
+        start_jack,
+        wait_for_jack,
+        test_tone,
+        latency,
+        calibrate_latency,
+    })
+}
+
+/// Where the configuration file is when none is given: in the
+/// application data folder on Windows, and elsewhere in
+/// `$XDG_CONFIG_HOME`, which defaults to `~/.config`
+pub fn default_config() -> Option<PathBuf> {
+    let dir = if cfg!(windows) {
+        PathBuf::from(env::var_os("APPDATA")?)
+    } else {
+        match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        }
+    };
+    Some(dir.join("midi_sample_qzt").join("config.json"))
+}
+
+/// The value of an option
+fn next(
+    args: &mut impl Iterator<Item = String>,
+    option: &str,
+) -> Result<String, String> {
+    args.next().ok_or(format!("{option}: Missing value"))
+}
@@ -0,0 +1,179 @@
+use crate::clock::{default_rate, Rate};
+use crate::player::Player;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The order the held notes are played in
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Up,
+    Down,
+    Random,
+}
+
+fn default_mode() -> Mode {
+    Mode::Up
+}
+
+fn default_octaves() -> u8 {
+    1
+}
+
+/// An arpeggiator.  While it is on, held notes are not played
+/// directly but one at a time in time with the clock, each `rate`
+/// long, over `octaves` octaves starting at the held notes.  If `cc`
+/// is given that controller turns it on (values 64 and up) and off,
+/// otherwise it is always on
+#[derive(Clone, Debug, Deserialize)]
+pub struct ArpeggiatorDescr {
+    #[serde(default = "default_mode")]
+    pub mode: Mode,
+    #[serde(default = "default_rate")]
+    pub rate: Rate,
+    #[serde(default = "default_octaves")]
+    pub octaves: u8,
+    #[serde(default)]
+    pub cc: Option<u8>,
+}
+
+pub struct Arpeggiator {
+    descr: ArpeggiatorDescr,
+    player: Arc<Player>,
+    on: AtomicBool,
+
+    /// The held notes and their velocities, in the order pressed
+    held: Mutex<Vec<(u8, u8)>>,
+
+    /// How many notes have been played, to find the next one
+    step: AtomicUsize,
+
+    /// The note last played, released when the next one plays
+    playing: Mutex<Option<u8>>,
+
+    /// State for the random mode's xorshift generator
+    random: AtomicU64,
+}
+
+impl Arpeggiator {
+    pub fn new(
+        descr: ArpeggiatorDescr,
+        player: Arc<Player>,
+    ) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Self {
+            on: AtomicBool::new(descr.cc.is_none()),
+            descr,
+            player,
+            held: Mutex::new(vec![]),
+            step: AtomicUsize::new(0),
+            playing: Mutex::new(None),
+            random: AtomicU64::new(seed | 1),
+        }
+    }
+
+    pub fn control_change(
+        &self,
+        controller: u8,
+        value: u8,
+    ) {
+        if Some(controller) == self.descr.cc {
+            let on = value >= 64;
+            if self.on.swap(on, Ordering::Relaxed) != on {
+                eprintln!("Arpeggiator {}", if on { "on" } else { "off" });
+            }
+        }
+    }
+
+    /// Hold a note.  True if the arpeggiator is on, and so plays the
+    /// note instead of it being played now
+    pub fn note_on(
+        &self,
+        note: u8,
+        velocity: u8,
+    ) -> bool {
+        let mut held = self.held.lock().unwrap();
+        held.retain(|(n, _)| *n != note);
+        held.push((note, velocity));
+        if held.len() == 1 {
+            // Start again from the bottom (or top) with a new chord
+            self.step.store(0, Ordering::Relaxed);
+        }
+        self.on.load(Ordering::Relaxed)
+    }
+
+    /// Let go of a note.  True if the arpeggiator is on, and so the
+    /// NoteOff is its business
+    pub fn note_off(
+        &self,
+        note: u8,
+    ) -> bool {
+        self.held.lock().unwrap().retain(|(n, _)| *n != note);
+        self.on.load(Ordering::Relaxed)
+    }
+
+    /// Called on each clock pulse.  Plays the next note when the
+    /// pulse is on the beat for the rate
+    pub fn pulse(
+        &self,
+        pulse: u64,
+    ) {
+        if !pulse.is_multiple_of(self.descr.rate.pulses()) {
+            return;
+        }
+        let next = if self.on.load(Ordering::Relaxed) {
+            self.next()
+        } else {
+            None
+        };
+        let mut playing = self.playing.lock().unwrap();
+        if let Some(note) = playing.take() {
+            self.player.note_off(note, 0);
+        }
+        if let Some((note, velocity)) = next {
+            self.player.note_on(note, velocity);
+            *playing = Some(note);
+        }
+    }
+
+    /// The next note to play, if any are held
+    fn next(&self) -> Option<(u8, u8)> {
+        let mut held = self.held.lock().unwrap().clone();
+        held.sort();
+        let notes: Vec<(u8, u8)> = (0..self.descr.octaves.max(1))
+            .flat_map(|octave| {
+                held.iter().filter_map(move |(note, velocity)| {
+                    Some((
+                        note.checked_add(octave.checked_mul(12)?)?,
+                        *velocity,
+                    ))
+                })
+            })
+            .filter(|(note, _)| *note < 128)
+            .collect();
+        if notes.is_empty() {
+            return None;
+        }
+        let step = self.step.fetch_add(1, Ordering::Relaxed);
+        let index = match self.descr.mode {
+            Mode::Up => step % notes.len(),
+            Mode::Down => notes.len() - 1 - step % notes.len(),
+            Mode::Random => self.random() as usize % notes.len(),
+        };
+        Some(notes[index])
+    }
+
+    fn random(&self) -> u64 {
+        let mut x = self.random.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.random.store(x, Ordering::Relaxed);
+        x
+    }
+}
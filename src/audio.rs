@@ -0,0 +1,541 @@
+use crate::engine::Engine;
+use jack::{
+    AsyncClient, AudioIn, AudioOut, Client, ClientStatus, Control,
+    NotificationHandler, Port, ProcessHandler, ProcessScope,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{
+    AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering,
+};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the supervisor checks whether the Jack server has gone
+/// away, and how often it tries to reconnect when it has
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Waiting for a Jack server at start up, the first retry comes
+/// after this, and each one after twice as long as the one before, up
+/// to the longest
+const FIRST_RETRY: Duration = Duration::from_millis(500);
+const LONGEST_RETRY: Duration = Duration::from_secs(8);
+
+/// How often the watchdog reports trouble
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How the audio processing is keeping up, and how much it is doing.
+/// Counted in the Jack thread and reported by the watchdog and the
+/// `stats` command
+pub struct Stats {
+    /// Process cycles run
+    pub cycles: AtomicU64,
+
+    /// Cycles that output silence because the engine was not
+    /// available, leaving a gap in whatever was playing
+    pub missed: AtomicU64,
+
+    /// Cycles Jack reported as late
+    pub xruns: AtomicU64,
+
+    /// The longest time spent in a cycle since the watchdog last
+    /// looked, in millionths of the time the cycle has.  Over a
+    /// million means the engine could not keep up
+    pub peak_load: AtomicU64,
+
+    /// Voices started
+    pub triggers: AtomicU64,
+
+    /// Triggers that could not play, for want of a voice or because
+    /// the sample was not loaded
+    pub failed: AtomicU64,
+
+    /// Samples started on each note, for the report on exit
+    pub note_triggers: Vec<AtomicU64>,
+
+    /// The most voices that have played at once
+    pub max_voices: AtomicU64,
+
+    /// The bits of the highest output level there has been, linear.
+    /// Levels are positive, so their bits order as they do
+    pub peak_level: AtomicU32,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            cycles: AtomicU64::new(0),
+            missed: AtomicU64::new(0),
+            xruns: AtomicU64::new(0),
+            peak_load: AtomicU64::new(0),
+            triggers: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            note_triggers: (0..128).map(|_| AtomicU64::new(0)).collect(),
+            max_voices: AtomicU64::new(0),
+            peak_level: AtomicU32::new(0),
+        }
+    }
+}
+
+impl Stats {
+    pub fn report(&self) -> String {
+        format!(
+            "cycles {} missed {} xruns {} peak_load {:.1}% triggers {} \
+             failed {}",
+            self.cycles.load(Ordering::Relaxed),
+            self.missed.load(Ordering::Relaxed),
+            self.xruns.load(Ordering::Relaxed),
+            self.peak_load.load(Ordering::Relaxed) as f64 / 10_000.0,
+            self.triggers.load(Ordering::Relaxed),
+            self.failed.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// Jack's sample rate, which can change while it runs, and the rate
+/// the samples were made for, the first it had.  Samples are played
+/// faster or slower by the difference so they keep their pitch
+#[derive(Default)]
+pub struct SampleRate {
+    current: AtomicUsize,
+    samples: AtomicUsize,
+}
+
+impl SampleRate {
+    /// Jack's sample rate now, for times and frequencies
+    pub fn current(&self) -> usize {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    /// The rate samples are made and played at
+    pub fn samples(&self) -> usize {
+        self.samples.load(Ordering::Relaxed)
+    }
+
+    /// Jack's rate is `rate`.  The first it is given is the samples'.
+    /// Returns the rate before if it has changed
+    fn set(
+        &self,
+        rate: usize,
+    ) -> Option<usize> {
+        let _ = self.samples.compare_exchange(
+            0,
+            rate,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+        let old = self.current.swap(rate, Ordering::Relaxed);
+        (old != 0 && old != rate).then_some(old)
+    }
+}
+
+/// Tell the engine Jack's sample rate, and say if it has changed
+fn set_sample_rate(
+    engine: &Mutex<Engine>,
+    sample_rate: &SampleRate,
+    rate: usize,
+) {
+    engine.lock().unwrap().set_sample_rate(rate);
+    if let Some(old) = sample_rate.set(rate) {
+        eprintln!(
+            "Jack sample rate changed from {old} to {rate}.  Samples made \
+             at {} are played at {:.4} times the speed to keep their pitch",
+            sample_rate.samples(),
+            sample_rate.samples() as f64 / rate as f64
+        );
+    }
+}
+
+/// The Jack process callback.  The engine is shared so that it
+/// survives the client being recreated after the server restarts.
+/// Nothing else locks it while a client is running
+pub struct Process {
+    input: Port<AudioIn>,
+
+    /// The output ports, one per channel, in the order of the engine's
+    /// channels
+    ports: Vec<Port<AudioOut>>,
+    engine: Arc<Mutex<Engine>>,
+    stats: Arc<Stats>,
+
+    /// The frames in a cycle, once Jack has said
+    buffer_size: usize,
+}
+
+impl ProcessHandler for Process {
+    /// Jack calls this before the first cycle and whenever the buffer
+    /// size changes, outside the process cycle, so the engine's
+    /// buffers can be reallocated here rather than in `process`
+    fn buffer_size(
+        &mut self,
+        _: &Client,
+        size: jack::Frames,
+    ) -> Control {
+        let size = size as usize;
+        if self.buffer_size != 0 && self.buffer_size != size {
+            eprintln!(
+                "Jack buffer size changed from {} to {size}",
+                self.buffer_size
+            );
+        }
+        self.buffer_size = size;
+        self.engine.lock().unwrap().set_buffer_size(size);
+        Control::Continue
+    }
+
+    fn process(
+        &mut self,
+        client: &Client,
+        ps: &ProcessScope,
+    ) -> Control {
+        let started = Instant::now();
+        let input = self.input.as_slice(ps);
+        let frames = ps.n_frames() as usize;
+        match self.engine.try_lock() {
+            Ok(mut engine) => {
+                engine.process(input, frames);
+                for (channel, port) in self.ports.iter_mut().enumerate() {
+                    port.as_mut_slice(ps)
+                        .copy_from_slice(&engine.output(channel)[..frames]);
+                }
+            },
+            Err(_) => {
+                for port in self.ports.iter_mut() {
+                    port.as_mut_slice(ps).fill(0.0);
+                }
+                self.stats.missed.fetch_add(1, Ordering::Relaxed);
+            },
+        }
+
+        let budget = frames as f64 / client.sample_rate() as f64;
+        let load = started.elapsed().as_secs_f64() / budget;
+        self.stats.cycles.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .peak_load
+            .fetch_max((load * 1e6) as u64, Ordering::Relaxed);
+        Control::Continue
+    }
+}
+
+/// Jack notifications.  The shutdown callback has to be async signal
+/// safe so all it does is set a flag for the supervisor
+pub struct Notifications {
+    shut_down: Arc<AtomicBool>,
+    stats: Arc<Stats>,
+    engine: Arc<Mutex<Engine>>,
+    sample_rate: Arc<SampleRate>,
+}
+
+impl NotificationHandler for Notifications {
+    fn shutdown(
+        &mut self,
+        _status: ClientStatus,
+        _reason: &str,
+    ) {
+        self.shut_down.store(true, Ordering::SeqCst);
+    }
+
+    fn xrun(
+        &mut self,
+        _: &Client,
+    ) -> Control {
+        self.stats.xruns.fetch_add(1, Ordering::Relaxed);
+        Control::Continue
+    }
+
+    /// The server's sample rate has changed, with a new word clock
+    /// say.  Not in the process thread, so it can wait for the engine
+    fn sample_rate(
+        &mut self,
+        _: &Client,
+        rate: jack::Frames,
+    ) -> Control {
+        set_sample_rate(&self.engine, &self.sample_rate, rate as usize);
+        Control::Continue
+    }
+}
+
+type ActiveClient = AsyncClient<Notifications, Process>;
+
+fn default_channels() -> usize {
+    1
+}
+
+/// A group of output ports, such as a stereo pair or the speakers of
+/// a surround system.  A mono output has one port called `name`, and
+/// its connections all go to it.  Otherwise the ports are called
+/// `name` followed by "_1", "_2" and so on, and each connection goes
+/// to the port in the same place.  The `cue` output is for the
+/// operator's headphones: the count in click and auditions play there,
+/// and samples only when they are routed there
+#[derive(Clone, Debug, Deserialize)]
+pub struct OutputDescr {
+    pub name: String,
+    #[serde(default = "default_channels")]
+    pub channels: usize,
+    #[serde(default)]
+    pub connect: Vec<String>,
+
+    /// The angle of the speaker on each channel, in degrees clockwise
+    /// from straight ahead, for panning samples round them
+    #[serde(default)]
+    pub speakers: Vec<f32>,
+    #[serde(default)]
+    pub cue: bool,
+}
+
+/// Where a sample plays: the main outputs, the cue output, or both
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Route {
+    #[default]
+    Main,
+    Cue,
+    Both,
+}
+
+impl OutputDescr {
+    /// The names of the ports
+    pub fn port_names(&self) -> Vec<String> {
+        if self.channels == 1 {
+            vec![self.name.clone()]
+        } else {
+            (1..=self.channels)
+                .map(|n| format!("{}_{n}", self.name))
+                .collect()
+        }
+    }
+}
+
+/// The client's ports, and the Jack ports to connect them to
+pub struct Connections {
+    pub outputs: Vec<OutputDescr>,
+
+    /// The ports connected to the input
+    pub input: Vec<String>,
+}
+
+/// What to do when there is no Jack server at start up.  With
+/// `start_server` the Jack library starts one, as set up in
+/// `~/.jackdrc`.  With `wait` the client is tried again, less and less
+/// often, until there is one.  Otherwise starting fails
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoServer {
+    pub start_server: bool,
+    pub wait: bool,
+}
+
+/// The connection to the Jack server.  If the server shuts down a
+/// new client is created, its ports registered and connected, and
+/// playing resumes when the server comes back
+pub struct Jack {
+    client: Arc<Mutex<Option<ActiveClient>>>,
+    sample_rate: Arc<SampleRate>,
+    stats: Arc<Stats>,
+}
+
+impl Jack {
+    /// Create the client and start the audio processing thread
+    pub fn start(
+        engine: Engine,
+        connections: Connections,
+        no_server: NoServer,
+    ) -> Result<Self, jack::Error> {
+        let stats = engine.stats();
+        let engine = Arc::new(Mutex::new(engine));
+        let shut_down = Arc::new(AtomicBool::new(false));
+        let sample_rate = Arc::new(SampleRate::default());
+        let mut retry = FIRST_RETRY;
+        let client = loop {
+            match activate(
+                &engine,
+                &connections,
+                &shut_down,
+                &stats,
+                &sample_rate,
+                no_server.start_server,
+            ) {
+                Ok(client) => break client,
+                Err(err) if no_server.wait => {
+                    eprintln!(
+                        "{err}: No Jack server, trying again in {:.1}s",
+                        retry.as_secs_f32()
+                    );
+                    thread::sleep(retry);
+                    retry = (retry * 2).min(LONGEST_RETRY);
+                },
+                Err(err) => return Err(err),
+            }
+        };
+        let client = Arc::new(Mutex::new(Some(client)));
+
+        watchdog(stats.clone());
+        let supervised = client.clone();
+        let supervised_stats = stats.clone();
+        let supervised_rate = sample_rate.clone();
+        thread::spawn(move || loop {
+            thread::sleep(POLL_INTERVAL);
+            if !shut_down.load(Ordering::SeqCst) {
+                continue;
+            }
+            eprintln!("Jack server shut down.  Waiting for it to return");
+
+            // The old client is dead.  Forget it rather than trying
+            // to close it, there is no server to talk to
+            if let Some(dead) = supervised.lock().unwrap().take() {
+                std::mem::forget(dead);
+            }
+            shut_down.store(false, Ordering::SeqCst);
+            loop {
+                match activate(
+                    &engine,
+                    &connections,
+                    &shut_down,
+                    &supervised_stats,
+                    &supervised_rate,
+                    false,
+                ) {
+                    Ok(client) => {
+                        eprintln!("Reconnected to Jack");
+                        *supervised.lock().unwrap() = Some(client);
+                        break;
+                    },
+                    Err(_) => thread::sleep(POLL_INTERVAL),
+                }
+            }
+        });
+
+        Ok(Self {
+            client,
+            sample_rate,
+            stats,
+        })
+    }
+
+    /// The rate samples are made at, the first Jack had
+    pub fn sample_rate(&self) -> usize {
+        self.sample_rate.samples()
+    }
+
+    /// Jack's sample rate as it changes, and the samples'
+    pub fn sample_rates(&self) -> Arc<SampleRate> {
+        self.sample_rate.clone()
+    }
+
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    /// Deactivate the Jack client and stop the audio processing thread
+    pub fn stop(self) {
+        if let Some(client) = self.client.lock().unwrap().take() {
+            if let Err(err) = client.deactivate() {
+                eprintln!("{err}: Deactivating Jack client");
+            }
+        }
+    }
+}
+
+/// Create a client, register the ports, activate it, and connect
+/// them.  With `start_server` a Jack server is started if there is
+/// none
+fn activate(
+    engine: &Arc<Mutex<Engine>>,
+    connections: &Connections,
+    shut_down: &Arc<AtomicBool>,
+    stats: &Arc<Stats>,
+    sample_rate: &Arc<SampleRate>,
+    start_server: bool,
+) -> Result<ActiveClient, jack::Error> {
+    let options = if start_server {
+        jack::ClientOptions::empty()
+    } else {
+        jack::ClientOptions::NO_START_SERVER
+    };
+    let (client, _status) = Client::new("MidiSampleQzt", options)?;
+    set_sample_rate(engine, sample_rate, client.sample_rate());
+    let input = client.register_port("input", AudioIn)?;
+    let input_name = input.name()?;
+    let mut ports = vec![];
+    let mut destinations: Vec<(String, String)> = vec![];
+    for output in connections.outputs.iter() {
+        let mut names = vec![];
+        for name in output.port_names() {
+            let port = client.register_port(&name, AudioOut)?;
+            names.push(port.name()?);
+            ports.push(port);
+        }
+        for (i, destination) in output.connect.iter().enumerate() {
+            match names.get(if names.len() == 1 { 0 } else { i }) {
+                Some(name) => {
+                    destinations.push((name.clone(), destination.clone()))
+                },
+                None => eprintln!(
+                    "{}: No channel to connect to {destination}",
+                    output.name
+                ),
+            }
+        }
+    }
+    let client = client.activate_async(
+        Notifications {
+            shut_down: shut_down.clone(),
+            stats: stats.clone(),
+            engine: engine.clone(),
+            sample_rate: sample_rate.clone(),
+        },
+        Process {
+            input,
+            ports,
+            engine: engine.clone(),
+            stats: stats.clone(),
+            buffer_size: 0,
+        },
+    )?;
+    for (port_name, destination) in destinations.iter() {
+        if let Err(err) = client
+            .as_client()
+            .connect_ports_by_name(port_name, destination)
+        {
+            eprintln!("{err}: Connecting {port_name} to {destination}");
+        }
+    }
+    for source in connections.input.iter() {
+        if let Err(err) = client
+            .as_client()
+            .connect_ports_by_name(source, &input_name)
+        {
+            eprintln!("{err}: Connecting {source} to {input_name}");
+        }
+    }
+    Ok(client)
+}
+
+/// Report gaps in the output, late cycles, and cycles that took
+/// longer than they had, rather than letting broken audio go
+/// unnoticed.  Only intervals where something went wrong are
+/// reported
+fn watchdog(stats: Arc<Stats>) {
+    thread::spawn(move || {
+        let mut missed = 0;
+        let mut xruns = 0;
+        loop {
+            thread::sleep(WATCHDOG_INTERVAL);
+            let now_missed = stats.missed.load(Ordering::Relaxed);
+            let now_xruns = stats.xruns.load(Ordering::Relaxed);
+            let peak_load = stats.peak_load.swap(0, Ordering::Relaxed);
+            if now_missed > missed || now_xruns > xruns || peak_load > 1_000_000
+            {
+                eprintln!(
+                    "Watchdog: {} silent cycles, {} xruns, peak load {:.1}% in the last {}s",
+                    now_missed - missed,
+                    now_xruns - xruns,
+                    peak_load as f64 / 10_000.0,
+                    WATCHDOG_INTERVAL.as_secs()
+                );
+            }
+            missed = now_missed;
+            xruns = now_xruns;
+        }
+    });
+}
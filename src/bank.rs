@@ -0,0 +1,130 @@
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+/// A bank of samples, selected by MIDI Bank Select (CC0 for the MSB,
+/// CC32 for the LSB) followed by Program Change, as in General MIDI.
+/// So up to 128 * 128 * 128 banks can be addressed.  With
+/// `target_lufs` each sample in the bank is turned up or down to that
+/// loudness when it is loaded
+#[derive(Clone, Debug, Deserialize)]
+pub struct BankDescr {
+    pub name: String,
+    #[serde(default)]
+    pub msb: u8,
+    #[serde(default)]
+    pub lsb: u8,
+    pub program: u8,
+    #[serde(default)]
+    pub target_lufs: Option<f32>,
+}
+
+/// No bank is selected
+const NO_BANK: usize = usize::MAX;
+
+/// Tracks Bank Select and Program Change messages to decide which
+/// bank is active.  Shared between the MIDI and control threads
+pub struct Banks {
+    banks: Vec<BankDescr>,
+    msb: AtomicU8,
+    lsb: AtomicU8,
+    active: AtomicUsize,
+}
+
+impl Banks {
+    /// The first bank, if there is one, starts active
+    pub fn new(banks: Vec<BankDescr>) -> Self {
+        let active = if banks.is_empty() { NO_BANK } else { 0 };
+        Self {
+            banks,
+            msb: AtomicU8::new(0),
+            lsb: AtomicU8::new(0),
+            active: AtomicUsize::new(active),
+        }
+    }
+
+    /// The index of the bank called `name`
+    pub fn index(
+        &self,
+        name: &str,
+    ) -> Option<usize> {
+        self.banks.iter().position(|b| b.name == name)
+    }
+
+    /// The index of the active bank
+    pub fn active(&self) -> Option<usize> {
+        match self.active.load(Ordering::Relaxed) {
+            NO_BANK => None,
+            active => Some(active),
+        }
+    }
+
+    pub fn name(
+        &self,
+        index: usize,
+    ) -> &str {
+        &self.banks[index].name
+    }
+
+    /// The loudness the samples in a bank are brought to, if any
+    pub fn target_lufs(
+        &self,
+        index: usize,
+    ) -> Option<f32> {
+        self.banks[index].target_lufs
+    }
+
+    /// How many banks there are
+    pub fn count(&self) -> usize {
+        self.banks.len()
+    }
+
+    pub fn active_name(&self) -> Option<&str> {
+        self.active().map(|active| self.banks[active].name.as_str())
+    }
+
+    /// Make the bank called `name` active
+    pub fn select(
+        &self,
+        name: &str,
+    ) -> Result<(), String> {
+        let index = self.index(name).ok_or(format!("No bank: {name}"))?;
+        self.active.store(index, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Remember the Bank Select controllers
+    pub fn control_change(
+        &self,
+        controller: u8,
+        value: u8,
+    ) {
+        match controller {
+            0 => self.msb.store(value, Ordering::Relaxed),
+            32 => self.lsb.store(value, Ordering::Relaxed),
+            _ => (),
+        }
+    }
+
+    /// Select the bank for `program` in the bank last selected by
+    /// Bank Select.  If there is no such bank the active bank stays
+    pub fn program_change(
+        &self,
+        program: u8,
+    ) {
+        let msb = self.msb.load(Ordering::Relaxed);
+        let lsb = self.lsb.load(Ordering::Relaxed);
+        match self
+            .banks
+            .iter()
+            .position(|b| b.msb == msb && b.lsb == lsb && b.program == program)
+        {
+            Some(index) => {
+                self.active.store(index, Ordering::Relaxed);
+                eprintln!("Bank {}", self.banks[index].name);
+            },
+            None => {
+                eprintln!("No bank for MSB {msb} LSB {lsb} program {program}")
+            },
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use crate::decode::Format;
+use crate::engine::SampleData;
+use crate::gain::db_to_gain;
+use crate::player::NO_NOTE;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::TAU;
+
+/// The beep made when no sound is given: two short tones, falling
+const FREQUENCIES: [f32; 2] = [880.0, 660.0];
+const TONE_SECONDS: f32 = 0.08;
+
+fn default_gain() -> f32 {
+    -20.0
+}
+
+/// A sound played when a note cannot play, because there is no voice
+/// free or its sample is not loaded yet, so the operator hears there
+/// is trouble without watching the log.  It is `path`, or a beep if
+/// that is not given, at `gain` dB, on `output`, a monitor mix say,
+/// rather than the first output.  A note with no sample on it only
+/// beeps if `unmapped` is set, as empty pads are played on purpose to
+/// pick where to record or to drive the arpeggiator and looper
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ErrorBeepDescr {
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub format: Option<Format>,
+    #[serde(default)]
+    pub output: Option<String>,
+    #[serde(default = "default_gain")]
+    pub gain: f32,
+    #[serde(default)]
+    pub unmapped: bool,
+}
+
+/// The beep's two tones, at `sample_rate`
+pub fn tone(sample_rate: usize) -> Vec<f32> {
+    let length = (TONE_SECONDS * sample_rate as f32) as usize;
+    FREQUENCIES
+        .iter()
+        .flat_map(|frequency| {
+            (0..length).map(move |frame| {
+                let time = frame as f32 / sample_rate as f32;
+                let fall = 1.0 - frame as f32 / length as f32;
+                fall * (TAU * frequency * time).sin()
+            })
+        })
+        .collect()
+}
+
+/// The beep's sample, `data` at `gain` dB on `channels`.  It is on no
+/// note, and takes a voice from any other sample if there is none free
+pub fn sample(
+    mut data: Vec<f32>,
+    gain: f32,
+    channels: Vec<usize>,
+) -> SampleData {
+    let gain = db_to_gain(gain);
+    for x in data.iter_mut() {
+        *x *= gain;
+    }
+    SampleData {
+        path: "error-beep".to_string(),
+        data,
+        note: NO_NOTE,
+        max_voices: Some(1),
+        priority: u8::MAX,
+        channels,
+        ..Default::default()
+    }
+}
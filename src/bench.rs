@@ -0,0 +1,111 @@
+use crate::engine::{
+    Command, Engine, Fade, FadeTarget, SampleData, Segment, VoiceStatus,
+};
+use std::sync::mpsc::sync_channel;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The sample rate the benchmark pretends to run at
+const SAMPLE_RATE: usize = 48_000;
+
+/// The buffer sizes the benchmark measures, in frames
+const BUFFER_SIZES: [usize; 5] = [64, 128, 256, 512, 1024];
+
+/// How long each buffer size is measured for, in audio time
+const AUDIO_TIME: Duration = Duration::from_secs(10);
+
+/// How long each synthetic sample is, in frames, before it loops
+const SAMPLE_FRAMES: usize = SAMPLE_RATE;
+
+/// Mix `voices` voices offline and report how much of each buffer's
+/// time the mixing takes, on average and at worst, and so roughly how
+/// many voices could be mixed in time.  The voices loop, play at a
+/// rate that is not one so they interpolate, and the master gain
+/// ramps all the time, so the costly paths are all taken
+pub fn synthetic(voices: usize) {
+    println!(
+        "{voices} voices at {SAMPLE_RATE} Hz, {} s of audio per buffer size",
+        AUDIO_TIME.as_secs()
+    );
+    println!("frames  average   worst  max voices");
+    for frames in BUFFER_SIZES {
+        let mut engine = engine(voices);
+        let cycles = AUDIO_TIME.as_secs() as usize * SAMPLE_RATE / frames;
+        let budget =
+            Duration::from_secs_f64(frames as f64 / SAMPLE_RATE as f64);
+        let mut total = Duration::ZERO;
+        let mut worst = Duration::ZERO;
+        for _ in 0..cycles {
+            let start = Instant::now();
+            engine.process(&[], frames);
+            let took = start.elapsed();
+            total += took;
+            worst = worst.max(took);
+        }
+        let average =
+            total.as_secs_f64() / cycles as f64 / budget.as_secs_f64();
+        let worst = worst.as_secs_f64() / budget.as_secs_f64();
+
+        // Mixing time grows with the voices, so the average says how
+        // many would fill the buffer's time.  The worst cycle shows
+        // how much the system gets in the way, which leaves less
+        println!(
+            "{frames:6}  {:6.1}%  {:5.1}%  {:10}",
+            average * 100.0,
+            worst * 100.0,
+            (voices as f64 / average) as usize
+        );
+    }
+}
+
+/// An engine with `voices` voices all playing, and a command queued
+/// to ramp the master gain.  The benchmarks mix with it too
+pub fn engine(voices: usize) -> Engine {
+    let samples: Vec<Arc<SampleData>> = (0..voices)
+        .map(|voice| {
+            Arc::new(SampleData {
+                path: format!("synthetic-{voice}"),
+                data: (0..SAMPLE_FRAMES)
+                    .map(|frame| {
+                        (frame as f32 * 0.01 * (voice + 1) as f32).sin()
+                    })
+                    .collect(),
+                note: (voice % 128) as u8,
+                segments: vec![Segment {
+                    start: 0,
+                    end: SAMPLE_FRAMES,
+                    looping: true,
+                }],
+                ..Default::default()
+            })
+        })
+        .collect();
+    let status: Arc<Vec<VoiceStatus>> =
+        Arc::new((0..voices).map(|_| VoiceStatus::default()).collect());
+    let (sender, receiver) = sync_channel::<Command>(voices + 256);
+    let mut engine = Engine::new(samples, status, receiver, 2, vec![0, 1]);
+    for sample in 0..voices {
+        let _ = sender.try_send(Command::Trigger {
+            sample,
+            gain: 0.5,
+            velocity: 64,
+            effects: vec![],
+        });
+    }
+    for note in 0..128 {
+        let _ = sender.try_send(Command::Expression {
+            note,
+            rate: 1.01,
+            gain: 1.0,
+        });
+    }
+    engine.process(&[], 1);
+
+    // Ramp the master gain down over the whole run
+    let _ = sender.try_send(Command::Fade(Fade {
+        target: FadeTarget::Master,
+        gain: 0.5,
+        frames: AUDIO_TIME.as_secs() as usize * SAMPLE_RATE,
+    }));
+    engine
+}
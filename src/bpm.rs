@@ -0,0 +1,155 @@
+use crate::config::expand_path;
+use crate::decode;
+use crate::loader;
+use serde_json::{Map, Value};
+use std::path::Path;
+
+/// Samples are taken to be at this rate if their file does not say
+pub const SAMPLE_RATE: usize = 48_000;
+
+/// The onset envelope has one value for each window of this many
+/// samples
+const WINDOW: usize = 512;
+
+/// The range of tempos looked for, in beats per minute.  A tempo
+/// outside it would be found at half or twice itself
+const SLOWEST: f32 = 70.0;
+const FASTEST: f32 = 180.0;
+
+/// A loop cut to a whole number of beats is taken to be exactly that
+/// long if the tempo found is this close, as a fraction
+const SNAP: f32 = 0.03;
+
+/// Estimate the tempo of a loop, in beats per minute.  The loudness
+/// of the loop is taken in windows, and how much it rises from each
+/// window to the next is the onset envelope.  The beat is the lag,
+/// within the range of tempos, at which the envelope best matches
+/// itself.  `data` is interleaved, `channels` samples to a frame at
+/// `sample_rate`, and mixed down to mono first.  None if the loop is
+/// too short or has no onsets
+pub fn estimate(
+    data: &[f32],
+    sample_rate: usize,
+    channels: usize,
+) -> Option<f32> {
+    let channels = channels.max(1);
+    let mono: Vec<f32> = data
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect();
+    let levels: Vec<f32> = mono
+        .chunks(WINDOW)
+        .map(|window| {
+            let power =
+                window.iter().map(|s| s * s).sum::<f32>() / window.len() as f32;
+            10.0 * power.max(1e-12).log10()
+        })
+        .collect();
+    let onsets: Vec<f32> = levels
+        .windows(2)
+        .map(|pair| (pair[1] - pair[0]).max(0.0))
+        .collect();
+    let per_minute = 60.0 * sample_rate as f32 / WINDOW as f32;
+    let shortest = (per_minute / FASTEST).floor() as usize;
+    let longest = (per_minute / SLOWEST).ceil() as usize;
+    if onsets.len() < longest * 2 {
+        return None;
+    }
+
+    // Each lag is scored by the mean product of the envelope with
+    // itself that far on
+    let score = |lag: usize| {
+        let pairs = onsets.len() - lag;
+        onsets
+            .iter()
+            .zip(onsets[lag..].iter())
+            .map(|(a, b)| a * b)
+            .sum::<f32>()
+            / pairs as f32
+    };
+    let scores: Vec<f32> = (shortest - 1..=longest + 1).map(score).collect();
+    let (best, peak) = (1..scores.len() - 1)
+        .map(|i| (i, scores[i]))
+        .max_by(|a, b| a.1.total_cmp(&b.1))?;
+    if peak <= 0.0 {
+        return None;
+    }
+
+    // Between windows the peak is where a parabola through the best
+    // lag and its neighbours is highest
+    let (before, after) = (scores[best - 1], scores[best + 1]);
+    let curve = before - 2.0 * peak + after;
+    let offset = if curve < 0.0 {
+        0.5 * (before - after) / curve
+    } else {
+        0.0
+    };
+    let lag = (best + shortest - 1) as f32 + offset;
+    let bpm = per_minute / lag;
+
+    // Loops are usually cut to whole bars, which gives the tempo more
+    // exactly than the onsets can
+    let minutes = mono.len() as f32 / sample_rate as f32 / 60.0;
+    let beats = (bpm * minutes).round();
+    let exact = beats / minutes;
+    Some(if beats >= 1.0 && (exact - bpm).abs() <= bpm * SNAP {
+        exact
+    } else {
+        bpm
+    })
+}
+
+/// Estimate the tempo of each sample in a configuration file with a
+/// "path", and write it into the file as the sample's "bpm".  If the
+/// file sets no "tempo" the first sample's becomes the tempo.  The
+/// file is rewritten, so its layout changes, and included files are
+/// left alone
+pub fn analyze(config: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = config.parent().unwrap_or(Path::new(""));
+    loader::rewrite(config, |settings| analyzed(settings, dir))
+}
+
+/// Write each sample's estimated tempo into `settings`, a parsed
+/// configuration file in `dir`
+fn analyzed(
+    settings: &mut Map<String, Value>,
+    dir: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut first: Option<f64> = None;
+    if let Some(samples) = settings
+        .get_mut("samples_descr")
+        .and_then(Value::as_array_mut)
+    {
+        for sample in samples.iter_mut().filter_map(Value::as_object_mut) {
+            let Some(path) = sample.get("path").and_then(Value::as_str) else {
+                continue;
+            };
+            let path = expand_path(path, dir)?.to_string_lossy().into_owned();
+            let format = match sample.get("format") {
+                Some(format) => Some(serde_json::from_value(format.clone())?),
+                None => None,
+            };
+            let data = decode::decode(&path, format)
+                .map_err(|err| format!("{path}: {err}"))?;
+            let info = decode::info(&path, format)
+                .map_err(|err| format!("{path}: {err}"))?;
+            let sample_rate =
+                info.sample_rate.map_or(SAMPLE_RATE, |rate| rate as usize);
+            let channels = info.channels.unwrap_or(1);
+            let Some(bpm) = estimate(&data, sample_rate, channels) else {
+                println!("{path}: No tempo found");
+                continue;
+            };
+
+            // More figures than this would be noise
+            let bpm = (bpm as f64 * 100.0).round() / 100.0;
+            println!("{path}: {bpm} BPM");
+            sample.insert("bpm".to_string(), bpm.into());
+            first.get_or_insert(bpm);
+        }
+    }
+    if let Some(bpm) = first {
+        settings.entry("tempo").or_insert(bpm.into());
+    }
+    Ok(())
+}
@@ -0,0 +1,430 @@
+use crate::config::{process_samples_json, Config};
+use crate::fx::EffectDescr;
+use crate::loader;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// The configuration in a bundle
+pub const MANIFEST: &str = "manifest.json";
+
+/// Tar blocks are this long, and headers take one
+const BLOCK: usize = 512;
+
+/// The longest name a tar header has room for
+const MAX_NAME: usize = 100;
+
+/// Pack a configuration and everything it plays into a bundle: an
+/// uncompressed tar file with the configuration as `manifest.json`,
+/// the sample files under "samples/", its deck tracks under
+/// "tracks/", and its script and effect plugins under "scripts/" and
+/// "plugins/".  Included files are merged into the manifest and paths
+/// made relative to the bundle, in its profiles as well, so the
+/// bundle works wherever it is unpacked
+pub fn pack(
+    config: &Path,
+    bundle: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = config.to_string_lossy();
+    let mut manifest: Value = loader::read(config)?;
+    let settings = manifest
+        .as_object_mut()
+        .ok_or(format!("{}: Not a JSON object", config.display()))?;
+    settings.remove("include");
+
+    // Each file goes in once however many samples use it
+    let mut files: BTreeMap<String, String> = BTreeMap::new();
+
+    // A profile's settings replace the file's, so the ones naming
+    // files are taken from the configuration as loaded with it.  Its
+    // samples are merged with the included files' if it has samples
+    // or includes of its own
+    let profiles = settings
+        .get_mut("profiles")
+        .and_then(Value::as_object_mut)
+        .into_iter()
+        .flat_map(|profiles| profiles.iter_mut());
+    for (name, profile) in profiles {
+        let profile = profile.as_object_mut().ok_or(format!(
+            "{}: Profile {name}: Not a JSON object",
+            config.display()
+        ))?;
+        let mut merged = process_samples_json(&path, Some(name))?;
+        let own: Vec<String> = profile.keys().cloned().collect();
+        let sets = |key: &str| {
+            own.iter().any(|own| own == key)
+                || (key == "samples_descr"
+                    && own.iter().any(|own| own == "include"))
+        };
+        profile.remove("include");
+        add_files(&mut merged, &mut files, profile, sets)?;
+    }
+
+    let mut merged = process_samples_json(&path, None)?;
+    add_files(&mut merged, &mut files, settings, |_| true)?;
+
+    let mut out = BufWriter::new(File::create(bundle)?);
+    write_entry(&mut out, MANIFEST, &serde_json::to_vec_pretty(&manifest)?)?;
+    for (path, name) in files.iter() {
+        let data = fs::read(path).map_err(|err| format!("{err}: {path}"))?;
+        write_entry(&mut out, name, &data)?;
+    }
+
+    // The end of the archive is marked by two empty blocks
+    out.write_all(&[0; 2 * BLOCK])?;
+    out.flush()?;
+    eprintln!(
+        "Packed {} samples from {} files into {}",
+        merged.samples_descr.len(),
+        files.len(),
+        bundle.display()
+    );
+    Ok(())
+}
+
+/// Add the files a loaded configuration uses to `files`, by their
+/// names in the bundle, and put the settings that name them into
+/// `settings` with those names.  Only the settings `sets` is true
+/// for are put in
+fn add_files(
+    merged: &mut Config,
+    files: &mut BTreeMap<String, String>,
+    settings: &mut Map<String, Value>,
+    sets: impl Fn(&str) -> bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut add = |path: &str, dir: &str| -> String {
+        let count = files.len();
+        files
+            .entry(path.to_string())
+            .or_insert_with(|| bundle_name(path, dir, count))
+            .clone()
+    };
+    for descr in merged.samples_descr.iter_mut() {
+        if descr.segments.is_empty() {
+            descr.path = add(&descr.path, "samples");
+        } else {
+            descr.path.clear();
+            for segment in descr.segments.iter_mut() {
+                segment.path = add(&segment.path, "samples");
+            }
+        }
+        if let Some(release) = &mut descr.release_sample {
+            release.path = add(&release.path, "samples");
+        }
+    }
+    if let Some(decks) = &mut merged.decks {
+        for track in decks.tracks.iter_mut() {
+            track.path = add(&track.path, "tracks");
+        }
+    }
+    if let Some(script) = &mut merged.script {
+        *script = add(script, "scripts");
+    }
+    if let Some(path) = merged.error_beep.as_mut().and_then(|b| b.path.as_mut())
+    {
+        *path = add(path, "samples");
+    }
+
+    // A plugin is a library for this machine, but goes in so the
+    // bundle has all it needs to play on another like it
+    let effects = merged
+        .samples_descr
+        .iter_mut()
+        .flat_map(|descr| descr.effects.iter_mut())
+        .chain(merged.groups.iter_mut().flat_map(|g| g.effects.iter_mut()));
+    for effect in effects {
+        if let EffectDescr::Plugin { path, .. } = effect {
+            *path = add(path, "plugins");
+        }
+    }
+
+    let values = [
+        (
+            "samples_descr",
+            Some(serde_json::to_value(&merged.samples_descr)?),
+        ),
+        ("groups", Some(serde_json::to_value(&merged.groups)?)),
+        (
+            "decks",
+            merged
+                .decks
+                .as_ref()
+                .map(serde_json::to_value)
+                .transpose()?,
+        ),
+        (
+            "script",
+            merged
+                .script
+                .as_ref()
+                .map(serde_json::to_value)
+                .transpose()?,
+        ),
+        (
+            "error_beep",
+            merged
+                .error_beep
+                .as_ref()
+                .map(serde_json::to_value)
+                .transpose()?,
+        ),
+    ];
+    for (key, value) in values {
+        if let Some(value) = value.filter(|_| sets(key)) {
+            settings.insert(key.to_string(), value);
+        }
+    }
+    Ok(())
+}
+
+/// Unpack a bundle into `dir`, and return the path of its manifest
+pub fn unpack(
+    bundle: &Path,
+    dir: &Path,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut input = BufReader::new(File::open(bundle)?);
+    let mut header = [0; BLOCK];
+    loop {
+        input
+            .read_exact(&mut header)
+            .map_err(|err| format!("{}: {err}", bundle.display()))?;
+        if header.iter().all(|b| *b == 0) {
+            break;
+        }
+        let name = field(&header[..MAX_NAME]);
+        if !checksum_matches(&header) {
+            return Err(
+                format!("{}: {name}: Bad checksum", bundle.display()).into()
+            );
+        }
+        let size = u64::from_str_radix(field(&header[124..136]).trim(), 8)
+            .map_err(|_| format!("{}: {name}: Bad size", bundle.display()))?;
+        let padded = size.div_ceil(BLOCK as u64) * BLOCK as u64;
+
+        // Only files are unpacked, and never outside `dir`.  The data
+        // is copied straight to the file, so a size that is wrong
+        // cannot take all the memory
+        let mut copied = 0;
+        if matches!(header[156], b'0' | 0) {
+            let relative = Path::new(name);
+            if relative
+                .components()
+                .any(|c| !matches!(c, Component::Normal(_)))
+            {
+                return Err(
+                    format!("{}: {name}: Bad path", bundle.display()).into()
+                );
+            }
+            let path = dir.join(relative);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut file = BufWriter::new(File::create(&path)?);
+            copied = io::copy(&mut input.by_ref().take(size), &mut file)?;
+            file.flush()?;
+        }
+        let skipped = io::copy(
+            &mut input.by_ref().take(padded - copied),
+            &mut io::sink(),
+        )?;
+        if copied + skipped < padded {
+            return Err(
+                format!("{}: {name}: Cut short", bundle.display()).into()
+            );
+        }
+    }
+    let manifest = dir.join(MANIFEST);
+    if !manifest.exists() {
+        return Err(format!("{}: No {MANIFEST}", bundle.display()).into());
+    }
+    Ok(manifest)
+}
+
+/// The name a file has in a bundle, in `dir`.  Numbered so files with
+/// the same name from different places do not collide
+fn bundle_name(
+    path: &str,
+    dir: &str,
+    number: usize,
+) -> String {
+    let path = Path::new(path);
+    let name = format!(
+        "{dir}/{number}-{}",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    );
+    if name.len() <= MAX_NAME {
+        return name;
+    }
+    match path.extension() {
+        Some(extension) => {
+            format!("{dir}/{number}.{}", extension.to_string_lossy())
+        },
+        None => format!("{dir}/{number}"),
+    }
+}
+
+/// Write a file into a tar archive, as a ustar header followed by the
+/// data padded to a whole number of blocks
+fn write_entry(
+    out: &mut impl Write,
+    name: &str,
+    data: &[u8],
+) -> std::io::Result<()> {
+    let mut header = [0; BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..107].copy_from_slice(b"0000644");
+    header[108..115].copy_from_slice(b"0000000");
+    header[116..123].copy_from_slice(b"0000000");
+    header[124..135].copy_from_slice(format!("{:011o}", data.len()).as_bytes());
+    header[136..147].copy_from_slice(b"00000000000");
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is worked out with its own field as spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|b| *b as u32).sum();
+    header[148..155].copy_from_slice(format!("{checksum:06o}\0").as_bytes());
+
+    out.write_all(&header)?;
+    out.write_all(data)?;
+    out.write_all(&vec![0; data.len().div_ceil(BLOCK) * BLOCK - data.len()])
+}
+
+/// Whether a tar header's checksum is right: the sum of its bytes,
+/// with its own field as spaces.  Some old tars summed them as signed
+/// bytes, so that is taken too
+fn checksum_matches(header: &[u8; BLOCK]) -> bool {
+    let Ok(given) = u32::from_str_radix(
+        field(&header[148..156]).trim_matches([' ', '\0']),
+        8,
+    ) else {
+        return false;
+    };
+    let spaces = 8 * b' ' as u32;
+    let (unsigned, signed) = header
+        .iter()
+        .enumerate()
+        .filter(|(at, _)| !(148..156).contains(at))
+        .fold((spaces, spaces as i32), |(unsigned, signed), (_, b)| {
+            (unsigned + *b as u32, signed + *b as i8 as i32)
+        });
+    given == unsigned || given as i32 == signed
+}
+
+/// A text field of a tar header, which ends at the first NUL
+fn field(bytes: &[u8]) -> &str {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    std::str::from_utf8(&bytes[..end]).unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory in the temporary directory, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("bundle-{}-{name}", std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A kit with a sample, a deck track, an error beep, and a profile
+    /// with samples of its own, packed into a bundle in `dir`
+    fn kit(dir: &Path) -> PathBuf {
+        let kit = dir.join("kit");
+        fs::create_dir_all(kit.join("songs")).unwrap();
+        for (name, contents) in [
+            ("kick.wav", "kick"),
+            ("snare.wav", "snare"),
+            ("beep.wav", "beep"),
+            ("songs/opener.flac", "opener"),
+        ] {
+            fs::write(kit.join(name), contents).unwrap();
+        }
+        let config = kit.join("config.json");
+        fs::write(
+            &config,
+            r#"{
+                "samples_descr": [{"path": "kick.wav", "note": 36}],
+                "decks": {
+                    "fader_cc": 8,
+                    "tracks": [{"path": "songs/opener.flac", "cue": 24}]
+                },
+                "error_beep": {"path": "beep.wav"},
+                "profiles": {
+                    "quiet": {
+                        "samples_descr": [{"path": "snare.wav", "note": 38}]
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        let bundle = dir.join("kit.qzt");
+        pack(&config, &bundle).unwrap();
+        bundle
+    }
+
+    /// The contents of the file at `path`
+    fn read(path: &str) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new("round-trip");
+        let bundle = kit(&dir.0);
+
+        // Take the kit away, so only the bundle's files can be used
+        fs::remove_dir_all(dir.0.join("kit")).unwrap();
+        let out = dir.0.join("out");
+        let manifest = unpack(&bundle, &out).unwrap();
+        let manifest = manifest.to_string_lossy();
+
+        let config = process_samples_json(&manifest, None).unwrap();
+        let paths: Vec<&str> = config
+            .samples_descr
+            .iter()
+            .map(|d| d.path.as_str())
+            .collect();
+        assert_eq!(paths.len(), 1);
+        assert!(paths[0].starts_with(&*out.to_string_lossy()));
+        assert_eq!(read(paths[0]), "kick");
+        let track = &config.decks.unwrap().tracks[0];
+        assert_eq!(read(&track.path), "opener");
+        let beep = config.error_beep.unwrap().path.unwrap();
+        assert_eq!(read(&beep), "beep");
+
+        let quiet = process_samples_json(&manifest, Some("quiet")).unwrap();
+        assert_eq!(quiet.samples_descr.len(), 1);
+        assert_eq!(quiet.samples_descr[0].note, 38);
+        assert_eq!(read(&quiet.samples_descr[0].path), "snare");
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let dir = TempDir::new("checksum");
+        let bundle = kit(&dir.0);
+        let mut data = fs::read(&bundle).unwrap();
+
+        // The first file's mode, which the checksum covers
+        data[101] = b'7';
+        fs::write(&bundle, &data).unwrap();
+        let err = unpack(&bundle, &dir.0.join("out")).unwrap_err();
+        assert!(err.to_string().contains("Bad checksum"), "{err}");
+    }
+}
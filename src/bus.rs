@@ -0,0 +1,149 @@
+use crate::fx::{Chain, EffectDescr};
+use crate::gain::db_to_gain;
+use crate::smooth::{Curve, Ramp};
+use serde::{Deserialize, Serialize};
+
+/// Frames a group takes to mute or unmute, so it does not click
+const MUTE_FRAMES: usize = 64;
+
+/// A named group of samples with a bus of its own, as a drum sampler
+/// has for the kick, the snare and so on.  The group is turned up or
+/// down by `gain` dB, and silenced if `mute`.  Its samples are mixed
+/// and put through `effects` together.  If it has an `output` its
+/// samples play on that output, unless they say otherwise
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GroupDescr {
+    pub name: String,
+    #[serde(default)]
+    pub gain: f32,
+    #[serde(default)]
+    pub mute: bool,
+    #[serde(default)]
+    pub effects: Vec<EffectDescr>,
+    #[serde(default)]
+    pub output: Option<String>,
+}
+
+/// A group's bus, in the Jack thread
+pub struct Bus {
+    /// The gain set, and whether the group is muted.  The ramp goes to
+    /// the gain, or to silence when muted
+    level: f32,
+    muted: bool,
+    gain: Ramp,
+
+    /// The group's effects, and how many output channels there are
+    descrs: Vec<EffectDescr>,
+    channels: usize,
+
+    /// The group's mix for the frame, for each output channel, and the
+    /// effects for each channel.  Empty if the group has no effects,
+    /// when its samples are mixed straight into the output
+    mix: Vec<f32>,
+    effects: Vec<Chain>,
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self {
+            level: 1.0,
+            muted: false,
+            gain: Ramp::new(1.0),
+            descrs: vec![],
+            channels: 0,
+            mix: vec![],
+            effects: vec![],
+        }
+    }
+}
+
+impl Bus {
+    /// The bus for a group, with `channels` output channels.  Its
+    /// effects are made when the sample rate is set
+    pub fn new(
+        descr: &GroupDescr,
+        channels: usize,
+    ) -> Self {
+        let level = db_to_gain(descr.gain);
+        Self {
+            level,
+            muted: descr.mute,
+            gain: Ramp::new(if descr.mute { 0.0 } else { level }),
+            descrs: descr.effects.clone(),
+            channels,
+            mix: vec![],
+            effects: vec![],
+        }
+    }
+
+    /// Make the effects afresh, for `sample_rate`
+    pub fn set_sample_rate(
+        &mut self,
+        sample_rate: usize,
+    ) {
+        if self.descrs.is_empty() {
+            return;
+        }
+        self.effects = (0..self.channels)
+            .map(|_| Chain::new(&self.descrs, sample_rate))
+            .collect();
+        self.mix = vec![0.0; self.channels];
+    }
+
+    /// The shape of the group's fades
+    pub fn set_curve(
+        &mut self,
+        curve: Curve,
+    ) {
+        self.gain.set_curve(curve);
+    }
+
+    /// Ramp to a gain over `frames` frames.  A muted group stays
+    /// silent until it is unmuted
+    pub fn set_gain(
+        &mut self,
+        gain: f32,
+        frames: usize,
+    ) {
+        self.level = gain;
+        if !self.muted {
+            self.gain.set(gain, frames);
+        }
+    }
+
+    pub fn mute(
+        &mut self,
+        muted: bool,
+    ) {
+        self.muted = muted;
+        let target = if muted { 0.0 } else { self.level };
+        self.gain.set(target, MUTE_FRAMES);
+    }
+
+    /// The gain for the next frame
+    pub fn tick(&mut self) -> f32 {
+        self.gain.tick()
+    }
+
+    /// Where the group's samples are mixed for the frame, if it has
+    /// effects
+    pub fn mix(&mut self) -> Option<&mut [f32]> {
+        (!self.effects.is_empty()).then_some(&mut self.mix[..])
+    }
+
+    /// Put the frame's mix through the effects into `output`
+    pub fn process(
+        &mut self,
+        output: &mut [f32],
+    ) {
+        for ((x, effects), output) in self
+            .mix
+            .iter_mut()
+            .zip(self.effects.iter_mut())
+            .zip(output.iter_mut())
+        {
+            *output += effects.tick(*x);
+            *x = 0.0;
+        }
+    }
+}
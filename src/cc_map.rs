@@ -0,0 +1,120 @@
+use crate::clock::Clock;
+use crate::control::ControlState;
+use crate::engine::{Fade, FadeTarget};
+use crate::gain::db_to_gain;
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// Gains set from a controller ramp over this long, in milliseconds,
+/// so they do not step audibly
+const GAIN_RAMP_MS: usize = 10;
+
+/// How sharply the exponential and logarithmic curves bend
+const CURVE_BEND: f32 = 4.0;
+
+/// What a controller changes.  Gains are in dB, the tempo in beats
+/// per minute, and pan in degrees round the speakers
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "parameter")]
+pub enum Parameter {
+    MasterGain,
+    NoteGain { note: u8 },
+    Tempo,
+    Pan { note: u8 },
+}
+
+/// How the controller's travel is spread over the range.
+/// "exponential" changes slowly at the bottom and quickly at the top,
+/// "logarithmic" the other way round
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Curve {
+    #[default]
+    Linear,
+    Exponential,
+    Logarithmic,
+}
+
+/// A controller mapped to a parameter.  The controller's values 0 to
+/// 127 move the parameter from `min` to `max` along the `curve`
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct CcMapping {
+    pub cc: u8,
+    #[serde(flatten)]
+    pub parameter: Parameter,
+    pub min: f32,
+    pub max: f32,
+    #[serde(default)]
+    pub curve: Curve,
+}
+
+impl CcMapping {
+    /// The parameter's value for a controller value
+    fn value(
+        &self,
+        value: u8,
+    ) -> f32 {
+        let x = value as f32 / 127.0;
+        let x = match self.curve {
+            Curve::Linear => x,
+            Curve::Exponential => {
+                ((CURVE_BEND * x).exp() - 1.0) / (CURVE_BEND.exp() - 1.0)
+            },
+            Curve::Logarithmic => (CURVE_BEND * x).ln_1p() / CURVE_BEND.ln_1p(),
+        };
+        self.min + x * (self.max - self.min)
+    }
+}
+
+/// Sets parameters from controllers as the `cc_map` says
+pub struct CcMap {
+    mappings: Vec<CcMapping>,
+    control: Arc<ControlState>,
+    clock: Arc<Clock>,
+}
+
+impl CcMap {
+    pub fn new(
+        mappings: Vec<CcMapping>,
+        control: Arc<ControlState>,
+        clock: Arc<Clock>,
+    ) -> Self {
+        Self {
+            mappings,
+            control,
+            clock,
+        }
+    }
+
+    pub fn control_change(
+        &self,
+        controller: u8,
+        value: u8,
+    ) {
+        for mapping in self.mappings.iter().filter(|m| m.cc == controller) {
+            let value = mapping.value(value);
+            let target = match mapping.parameter {
+                Parameter::MasterGain => FadeTarget::Master,
+                Parameter::NoteGain { note } => FadeTarget::Note(note),
+                Parameter::Tempo => {
+                    self.clock.set_tempo(value);
+                    continue;
+                },
+                Parameter::Pan { note } => {
+                    if let Err(err) = self.control.pan(note, value) {
+                        eprintln!("{err}: CC {controller}");
+                    }
+                    continue;
+                },
+            };
+            if let Err(err) = self.control.fade(Fade {
+                target,
+                gain: db_to_gain(value),
+                frames: GAIN_RAMP_MS * self.control.sample_rate.current()
+                    / 1000,
+            }) {
+                eprintln!("{err}: CC {controller}");
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn default_hit() -> u32 {
+    1
+}
+
+/// Only the `hit`th of every `every` hits plays, counting from one.
+/// With `"every": 4` the first of every four hits plays, and with
+/// `"hit": 3` as well the third
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct Condition {
+    pub every: u32,
+    #[serde(default = "default_hit")]
+    pub hit: u32,
+}
+
+/// Whether a sample plays when its note is hit: with `probability`,
+/// from 0 to 1, on the hits its `condition` allows
+#[derive(Clone, Copy, Debug)]
+pub struct Chance {
+    pub probability: f32,
+    pub condition: Option<Condition>,
+}
+
+impl Default for Chance {
+    fn default() -> Self {
+        Self {
+            probability: 1.0,
+            condition: None,
+        }
+    }
+}
+
+impl Chance {
+    /// Whether anything is left to chance, so hits need counting or
+    /// dice rolling
+    pub fn is_certain(&self) -> bool {
+        self.probability >= 1.0 && self.condition.is_none()
+    }
+
+    /// Whether the sample plays on `hit`, counting from zero, given a
+    /// roll of the dice from 0 to 1
+    pub fn plays(
+        &self,
+        hit: u32,
+        roll: f32,
+    ) -> bool {
+        let allowed = match self.condition {
+            Some(Condition { every, hit: nth }) => {
+                hit % every.max(1) + 1 == nth
+            },
+            None => true,
+        };
+        allowed && roll < self.probability
+    }
+}
+
+/// A cheap random number generator for rolling the dice.  Seeded from
+/// the clock, so no two runs play alike
+pub struct Dice {
+    state: u32,
+}
+
+impl Default for Dice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Dice {
+    pub fn new() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.subsec_nanos())
+            .unwrap_or(0);
+        Self { state: nanos | 1 }
+    }
+
+    /// A number from 0 up to 1
+    pub fn roll(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        (self.state >> 8) as f32 / (1 << 24) as f32
+    }
+}
@@ -0,0 +1,32 @@
+use serde::{Deserialize, Serialize};
+
+/// A chord played from one note, for guitar chord samples and the
+/// like.  The sample plays once for each of `intervals`, in semitones
+/// from its own pitch, faster or slower to match.  Each voice starts
+/// `strum` milliseconds after the one below it, or, if `strum` is
+/// negative, after the one above it, as a strum up the strings
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChordDescr {
+    pub intervals: Vec<f32>,
+    #[serde(default)]
+    pub strum: f32,
+}
+
+impl ChordDescr {
+    /// The playback rate of each voice, and how long it waits to
+    /// start, in frames at `sample_rate`
+    pub fn voices(
+        &self,
+        sample_rate: usize,
+    ) -> impl Iterator<Item = (f32, usize)> + '_ {
+        let step = self.strum.abs() * sample_rate as f32 / 1000.0;
+        let count = self.intervals.len();
+        self.intervals
+            .iter()
+            .enumerate()
+            .map(move |(i, semitones)| {
+                let place = if self.strum < 0.0 { count - 1 - i } else { i };
+                ((semitones / 12.0).exp2(), (place as f32 * step) as usize)
+            })
+    }
+}
@@ -0,0 +1,195 @@
+use crate::realtime::RealtimeDescr;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// MIDI clock runs at 24 pulses per quarter note, and so does the
+/// internal clock
+pub const PPQN: u64 = 24;
+
+/// The internal tempo, in beats per minute, if the configuration does
+/// not give one
+pub const DEFAULT_TEMPO: f32 = 120.0;
+
+/// If no MIDI clock has arrived for this long the internal clock
+/// takes over
+const MIDI_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How often something happens, in notes
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub enum Rate {
+    #[serde(rename = "1/8")]
+    Eighth,
+    #[serde(rename = "1/16")]
+    Sixteenth,
+    #[serde(rename = "1/32")]
+    ThirtySecond,
+}
+
+impl Rate {
+    /// Clock pulses between each time
+    pub fn pulses(self) -> u64 {
+        match self {
+            Rate::Eighth => PPQN / 2,
+            Rate::Sixteenth => PPQN / 4,
+            Rate::ThirtySecond => PPQN / 8,
+        }
+    }
+}
+
+pub fn default_rate() -> Rate {
+    Rate::Sixteenth
+}
+
+type Listener = Box<dyn Fn(u64) + Send>;
+
+/// A clock for things that keep time, such as note repeat.  It
+/// follows MIDI clock when there is one, and runs at an internal
+/// tempo when there is not.  Listeners are called on each pulse with
+/// the number of pulses since the start, so pulse 0 is the first
+/// downbeat.  MIDI Start resets the count
+pub struct Clock {
+    pulse: AtomicU64,
+
+    /// The internal clock's time between pulses, in nanoseconds
+    period: AtomicU64,
+
+    /// A beat and when it was, from a shared timeline the internal
+    /// clock lines its next pulse up with
+    timeline: Mutex<Option<(f64, Instant)>>,
+    last_midi: Mutex<Option<Instant>>,
+    listeners: Mutex<Vec<Listener>>,
+}
+
+impl Clock {
+    /// Start the internal clock running at `tempo` beats per minute
+    pub fn start(
+        tempo: f32,
+        realtime: Option<RealtimeDescr>,
+    ) -> Arc<Self> {
+        let clock = Arc::new(Self {
+            pulse: AtomicU64::new(0),
+            period: AtomicU64::new(period(tempo)),
+            timeline: Mutex::new(None),
+            last_midi: Mutex::new(None),
+            listeners: Mutex::new(vec![]),
+        });
+        let internal = clock.clone();
+        thread::spawn(move || {
+            if let Some(realtime) = realtime {
+                realtime.apply("Clock");
+            }
+
+            // Sleep to a deadline rather than for the period so the
+            // clock does not drift
+            let mut next = Instant::now();
+            loop {
+                let period = Duration::from_nanos(
+                    internal.period.load(Ordering::Relaxed),
+                );
+                next += period;
+                if let Some((beat, at)) =
+                    internal.timeline.lock().unwrap().take()
+                {
+                    let (pulse, time) = aligned(beat, at, period, next);
+                    internal.pulse.store(pulse, Ordering::Relaxed);
+                    next = time;
+                }
+                thread::sleep(next.saturating_duration_since(Instant::now()));
+                let following_midi = internal
+                    .last_midi
+                    .lock()
+                    .unwrap()
+                    .is_some_and(|last| last.elapsed() < MIDI_TIMEOUT);
+                if !following_midi {
+                    internal.pulse();
+                }
+            }
+        });
+        clock
+    }
+
+    /// Change the internal clock's tempo, in beats per minute
+    pub fn set_tempo(
+        &self,
+        tempo: f32,
+    ) {
+        self.period.store(period(tempo), Ordering::Relaxed);
+    }
+
+    /// Line the internal clock's pulses up with a shared timeline, on
+    /// which it was `beat` at `at`.  The next pulse is counted and
+    /// timed as the timeline has it, so things that keep time match
+    /// the timeline's phase as well as its tempo
+    pub fn align(
+        &self,
+        beat: f64,
+        at: Instant,
+    ) {
+        *self.timeline.lock().unwrap() = Some((beat, at));
+    }
+
+    /// Call `listener` on every pulse
+    pub fn on_pulse(
+        &self,
+        listener: impl Fn(u64) + Send + 'static,
+    ) {
+        self.listeners.lock().unwrap().push(Box::new(listener));
+    }
+
+    /// Follow MIDI Timing Clock and Start messages
+    pub fn midi(
+        &self,
+        status: u8,
+    ) {
+        match status {
+            0xf8 => {
+                *self.last_midi.lock().unwrap() = Some(Instant::now());
+                self.pulse();
+            },
+            0xfa => self.pulse.store(0, Ordering::Relaxed),
+            _ => (),
+        }
+    }
+
+    fn pulse(&self) {
+        let pulse = self.pulse.fetch_add(1, Ordering::Relaxed);
+        for listener in self.listeners.lock().unwrap().iter() {
+            listener(pulse);
+        }
+    }
+}
+
+/// The count and time of the pulse due next, near `next`, on a
+/// timeline where it was `beat` at `at`.  The pulse nearest `next` is
+/// taken so a clock a little ahead of or behind the timeline neither
+/// skips a pulse nor gives one twice.  Pulses before the timeline's
+/// zero are counted as zero
+fn aligned(
+    beat: f64,
+    at: Instant,
+    period: Duration,
+    next: Instant,
+) -> (u64, Instant) {
+    let since = if next >= at {
+        (next - at).as_secs_f64()
+    } else {
+        -(at - next).as_secs_f64()
+    };
+    let period = period.as_secs_f64();
+    let pulse = (beat * PPQN as f64 + since / period).round();
+    let after = pulse - beat * PPQN as f64;
+    let time = if after >= 0.0 {
+        at + Duration::from_secs_f64(after * period)
+    } else {
+        at - Duration::from_secs_f64(-after * period)
+    };
+    (pulse.max(0.0) as u64, time)
+}
+
+/// The time between pulses at `tempo`, in nanoseconds
+fn period(tempo: f32) -> u64 {
+    (60e9 / tempo.max(1.0) as f64 / PPQN as f64) as u64
+}
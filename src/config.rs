@@ -0,0 +1,724 @@
+use crate::arpeggiator::ArpeggiatorDescr;
+use crate::audio::{OutputDescr, Route};
+use crate::bank::BankDescr;
+use crate::beep::ErrorBeepDescr;
+use crate::bus::GroupDescr;
+use crate::cc_map::CcMapping;
+use crate::chance::Condition;
+use crate::chord::ChordDescr;
+use crate::deck::DecksDescr;
+use crate::decode::Format;
+use crate::duck::DuckDescr;
+use crate::eq::EqDescr;
+use crate::feedback::{Color, FeedbackDescr};
+use crate::fx::EffectDescr;
+use crate::keys::KeysDescr;
+use crate::loader;
+use crate::loop_sync::LoopSyncDescr;
+use crate::looper::LooperDescr;
+use crate::metadata;
+use crate::migrate;
+use crate::mirror::MirrorDescr;
+use crate::mpe::MpeDescr;
+use crate::pitch::PitchEnvelopeDescr;
+use crate::realtime::RealtimeDescr;
+use crate::repeat::NoteRepeatDescr;
+use crate::resample::Quality;
+use crate::rtp_midi::RtpMidiDescr;
+use crate::scene::SceneDescr;
+use crate::sequencer::SequencerDescr;
+use crate::sha256;
+use crate::slice::SliceDescr;
+use crate::smooth::Curve;
+use crate::template::Template;
+use crate::velocity::VelocityCurve;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// Each sample is described by a path to an audio file and a MIDI
+/// note, and a `name` it goes by in logs and reports if given.  If
+/// `layer` is set the sample plays along with the other samples on the
+/// note rather than replacing them.  A sample in a `bank` only plays
+/// when that bank is active.
+///
+/// Instead of a path a sample can be a list of `segments` played back
+/// to back.  A WAV file's saved loop and root key are used when the
+/// sample has no segments or no note.  A sample with `slice` is cut
+/// into pieces spread over consecutive notes.  `max_voices` limits how
+/// many voices can play the sample at once.  Voices playing samples
+/// with a higher `priority` are not cut off for samples with a lower
+/// one.  Samples marked `mono` cut each other off.  A sample plays on
+/// all the channels of the first output unless it gives an `output`,
+/// or the `channels` (numbered from 1) of its output it plays on, and
+/// `route` sends it to the cue output instead or as well.  A
+/// sample with `pan` is panned to that angle round its output's
+/// speakers.  If `sha256` is given the file must have that digest, so
+/// a rig can be shown to be playing exactly the audio that was
+/// checked.  `format` forces the file format, for files with the
+/// wrong extension.  `quality` is how the sample is played between
+/// frames when it plays faster or slower, if not the configuration's.
+/// `bpm` is the tempo of a loop, as `analyze` found it.  `color` is
+/// the colour its pad is lit on a controller with feedback.  A sample
+/// with a `probability` below 1 plays on only that fraction of hits,
+/// and one with a `condition` only on some of them.  `effects` is a
+/// chain each voice playing the sample goes through.  A voice playing
+/// the sample fades out after `max_play_seconds`, if it is given.  A
+/// sample with a `chord` plays a voice for each note of the chord.  A
+/// sample with `keys` plays on a range of notes, pitched to each
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SampleDescr {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub path: String,
+    #[serde(default)]
+    pub segments: Vec<SegmentDescr>,
+    pub note: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub layer: bool,
+    #[serde(default)]
+    pub bank: Option<String>,
+    #[serde(default)]
+    pub slice: Option<SliceDescr>,
+    #[serde(default)]
+    pub max_voices: Option<usize>,
+    #[serde(default)]
+    pub priority: u8,
+    #[serde(default)]
+    pub mono: bool,
+    #[serde(default)]
+    pub output: Option<String>,
+    #[serde(default)]
+    pub channels: Vec<usize>,
+    #[serde(default)]
+    pub route: Route,
+    #[serde(default)]
+    pub pan: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<Format>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<Quality>,
+    #[serde(default)]
+    pub count_in: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eq: Option<EqDescr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pitch_envelope: Option<PitchEnvelopeDescr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bpm: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<Color>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probability: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub condition: Option<Condition>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub effects: Vec<EffectDescr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_play_seconds: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chord: Option<ChordDescr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keys: Option<KeysDescr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub release_sample: Option<ReleaseDescr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sync: Option<LoopSyncDescr>,
+}
+
+/// The longest a note can be held back, in milliseconds
+pub const MAX_DELAY_MS: f32 = 100.0;
+
+fn default_release_velocity() -> u8 {
+    64
+}
+
+/// A sample played when its note is released, such as the noise of a
+/// key coming up, as loud as the NoteOff velocity says.  Keyboards
+/// that do not sense release velocity send zero or 64, and zero is
+/// taken as `velocity`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ReleaseDescr {
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<Format>,
+    #[serde(default = "default_release_velocity")]
+    pub velocity: u8,
+}
+
+/// A part of a sample made of segments, such as intro, loop, and
+/// outro.  A segment that loops repeats until NoteOff, and NoteOff
+/// jumps to the last segment
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SegmentDescr {
+    pub path: String,
+    #[serde(default, rename = "loop")]
+    pub looping: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// The programme is initialised with a JSON representation of this.
+/// `include` lists other configuration files (relative to this one)
+/// that are loaded first, so a master file can pull in per-kit
+/// files.  Apart from the samples, settings are only taken from the
+/// top level file.  Its "profiles" are named sets of settings, and
+/// the one selected replaces the file's own settings with its own.
+/// A file's "template" gives notes to its samples by pad name, or in
+/// the template's order
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    pub samples_descr: Vec<SampleDescr>,
+
+    /// The Unix domain socket that listens for control commands
+    #[serde(default)]
+    pub control_socket: Option<String>,
+
+    /// The name, or part of the name, of the MIDI input port to use.
+    /// The first port if not given
+    #[serde(default)]
+    pub midi_input: Option<String>,
+
+    /// Banks of samples selected by MIDI Bank Select and Program
+    /// Change
+    #[serde(default)]
+    pub banks: Vec<BankDescr>,
+
+    /// The banks in the order the show plays them.  Only the active
+    /// bank and the next are kept loaded
+    #[serde(default)]
+    pub setlist: Vec<String>,
+
+    /// Groups of output ports.  If there are none there is a single
+    /// mono output, not connected to anything
+    #[serde(default)]
+    pub outputs: Vec<OutputDescr>,
+
+    /// Jack ports connected to the input, for recording
+    #[serde(default)]
+    pub connect_input: Vec<String>,
+
+    /// The controller that records into the last note played while it
+    /// is held down
+    #[serde(default)]
+    pub record_cc: Option<u8>,
+
+    /// Limits on how many voices play at once, in all and on one
+    /// note
+    #[serde(default)]
+    pub max_voices: Option<usize>,
+    #[serde(default)]
+    pub max_voices_per_note: Option<usize>,
+
+    /// How long any voice can play, in seconds, before it is faded
+    /// out, so a long file mapped to a pad by mistake cannot play for
+    /// an hour.  Samples can set their own
+    #[serde(default)]
+    pub max_play_seconds: Option<f32>,
+
+    /// The shape of every fade: of gains, of groups muting, and of
+    /// voices cut off or stolen
+    #[serde(default)]
+    pub fade_curve: Curve,
+
+    /// Where runtime state is saved between runs
+    #[serde(default)]
+    pub state_file: Option<String>,
+
+    /// The internal tempo in beats per minute, used when there is no
+    /// MIDI clock
+    #[serde(default)]
+    pub tempo: Option<f32>,
+
+    /// Follow the tempo of the Ableton Link session on the network
+    #[serde(default)]
+    pub link: bool,
+
+    /// Controllers mapped to parameters
+    #[serde(default)]
+    pub cc_map: Vec<CcMapping>,
+
+    /// Per note pitch bend and pressure from an MPE controller
+    #[serde(default)]
+    pub mpe: Option<MpeDescr>,
+
+    /// Note repeat, turned on and off by a controller
+    #[serde(default)]
+    pub note_repeat: Option<NoteRepeatDescr>,
+
+    /// A step sequencer playing patterns in time with the clock
+    #[serde(default)]
+    pub sequencer: Option<SequencerDescr>,
+
+    /// An arpeggiator playing the held notes in time with the clock
+    #[serde(default)]
+    pub arpeggiator: Option<ArpeggiatorDescr>,
+
+    /// A looper recording the notes played and playing them back in
+    /// time with the clock
+    #[serde(default)]
+    pub looper: Option<LooperDescr>,
+
+    /// Two decks of long backing tracks, crossfaded by a controller
+    #[serde(default)]
+    pub decks: Option<DecksDescr>,
+
+    /// A sound played when a note cannot play
+    #[serde(default)]
+    pub error_beep: Option<ErrorBeepDescr>,
+
+    /// Take control commands in system exclusive messages, and
+    /// answer them on a MIDI output
+    #[serde(default)]
+    pub sysex: bool,
+
+    /// The master gain at start up, in dB.  Gain restored from the
+    /// state file takes over
+    #[serde(default)]
+    pub gain: Option<f32>,
+
+    /// Sets of gains switched together
+    #[serde(default)]
+    pub scenes: Vec<SceneDescr>,
+
+    /// Groups of samples with buses of their own
+    #[serde(default)]
+    pub groups: Vec<GroupDescr>,
+
+    /// Groups of samples turned down while other groups play
+    #[serde(default)]
+    pub ducking: Vec<DuckDescr>,
+
+    /// The bit depth of the audio interface, to dither the output for
+    #[serde(default)]
+    pub dither: Option<u8>,
+
+    /// How samples are played between frames when they play faster
+    /// or slower, unless they say
+    #[serde(default)]
+    pub quality: Quality,
+
+    /// Realtime priority and CPUs for the clock and MIDI threads
+    #[serde(default)]
+    pub realtime: Option<RealtimeDescr>,
+
+    /// Take MIDI from the network, as RTP-MIDI sessions
+    #[serde(default)]
+    pub rtp_midi: Option<RtpMidiDescr>,
+
+    /// Evens out how the controller's pads respond, as
+    /// `calibrate-velocity` sets it
+    #[serde(default)]
+    pub velocity_curve: Option<VelocityCurve>,
+
+    /// How long to hold back each note, in milliseconds, so pads that
+    /// trigger sooner than others are in time with them, as
+    /// `--calibrate-latency` sets it
+    #[serde(default)]
+    pub delay_ms: BTreeMap<u8, f32>,
+
+    /// Light a grid controller's pads in the samples' colours
+    #[serde(default)]
+    pub feedback: Option<FeedbackDescr>,
+
+    /// Mirror another instance's playing, or be mirrored, for a
+    /// backup machine that can take over
+    #[serde(default)]
+    pub mirror: Option<MirrorDescr>,
+
+    /// The address the HTTP API listens on, such as "0.0.0.0:8080".
+    /// Needs the "http" feature
+    #[serde(default)]
+    pub http: Option<String>,
+
+    /// The token HTTP API requests must have.  Without one the API
+    /// can only be looked at, and only from pages it serves itself
+    #[serde(default)]
+    pub http_token: Option<String>,
+
+    /// Only let the HTTP API look, at the status, events and metrics,
+    /// not play notes or run commands
+    #[serde(default)]
+    pub http_read_only: bool,
+
+    /// A Rhai script with hooks called on note events.  Needs the
+    /// "scripting" feature
+    #[serde(default)]
+    pub script: Option<String>,
+}
+
+/// A `SampleDescr` along with the configuration file it came from, so
+/// conflicts can be reported usefully
+struct Mapping {
+    descr: SampleDescr,
+    source: PathBuf,
+}
+
+/// The configuration file processing.  Included files are loaded in
+/// order before the samples of the including file.  When a note is
+/// assigned more than once the later assignment wins and the conflict
+/// is reported, unless the later sample is a layer.  `profile` is the
+/// name of the profile to use, if any
+pub fn process_samples_json(
+    file_path: &str,
+    profile: Option<&str>,
+) -> Result<Config, Box<dyn std::error::Error>> {
+    let mut mappings: Vec<Mapping> = vec![];
+    let mut loading: Vec<PathBuf> = vec![];
+    let mut config = load_config(
+        Path::new(file_path),
+        profile,
+        &mut loading,
+        &mut mappings,
+    )?;
+    if mappings.len() > loader::MAX_SAMPLES {
+        return Err(format!(
+            "{file_path}: {} samples with its includes, more than the {} \
+             there can be",
+            mappings.len(),
+            loader::MAX_SAMPLES
+        )
+        .into());
+    }
+    config.samples_descr = mappings.into_iter().map(|m| m.descr).collect();
+    Ok(config)
+}
+
+/// Load one configuration file, recursing into its includes.
+/// `loading` is the chain of files currently being loaded, used to
+/// detect include cycles.  Only the top level file has a `profile`
+fn load_config(
+    file_path: &Path,
+    profile: Option<&str>,
+    loading: &mut Vec<PathBuf>,
+    mappings: &mut Vec<Mapping>,
+) -> Result<Config, Box<dyn std::error::Error>> {
+    let canonical = file_path
+        .canonicalize()
+        .map_err(|err| format!("{}: {err}", file_path.display()))?;
+    if loading.contains(&canonical) {
+        return Err(
+            format!("{}: Include cycle detected", file_path.display()).into()
+        );
+    }
+    if loading.len() >= loader::MAX_INCLUDE_DEPTH {
+        return Err(format!(
+            "{}: Included more than {} deep",
+            file_path.display(),
+            loader::MAX_INCLUDE_DEPTH
+        )
+        .into());
+    }
+
+    // Convert JSON, with the profile's settings in place of the
+    // file's
+    let mut json = loader::read(file_path)?;
+    let changes = migrate::migrate(&mut json)
+        .map_err(|err| format!("{}: {err}", file_path.display()))?;
+    for change in changes {
+        eprintln!("{}: {change}", file_path.display());
+    }
+    if let Some(profile) = profile {
+        let settings = json
+            .get("profiles")
+            .and_then(|profiles| profiles.get(profile))
+            .and_then(Value::as_object)
+            .cloned()
+            .ok_or(format!(
+                "{}: No profile called {profile}",
+                file_path.display()
+            ))?;
+        if let Some(json) = json.as_object_mut() {
+            json.extend(settings);
+        }
+    }
+
+    // Included files and samples are relative to the file that refers
+    // to them
+    let dir = file_path.parent().unwrap_or(Path::new(""));
+
+    // A sample with no note, or pad for a template, plays on the root
+    // key saved in its file, if it has one
+    if let Some(samples) =
+        json.get_mut("samples_descr").and_then(Value::as_array_mut)
+    {
+        for sample in samples.iter_mut().filter_map(Value::as_object_mut) {
+            if sample.contains_key("note") || sample.contains_key("pad") {
+                continue;
+            }
+            let Some(path) = sample.get("path").and_then(Value::as_str) else {
+                continue;
+            };
+            if let Some(root) = metadata::read(&expand_path(path, dir)?).root {
+                sample.insert("note".to_string(), root.into());
+            }
+        }
+    }
+
+    // A template gives the file's samples their notes
+    if let Some(template) = json.get("template") {
+        let template: Template = loader::from_value(template.clone())
+            .map_err(|err| format!("{}: {err}", file_path.display()))?;
+        if let Some(samples) =
+            json.get_mut("samples_descr").and_then(Value::as_array_mut)
+        {
+            template
+                .assign(samples)
+                .map_err(|err| format!("{}: {err}", file_path.display()))?;
+        }
+    }
+    let mut config: Config = loader::from_value(json)
+        .map_err(|err| format!("{}: {err}", file_path.display()))?;
+
+    loading.push(canonical);
+    for include in config.include.iter() {
+        load_config(&expand_path(include, dir)?, None, loading, mappings)?;
+    }
+    loading.pop();
+
+    for setting in
+        [
+            &mut config.control_socket,
+            &mut config.state_file,
+            &mut config.script,
+        ]
+        .into_iter()
+        .flatten()
+        .chain(config.error_beep.iter_mut().flat_map(|beep| &mut beep.path))
+        .chain(config.decks.iter_mut().flat_map(|decks| {
+            decks.tracks.iter_mut().map(|track| &mut track.path)
+        }))
+    {
+        *setting = expand_path(setting, dir)?.to_string_lossy().into_owned();
+    }
+
+    for group in config.groups.iter_mut() {
+        for effect in group.effects.iter_mut() {
+            effect.load(dir).map_err(|err| {
+                format!("{}: Group {}: {err}", file_path.display(), group.name)
+            })?;
+        }
+    }
+
+    for output in config.outputs.iter() {
+        let problem = if output.channels == 0 {
+            Some("has no channels")
+        } else if !output.speakers.is_empty()
+            && output.speakers.len() != output.channels
+        {
+            Some("needs a speaker for each channel")
+        } else {
+            None
+        };
+        if let Some(problem) = problem {
+            return Err(format!(
+                "{}: Output {} {problem}",
+                file_path.display(),
+                output.name
+            )
+            .into());
+        }
+    }
+
+    let cues = config.outputs.iter().filter(|output| output.cue).count();
+    let problem = if cues > 1 {
+        Some("outputs: There can only be one cue output")
+    } else if cues > 0 && cues == config.outputs.len() {
+        Some("outputs: There must be an output that is not the cue")
+    } else {
+        None
+    };
+    if let Some(problem) = problem {
+        return Err(format!("{}: {problem}", file_path.display()).into());
+    }
+
+    for (note, delay) in config.delay_ms.iter() {
+        if *note > 127 || !(0.0..=MAX_DELAY_MS).contains(delay) {
+            return Err(format!(
+                "{}: delay_ms for note {note} must be from 0 to {MAX_DELAY_MS}",
+                file_path.display()
+            )
+            .into());
+        }
+    }
+
+    if config.dither.is_some_and(|bits| !(8..=24).contains(&bits)) {
+        return Err(format!(
+            "{}: dither must be from 8 to 24 bits",
+            file_path.display()
+        )
+        .into());
+    }
+
+    for mut descr in config.samples_descr.drain(..) {
+        if descr.path.is_empty() == descr.segments.is_empty() {
+            return Err(format!(
+                "{}: Note {} needs either a path or segments",
+                file_path.display(),
+                descr.note
+            )
+            .into());
+        }
+        for effect in descr.effects.iter_mut() {
+            effect.load(dir).map_err(|err| {
+                format!("{}: Note {}: {err}", file_path.display(), descr.note)
+            })?;
+        }
+        if let Some(slice) = &descr.slice {
+            let problem = if !descr.segments.is_empty() {
+                Some("cannot slice segments")
+            } else if slice.markers.windows(2).any(|m| m[0] >= m[1]) {
+                Some("slice markers must increase")
+            } else if !slice.notes.is_empty()
+                && slice.notes.len() != slice.markers.len()
+            {
+                Some("needs a note for each slice marker")
+            } else if descr.release_sample.is_some() {
+                Some("cannot have a release sample on slices")
+            } else {
+                None
+            };
+            if let Some(problem) = problem {
+                return Err(format!(
+                    "{}: Note {} {problem}",
+                    file_path.display(),
+                    descr.note
+                )
+                .into());
+            }
+        }
+        if let Some(release) = &mut descr.release_sample {
+            release.path = expand_path(&release.path, dir)?
+                .to_string_lossy()
+                .into_owned();
+        }
+        for segment in descr.segments.iter_mut() {
+            segment.path = expand_path(&segment.path, dir)?
+                .to_string_lossy()
+                .into_owned();
+            verify(&segment.path, &segment.sha256)?;
+        }
+        if descr.path.is_empty() {
+            if descr.sha256.is_some() {
+                return Err(format!(
+                    "{}: Note {} needs sha256 on its segments",
+                    file_path.display(),
+                    descr.note
+                )
+                .into());
+            }
+
+            // Samples are reported by their first segment
+            descr.path = descr.segments[0].path.clone();
+        } else {
+            descr.path = expand_path(&descr.path, dir)?
+                .to_string_lossy()
+                .into_owned();
+            verify(&descr.path, &descr.sha256)?;
+        }
+        let mapping = Mapping {
+            descr,
+            source: file_path.to_path_buf(),
+        };
+        if !mapping.descr.layer {
+            mappings.retain(|previous| {
+                if previous.descr.note != mapping.descr.note
+                    || previous.descr.bank != mapping.descr.bank
+                {
+                    return true;
+                }
+                eprintln!(
+                    "Note {}: {} ({}) overrides {} ({})",
+                    mapping.descr.note,
+                    mapping.descr.path,
+                    mapping.source.display(),
+                    previous.descr.path,
+                    previous.source.display(),
+                );
+                false
+            });
+        }
+        mappings.push(mapping);
+    }
+    Ok(config)
+}
+
+/// Check a file has the SHA-256 digest it should, if it has one
+fn verify(
+    path: &str,
+    sha256: &Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(expected) = sha256 else {
+        return Ok(());
+    };
+    let actual = sha256::file(Path::new(path))
+        .map_err(|err| format!("{path}: {err}"))?;
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(format!(
+            "{path}: SHA-256 is {actual}, expected {expected}"
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Expand a leading `~` to the home directory and `$VAR` or `${VAR}`
+/// to the value of the environment variable.  A path that is still
+/// relative after that is resolved against `dir`, the directory of
+/// the configuration file, so configurations do not depend on where
+/// the programme is started from
+pub fn expand_path(
+    path: &str,
+    dir: &Path,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut expanded = String::new();
+    let mut rest = path;
+    if rest == "~" || rest.starts_with("~/") {
+        expanded.push_str(&env::var("HOME").map_err(|_| {
+            format!("{path}: Cannot expand `~`, HOME is not set")
+        })?);
+        rest = &rest[1..];
+    }
+    while let Some(idx) = rest.find('$') {
+        expanded.push_str(&rest[..idx]);
+        rest = &rest[idx + 1..];
+        let (name, tail) = if let Some(braced) = rest.strip_prefix('{') {
+            let end = braced
+                .find('}')
+                .ok_or(format!("{path}: Unterminated `${{`"))?;
+            (&braced[..end], &braced[end + 1..])
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+        if name.is_empty() {
+            // A lone `$` is taken literally
+            expanded.push('$');
+        } else {
+            expanded.push_str(&env::var(name).map_err(|_| {
+                format!("{path}: Environment variable `{name}` is not set")
+            })?);
+        }
+        rest = tail;
+    }
+    expanded.push_str(rest);
+
+    let expanded = PathBuf::from(expanded);
+    Ok(if expanded.is_relative() {
+        dir.join(expanded)
+    } else {
+        expanded
+    })
+}
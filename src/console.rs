@@ -0,0 +1,150 @@
+use crate::control::{self, parse_note, ControlState};
+use crate::decode;
+use crate::engine::{Fade, FadeTarget};
+use crate::gain::db_to_gain;
+use crate::message::Message;
+use crate::metadata;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+/// Gains set from the console ramp over this long, in milliseconds,
+/// so they do not click
+const GAIN_RAMP_MS: usize = 10;
+
+const VELOCITY: u8 = 100;
+
+const HELP: &str =
+    "trigger <note> [velocity]  Play a note, and name what it plays
+release <note>             Release a note
+load <file> [note]         Play a file on a note, by default its root key
+gain <db>                  Set the master gain
+status                     The bank, the voices playing and the stats
+quit                       Exit
+Anything else is a control command";
+
+/// Read commands from stdin, a line at a time, until "quit" or the
+/// end of the input.  Notes are sent to `handler` as if they came from
+/// MIDI.  Commands the console does not know are control commands,
+/// answered as the control socket answers them
+pub fn run(
+    handler: impl Fn(Message),
+    state: &ControlState,
+) {
+    eprintln!("Type \"help\" for commands, \"quit\" to exit");
+    let mut lines = std::io::stdin().lock().lines();
+    loop {
+        eprint!("> ");
+        let _ = std::io::stderr().flush();
+        let Some(Ok(line)) = lines.next() else {
+            break;
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let result = match words.as_slice() {
+            [] => continue,
+            ["quit" | "exit"] => break,
+            ["help"] => Ok(format!("{HELP}\n")),
+            ["trigger", note, rest @ ..] => {
+                trigger(&handler, note, rest.first().copied(), state)
+            },
+            ["release", note] => parse_note(note).map(|note| {
+                handler(Message::NoteOff {
+                    channel: 0,
+                    note,
+                    velocity: 0,
+                });
+                String::new()
+            }),
+            ["load", path, rest @ ..] => {
+                load(path, rest.first().copied(), state)
+            },
+            ["gain", db] => match db.parse::<f32>() {
+                Ok(db) => state
+                    .fade(Fade {
+                        target: FadeTarget::Master,
+                        gain: db_to_gain(db),
+                        frames: GAIN_RAMP_MS * state.sample_rate.current()
+                            / 1000,
+                    })
+                    .map(|()| String::new()),
+                Err(_) => Err(format!("Bad level: {db}")),
+            },
+            ["status"] => Ok(status(state)),
+            _ => control::command(&line, state),
+        };
+        match result {
+            Ok(data) => print!("{data}"),
+            Err(err) => eprintln!("{err}"),
+        }
+    }
+}
+
+fn trigger(
+    handler: &impl Fn(Message),
+    note: &str,
+    velocity: Option<&str>,
+    state: &ControlState,
+) -> Result<String, String> {
+    let note = parse_note(note)?;
+    let velocity = match velocity {
+        Some(velocity) => velocity
+            .parse::<u8>()
+            .ok()
+            .filter(|v| (1..128).contains(v))
+            .ok_or(format!("Bad velocity: {velocity}"))?,
+        None => VELOCITY,
+    };
+    handler(Message::NoteOn {
+        channel: 0,
+        note,
+        velocity,
+    });
+
+    // Name what the note plays in the active bank
+    let active = state.banks.active();
+    let names: Vec<String> = state
+        .player
+        .samples()
+        .iter()
+        .filter(|s| s.note == note && (s.bank.is_none() || s.bank == active))
+        .map(|s| format!("'{}'", s.label()))
+        .collect();
+    Ok(if names.is_empty() {
+        format!("Note {note} has no sample\n")
+    } else {
+        format!("Triggered {}\n", names.join(", "))
+    })
+}
+
+/// Decode a file and put it on a note, where it takes over from the
+/// configured samples as a live recording does
+fn load(
+    path: &str,
+    note: Option<&str>,
+    state: &ControlState,
+) -> Result<String, String> {
+    let note = match note {
+        Some(note) => parse_note(note)?,
+        None => metadata::read(Path::new(path))
+            .root
+            .ok_or(format!("{path}: No root key, give a note"))?,
+    };
+    let data =
+        decode::decode(path, None).map_err(|err| format!("{path}: {err}"))?;
+    let frames = data.len();
+    state.player.record(note, data);
+    Ok(format!("{path}: {frames} frames on note {note}\n"))
+}
+
+fn status(state: &ControlState) -> String {
+    let playing = state
+        .voices
+        .iter()
+        .filter(|voice| voice.length.load(Ordering::Relaxed) > 0)
+        .count();
+    format!(
+        "bank {}\nvoices {playing}\n{}\n",
+        state.banks.active_name().unwrap_or("none"),
+        state.stats.report()
+    )
+}
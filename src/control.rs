@@ -0,0 +1,769 @@
+use crate::audio::{SampleRate, Stats};
+use crate::bank::Banks;
+use crate::decode;
+use crate::engine::{
+    Command, Fade, FadeTarget, Levels, SampleData, VoiceStatus,
+};
+use crate::footprint::{self, Footprint};
+use crate::gain::db_to_gain;
+use crate::looper::Looper;
+use crate::peaks::{Peaks, MAX_BLOCKS};
+use crate::player::Player;
+use crate::record::Recorder;
+use crate::scene::Scenes;
+use crate::sequencer::Sequencer;
+use crate::setlist;
+use crate::state::{self, Snapshot, State};
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::SyncSender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the state is saved if it has changed
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// What the control socket commands need to get at
+pub struct ControlState {
+    pub voices: Arc<Vec<VoiceStatus>>,
+    pub sample_rate: Arc<SampleRate>,
+    pub commands: SyncSender<Command>,
+    pub banks: Arc<Banks>,
+    pub sequencer: Option<Arc<Sequencer>>,
+    pub looper: Option<Arc<Looper>>,
+    pub recorder: Arc<Recorder>,
+    pub stats: Arc<Stats>,
+    pub levels: Arc<Levels>,
+    pub scenes: Scenes,
+    pub player: Arc<Player>,
+    pub peaks: Peaks,
+
+    /// The names of the groups, in the engine's order
+    pub groups: Vec<String>,
+
+    /// A copy of the gains the Jack thread has been told to use
+    pub state: Mutex<State>,
+    pub state_file: PathBuf,
+
+    /// Set when the state changes, until it is saved
+    pub changed: AtomicBool,
+
+    /// Held while saving, so saves do not trip over each other
+    pub saving: Mutex<()>,
+
+    /// Set by the "quit" command, for a sampler running headless
+    pub quit: AtomicBool,
+}
+
+impl ControlState {
+    /// Send a fade to the Jack thread and keep track of the gain it
+    /// will end up at
+    pub fn fade(
+        &self,
+        fade: Fade,
+    ) -> Result<(), String> {
+        {
+            let mut state = self.state.lock().unwrap();
+            match fade.target {
+                FadeTarget::Master => state.master_gain = fade.gain,
+                FadeTarget::Note(note) => {
+                    if fade.gain == 1.0 {
+                        state.note_gains.remove(&note);
+                    } else {
+                        state.note_gains.insert(note, fade.gain);
+                    }
+                },
+                FadeTarget::Channel(channel) => {
+                    if fade.gain == 1.0 {
+                        state.channel_gains.remove(&channel);
+                    } else {
+                        state.channel_gains.insert(channel, fade.gain);
+                    }
+                },
+
+                // Groups start at the gain configured, so even unity
+                // is kept
+                FadeTarget::Group(group) => {
+                    state
+                        .group_gains
+                        .insert(self.groups[group].clone(), fade.gain);
+                },
+                FadeTarget::Bank(bank) => {
+                    let name = self.banks.name(bank).to_string();
+                    if fade.gain == 1.0 {
+                        state.bank_gains.remove(&name);
+                    } else {
+                        state.bank_gains.insert(name, fade.gain);
+                    }
+                },
+            }
+        }
+        self.changed.store(true, Ordering::Relaxed);
+        self.commands
+            .try_send(Command::Fade(fade))
+            .map_err(|err| err.to_string())
+    }
+
+    /// Mute or unmute a group, and keep track of it
+    pub fn mute(
+        &self,
+        group: usize,
+        muted: bool,
+    ) -> Result<(), String> {
+        {
+            let mut state = self.state.lock().unwrap();
+            let name = self.groups[group].clone();
+            if muted {
+                state.muted_groups.insert(name);
+            } else {
+                state.muted_groups.remove(&name);
+            }
+        }
+        self.changed.store(true, Ordering::Relaxed);
+        self.commands
+            .try_send(Command::Mute { group, muted })
+            .map_err(|err| err.to_string())
+    }
+
+    /// Pan the samples on `note`, and keep track of it
+    pub fn pan(
+        &self,
+        note: u8,
+        degrees: f32,
+    ) -> Result<(), String> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if degrees == 0.0 {
+                state.note_pans.remove(&note);
+            } else {
+                state.note_pans.insert(note, degrees);
+            }
+        }
+        self.changed.store(true, Ordering::Relaxed);
+        self.commands
+            .try_send(Command::Pan { note, degrees })
+            .map_err(|err| err.to_string())
+    }
+
+    /// Keep the mixer as it is as the snapshot called `name`,
+    /// replacing any snapshot of that name
+    pub fn save_snapshot(
+        &self,
+        name: &str,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        state.bank = self.banks.active_name().map(String::from);
+        let snapshot = state.snapshot();
+        state.snapshots.insert(name.to_string(), snapshot);
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    /// Fade the mixer to the snapshot called `name` over `frames`
+    pub fn recall_snapshot(
+        &self,
+        name: &str,
+        frames: usize,
+    ) -> Result<(), String> {
+        let snapshot = self
+            .state
+            .lock()
+            .unwrap()
+            .snapshots
+            .get(name)
+            .cloned()
+            .ok_or(format!("No snapshot called {name}"))?;
+        self.apply(&snapshot, frames)
+    }
+
+    /// Fade the mixer to `snapshot` over `frames`.  Notes and channels
+    /// it does not have go back to unity gain, and notes it does not
+    /// pan go back to straight
+    pub fn apply(
+        &self,
+        snapshot: &Snapshot,
+        frames: usize,
+    ) -> Result<(), String> {
+        let state = self.state.lock().unwrap().snapshot();
+        let fade = |target, gain| {
+            self.fade(Fade {
+                target,
+                gain,
+                frames,
+            })
+        };
+        fade(FadeTarget::Master, snapshot.master_gain)?;
+        for note in state.note_gains.keys() {
+            if !snapshot.note_gains.contains_key(note) {
+                fade(FadeTarget::Note(*note), 1.0)?;
+            }
+        }
+        for (note, gain) in snapshot.note_gains.iter() {
+            fade(FadeTarget::Note(*note), *gain)?;
+        }
+        for channel in state.channel_gains.keys() {
+            if !snapshot.channel_gains.contains_key(channel) {
+                fade(FadeTarget::Channel(*channel), 1.0)?;
+            }
+        }
+        for (channel, gain) in snapshot.channel_gains.iter() {
+            fade(FadeTarget::Channel(*channel), *gain)?;
+        }
+        for (name, gain) in snapshot.group_gains.iter() {
+            if let Ok(group) = self.group(name) {
+                fade(FadeTarget::Group(group), *gain)?;
+            }
+        }
+        for (group, name) in self.groups.iter().enumerate() {
+            self.mute(group, snapshot.muted_groups.contains(name))?;
+        }
+        for name in state.bank_gains.keys() {
+            if let (Some(bank), false) = (
+                self.banks.index(name),
+                snapshot.bank_gains.contains_key(name),
+            ) {
+                fade(FadeTarget::Bank(bank), 1.0)?;
+            }
+        }
+        for (name, gain) in snapshot.bank_gains.iter() {
+            if let Some(bank) = self.banks.index(name) {
+                fade(FadeTarget::Bank(bank), *gain)?;
+            }
+        }
+        for note in state.note_pans.keys() {
+            if !snapshot.note_pans.contains_key(note) {
+                self.pan(*note, 0.0)?;
+            }
+        }
+        for (note, degrees) in snapshot.note_pans.iter() {
+            self.pan(*note, *degrees)?;
+        }
+        if let Some(bank) = &snapshot.bank {
+            self.banks.select(bank)?;
+        }
+        Ok(())
+    }
+
+    /// The index of the group called `name`
+    pub fn group(
+        &self,
+        name: &str,
+    ) -> Result<usize, String> {
+        self.groups
+            .iter()
+            .position(|group| group == name)
+            .ok_or(format!("No group called {name}"))
+    }
+
+    pub fn save_state(&self) -> Result<(), String> {
+        let _saving = self.saving.lock().unwrap();
+        self.changed.store(false, Ordering::Relaxed);
+        let state = {
+            let mut state = self.state.lock().unwrap();
+            state.bank = self.banks.active_name().map(String::from);
+            if let Some(sequencer) = &self.sequencer {
+                state.patterns = sequencer.patterns();
+            }
+            state.clone()
+        };
+        state::save(&self.state_file, &state).map_err(|err| {
+            format!("{err}: Saving state to {}", self.state_file.display())
+        })
+    }
+
+    /// Whether the state has changed since it was saved.  The bank
+    /// can change from MIDI, so it is compared with the saved one
+    fn unsaved(&self) -> bool {
+        self.changed.load(Ordering::Relaxed)
+            || self.state.lock().unwrap().bank.as_deref()
+                != self.banks.active_name()
+    }
+}
+
+/// Save the state soon after it changes, so a crash loses little
+pub fn autosave(state: Arc<ControlState>) {
+    thread::spawn(move || loop {
+        thread::sleep(AUTOSAVE_INTERVAL);
+        if state.unsaved() {
+            if let Err(err) = state.save_state() {
+                eprintln!("{err}");
+            }
+        }
+    });
+}
+
+/// Where the control socket goes if the configuration does not say
+pub fn default_control_socket() -> PathBuf {
+    env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir)
+        .join("midi_sample_qzt.sock")
+}
+
+/// Answer a command from the control socket
+pub fn command(
+    command: &str,
+    state: &ControlState,
+) -> Result<String, String> {
+    let words: Vec<&str> = command.split_whitespace().collect();
+    match words.as_slice() {
+        // One line for each voice that is playing: voice, note,
+        // position and length in frames, seconds remaining, and the
+        // sample
+        ["voices"] => {
+            let mut result = String::new();
+            for (i, voice) in state.voices.iter().enumerate() {
+                let played = voice.position.load(Ordering::Relaxed);
+                let length = voice.length.load(Ordering::Relaxed);
+                if length > 0 {
+                    let note = voice.note.load(Ordering::Relaxed);
+                    let sample = voice.sample.load(Ordering::Relaxed);
+                    let remaining = (length - played) as f64
+                        / state.sample_rate.samples() as f64;
+                    result += &format!(
+                        "{i} {note} {played} {length} {remaining:.3} {sample}\n"
+                    );
+                }
+            }
+            Ok(result)
+        },
+
+        // One line for each sample: its number, note, length in
+        // frames, and name or path
+        ["samples"] => Ok(state
+            .player
+            .samples()
+            .iter()
+            .enumerate()
+            .map(|(i, sample)| {
+                format!(
+                    "{i} {} {} {}\n",
+                    sample.note,
+                    sample.data.len(),
+                    sample.label()
+                )
+            })
+            .collect()),
+
+        // The lowest and highest value in each of a number of blocks
+        // of a sample, one block a line, to draw its waveform
+        ["peaks", sample, blocks] => {
+            let index: usize = sample
+                .parse()
+                .map_err(|_| format!("Bad sample: {sample}"))?;
+            let blocks: usize = blocks
+                .parse()
+                .ok()
+                .filter(|b| (1..=MAX_BLOCKS).contains(b))
+                .ok_or(format!("Bad number of blocks: {blocks}"))?;
+            let samples = state.player.samples();
+            let data =
+                samples.get(index).ok_or(format!("No sample {index}"))?;
+            Ok(state
+                .peaks
+                .get(index, data, blocks)
+                .iter()
+                .map(|(min, max)| format!("{min:.4} {max:.4}\n"))
+                .collect())
+        },
+
+        // Ramp the gain of the whole output, of the sample on a note,
+        // of a group, or of a bank, to a level in dB over a time in
+        // milliseconds
+        ["fade", "bank", name, db, ms] => {
+            let bank = state
+                .banks
+                .index(name)
+                .ok_or(format!("Bad fade target: {name}"))?;
+            state.fade(Fade {
+                target: FadeTarget::Bank(bank),
+                gain: parse_level(db)?,
+                frames: ms_to_frames(ms, state.sample_rate.current())?,
+            })?;
+            Ok(String::new())
+        },
+        ["fade", target, db, ms] => {
+            let target = match *target {
+                "master" => FadeTarget::Master,
+                note if note.starts_with(|c: char| c.is_ascii_digit()) => {
+                    FadeTarget::Note(
+                        note.parse::<u8>()
+                            .ok()
+                            .filter(|n| *n < 128)
+                            .ok_or(format!("Bad fade target: {note}"))?,
+                    )
+                },
+                name => FadeTarget::Group(
+                    state
+                        .group(name)
+                        .map_err(|_| format!("Bad fade target: {name}"))?,
+                ),
+            };
+            state.fade(Fade {
+                target,
+                gain: parse_level(db)?,
+                frames: ms_to_frames(ms, state.sample_rate.current())?,
+            })?;
+            Ok(String::new())
+        },
+
+        // One line for each group: its name, its gain in dB, and
+        // whether it is muted
+        ["groups"] => {
+            let saved = state.state.lock().unwrap();
+            Ok(state
+                .groups
+                .iter()
+                .map(|name| {
+                    let gain = saved.group_gains.get(name).copied();
+                    let muted = saved.muted_groups.contains(name);
+                    format!(
+                        "{name} {:.1}{}\n",
+                        20.0 * gain.unwrap_or(1.0).log10(),
+                        if muted { " muted" } else { "" }
+                    )
+                })
+                .collect())
+        },
+        ["group", name, "mute"] => {
+            state.mute(state.group(name)?, true)?;
+            Ok(String::new())
+        },
+        ["group", name, "unmute"] => {
+            state.mute(state.group(name)?, false)?;
+            Ok(String::new())
+        },
+
+        // Report the scene selected last, or fade to another
+        ["scene"] => Ok(state.scenes.active().unwrap_or_default()),
+        ["scene", name] => {
+            state.scenes.select(name, state)?;
+            Ok(String::new())
+        },
+
+        // Keep the mixer as a snapshot, list the snapshots, or fade
+        // to one, straight away or over a time in milliseconds
+        ["snapshot", "save", name] => {
+            state.save_snapshot(name);
+            Ok(String::new())
+        },
+        ["snapshot", "recall", name, ms @ ..] if ms.len() <= 1 => {
+            let frames = match ms.first() {
+                Some(ms) => ms_to_frames(ms, state.sample_rate.current())?,
+                None => 0,
+            };
+            state.recall_snapshot(name, frames)?;
+            Ok(String::new())
+        },
+        ["snapshots"] => Ok(state
+            .state
+            .lock()
+            .unwrap()
+            .snapshots
+            .keys()
+            .map(|name| format!("{name}\n"))
+            .collect()),
+
+        // Report or change the active bank
+        ["bank"] => Ok(state.banks.active_name().unwrap_or("").to_string()),
+        ["bank", name] => {
+            state.banks.select(name)?;
+            state.changed.store(true, Ordering::Relaxed);
+            Ok(String::new())
+        },
+
+        // Start and stop the sequencer, and report if it is playing
+        ["sequencer", rest @ ..] => {
+            let sequencer = state.sequencer.as_ref().ok_or("No sequencer")?;
+            match rest {
+                [] => (),
+                ["start"] => sequencer.play(true),
+                ["stop"] => sequencer.play(false),
+                _ => return Err(format!("Unknown command: {command}")),
+            }
+            Ok(if sequencer.playing() {
+                "playing"
+            } else {
+                "stopped"
+            }
+            .to_string())
+        },
+
+        // Record a new loop, overdub onto it or clear it, and report
+        // what the looper is doing and how many notes the loop has
+        ["looper", rest @ ..] => {
+            let looper = state.looper.as_ref().ok_or("No looper")?;
+            match rest {
+                [] => (),
+                ["record"] => looper.record(),
+                ["overdub", "on"] => looper.overdub(true)?,
+                ["overdub", "off"] => looper.overdub(false)?,
+                ["clear"] => looper.clear(),
+                _ => return Err(format!("Unknown command: {command}")),
+            }
+            Ok(looper.describe())
+        },
+
+        // Report the sequencer's patterns, or change the steps for a
+        // note
+        ["pattern"] => {
+            Ok(state.sequencer.as_ref().ok_or("No sequencer")?.describe())
+        },
+        ["pattern", note, steps @ ..] => {
+            let note = note
+                .parse::<u8>()
+                .ok()
+                .filter(|n| *n < 128)
+                .ok_or(format!("Bad note: {note}"))?;
+            state
+                .sequencer
+                .as_ref()
+                .ok_or("No sequencer")?
+                .set_track(note, &steps.concat())?;
+            state.changed.store(true, Ordering::Relaxed);
+            Ok(String::new())
+        },
+
+        // Record the input into a new sample on a note, and stop
+        // recording
+        ["record", "stop"] => {
+            let (note, seconds) = state.recorder.stop()?;
+            Ok(format!("{note} {seconds:.3}"))
+        },
+        // Play and release notes, so the sampler can be played with no
+        // controller connected
+        ["trigger", note, rest @ ..] if rest.len() <= 1 => {
+            let note = parse_note(note)?;
+            let velocity = match rest.first() {
+                Some(velocity) => velocity
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|v| (1..128).contains(v))
+                    .ok_or(format!("Bad velocity: {velocity}"))?,
+                None => 127,
+            };
+            state.player.note_on(note, velocity);
+            Ok(String::new())
+        },
+        ["release", note] => {
+            state.player.note_off(parse_note(note)?, 0);
+            Ok(String::new())
+        },
+
+        ["record", note] => {
+            state.recorder.start(parse_note(note)?)?;
+            Ok(String::new())
+        },
+
+        // Decode a file in the background and put it in place of the
+        // sample a note plays when it is ready
+        ["replace", note, path @ ..] if !path.is_empty() => {
+            let note = note
+                .parse::<u8>()
+                .ok()
+                .filter(|n| *n < 128)
+                .ok_or(format!("Bad note: {note}"))?;
+            let path = path.join(" ");
+            let sample = state
+                .player
+                .sample_on(note)
+                .ok_or(format!("Note {note} has no sample"))?;
+            if !Path::new(&path).exists() {
+                return Err(format!("{path}: No such file"));
+            }
+            let player = state.player.clone();
+            thread::spawn(move || {
+                let started = Instant::now();
+                let (data, segments) = match setlist::load(&path, None, &[]) {
+                    Ok(loaded) => loaded,
+                    Err(err) => {
+                        eprintln!("{err}");
+                        return;
+                    },
+                };
+                let old = player.samples()[sample].clone();
+                let replaced = player.replace(
+                    sample,
+                    SampleData {
+                        path: path.clone(),
+                        source: decode::info(&path, None).unwrap_or_default(),
+                        ..old.with_data(data, segments)
+                    },
+                );
+                eprintln!(
+                    "Note {note}: {path} in place of {} in {:.3}s",
+                    replaced.label(),
+                    started.elapsed().as_secs_f64()
+                );
+            });
+            Ok(String::new())
+        },
+
+        // How the audio processing is keeping up
+        ["stats"] => Ok(state.stats.report()),
+
+        // One line for each sample loaded: its number, bank, seconds,
+        // bytes, sample rate and name or path, then a line for each
+        // bank's totals and one for all of them
+        ["memory"] => {
+            let mut result = String::new();
+            let mut banks: BTreeMap<Option<usize>, Footprint> = BTreeMap::new();
+            for (i, sample) in state.player.samples().iter().enumerate() {
+                let footprint = Footprint {
+                    samples: 1,
+                    ..footprint::measure(sample.data.len(), &sample.source)
+                };
+                let bank = sample.bank.map_or("-", |b| state.banks.name(b));
+                let source = sample.source;
+                result += &format!(
+                    "{i} {bank} {:.3} {} {} {} {}\n",
+                    footprint.seconds,
+                    footprint.bytes,
+                    source
+                        .sample_rate
+                        .map_or("?".to_string(), |r| r.to_string()),
+                    source.channels.map_or("?".to_string(), |c| c.to_string()),
+                    sample.label()
+                );
+                banks.entry(sample.bank).or_default().add(&footprint);
+            }
+            let mut total = Footprint::default();
+            for (bank, footprint) in banks.iter() {
+                total.add(footprint);
+                let bank = bank.map_or("-", |b| state.banks.name(b));
+                result += &format!(
+                    "bank {bank} {} {:.3} {}\n",
+                    footprint.samples, footprint.seconds, footprint.bytes
+                );
+            }
+            result += &format!(
+                "total {} {:.3} {}\n",
+                total.samples, total.seconds, total.bytes
+            );
+            Ok(result)
+        },
+
+        // The level of each output channel, in dB
+        ["levels"] => Ok(state
+            .levels
+            .peaks()
+            .iter()
+            .enumerate()
+            .map(|(channel, peak)| {
+                format!("{} {:.1}\n", channel + 1, 20.0 * peak.log10())
+            })
+            .collect()),
+
+        // Save the runtime state now rather than waiting for exit
+        ["save"] => {
+            state.save_state()?;
+            Ok(String::new())
+        },
+        ["quit"] => {
+            state.quit.store(true, Ordering::Relaxed);
+            Ok(String::new())
+        },
+        _ => Err(format!("Unknown command: {command}")),
+    }
+}
+
+/// A note number, 0 to 127
+pub fn parse_note(note: &str) -> Result<u8, String> {
+    note.parse::<u8>()
+        .ok()
+        .filter(|n| *n < 128)
+        .ok_or(format!("Bad note: {note}"))
+}
+
+/// A level in dB, as a gain.  `-inf` is silence, but any other level
+/// that is not a number would leave the gain meaningless
+fn parse_level(db: &str) -> Result<f32, String> {
+    db.parse::<f32>()
+        .ok()
+        .map(db_to_gain)
+        .filter(|gain| gain.is_finite())
+        .ok_or(format!("Bad level: {db}"))
+}
+
+/// A time in milliseconds, as frames at `sample_rate`
+fn ms_to_frames(
+    ms: &str,
+    sample_rate: usize,
+) -> Result<usize, String> {
+    ms.parse::<usize>()
+        .ok()
+        .and_then(|ms| ms.checked_mul(sample_rate))
+        .map(|frames| frames / 1000)
+        .ok_or(format!("Bad time: {ms}"))
+}
+
+/// The control socket.  Clients connect to a Unix domain socket and
+/// send one command per line.  Each command is answered with zero or
+/// more lines of data followed by a line "ok", or by a single line
+/// "error <description>".
+///
+/// `handle` is called, on the connection's thread, for each command
+/// and returns the data lines (without the trailing "ok")
+pub fn listen<F>(
+    path: &Path,
+    handle: F,
+) -> std::io::Result<()>
+where
+    F: Fn(&str) -> Result<String, String> + Send + Sync + 'static,
+{
+    // A socket left behind by an earlier run stops `bind` working
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    let handle = Arc::new(handle);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let handle = handle.clone();
+                    thread::spawn(move || {
+                        if let Err(err) = serve(stream, handle.as_ref()) {
+                            eprintln!("Control connection: {err}");
+                        }
+                    });
+                },
+                Err(err) => eprintln!("Control socket: {err}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Answer commands on one connection until the client hangs up
+fn serve<F>(
+    stream: UnixStream,
+    handle: &F,
+) -> std::io::Result<()>
+where
+    F: Fn(&str) -> Result<String, String>,
+{
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let command = line.trim();
+        if command.is_empty() {
+            continue;
+        }
+        match handle(command) {
+            Ok(data) => {
+                writer.write_all(data.as_bytes())?;
+                if !data.is_empty() && !data.ends_with('\n') {
+                    writer.write_all(b"\n")?;
+                }
+                writer.write_all(b"ok\n")?;
+            },
+            Err(err) => writeln!(writer, "error {err}")?,
+        }
+    }
+    Ok(())
+}
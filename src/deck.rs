@@ -0,0 +1,188 @@
+use crate::decode::{self, Format};
+use crate::engine::{Command, SampleData};
+use crate::player::Player;
+use crate::setlist;
+use crate::smooth::Curve;
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::SyncSender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+/// The names of the decks, for the log
+const NAMES: [&str; 2] = ["A", "B"];
+
+fn default_curve() -> Curve {
+    Curve::EqualPower
+}
+
+/// A song for the decks.  `cue` is the note that loads it
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TrackDescr {
+    pub path: String,
+    pub cue: u8,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub format: Option<Format>,
+}
+
+/// Two decks for long backing tracks, and a crossfader between them.
+/// A track's cue note loads it, in the background, onto the deck the
+/// crossfader is furthest from.  `fader_cc` is the crossfader: deck A
+/// at 0 and deck B at 127, following `curve`.  A deck starts its
+/// track from the top when the crossfader moves towards it, and stops
+/// when the crossfader reaches the other end
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DecksDescr {
+    pub tracks: Vec<TrackDescr>,
+    pub fader_cc: u8,
+    #[serde(default = "default_curve")]
+    pub curve: Curve,
+}
+
+/// The sample a deck plays, empty until a track is loaded onto it
+pub fn slot(deck: usize) -> SampleData {
+    SampleData {
+        path: format!("deck-{}", NAMES[deck].to_lowercase()),
+        max_voices: Some(1),
+        priority: u8::MAX,
+        deck: Some(deck),
+        ..Default::default()
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Where the crossfader is, from 0 for deck A to 1 for deck B
+    fader: f32,
+
+    /// The track loaded on each deck, and whether it is playing
+    loaded: [Option<usize>; 2],
+    playing: [bool; 2],
+}
+
+pub struct Decks {
+    descr: DecksDescr,
+
+    /// The sample each deck plays
+    samples: [usize; 2],
+    player: Arc<Player>,
+    commands: SyncSender<Command>,
+    state: Arc<Mutex<State>>,
+}
+
+impl Decks {
+    pub fn new(
+        descr: DecksDescr,
+        samples: [usize; 2],
+        player: Arc<Player>,
+        commands: SyncSender<Command>,
+    ) -> Self {
+        Self {
+            descr,
+            samples,
+            player,
+            commands,
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    /// The gain of each deck with the crossfader at `fader`
+    fn gains(
+        &self,
+        fader: f32,
+    ) -> [f32; 2] {
+        [
+            self.descr.curve.shape(1.0 - fader),
+            self.descr.curve.shape(fader),
+        ]
+    }
+
+    /// Load the track cued by `note`, if there is one, and say if
+    /// there was.  The deck it goes on stops
+    pub fn cue(
+        &self,
+        note: u8,
+    ) -> bool {
+        let Some(track) = self.descr.tracks.iter().position(|t| t.cue == note)
+        else {
+            return false;
+        };
+        let deck = match self.state.lock().unwrap().fader < 0.5 {
+            true => 1,
+            false => 0,
+        };
+        let descr = self.descr.tracks[track].clone();
+        let sample = self.samples[deck];
+        let player = self.player.clone();
+        let state = self.state.clone();
+        thread::spawn(move || {
+            let started = Instant::now();
+            let (data, segments) =
+                match setlist::load(&descr.path, descr.format, &[]) {
+                    Ok(loaded) => loaded,
+                    Err(err) => {
+                        eprintln!("{err}");
+                        return;
+                    },
+                };
+            let source =
+                decode::info(&descr.path, descr.format).unwrap_or_default();
+            {
+                let mut state = state.lock().unwrap();
+                state.loaded[deck] = Some(track);
+                state.playing[deck] = false;
+                player.stop(sample);
+                let old = player.samples()[sample].clone();
+                player.replace(
+                    sample,
+                    SampleData {
+                        source,
+                        ..old.with_data(data, segments)
+                    },
+                );
+            }
+            eprintln!(
+                "Deck {}: {} loaded in {:.3}s",
+                NAMES[deck],
+                descr.name.as_deref().unwrap_or(&descr.path),
+                started.elapsed().as_secs_f64()
+            );
+        });
+        true
+    }
+
+    /// Move the crossfader, starting the deck it moves towards and
+    /// stopping the one it leaves behind at the end
+    pub fn control_change(
+        &self,
+        controller: u8,
+        value: u8,
+    ) {
+        if controller != self.descr.fader_cc {
+            return;
+        }
+        let fader = value as f32 / 127.0;
+        let gains = self.gains(fader);
+        if let Err(err) = self.commands.try_send(Command::Crossfade { gains }) {
+            eprintln!("{err}: Dropped command");
+        }
+        let mut state = self.state.lock().unwrap();
+        let towards = [fader < state.fader, fader > state.fader];
+        state.fader = fader;
+        for deck in 0..2 {
+            if gains[deck] == 0.0 && state.playing[deck] {
+                self.player.stop(self.samples[deck]);
+                state.playing[deck] = false;
+            } else if towards[deck]
+                && state.loaded[deck].is_some()
+                && !state.playing[deck]
+            {
+                self.player.play(self.samples[deck]);
+                state.playing[deck] = true;
+                eprintln!("Deck {} playing", NAMES[deck]);
+            }
+        }
+    }
+}
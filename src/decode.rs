@@ -0,0 +1,307 @@
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use symphonia::core::audio::{SampleBuffer, SignalSpec};
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatOptions, FormatReader, Track};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+#[cfg(feature = "flac")]
+use symphonia::default::formats::FlacReader;
+#[cfg(feature = "mkv")]
+use symphonia::default::formats::MkvReader;
+#[cfg(feature = "mp3")]
+use symphonia::default::formats::MpaReader;
+#[cfg(feature = "ogg")]
+use symphonia::default::formats::OggReader;
+#[cfg(feature = "wav")]
+use symphonia::default::formats::WavReader;
+#[cfg(feature = "aac")]
+use symphonia::default::formats::{AdtsReader, IsoMp4Reader};
+
+/// How long has been spent decoding, in microseconds
+static DECODE_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// How long has been spent decoding files altogether
+pub fn decode_time() -> Duration {
+    Duration::from_micros(DECODE_TIME.load(Ordering::Relaxed))
+}
+
+/// The file formats that can be read, if the cargo feature for them
+/// is enabled.  Normally the format is found from the file's
+/// extension and contents, but it can be forced for a file with the
+/// wrong extension or none
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Wav,
+    Flac,
+    Ogg,
+    Mkv,
+    Mp3,
+    Aac,
+    Mp4,
+}
+
+impl Format {
+    /// The format a file extension usually means
+    fn from_extension(extension: &str) -> Option<Self> {
+        Some(match extension.to_ascii_lowercase().as_str() {
+            "wav" | "wave" => Self::Wav,
+            "flac" => Self::Flac,
+            "ogg" | "oga" => Self::Ogg,
+            "mkv" | "mka" | "webm" => Self::Mkv,
+            "mp3" => Self::Mp3,
+            "aac" => Self::Aac,
+            "mp4" | "m4a" => Self::Mp4,
+            _ => return None,
+        })
+    }
+
+    /// The cargo feature that builds in support for the format
+    fn feature(self) -> &'static str {
+        match self {
+            Self::Wav => "wav",
+            Self::Flac => "flac",
+            Self::Ogg => "ogg",
+            Self::Mkv => "mkv",
+            Self::Mp3 => "mp3",
+            Self::Aac | Self::Mp4 => "aac",
+        }
+    }
+
+    fn built(self) -> bool {
+        match self {
+            Self::Wav => cfg!(feature = "wav"),
+            Self::Flac => cfg!(feature = "flac"),
+            Self::Ogg => cfg!(feature = "ogg"),
+            Self::Mkv => cfg!(feature = "mkv"),
+            Self::Mp3 => cfg!(feature = "mp3"),
+            Self::Aac | Self::Mp4 => cfg!(feature = "aac"),
+        }
+    }
+
+    /// Says which feature to build with to read the format
+    fn missing(self) -> String {
+        format!(
+            "Built without {self:?} support, rebuild with `--features {}`",
+            self.feature()
+        )
+    }
+
+    /// A reader for the format.  Built with no formats there is none,
+    /// and the stream and options go unused
+    #[cfg_attr(
+        not(any(
+            feature = "wav",
+            feature = "flac",
+            feature = "ogg",
+            feature = "mkv",
+            feature = "mp3",
+            feature = "aac"
+        )),
+        allow(unused_variables)
+    )]
+    fn reader(
+        self,
+        mss: MediaSourceStream,
+        options: &FormatOptions,
+    ) -> Result<Box<dyn FormatReader>, Box<dyn std::error::Error>> {
+        match self {
+            #[cfg(feature = "wav")]
+            Self::Wav => Ok(Box::new(WavReader::try_new(mss, options)?)),
+            #[cfg(feature = "flac")]
+            Self::Flac => Ok(Box::new(FlacReader::try_new(mss, options)?)),
+            #[cfg(feature = "ogg")]
+            Self::Ogg => Ok(Box::new(OggReader::try_new(mss, options)?)),
+            #[cfg(feature = "mkv")]
+            Self::Mkv => Ok(Box::new(MkvReader::try_new(mss, options)?)),
+            #[cfg(feature = "mp3")]
+            Self::Mp3 => Ok(Box::new(MpaReader::try_new(mss, options)?)),
+            #[cfg(feature = "aac")]
+            Self::Aac => Ok(Box::new(AdtsReader::try_new(mss, options)?)),
+            #[cfg(feature = "aac")]
+            Self::Mp4 => Ok(Box::new(IsoMp4Reader::try_new(mss, options)?)),
+            #[allow(unreachable_patterns)]
+            _ => Err(self.missing().into()),
+        }
+    }
+}
+
+/// The format reader for an audio file, read as `container` if it is
+/// given
+fn open(
+    path: &str,
+    container: Option<Format>,
+) -> Result<Box<dyn FormatReader>, Box<dyn std::error::Error>> {
+    // Create a media source. Note that the MediaSource trait is
+    // automatically implemented for File, among other types.
+    let file = Box::new(File::open(Path::new(path))?);
+
+    // Create the media source stream using the boxed media source from above.
+    let mss = MediaSourceStream::new(file, Default::default());
+
+    // Use the default options when reading.
+    let format_opts: FormatOptions = Default::default();
+    let metadata_opts: MetadataOptions = Default::default();
+
+    // Get a format reader.  Unless the format is forced the media
+    // source stream is probed for it, with the file's extension as a
+    // hint to the format registry about which reader is appropriate.
+    // If nothing can read it, and the extension is for a format that
+    // was not built in, say which feature would
+    let extension = Path::new(path).extension().and_then(|e| e.to_str());
+    Ok(match container {
+        Some(container) => container.reader(mss, &format_opts)?,
+        None => {
+            let mut hint = Hint::new();
+            if let Some(extension) = extension {
+                hint.with_extension(extension);
+            }
+            match symphonia::default::get_probe().format(
+                &hint,
+                mss,
+                &format_opts,
+                &metadata_opts,
+            ) {
+                Ok(probed) => probed.format,
+                Err(Error::Unsupported(_)) => {
+                    match extension.and_then(Format::from_extension) {
+                        Some(format) if !format.built() => {
+                            return Err(format.missing().into())
+                        },
+                        _ => return Err("Unsupported file format".into()),
+                    }
+                },
+                Err(err) => return Err(err.into()),
+            }
+        },
+    })
+}
+
+/// What an audio file holds, as far as its header says
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Info {
+    pub sample_rate: Option<u32>,
+    pub channels: Option<usize>,
+    pub frames: Option<u64>,
+}
+
+/// Read what an audio file holds without decoding it
+pub fn info(
+    path: &str,
+    container: Option<Format>,
+) -> Result<Info, Box<dyn std::error::Error>> {
+    let format = open(path, container)?;
+    let track: &Track = format.default_track().ok_or("No default track")?;
+    let params = &track.codec_params;
+    Ok(Info {
+        sample_rate: params.sample_rate,
+        channels: params.channels.map(|channels| channels.count()),
+        frames: params.n_frames,
+    })
+}
+
+/// Decode an audio file into a buffer of interleaved `f32` samples,
+/// read as `container` if it is given.  This code is from the
+/// Symphonia example
+pub fn decode(
+    path: &str,
+    container: Option<Format>,
+) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+    let started = Instant::now();
+
+    let mut format = open(path, container)?;
+    let decoder_opts: DecoderOptions = Default::default();
+
+    // Get the default track.
+    let track: &Track = format.default_track().ok_or("No default track")?;
+
+    // Create a decoder for the track.
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &decoder_opts)?;
+
+    // Store the track identifier, we'll use it to filter packets.
+    let track_id = track.id;
+
+    let mut sample_count = 0;
+    let mut sample_buf: Option<SampleBuffer<f32>> = None;
+    let mut data: Vec<f32> = vec![];
+
+    loop {
+        // Get the next packet from the format reader.
+        if let Ok(packet) = format.next_packet() {
+            // If the packet does not belong to the selected track, skip it.
+            if packet.track_id() != track_id {
+                continue;
+            }
+
+            // Decode the packet into audio samples, ignoring any decode errors.
+            match decoder.decode(&packet) {
+                Ok(audio_buf) => {
+                    // The decoded audio samples may now be accessed via
+                    // the audio buffer if per-channel slices of samples
+                    // in their native decoded format is
+                    // desired. Use-cases where the samples need to be
+                    // accessed in an interleaved order or converted into
+                    // another sample format, or a byte buffer is
+                    // required, are covered by copying the audio buffer
+                    // into a sample buffer or raw sample buffer,
+                    // respectively. In the example below, we will copy
+                    // the audio buffer into a sample buffer in an
+                    // interleaved order while also converting to a f32
+                    // sample format.
+
+                    // If this is the *first* decoded packet, create a
+                    // sample buffer matching the decoded audio buffer
+                    // format.
+                    if sample_buf.is_none() {
+                        // Get the audio buffer specification.
+                        let spec: SignalSpec = *audio_buf.spec();
+
+                        // Get the capacity of the decoded buffer. Note:
+                        // This is capacity, not length!
+                        let duration = audio_buf.capacity() as u64;
+
+                        // Create the f32 sample buffer.
+                        sample_buf =
+                            Some(SampleBuffer::<f32>::new(duration, spec));
+                    }
+
+                    // Copy the decoded audio buffer into the sample
+                    // buffer in an interleaved format.
+                    if let Some(buf) = &mut sample_buf {
+                        buf.copy_interleaved_ref(audio_buf);
+
+                        // The samples may now be access via the
+                        // `samples()` function.
+                        sample_count += buf.samples().len();
+                        data.append(&mut buf.samples().to_vec());
+                    }
+                },
+                Err(Error::DecodeError(_)) => (),
+                Err(_) => break,
+            }
+
+            continue;
+        }
+        break;
+    }
+
+    // Extract the file name part of the sample to output some
+    // stats.
+    let disp_path = if let Some(idx) = path.rfind('/') {
+        path.get(idx..).unwrap()
+    } else {
+        path
+    };
+    eprintln!("{disp_path}  Total size() {sample_count}");
+    DECODE_TIME
+        .fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+
+    Ok(data)
+}
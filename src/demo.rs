@@ -0,0 +1,21 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The demo kit's configuration and samples, built into the programme
+const FILES: [(&str, &[u8]); 5] = [
+    ("config.json", include_bytes!("../demo/config.json")),
+    ("kick.wav", include_bytes!("../demo/kick.wav")),
+    ("snare.wav", include_bytes!("../demo/snare.wav")),
+    ("clap.wav", include_bytes!("../demo/clap.wav")),
+    ("hat.wav", include_bytes!("../demo/hat.wav")),
+];
+
+/// Write the demo kit into `dir`, and return the path of its
+/// configuration
+pub fn unpack(dir: &Path) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    fs::create_dir_all(dir)?;
+    for (name, contents) in FILES {
+        fs::write(dir.join(name), contents)?;
+    }
+    Ok(dir.join(FILES[0].0))
+}
@@ -0,0 +1,112 @@
+use crate::gain::db_to_gain;
+use serde::Deserialize;
+
+fn default_threshold() -> f32 {
+    -40.0
+}
+
+fn default_depth() -> f32 {
+    12.0
+}
+
+fn default_attack() -> f32 {
+    10.0
+}
+
+fn default_release() -> f32 {
+    300.0
+}
+
+/// How long the level of the source is held for, in milliseconds, so
+/// it does not fall between the peaks of its waves
+const HOLD: f32 = 20.0;
+
+/// While the samples in the `source` group are louder than
+/// `threshold` dB, the samples in the `target` group are turned down
+/// by `depth` dB.  They go down over `attack` milliseconds and come
+/// back up over `release`
+#[derive(Clone, Debug, Deserialize)]
+pub struct DuckDescr {
+    pub source: String,
+    pub target: String,
+    #[serde(default = "default_threshold")]
+    pub threshold: f32,
+    #[serde(default = "default_depth")]
+    pub depth: f32,
+    #[serde(default = "default_attack")]
+    pub attack: f32,
+    #[serde(default = "default_release")]
+    pub release: f32,
+}
+
+/// One group ducking another, in the Jack thread
+#[derive(Clone, Debug)]
+pub struct Duck {
+    /// The groups, as indexes into the engine's groups
+    pub source: usize,
+    pub target: usize,
+    threshold: f32,
+    depth: f32,
+    attack_ms: f32,
+    release_ms: f32,
+
+    /// How much of the way to its target the gain goes in a frame,
+    /// and how much of the held level is left after one
+    attack: f32,
+    release: f32,
+    hold: f32,
+
+    level: f32,
+    gain: f32,
+}
+
+impl Duck {
+    pub fn new(
+        descr: &DuckDescr,
+        source: usize,
+        target: usize,
+    ) -> Self {
+        let mut duck = Self {
+            source,
+            target,
+            threshold: db_to_gain(descr.threshold),
+            depth: db_to_gain(-descr.depth.abs()),
+            attack_ms: descr.attack,
+            release_ms: descr.release,
+            attack: 1.0,
+            release: 1.0,
+            hold: 0.0,
+            level: 0.0,
+            gain: 1.0,
+        };
+        duck.set_sample_rate(48000);
+        duck
+    }
+
+    pub fn set_sample_rate(
+        &mut self,
+        sample_rate: usize,
+    ) {
+        let frames = |ms: f32| ms.max(0.0) * sample_rate as f32 / 1000.0;
+        let step = |ms: f32| 1.0 - (-1.0 / frames(ms).max(1.0)).exp();
+        self.attack = step(self.attack_ms);
+        self.release = step(self.release_ms);
+        self.hold = 1.0 - step(HOLD);
+    }
+
+    /// The gain for the target group this frame, given the source
+    /// group's mix from the frame before
+    pub fn tick(
+        &mut self,
+        source: f32,
+    ) -> f32 {
+        self.level = source.abs().max(self.level * self.hold);
+        let (target, step) = if self.level > self.threshold {
+            (self.depth, self.attack)
+        } else {
+            (1.0, self.release)
+        };
+        self.gain += (target - self.gain) * step;
+        self.gain
+    }
+}
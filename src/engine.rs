@@ -0,0 +1,1399 @@
+use crate::audio::Stats;
+use crate::bus::Bus;
+use crate::chance::Chance;
+use crate::chord::ChordDescr;
+use crate::decode::Info;
+use crate::duck::Duck;
+use crate::eq::{Eq, EqDescr};
+use crate::fx::{Chain, EffectDescr};
+use crate::loop_sync::LoopSync;
+use crate::pan::Panner;
+use crate::pitch::{PitchEnvelope, PitchEnvelopeDescr};
+use crate::resample::Quality;
+use crate::smooth::{Curve, OnePole, Ramp};
+use std::sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::time::SystemTime;
+
+// There need to be enough of these that there is allways one voice
+// available.  When they run out the voice that has been playing the
+// longest is cut off to make room
+pub const NUM_VOICES: usize = 300;
+
+/// How many commands can be waiting for the Jack thread.  If the
+/// MIDI or control threads get this far ahead commands are dropped
+/// rather than blocking them
+pub const COMMAND_QUEUE: usize = 1024;
+
+/// How many frames a voice that is cut off takes to fade out, so it
+/// does not click
+const DECLICK: usize = 64;
+
+/// How many frames a voice taken for a new one takes to fade out,
+/// crossfading into the new voice, and how many can be fading out at
+/// once.  When they are all in use the one closest to silence is
+/// dropped
+const STEAL_FADE: usize = 256;
+const STEAL_SLOTS: usize = 32;
+
+/// How long a voice that has played for as long as it may takes to
+/// fade out, in milliseconds
+const LIMIT_FADE_MS: usize = 50;
+
+/// How much the output levels fall back each frame
+const LEVEL_DECAY: f32 = 0.9999;
+
+/// Room is kept for this many samples added while running, such as
+/// recordings, one per note, so adding one does not allocate in the
+/// Jack thread.  Samples past these are refused
+pub const LIVE_SLOTS: usize = 128;
+
+/// How many things the Jack thread has finished with can wait to be
+/// dropped
+const RETIRE_QUEUE: usize = 1024;
+
+/// The sample rate until Jack says what it is
+const DEFAULT_SAMPLE_RATE: usize = 48000;
+
+/// Each sample is converted to a `Vec<32>` buffer and a MIDI note on
+/// start up.  When the MIDI note is received the buffer is played on
+/// the output, if the sample is not in a bank or its bank (an index
+/// into the banks) is active
+#[derive(Clone, Debug, Default)]
+pub struct SampleData {
+    pub path: String,
+    pub data: Vec<f32>,
+
+    /// The rate and channels of the file the data was decoded from,
+    /// as far as its header says, to tell how long the data plays
+    pub source: Info,
+    pub note: u8,
+    pub bank: Option<usize>,
+
+    /// For a sample made of segments, where each is in `data`.  Empty
+    /// for an ordinary sample
+    pub segments: Vec<Segment>,
+
+    /// How many voices can play this sample at once
+    pub max_voices: Option<usize>,
+
+    /// Voices are only cut off to make room for a sample with the
+    /// same or higher priority, and the lowest priority goes first
+    pub priority: u8,
+
+    /// Mono samples cut off any mono sample already playing, so only
+    /// one plays at a time, as for a bass line
+    pub mono: bool,
+
+    /// The output channels the sample plays on.  Empty for the
+    /// channels of the first output
+    pub channels: Vec<usize>,
+
+    /// A panned sample plays round its output's speakers at an angle
+    /// in degrees, instead of on `channels`
+    pub pan: Option<(f32, Arc<Panner>)>,
+
+    /// How the sample is played between frames
+    pub quality: Quality,
+
+    /// Beats of click played before the sample starts.  The player
+    /// counts in, the engine only plays what it is told
+    pub count_in: u32,
+
+    /// The group the sample is in, for its bus and ducking
+    pub group: Option<usize>,
+
+    /// Tone controls, applied as the sample plays
+    pub eq: Option<EqDescr>,
+
+    /// A pitch envelope, applied as the sample plays
+    pub pitch_envelope: Option<PitchEnvelopeDescr>,
+
+    /// Effects each voice playing the sample goes through, in order
+    pub effects: Vec<EffectDescr>,
+
+    /// How long a voice can play the sample before it is faded out,
+    /// if not the engine's limit
+    pub max_play_seconds: Option<f32>,
+
+    /// Whether the sample plays when its note is hit.  The player
+    /// decides, the engine only plays what it is told
+    pub chance: Chance,
+
+    /// What the sample is called in logs and reports, if not its path
+    pub name: Option<String>,
+
+    /// A chord the sample plays, a voice for each note, rather than
+    /// one voice
+    pub chord: Option<ChordDescr>,
+
+    /// The deck a backing track is on.  Only the decks play it, never
+    /// its note, and it follows the crossfader rather than the note's
+    /// gain
+    pub deck: Option<usize>,
+
+    /// For a sample played when its note is released, the velocity
+    /// taken for a NoteOff that has none.  Its note never plays it
+    pub release: Option<u8>,
+
+    /// How a loop keeps time with the clock, if it does
+    pub sync: Option<LoopSync>,
+}
+
+impl SampleData {
+    /// The sample's name, or its path if it has none
+    pub fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.path)
+    }
+
+    /// The effects for the voices a trigger of the sample starts, one
+    /// for each note of its chord, at `sample_rate`.  They are made
+    /// before the trigger is sent, as making them allocates
+    pub fn voice_effects(
+        &self,
+        sample_rate: usize,
+    ) -> Vec<Chain> {
+        if self.effects.is_empty() {
+            return vec![];
+        }
+        let voices = self.chord.as_ref().map_or(1, |c| c.intervals.len());
+        (0..voices)
+            .map(|_| Chain::new(&self.effects, sample_rate))
+            .collect()
+    }
+
+    /// The same sample with other data.  A loop that keeps time has
+    /// its segments made for it
+    pub fn with_data(
+        &self,
+        data: Vec<f32>,
+        segments: Vec<Segment>,
+    ) -> Self {
+        let (data, segments) = match &self.sync {
+            Some(sync) => sync.prepare(data),
+            None => (data, segments),
+        };
+        Self {
+            data,
+            segments,
+            ..self.clone()
+        }
+    }
+}
+
+/// A part of a sample's data.  `start` and `end` are positions in the
+/// data, and the segments are contiguous so playing runs from one
+/// straight into the next
+#[derive(Clone, Copy, Debug)]
+pub struct Segment {
+    pub start: usize,
+    pub end: usize,
+    pub looping: bool,
+}
+
+/// What a `fade` command changes the gain of
+#[derive(Clone, Copy, Debug)]
+pub enum FadeTarget {
+    Master,
+    Note(u8),
+
+    /// An output channel, after the master gain
+    Channel(usize),
+
+    /// A group's bus
+    Group(usize),
+
+    /// The samples in a bank
+    Bank(usize),
+}
+
+/// A gain change for the Jack thread to ramp to over `frames` frames
+#[derive(Debug)]
+pub struct Fade {
+    pub target: FadeTarget,
+    pub gain: f32,
+    pub frames: usize,
+}
+
+/// Messages to the Jack thread.  Sending one never blocks and never
+/// allocates in the Jack thread, so the MIDI callback returns quickly
+/// however long the sample is
+#[derive(Debug)]
+pub enum Command {
+    /// Start playing a sample (an index into the samples) at a gain.
+    /// The velocity is only reported, it has already been turned into
+    /// the gain.  `effects` are the voices' effects, made with
+    /// `SampleData::voice_effects`.  Without them the voices play dry
+    Trigger {
+        sample: usize,
+        gain: f32,
+        velocity: u8,
+        effects: Vec<Chain>,
+    },
+
+    /// NoteOff.  Samples made of segments on the note jump to their
+    /// last segment
+    Release {
+        note: u8,
+    },
+    Fade(Fade),
+
+    /// Start recording the input into `buffer`, which is sent back
+    /// on `done` when recording stops or the buffer is full.  It
+    /// never grows past its capacity
+    Record {
+        buffer: Vec<f32>,
+        done: SyncSender<Vec<f32>>,
+    },
+    StopRecording,
+
+    /// Add a sample to the end of the samples
+    AddSample(Arc<SampleData>),
+
+    /// Add the sample played when a trigger fails
+    ErrorBeep(Arc<SampleData>),
+
+    /// Keep the last of the input in this buffer, as long as it is,
+    /// and start each recording with it
+    PreRoll(Vec<f32>),
+
+    /// Play the samples on a note faster or slower, by a ratio, and
+    /// louder or quieter, by a gain.  From MPE pitch bend and
+    /// pressure
+    Expression {
+        note: u8,
+        rate: f32,
+        gain: f32,
+    },
+
+    /// Move the samples on a note round the speakers, by an angle in
+    /// degrees from where they are set to play.  From a controller
+    Pan {
+        note: u8,
+        degrees: f32,
+    },
+
+    /// Replace a sample.  Voices playing the old one play it to its
+    /// end
+    ReplaceSample {
+        sample: usize,
+        data: Arc<SampleData>,
+    },
+
+    /// Silence a group's bus, or bring it back
+    Mute {
+        group: usize,
+        muted: bool,
+    },
+
+    /// Stop the voices playing a sample, fading them out quickly
+    Stop {
+        sample: usize,
+    },
+
+    /// A note with no sample on it was played.  It fails as a trigger
+    /// that cannot play does
+    Unmapped,
+
+    /// The gain of each of the two decks, from the crossfader
+    Crossfade {
+        gains: [f32; 2],
+    },
+}
+
+/// What the Jack thread has finished with.  Freeing memory can take a
+/// lock, so it is sent to another thread to be dropped.  Nothing
+/// reads it there
+#[allow(dead_code)]
+enum Retired {
+    Sample(Arc<SampleData>),
+    Effects(Chain),
+    Chains(Vec<Chain>),
+}
+
+/// Reported by the Jack thread each time a voice starts
+#[derive(Clone)]
+pub struct TriggerEvent {
+    pub time: SystemTime,
+    pub sample: Arc<SampleData>,
+    pub velocity: u8,
+    pub voice: usize,
+}
+
+/// What each voice is playing.  Shared between the Jack thread and
+/// the control thread so playback progress can be reported.  `length`
+/// is zero when the voice is idle.  `sample` is an index into the
+/// samples
+#[derive(Default)]
+pub struct VoiceStatus {
+    pub note: AtomicU8,
+    pub sample: AtomicUsize,
+    pub position: AtomicUsize,
+    pub length: AtomicUsize,
+}
+
+/// The level of each output channel, linear, as a meter shows it: a
+/// peak that falls back by about 40dB a second at 48kHz.  Set by the
+/// Jack thread
+pub struct Levels {
+    /// The bits of each level, so it can be shared without a lock
+    peaks: Vec<AtomicU32>,
+}
+
+impl Levels {
+    pub fn peaks(&self) -> Vec<f32> {
+        self.peaks
+            .iter()
+            .map(|peak| f32::from_bits(peak.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+/// A sample being played
+struct Voice {
+    sample: usize,
+    position: usize,
+
+    /// How far between `position` and the next frame playback is,
+    /// when the rate is not one
+    fraction: f32,
+    gain: f32,
+
+    /// The segment being played, and whether NoteOff has arrived
+    segment: usize,
+    released: bool,
+
+    /// When the voice started, in frames since the engine started.
+    /// Used to find the oldest voice.  It fades out at `limit`
+    started: u64,
+    limit: u64,
+
+    /// The voice's own playback rate, for a note of a chord, and the
+    /// frames it waits before it starts, to strum the chord
+    rate: f32,
+    delay: usize,
+
+    /// Frames left of fading out when the voice is being cut off,
+    /// otherwise zero, and how many frames the fade takes
+    cut: usize,
+    fade: usize,
+
+    /// For a loop that keeps time, where it is cut off after NoteOff
+    stop: Option<usize>,
+
+    /// The sample's EQ and pitch envelope, if it has them
+    eq: Option<Eq>,
+    pitch_envelope: Option<PitchEnvelope>,
+
+    /// The sample's effects, with their own state for this voice
+    effects: Chain,
+
+    /// The sample the voice started on, once it has been replaced.
+    /// The voice plays it to its end rather than stopping
+    replaced: Option<Arc<SampleData>>,
+}
+
+impl Voice {
+    /// The sample the voice is playing
+    fn sample_data<'a>(
+        &'a self,
+        samples: &'a [Arc<SampleData>],
+    ) -> &'a SampleData {
+        match &self.replaced {
+            Some(replaced) => replaced,
+            None => &samples[self.sample],
+        }
+    }
+}
+
+/// The mixer that runs in the Jack thread.  The samples are shared,
+/// read only, with the MIDI thread that looks up what to trigger.
+/// Samples recorded live are added with commands
+pub struct Engine {
+    samples: Vec<Arc<SampleData>>,
+    voices: Vec<Option<Voice>>,
+
+    /// Voices taken for new ones, fading out
+    stolen: Vec<Option<Voice>>,
+    status: Arc<Vec<VoiceStatus>>,
+    commands: Receiver<Command>,
+    master_gain: Ramp,
+    note_gains: [Ramp; 128],
+    channel_gains: Vec<Ramp>,
+
+    /// The gains of the samples in each bank, and their values for
+    /// this frame
+    bank_gains: Vec<Ramp>,
+    bank_frame_gains: Vec<f32>,
+    frame: u64,
+
+    /// The mix for each output channel, and the channels samples
+    /// play on when they do not say
+    outputs: Vec<Vec<f32>>,
+    default_channels: Vec<usize>,
+
+    /// One frame of the mix, for each channel
+    frame_mix: Vec<f32>,
+
+    /// Per note expression, the playback rate and gain
+    note_rates: [OnePole; 128],
+    note_expression_gains: [OnePole; 128],
+
+    /// How far the panned samples on each note have been moved, in
+    /// degrees
+    note_pans: [OnePole; 128],
+
+    /// Where trigger events go, if anything wants them
+    events: Vec<SyncSender<TriggerEvent>>,
+
+    /// The output levels, for meters
+    levels: Arc<Levels>,
+
+    /// Counts of what the engine and the Jack thread have done
+    stats: Arc<Stats>,
+
+    /// Where the input goes while recording
+    recording: Option<(Vec<f32>, SyncSender<Vec<f32>>)>,
+
+    /// The last of the input, kept to start recordings with, and
+    /// where the next frame of it goes
+    pre_roll: Vec<f32>,
+    pre_roll_at: usize,
+
+    /// How long each note's voices wait to start, in milliseconds, so
+    /// pads that are quicker than others can be held back to match
+    note_delays: [f32; 128],
+
+    /// How many voices can play at once, and how many on one note
+    max_voices: usize,
+    max_voices_per_note: usize,
+
+    /// How long a voice can play, in seconds, for samples that do not
+    /// say
+    max_play_seconds: Option<f32>,
+
+    /// The shape of fades, of gains and of voices cut off or stolen
+    curve: Curve,
+
+    /// The size of the least significant bit of the interface the
+    /// output is dithered for, zero for no dither, and the state of
+    /// the random numbers for the dither
+    dither: f32,
+    noise: u32,
+
+    /// Groups ducking other groups, and each group's mix and gain for
+    /// the frame
+    ducks: Vec<Duck>,
+    group_mix: Vec<f32>,
+    group_gains: Vec<f32>,
+
+    /// Each group's bus
+    buses: Vec<Bus>,
+
+    /// The gain of each deck of backing tracks.  The first is heard
+    /// until the crossfader moves
+    deck_gains: [OnePole; 2],
+
+    /// Jack's sample rate, for things set in seconds or Hz
+    sample_rate: usize,
+
+    /// The rate the samples were made for, the first Jack had, and how
+    /// fast they play to keep their pitch at the rate Jack has now
+    samples_rate: Option<usize>,
+    rate_correction: f32,
+
+    /// The sample played when a trigger fails
+    error_beep: Option<usize>,
+
+    /// Where what the Jack thread has finished with goes to be dropped
+    retire: SyncSender<Retired>,
+}
+
+impl Engine {
+    /// `channels` is how many output channels there are altogether.
+    /// `default_channels` are the channels samples that do not say
+    /// play on
+    pub fn new(
+        mut samples: Vec<Arc<SampleData>>,
+        status: Arc<Vec<VoiceStatus>>,
+        commands: Receiver<Command>,
+        channels: usize,
+        default_channels: Vec<usize>,
+    ) -> Self {
+        samples.reserve_exact(LIVE_SLOTS);
+        let (retire, retired) = sync_channel(RETIRE_QUEUE);
+        thread::spawn(move || for _ in retired {});
+        Self {
+            samples,
+            voices: (0..status.len()).map(|_| None).collect(),
+            stolen: (0..STEAL_SLOTS).map(|_| None).collect(),
+            status,
+            commands,
+            master_gain: Ramp::new(1.0),
+            note_gains: [Ramp::new(1.0); 128],
+            channel_gains: vec![Ramp::new(1.0); channels],
+            bank_gains: vec![],
+            bank_frame_gains: vec![],
+            frame: 0,
+            outputs: vec![vec![]; channels],
+            default_channels,
+            frame_mix: vec![0.0; channels],
+            note_rates: [OnePole::new(1.0); 128],
+            note_expression_gains: [OnePole::new(1.0); 128],
+            note_pans: [OnePole::new(0.0); 128],
+            events: vec![],
+            levels: Arc::new(Levels {
+                peaks: (0..channels).map(|_| AtomicU32::new(0)).collect(),
+            }),
+            stats: Arc::new(Stats::default()),
+            recording: None,
+            pre_roll: vec![],
+            pre_roll_at: 0,
+            note_delays: [0.0; 128],
+            max_voices: usize::MAX,
+            max_voices_per_note: usize::MAX,
+            max_play_seconds: None,
+            curve: Curve::Linear,
+            dither: 0.0,
+            noise: 0x9e3779b9,
+            ducks: vec![],
+            group_mix: vec![],
+            group_gains: vec![],
+            buses: vec![],
+            deck_gains: [OnePole::new(1.0), OnePole::new(0.0)],
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            samples_rate: None,
+            rate_correction: 1.0,
+            error_beep: None,
+            retire,
+        }
+    }
+
+    /// Report each voice started to `events`, as well as to any
+    /// already given.  If a receiver falls behind events are dropped
+    /// rather than holding up the Jack thread
+    pub fn log_triggers(
+        &mut self,
+        events: SyncSender<TriggerEvent>,
+    ) {
+        self.events.push(events);
+    }
+
+    /// The counts kept by the engine and the Jack thread
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    /// The output levels, which the Jack thread keeps up to date
+    pub fn levels(&self) -> Arc<Levels> {
+        self.levels.clone()
+    }
+
+    /// Limit the number of voices playing at once, and on one note,
+    /// so long samples hit repeatedly cannot take all the voices.
+    /// Going over a limit cuts off the oldest voice it applies to
+    pub fn limit_voices(
+        &mut self,
+        max_voices: Option<usize>,
+        max_voices_per_note: Option<usize>,
+    ) {
+        self.max_voices = max_voices.unwrap_or(usize::MAX);
+        self.max_voices_per_note = max_voices_per_note.unwrap_or(usize::MAX);
+    }
+
+    /// Hold back the voices on each note by its delay, in
+    /// milliseconds
+    pub fn delay_notes(
+        &mut self,
+        delays: impl IntoIterator<Item = (u8, f32)>,
+    ) {
+        for (note, delay) in delays {
+            if let Some(slot) = self.note_delays.get_mut(note as usize) {
+                *slot = delay;
+            }
+        }
+    }
+
+    /// Fade out voices that have played for `max_play_seconds`, unless
+    /// their samples set a limit of their own
+    pub fn limit_play(
+        &mut self,
+        max_play_seconds: Option<f32>,
+    ) {
+        self.max_play_seconds = max_play_seconds;
+    }
+
+    /// Shape every fade with `curve`: the gains faded by commands and
+    /// scenes, groups muted, and voices cut off, stolen or faded out
+    /// at their limit
+    pub fn fade_curve(
+        &mut self,
+        curve: Curve,
+    ) {
+        self.curve = curve;
+        for ramp in std::iter::once(&mut self.master_gain)
+            .chain(self.note_gains.iter_mut())
+            .chain(self.channel_gains.iter_mut())
+            .chain(self.bank_gains.iter_mut())
+        {
+            ramp.set_curve(curve);
+        }
+        for bus in self.buses.iter_mut() {
+            bus.set_curve(curve);
+        }
+    }
+
+    /// Add TPDF dither to the output, for an interface that takes
+    /// `bits` bit samples.  Jack hands the interface floats, and it
+    /// truncates them, which distorts very quiet sounds
+    pub fn dither(
+        &mut self,
+        bits: u8,
+    ) {
+        self.dither = 2.0_f32.powi(1 - bits as i32);
+    }
+
+    /// Give each of the `banks` a gain that can be faded
+    pub fn banks(
+        &mut self,
+        banks: usize,
+    ) {
+        let mut ramp = Ramp::new(1.0);
+        ramp.set_curve(self.curve);
+        self.bank_gains = vec![ramp; banks];
+        self.bank_frame_gains = vec![1.0; banks];
+    }
+
+    /// Duck groups of samples when other groups play.  `groups` is how
+    /// many groups there are
+    pub fn duck(
+        &mut self,
+        ducks: Vec<Duck>,
+        groups: usize,
+    ) {
+        self.ducks = ducks;
+        self.group_mix = vec![0.0; groups];
+        self.group_gains = vec![1.0; groups];
+    }
+
+    /// Give the groups their buses, one for each group
+    pub fn buses(
+        &mut self,
+        mut buses: Vec<Bus>,
+    ) {
+        for bus in buses.iter_mut() {
+            bus.set_sample_rate(self.sample_rate);
+            bus.set_curve(self.curve);
+        }
+        self.buses = buses;
+    }
+
+    /// Times in milliseconds are turned into frames, and frequencies
+    /// into filters, at this rate.  Jack sets it when it connects, and
+    /// again if it changes.  The first rate is the one the samples were
+    /// made for, and after a change they are played faster or slower
+    /// to keep their pitch.  Voices already playing keep the filters
+    /// and effects they started with
+    pub fn set_sample_rate(
+        &mut self,
+        sample_rate: usize,
+    ) {
+        self.sample_rate = sample_rate;
+        let samples_rate = *self.samples_rate.get_or_insert(sample_rate);
+        self.rate_correction = samples_rate as f32 / sample_rate as f32;
+        for duck in self.ducks.iter_mut() {
+            duck.set_sample_rate(sample_rate);
+        }
+        for bus in self.buses.iter_mut() {
+            bus.set_sample_rate(sample_rate);
+        }
+    }
+
+    /// Make room to mix `frames` frames a cycle.  Jack says when its
+    /// buffer size changes, outside the process cycle, so `process`
+    /// does not have to allocate
+    pub fn set_buffer_size(
+        &mut self,
+        frames: usize,
+    ) {
+        for output in self.outputs.iter_mut() {
+            output.resize(frames, 0.0);
+        }
+    }
+
+    /// The mix for an output channel, after `process`
+    pub fn output(
+        &self,
+        channel: usize,
+    ) -> &[f32] {
+        &self.outputs[channel]
+    }
+
+    /// Mix the next `frames` frames for each output channel, and
+    /// record `input` if recording
+    pub fn process(
+        &mut self,
+        input: &[f32],
+        frames: usize,
+    ) {
+        // In case Jack did not say its buffer size had grown
+        for output in self.outputs.iter_mut() {
+            if output.len() < frames {
+                output.resize(frames, 0.0);
+            }
+        }
+
+        while let Ok(command) = self.commands.try_recv() {
+            match command {
+                Command::Trigger {
+                    sample,
+                    gain,
+                    velocity,
+                    effects,
+                } => self.start(sample, gain, velocity, effects),
+                Command::Release { note } => self.release(note),
+                Command::Fade(fade) => match fade.target {
+                    FadeTarget::Master => {
+                        self.master_gain.set(fade.gain, fade.frames)
+                    },
+                    FadeTarget::Note(note) => self.note_gains[note as usize]
+                        .set(fade.gain, fade.frames),
+                    FadeTarget::Channel(channel) => {
+                        if let Some(ramp) = self.channel_gains.get_mut(channel)
+                        {
+                            ramp.set(fade.gain, fade.frames)
+                        }
+                    },
+                    FadeTarget::Group(group) => {
+                        if let Some(bus) = self.buses.get_mut(group) {
+                            bus.set_gain(fade.gain, fade.frames)
+                        }
+                    },
+                    FadeTarget::Bank(bank) => {
+                        if let Some(ramp) = self.bank_gains.get_mut(bank) {
+                            ramp.set(fade.gain, fade.frames)
+                        }
+                    },
+                },
+                Command::Record { mut buffer, done } => {
+                    self.stop_recording();
+                    let (newer, older) =
+                        self.pre_roll.split_at(self.pre_roll_at);
+                    for part in [older, newer] {
+                        let room = buffer.capacity() - buffer.len();
+                        buffer.extend_from_slice(&part[..part.len().min(room)]);
+                    }
+                    self.recording = Some((buffer, done));
+                },
+                Command::StopRecording => self.stop_recording(),
+                Command::Expression { note, rate, gain } => {
+                    self.note_rates[note as usize].set(rate);
+                    self.note_expression_gains[note as usize].set(gain);
+                },
+                Command::Pan { note, degrees } => {
+                    self.note_pans[note as usize].set(degrees)
+                },
+                Command::AddSample(data) => {
+                    let _ = self.add_sample(data);
+                },
+                Command::ErrorBeep(data) => {
+                    self.error_beep = self.add_sample(data);
+                },
+                Command::PreRoll(buffer) => {
+                    self.pre_roll = buffer;
+                    self.pre_roll_at = 0;
+                },
+                Command::ReplaceSample { sample, data } => {
+                    let Some(slot) = self.samples.get_mut(sample) else {
+                        self.retire(Retired::Sample(data));
+                        continue;
+                    };
+                    let replaced = std::mem::replace(slot, data);
+                    for voice in self
+                        .voices
+                        .iter_mut()
+                        .chain(self.stolen.iter_mut())
+                        .flatten()
+                    {
+                        if voice.sample == sample && voice.replaced.is_none() {
+                            voice.replaced = Some(replaced.clone());
+                        }
+                    }
+                    self.retire(Retired::Sample(replaced));
+                },
+                Command::Mute { group, muted } => {
+                    if let Some(bus) = self.buses.get_mut(group) {
+                        bus.mute(muted)
+                    }
+                },
+                Command::Stop { sample } => {
+                    for voice in self.voices.iter_mut().flatten() {
+                        if voice.sample == sample && voice.cut == 0 {
+                            voice.cut = DECLICK;
+                            voice.fade = DECLICK;
+                        }
+                    }
+                },
+                Command::Crossfade { gains } => {
+                    for (deck, gain) in self.deck_gains.iter_mut().zip(gains) {
+                        deck.set(gain);
+                    }
+                },
+                Command::Unmapped => self.failed(None),
+            }
+        }
+
+        if let Some((buffer, _)) = &mut self.recording {
+            let room = buffer.capacity() - buffer.len();
+            buffer.extend_from_slice(&input[..input.len().min(room)]);
+            if buffer.len() == buffer.capacity() {
+                self.stop_recording();
+            }
+        }
+        if !self.pre_roll.is_empty() {
+            for x in input {
+                self.pre_roll[self.pre_roll_at] = *x;
+                self.pre_roll_at = (self.pre_roll_at + 1) % self.pre_roll.len();
+            }
+        }
+
+        // Loops keep time at the rate their samples were made for
+        let samples_rate = self.samples_rate.unwrap_or(self.sample_rate);
+        for frame in 0..frames {
+            let mut gains = [0.0; 128];
+            for (gain, ramp) in gains.iter_mut().zip(self.note_gains.iter_mut())
+            {
+                *gain = ramp.tick();
+            }
+            let mut rates = [0.0; 128];
+            let mut pans = [0.0; 128];
+            for (note, ((rate, gain), pan)) in rates
+                .iter_mut()
+                .zip(gains.iter_mut())
+                .zip(pans.iter_mut())
+                .enumerate()
+            {
+                *rate = self.note_rates[note].tick();
+                *gain *= self.note_expression_gains[note].tick();
+                *pan = self.note_pans[note].tick();
+            }
+            let deck_gains = self.deck_gains.each_mut().map(OnePole::tick);
+            for (gain, ramp) in self
+                .bank_frame_gains
+                .iter_mut()
+                .zip(self.bank_gains.iter_mut())
+            {
+                *gain = ramp.tick();
+            }
+
+            // Ducking follows the groups' mix from the frame before
+            self.group_gains.fill(1.0);
+            for (gain, bus) in
+                self.group_gains.iter_mut().zip(self.buses.iter_mut())
+            {
+                *gain = bus.tick();
+            }
+            for duck in self.ducks.iter_mut() {
+                self.group_gains[duck.target] *=
+                    duck.tick(self.group_mix[duck.source]);
+            }
+            self.group_mix.fill(0.0);
+            self.frame_mix.fill(0.0);
+            for slot in self.voices.iter_mut().chain(self.stolen.iter_mut()) {
+                if let Some(voice) = slot {
+                    // A voice strummed after the others waits its turn.
+                    // One cut off before its turn never plays
+                    if voice.delay > 0 {
+                        voice.delay -= 1;
+                        if voice.cut > 0 {
+                            if let Some(voice) = slot.take() {
+                                retire_voice(&self.retire, voice);
+                            }
+                        }
+                        continue;
+                    }
+
+                    // Mixing the voices together
+                    let sample_data = match &voice.replaced {
+                        Some(replaced) => replaced,
+                        None => &self.samples[voice.sample],
+                    };
+                    let mut gain = voice.gain;
+                    let mut faded = false;
+                    if voice.cut == 0
+                        && self.frame + frame as u64 >= voice.limit
+                    {
+                        voice.fade = LIMIT_FADE_MS * self.sample_rate / 1000;
+                        voice.cut = voice.fade;
+                    }
+                    if voice.cut > 0 {
+                        gain *= self
+                            .curve
+                            .shape(voice.cut as f32 / voice.fade as f32);
+                        voice.cut -= 1;
+                        faded = voice.cut == 0;
+                    }
+
+                    // A sample on no MIDI note, such as the click, has
+                    // no note's gain, pan or rate
+                    let note = sample_data.note as usize;
+                    let (note_gain, note_pan, note_rate) = match note {
+                        0..128 => (gains[note], pans[note], rates[note]),
+                        _ => (1.0, 0.0, 1.0),
+                    };
+
+                    // Between frames when the rate is not one
+                    let value = sample_data.quality.interpolate(
+                        &sample_data.data,
+                        voice.position,
+                        voice.fraction,
+                    );
+                    let value = match &mut voice.eq {
+                        Some(eq) => eq.tick(value),
+                        None => value,
+                    };
+                    let value = if voice.effects.is_empty() {
+                        value
+                    } else {
+                        voice.effects.tick(value)
+                    };
+                    let note_gain = match sample_data.deck {
+                        Some(deck) => deck_gains[deck],
+                        None => note_gain,
+                    };
+                    let mut value = value * gain * note_gain;
+                    if let Some(bank_gain) = sample_data
+                        .bank
+                        .and_then(|bank| self.bank_frame_gains.get(bank))
+                    {
+                        value *= bank_gain;
+                    }
+                    if let Some(group) = sample_data.group {
+                        value *= self.group_gains[group];
+                        self.group_mix[group] += value;
+                    }
+
+                    // A group with effects has a mix of its own
+                    let frame_mix = match sample_data
+                        .group
+                        .and_then(|group| self.buses.get_mut(group))
+                        .and_then(Bus::mix)
+                    {
+                        Some(mix) => mix,
+                        None => &mut self.frame_mix[..],
+                    };
+                    if let Some((degrees, panner)) = &sample_data.pan {
+                        panner.pan(degrees + note_pan, |channel, gain| {
+                            frame_mix[channel] += value * gain
+                        });
+                    } else {
+                        let channels = if sample_data.channels.is_empty() {
+                            &self.default_channels
+                        } else {
+                            &sample_data.channels
+                        };
+                        for channel in channels {
+                            frame_mix[*channel] += value;
+                        }
+                    }
+
+                    let sync_rate = match &sample_data.sync {
+                        Some(sync) => {
+                            sync.rate(sample_data.data.len(), samples_rate)
+                        },
+                        None => 1.0,
+                    };
+                    voice.fraction += voice.rate
+                        * self.rate_correction
+                        * sync_rate
+                        * match &mut voice.pitch_envelope {
+                            Some(envelope) => note_rate * envelope.tick(),
+                            None => note_rate,
+                        };
+                    while voice.fraction >= 1.0
+                        && voice.position < sample_data.data.len()
+                    {
+                        voice.fraction -= 1.0;
+                        voice.position += 1;
+
+                        // Move on to the next segment, or go round again
+                        if let Some(segment) =
+                            sample_data.segments.get(voice.segment)
+                        {
+                            if voice.position == segment.end {
+                                if segment.looping && !voice.released {
+                                    voice.position = segment.start;
+                                } else {
+                                    voice.segment += 1;
+                                }
+                            }
+                        }
+                    }
+                    if voice.stop.is_some_and(|stop| voice.position >= stop)
+                        && voice.cut == 0
+                    {
+                        voice.cut = DECLICK;
+                        voice.fade = DECLICK;
+                    }
+                    if voice.position >= sample_data.data.len() || faded {
+                        if let Some(voice) = slot.take() {
+                            retire_voice(&self.retire, voice);
+                        }
+                    }
+                }
+            }
+            for bus in self.buses.iter_mut() {
+                bus.process(&mut self.frame_mix);
+            }
+            let master_gain = self.master_gain.tick();
+            for ((output, f), channel_gain) in self
+                .outputs
+                .iter_mut()
+                .zip(self.frame_mix.iter())
+                .zip(self.channel_gains.iter_mut())
+            {
+                // Unsure if this is the thing to do.  `tanh` is almost
+                // linear except in the extremes where it assymptotically
+                // approaches -1 and 1
+                output[frame] = (f * master_gain * channel_gain.tick()).tanh();
+                if self.dither > 0.0 {
+                    output[frame] += tpdf(&mut self.noise) * self.dither;
+                }
+            }
+        }
+        self.frame += frames as u64;
+
+        for (output, peak) in self.outputs.iter().zip(self.levels.peaks.iter())
+        {
+            let level = output[..frames]
+                .iter()
+                .fold(0.0_f32, |level, x| level.max(x.abs()));
+            let held = f32::from_bits(peak.load(Ordering::Relaxed))
+                * LEVEL_DECAY.powi(frames as i32);
+            peak.store(level.max(held).to_bits(), Ordering::Relaxed);
+            self.stats
+                .peak_level
+                .fetch_max(level.to_bits(), Ordering::Relaxed);
+        }
+        let playing = self.voices.iter().flatten().count();
+        self.stats
+            .max_voices
+            .fetch_max(playing as u64, Ordering::Relaxed);
+
+        for (voice, status) in self.voices.iter().zip(self.status.iter()) {
+            match voice {
+                Some(voice) => {
+                    let sample_data = voice.sample_data(&self.samples);
+                    status.note.store(sample_data.note, Ordering::Relaxed);
+                    status.sample.store(voice.sample, Ordering::Relaxed);
+                    status.position.store(voice.position, Ordering::Relaxed);
+                    status
+                        .length
+                        .store(sample_data.data.len(), Ordering::Relaxed);
+                },
+                None => status.length.store(0, Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Add a sample to the end of the samples, in the room kept for
+    /// it, returning its index.  With no room left it is refused
+    fn add_sample(
+        &mut self,
+        data: Arc<SampleData>,
+    ) -> Option<usize> {
+        if self.samples.len() == self.samples.capacity() {
+            self.retire(Retired::Sample(data));
+            return None;
+        }
+        self.samples.push(data);
+        Some(self.samples.len() - 1)
+    }
+
+    /// Send something the Jack thread has finished with to be dropped.
+    /// If the queue is full it is dropped here after all
+    fn retire(
+        &self,
+        retired: Retired,
+    ) {
+        let _ = self.retire.try_send(retired);
+    }
+
+    /// A trigger of `sample`, or of a note with none, could not play.
+    /// Count it, and beep, if there is a beep and it is not already
+    /// beeping
+    fn failed(
+        &mut self,
+        sample: Option<usize>,
+    ) {
+        self.stats.failed.fetch_add(1, Ordering::Relaxed);
+        let Some(beep) = self.error_beep else {
+            return;
+        };
+        if sample != Some(beep)
+            && !self.voices.iter().flatten().any(|v| v.sample == beep)
+        {
+            self.start_voice(beep, 1.0, 127, 1.0, 0, Chain::default());
+        }
+    }
+
+    /// Send the recording back
+    fn stop_recording(&mut self) {
+        if let Some((buffer, done)) = self.recording.take() {
+            let _ = done.try_send(buffer);
+        }
+    }
+
+    /// Start playing a sample: a voice for each note of its chord, if
+    /// it has one, otherwise just the one
+    fn start(
+        &mut self,
+        sample: usize,
+        gain: f32,
+        velocity: u8,
+        mut effects: Vec<Chain>,
+    ) {
+        let Some(sample_data) = self.samples.get(sample).cloned() else {
+            self.retire(Retired::Chains(effects));
+            self.failed(Some(sample));
+            return;
+        };
+        if let Some(count) =
+            self.stats.note_triggers.get(sample_data.note as usize)
+        {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+        let note_delay = match self.note_delays.get(sample_data.note as usize) {
+            Some(ms) => (ms * self.sample_rate as f32 / 1000.0) as usize,
+            None => 0,
+        };
+        match &sample_data.chord {
+            Some(chord) => {
+                for (rate, delay) in chord.voices(self.sample_rate) {
+                    let delay = delay + note_delay;
+                    let chain = effects.pop().unwrap_or_default();
+                    self.start_voice(
+                        sample, gain, velocity, rate, delay, chain,
+                    );
+                }
+            },
+            None => {
+                let chain = effects.pop().unwrap_or_default();
+                self.start_voice(sample, gain, velocity, 1.0, note_delay, chain)
+            },
+        }
+        if effects.capacity() > 0 {
+            self.retire(Retired::Chains(effects));
+        }
+    }
+
+    /// Start a voice playing a sample.  If all the voices are busy,
+    /// or a limit on voices has been reached, a voice the limit
+    /// applies to is replaced: the oldest of those with the lowest
+    /// priority.  It fades out over a few milliseconds as the new
+    /// voice starts.  If they all have a higher priority than the
+    /// sample it does not play.  `effects` are the voice's effects
+    fn start_voice(
+        &mut self,
+        sample: usize,
+        gain: f32,
+        velocity: u8,
+        rate: f32,
+        delay: usize,
+        effects: Chain,
+    ) {
+        let sample_data = &self.samples[sample];
+        if sample_data.data.is_empty() {
+            retire_effects(&self.retire, effects);
+            self.failed(Some(sample));
+            return;
+        }
+        let note = sample_data.note;
+        if sample_data.mono {
+            for voice in self.voices.iter_mut().flatten() {
+                if voice.sample_data(&self.samples).mono && voice.cut == 0 {
+                    voice.cut = DECLICK;
+                    voice.fade = DECLICK;
+                }
+            }
+        }
+        let sample_data = &self.samples[sample];
+        let max_voices = sample_data.max_voices.unwrap_or(usize::MAX);
+        let priority = sample_data.priority;
+        let on_note = |v: &Voice| v.sample_data(&self.samples).note == note;
+        let count = |f: &dyn Fn(&Voice) -> bool| {
+            self.voices.iter().flatten().filter(|v| f(v)).count()
+        };
+        let victim = |f: &dyn Fn(&Voice) -> bool| {
+            self.voices
+                .iter()
+                .enumerate()
+                .filter_map(|(slot, v)| {
+                    let v = v.as_ref().filter(|v| f(v))?;
+                    let v_priority = v.sample_data(&self.samples).priority;
+                    (v_priority <= priority)
+                        .then_some((slot, (v_priority, v.started)))
+                })
+                .min_by_key(|(_, key)| *key)
+                .map(|(slot, _)| slot)
+        };
+        let slot = if count(&|v| v.sample == sample) >= max_voices {
+            victim(&|v| v.sample == sample)
+        } else if count(&on_note) >= self.max_voices_per_note {
+            victim(&on_note)
+        } else if count(&|_| true) >= self.max_voices {
+            victim(&|_| true)
+        } else {
+            self.voices
+                .iter()
+                .position(|v| v.is_none())
+                .or_else(|| victim(&|_| true))
+        };
+        let Some(slot) = slot else {
+            retire_effects(&self.retire, effects);
+            self.failed(Some(sample));
+            return;
+        };
+        if let Some(stolen) = self.voices[slot].take() {
+            self.steal(stolen);
+        }
+        let eq = self.samples[sample]
+            .eq
+            .map(|descr| Eq::new(&descr, self.sample_rate));
+        let pitch_envelope = self.samples[sample]
+            .pitch_envelope
+            .map(|descr| PitchEnvelope::new(&descr, self.sample_rate));
+        let limit = match self.samples[sample]
+            .max_play_seconds
+            .or(self.max_play_seconds)
+        {
+            Some(seconds) => {
+                self.frame + (seconds.max(0.0) * self.sample_rate as f32) as u64
+            },
+            None => u64::MAX,
+        };
+        self.voices[slot] = Some(Voice {
+            sample,
+            position: 0,
+            fraction: 0.0,
+            gain,
+            segment: 0,
+            released: false,
+            started: self.frame,
+            limit,
+            rate,
+            delay,
+            cut: 0,
+            fade: 0,
+            stop: None,
+            eq,
+            pitch_envelope,
+            effects,
+            replaced: None,
+        });
+        self.stats.triggers.fetch_add(1, Ordering::Relaxed);
+        if !self.events.is_empty() {
+            let event = TriggerEvent {
+                time: SystemTime::now(),
+                sample: self.samples[sample].clone(),
+                velocity,
+                voice: slot,
+            };
+            for events in self.events.iter() {
+                let _ = events.try_send(event.clone());
+            }
+        }
+    }
+
+    /// Fade out a voice taken for a new one, rather than cutting it
+    /// off with a click.  A voice already fading carries on as it was
+    fn steal(
+        &mut self,
+        mut voice: Voice,
+    ) {
+        if voice.cut == 0 {
+            voice.cut = STEAL_FADE;
+            voice.fade = STEAL_FADE;
+        }
+        match self
+            .stolen
+            .iter_mut()
+            .min_by_key(|slot| slot.as_ref().map_or(0, |v| v.cut))
+        {
+            Some(slot) => {
+                if let Some(old) = slot.replace(voice) {
+                    retire_voice(&self.retire, old);
+                }
+            },
+            None => retire_voice(&self.retire, voice),
+        }
+    }
+
+    /// Jump voices playing segmented samples on `note` to their last
+    /// segment
+    fn release(
+        &mut self,
+        note: u8,
+    ) {
+        for voice in self.voices.iter_mut().flatten() {
+            let sample_data = match &voice.replaced {
+                Some(replaced) => replaced,
+                None => &self.samples[voice.sample],
+            };
+            if sample_data.note != note || voice.released {
+                continue;
+            }
+            voice.released = true;
+
+            // A loop that keeps time plays to the end of its bar
+            if let Some(sync) = &sample_data.sync {
+                voice.stop =
+                    Some(sync.bar_end(voice.position, sample_data.data.len()));
+                continue;
+            }
+            let last = sample_data.segments.len().saturating_sub(1);
+            if voice.segment < last {
+                voice.segment = last;
+                voice.position = sample_data.segments[last].start;
+            }
+        }
+    }
+}
+
+/// Triangular noise from -1 to 1, the sum of two uniform random
+/// numbers, from a xorshift generator
+fn tpdf(state: &mut u32) -> f32 {
+    let mut uniform = || {
+        *state ^= *state << 13;
+        *state ^= *state >> 17;
+        *state ^= *state << 5;
+        *state as f32 / u32::MAX as f32 - 0.5
+    };
+    uniform() + uniform()
+}
+
+/// Send a voice's effects to be dropped off the Jack thread as the
+/// voice stops.  A voice with none has nothing to free
+fn retire_effects(
+    retire: &SyncSender<Retired>,
+    effects: Chain,
+) {
+    if !effects.is_empty() {
+        let _ = retire.try_send(Retired::Effects(effects));
+    }
+}
+
+/// Send what a voice that has stopped holds to be dropped off the
+/// Jack thread: its effects, and the sample it was playing if that
+/// has been replaced
+fn retire_voice(
+    retire: &SyncSender<Retired>,
+    voice: Voice,
+) {
+    retire_effects(retire, voice.effects);
+    if let Some(replaced) = voice.replaced {
+        let _ = retire.try_send(Retired::Sample(replaced));
+    }
+}
@@ -0,0 +1,172 @@
+use serde::{Deserialize, Serialize};
+use std::f32::consts::{SQRT_2, TAU};
+
+fn default_low_frequency() -> f32 {
+    200.0
+}
+
+fn default_mid_frequency() -> f32 {
+    1000.0
+}
+
+fn default_high_frequency() -> f32 {
+    5000.0
+}
+
+/// How wide the mid band is
+const MID_Q: f32 = 0.7;
+
+/// A three band EQ for a sample: a low shelf, a mid peak and a high
+/// shelf.  Gains are in dB, and a band with no gain is left out.
+/// Frequencies are in Hz
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct EqDescr {
+    #[serde(default)]
+    pub low: f32,
+    #[serde(default)]
+    pub mid: f32,
+    #[serde(default)]
+    pub high: f32,
+    #[serde(default = "default_low_frequency")]
+    pub low_frequency: f32,
+    #[serde(default = "default_mid_frequency")]
+    pub mid_frequency: f32,
+    #[serde(default = "default_high_frequency")]
+    pub high_frequency: f32,
+}
+
+/// The EQ for a voice, with its filters' state
+#[derive(Clone, Copy, Debug)]
+pub struct Eq {
+    bands: [Option<Biquad>; 3],
+}
+
+impl Eq {
+    /// Work out the filters at `sample_rate`
+    pub fn new(
+        descr: &EqDescr,
+        sample_rate: usize,
+    ) -> Self {
+        let rate = sample_rate as f32;
+        let band = |gain: f32, shape: Shape, frequency: f32| {
+            (gain != 0.0).then(|| Biquad::new(shape, gain, frequency, rate))
+        };
+        Self {
+            bands: [
+                band(descr.low, Shape::LowShelf, descr.low_frequency),
+                band(descr.mid, Shape::Peak, descr.mid_frequency),
+                band(descr.high, Shape::HighShelf, descr.high_frequency),
+            ],
+        }
+    }
+
+    /// Filter the next frame
+    pub fn tick(
+        &mut self,
+        x: f32,
+    ) -> f32 {
+        self.bands
+            .iter_mut()
+            .flatten()
+            .fold(x, |x, band| band.tick(x))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Shape {
+    LowShelf,
+    Peak,
+    HighShelf,
+}
+
+/// A filter from the Audio EQ Cookbook, normalised so `a0` is one,
+/// and its last two inputs and outputs
+#[derive(Clone, Copy, Debug)]
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl Biquad {
+    fn new(
+        shape: Shape,
+        gain: f32,
+        frequency: f32,
+        sample_rate: f32,
+    ) -> Self {
+        let a = 10_f32.powf(gain / 40.0);
+        let w0 = TAU * frequency.clamp(1.0, sample_rate * 0.49) / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let [b0, b1, b2, a0, a1, a2] = match shape {
+            Shape::Peak => {
+                let alpha = sin / (2.0 * MID_Q);
+                [
+                    1.0 + alpha * a,
+                    -2.0 * cos,
+                    1.0 - alpha * a,
+                    1.0 + alpha / a,
+                    -2.0 * cos,
+                    1.0 - alpha / a,
+                ]
+            },
+
+            // Shelves with a slope of one
+            Shape::LowShelf | Shape::HighShelf => {
+                let alpha = sin / 2.0 * SQRT_2;
+                let root = 2.0 * a.sqrt() * alpha;
+                let (p, m) = (a + 1.0, a - 1.0);
+                if matches!(shape, Shape::LowShelf) {
+                    [
+                        a * (p - m * cos + root),
+                        2.0 * a * (m - p * cos),
+                        a * (p - m * cos - root),
+                        p + m * cos + root,
+                        -2.0 * (m + p * cos),
+                        p + m * cos - root,
+                    ]
+                } else {
+                    [
+                        a * (p + m * cos + root),
+                        -2.0 * a * (m + p * cos),
+                        a * (p + m * cos - root),
+                        p - m * cos + root,
+                        2.0 * (m - p * cos),
+                        p - m * cos - root,
+                    ]
+                }
+            },
+        };
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    fn tick(
+        &mut self,
+        x: f32,
+    ) -> f32 {
+        let y = self.b0 * x + self.b1 * self.x1 + self.b2 * self.x2
+            - self.a1 * self.y1
+            - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
@@ -0,0 +1,238 @@
+use crate::config::{process_samples_json, SampleDescr};
+use crate::decode;
+use crate::engine::{
+    Command, Engine, Fade, FadeTarget, SampleData, Segment, VoiceStatus,
+};
+use crate::gain::db_to_gain;
+use crate::loudness;
+use crate::resample::Quality;
+use crate::slice;
+use crate::smooth::Curve;
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::sync_channel;
+use std::sync::Arc;
+
+/// The sample rate the kit is rendered at
+const SAMPLE_RATE: usize = 48_000;
+
+/// How many frames are rendered at a time
+const FRAMES: usize = 1024;
+
+/// Render every sample in a configuration, as it plays at full
+/// velocity with its EQ, pitch envelope and the configuration's gain,
+/// to a mono WAV file in `dir`, so the kit can be loaded into another
+/// sampler.  Each slice of a sliced sample, and each note of a sample
+/// on keys, gets its own file.  Looped segments are played once, as
+/// NoteOff has arrived.  Files are named after the note and the
+/// sample, and the bank if it has one.  A bank's loudness target
+/// applies to its samples
+pub fn kit(
+    config: &Path,
+    dir: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = process_samples_json(&config.to_string_lossy(), None)?;
+    let gain = config.gain.map_or(1.0, db_to_gain);
+    fs::create_dir_all(dir)?;
+    let mut names: BTreeSet<String> = BTreeSet::new();
+    for descr in config.samples_descr.iter() {
+        let target = config
+            .banks
+            .iter()
+            .find(|bank| descr.bank.as_ref() == Some(&bank.name))
+            .and_then(|bank| bank.target_lufs);
+        for (suffix, mut sample) in samples(descr, config.quality)? {
+            if let Some(target) = target {
+                let label = sample.label().to_string();
+                loudness::normalize(
+                    &mut sample.data,
+                    sample.source,
+                    target,
+                    &label,
+                );
+            }
+            let stem = Path::new(&descr.path)
+                .file_stem()
+                .or_else(|| {
+                    descr
+                        .segments
+                        .first()
+                        .and_then(|s| Path::new(&s.path).file_stem())
+                })
+                .map_or("sample".into(), |stem| stem.to_string_lossy());
+            let mut name = format!("{:03}-{stem}{suffix}", sample.note);
+            if let Some(bank) = &descr.bank {
+                name = format!("{bank}-{name}");
+            }
+
+            // Layers on the same note from files with the same name
+            // are numbered
+            let mut unique = name.clone();
+            for n in 2.. {
+                if names.insert(unique.clone()) {
+                    break;
+                }
+                unique = format!("{name}-{n}");
+            }
+            let path = dir.join(format!("{unique}.wav"));
+            let data = render(sample, gain, config.fade_curve);
+            write_wav(&path, &data)
+                .map_err(|err| format!("{}: {err}", path.display()))?;
+            eprintln!("{}: {} frames", path.display(), data.len());
+        }
+    }
+    Ok(())
+}
+
+/// The samples a sample description plays, with the suffix for each
+/// one's file name
+fn samples(
+    descr: &SampleDescr,
+    quality: Quality,
+) -> Result<Vec<(String, SampleData)>, Box<dyn std::error::Error>> {
+    let first = descr.segments.first().map_or(&descr.path, |s| &s.path);
+    let source = decode::info(first, descr.format).unwrap_or_default();
+    let sample =
+        |data: Vec<f32>, note: u8, segments: Vec<Segment>| SampleData {
+            path: descr.path.clone(),
+            source,
+            data,
+            note,
+            segments,
+            quality: descr.quality.unwrap_or(quality),
+            eq: descr.eq,
+            pitch_envelope: descr.pitch_envelope,
+            effects: descr.effects.clone(),
+            max_play_seconds: descr.max_play_seconds,
+            name: descr.name.clone(),
+            chord: descr.chord.clone(),
+            ..Default::default()
+        };
+    let decode = |path: &str| {
+        decode::decode(path, descr.format)
+            .map_err(|err| format!("{path}: {err}"))
+    };
+    if let Some(slice) = &descr.slice {
+        let data = decode(&descr.path)?;
+        let starts = slice::starts(&data, slice);
+        let ends = starts.iter().skip(1).copied().chain([data.len()]);
+        return Ok(starts
+            .iter()
+            .zip(ends)
+            .enumerate()
+            .map_while(|(i, (start, end))| {
+                let note = slice.note(descr.note, i)?;
+                let data = data[*start..end].to_vec();
+                Some((format!("-{i}"), sample(data, note, vec![])))
+            })
+            .collect());
+    }
+    if let Some(keys) = &descr.keys {
+        let data = decode(&descr.path)?;
+        let quality = descr.quality.unwrap_or(quality);
+        return Ok(keys
+            .notes()
+            .map(|key| {
+                let semitones = key as i32 - descr.note as i32;
+                let data = keys.render(&data, semitones, quality);
+                (String::new(), sample(data, key, vec![]))
+            })
+            .collect());
+    }
+    if descr.segments.is_empty() {
+        let data = decode(&descr.path)?;
+        return Ok(vec![(String::new(), sample(data, descr.note, vec![]))]);
+    }
+    let mut data: Vec<f32> = vec![];
+    let mut segments: Vec<Segment> = vec![];
+    for segment in descr.segments.iter() {
+        let start = data.len();
+        data.append(&mut decode(&segment.path)?);
+        segments.push(Segment {
+            start,
+            end: data.len(),
+            looping: false,
+        });
+    }
+    Ok(vec![(String::new(), sample(data, descr.note, segments))])
+}
+
+/// Play a sample through an engine of its own until it finishes
+fn render(
+    sample: SampleData,
+    gain: f32,
+    curve: Curve,
+) -> Vec<f32> {
+    // A voice for each note of a chord
+    let voices = sample.chord.as_ref().map_or(1, |c| c.intervals.len());
+    let status: Arc<Vec<VoiceStatus>> =
+        Arc::new((0..voices.max(1)).map(|_| VoiceStatus::default()).collect());
+    let (sender, receiver) = sync_channel::<Command>(2);
+    let effects = sample.voice_effects(SAMPLE_RATE);
+    let mut engine = Engine::new(
+        vec![Arc::new(sample)],
+        status.clone(),
+        receiver,
+        1,
+        vec![0],
+    );
+    engine.set_sample_rate(SAMPLE_RATE);
+    engine.fade_curve(curve);
+    let _ = sender.try_send(Command::Fade(Fade {
+        target: FadeTarget::Master,
+        gain,
+        frames: 0,
+    }));
+    let _ = sender.try_send(Command::Trigger {
+        sample: 0,
+        gain: 1.0,
+        velocity: 127,
+        effects,
+    });
+    let mut data: Vec<f32> = vec![];
+    loop {
+        engine.process(&[], FRAMES);
+        data.extend_from_slice(&engine.output(0)[..FRAMES]);
+        if status.iter().all(|s| s.length.load(Ordering::Relaxed) == 0) {
+            break;
+        }
+    }
+
+    // The last buffer is mostly silence after the voice ended
+    let end = data
+        .iter()
+        .rposition(|x| *x != 0.0)
+        .map_or(0, |end| end + 1);
+    data.truncate(end);
+    data
+}
+
+/// Write mono 32 bit float WAV
+fn write_wav(
+    path: &Path,
+    data: &[f32],
+) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let bytes = (data.len() * 4) as u32;
+    file.write_all(b"RIFF")?;
+    file.write_all(&(36 + bytes).to_le_bytes())?;
+    file.write_all(b"WAVEfmt ")?;
+    file.write_all(&16_u32.to_le_bytes())?;
+
+    // IEEE float, one channel
+    file.write_all(&3_u16.to_le_bytes())?;
+    file.write_all(&1_u16.to_le_bytes())?;
+    file.write_all(&(SAMPLE_RATE as u32).to_le_bytes())?;
+    file.write_all(&(SAMPLE_RATE as u32 * 4).to_le_bytes())?;
+    file.write_all(&4_u16.to_le_bytes())?;
+    file.write_all(&32_u16.to_le_bytes())?;
+    file.write_all(b"data")?;
+    file.write_all(&bytes.to_le_bytes())?;
+    for x in data {
+        file.write_all(&x.to_le_bytes())?;
+    }
+    file.flush()
+}
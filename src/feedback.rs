@@ -0,0 +1,149 @@
+use crate::bank::Banks;
+use midir::{MidiOutput, MidiOutputConnection};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How often the output port and the active bank are checked
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// A few colours by name, as their numbers in the palette the
+/// Launchpad and APC mini mk2 share
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorName {
+    White,
+    Red,
+    Orange,
+    Yellow,
+    Green,
+    Cyan,
+    Blue,
+    Purple,
+    Pink,
+}
+
+/// A pad's colour, by name or as a number in the controller's palette
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Color {
+    Palette(u8),
+    Name(ColorName),
+}
+
+impl Color {
+    /// The velocity that lights a pad in the colour
+    fn velocity(self) -> u8 {
+        match self {
+            Color::Palette(index) => index.min(127),
+            Color::Name(name) => match name {
+                ColorName::White => 3,
+                ColorName::Red => 5,
+                ColorName::Orange => 9,
+                ColorName::Yellow => 13,
+                ColorName::Green => 21,
+                ColorName::Cyan => 37,
+                ColorName::Blue => 45,
+                ColorName::Purple => 49,
+                ColorName::Pink => 53,
+            },
+        }
+    }
+}
+
+/// Light the pads of a grid controller in their samples' colours, by
+/// sending a NoteOn for each sample's note on MIDI `channel` (from
+/// zero) to the output port whose name contains `output`.  Launchpads
+/// in programmer mode and APCs take the velocity as the colour
+#[derive(Clone, Debug, Deserialize)]
+pub struct FeedbackDescr {
+    pub output: String,
+    #[serde(default)]
+    pub channel: u8,
+}
+
+/// A sample's pad: its note, its bank if it is in one, and its colour
+pub struct Pad {
+    pub note: u8,
+    pub bank: Option<usize>,
+    pub color: Color,
+}
+
+/// Keep the controller's pads lit.  The output port is watched, so the
+/// pads are lit again when the controller is plugged back in, and
+/// when the active bank changes only its samples are lit
+pub fn start(
+    descr: FeedbackDescr,
+    pads: Vec<Pad>,
+    banks: Arc<Banks>,
+) {
+    thread::spawn(move || {
+        let mut connection: Option<(String, MidiOutputConnection)> = None;
+        let mut shown: Option<Option<usize>> = None;
+        let mut lit: Vec<u8> = vec![];
+        loop {
+            let names = match MidiOutput::new("MidiSampleQzt feedback") {
+                Ok(output) => output
+                    .ports()
+                    .iter()
+                    .filter_map(|port| output.port_name(port).ok())
+                    .collect(),
+                Err(_) => vec![],
+            };
+            if let Some((name, _)) = &connection {
+                if !names.contains(name) {
+                    eprintln!("Feedback output {name} disconnected");
+                    connection = None;
+                }
+            }
+            if connection.is_none() {
+                if let Some(name) =
+                    names.iter().find(|name| name.contains(&descr.output))
+                {
+                    match connect(name) {
+                        Ok(output) => {
+                            eprintln!("Lighting pads on {name}");
+                            connection = Some((name.clone(), output));
+                            shown = None;
+                            lit.clear();
+                        },
+                        Err(err) => eprintln!("{err}: Feedback output {name}"),
+                    }
+                }
+            }
+            let active = banks.active();
+            if let Some((_, output)) = &mut connection {
+                if shown != Some(active) {
+                    let status = 0x90 | (descr.channel & 0x0f);
+                    for note in lit.drain(..) {
+                        let _ = output.send(&[status, note, 0]);
+                    }
+                    for pad in pads.iter() {
+                        if pad.bank.is_none() || pad.bank == active {
+                            let velocity = pad.color.velocity();
+                            let _ = output.send(&[status, pad.note, velocity]);
+                            lit.push(pad.note);
+                        }
+                    }
+                    shown = Some(active);
+                }
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    });
+}
+
+fn connect(
+    name: &str
+) -> Result<MidiOutputConnection, Box<dyn std::error::Error>> {
+    let output = MidiOutput::new("MidiSampleQzt")?;
+    let ports = output.ports();
+    let port = ports
+        .iter()
+        .find(|port| output.port_name(port).ok().as_deref() == Some(name))
+        .ok_or("Port went away")?;
+    Ok(output
+        .connect(port, "feedback")
+        .map_err(|err| err.to_string())?)
+}
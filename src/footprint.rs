@@ -0,0 +1,158 @@
+use crate::config::{process_samples_json, SampleDescr};
+use crate::decode::{self, Format, Info};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Bytes each decoded value takes in memory
+pub const VALUE_BYTES: usize = std::mem::size_of::<f32>();
+
+/// The memory and playing time of some samples
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Footprint {
+    pub samples: usize,
+    pub seconds: f64,
+    pub bytes: usize,
+}
+
+impl Footprint {
+    pub fn add(
+        &mut self,
+        other: &Footprint,
+    ) {
+        self.samples += other.samples;
+        self.seconds += other.seconds;
+        self.bytes += other.bytes;
+    }
+
+    /// The bytes in mebibytes
+    pub fn mib(&self) -> f64 {
+        self.bytes as f64 / 1024.0 / 1024.0
+    }
+}
+
+/// Print how much memory each sample in a configuration takes once it
+/// is decoded, how long it plays, and the sample rate and channels of
+/// its file, then the totals for each bank, so a kit can be planned
+/// to fit a small machine.  A sample on keys has a copy for each key,
+/// and a release sample one for each note, as when they are loaded
+pub fn stats(config: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let config = process_samples_json(&config.to_string_lossy(), None)?;
+    let mut banks: BTreeMap<Option<String>, Footprint> = BTreeMap::new();
+    println!(
+        "{:>4}  {:<12} {:>9} {:>9} {:>6} {:>3}  Sample",
+        "Note", "Bank", "Seconds", "MiB", "Rate", "Ch"
+    );
+    for descr in config.samples_descr.iter() {
+        let (footprint, info) = sample(descr)?;
+        println!(
+            "{:>4}  {:<12} {:>9.3} {:>9.3} {:>6} {:>3}  {}",
+            descr.note,
+            descr.bank.as_deref().unwrap_or("-"),
+            footprint.seconds,
+            footprint.mib(),
+            info.sample_rate.map_or("?".to_string(), |r| r.to_string()),
+            info.channels.map_or("?".to_string(), |c| c.to_string()),
+            descr.name.as_deref().unwrap_or(&descr.path),
+        );
+        banks.entry(descr.bank.clone()).or_default().add(&footprint);
+    }
+    println!();
+    let mut total = Footprint::default();
+    for (bank, footprint) in banks.iter() {
+        total.add(footprint);
+        println!(
+            "{}: {} samples, {:.3} s, {:.3} MiB",
+            bank.as_deref()
+                .map_or("No bank".to_string(), |b| format!("Bank {b}")),
+            footprint.samples,
+            footprint.seconds,
+            footprint.mib()
+        );
+    }
+    println!(
+        "Total: {} samples, {:.3} s, {:.3} MiB",
+        total.samples,
+        total.seconds,
+        total.mib()
+    );
+    Ok(())
+}
+
+/// What one sample takes, its segments and release sample included,
+/// and what its first file holds
+fn sample(
+    descr: &SampleDescr
+) -> Result<(Footprint, Info), Box<dyn std::error::Error>> {
+    let paths: Vec<&str> = if descr.segments.is_empty() {
+        vec![&descr.path]
+    } else {
+        descr.segments.iter().map(|s| s.path.as_str()).collect()
+    };
+    let notes = descr.keys.as_ref().map_or(1, |keys| keys.notes().len());
+    let mut footprint = Footprint {
+        samples: notes,
+        ..Footprint::default()
+    };
+    let mut first: Option<Info> = None;
+    for path in paths {
+        let info = decode::info(path, descr.format)
+            .map_err(|err| format!("{path}: {err}"))?;
+        first.get_or_insert(info);
+        let values = match &descr.keys {
+            // Each key's copy is as long as its pitch makes it
+            Some(keys) => {
+                let data = decode::decode(path, descr.format)
+                    .map_err(|err| format!("{path}: {err}"))?;
+                keys.notes()
+                    .map(|key| {
+                        let semitones = key as i32 - descr.note as i32;
+                        keys.render(&data, semitones, Default::default()).len()
+                    })
+                    .sum()
+            },
+            None => values(path, descr.format, &info)?,
+        };
+        footprint.add(&measure(values, &info));
+    }
+    if let Some(release) = &descr.release_sample {
+        let info = decode::info(&release.path, release.format)
+            .map_err(|err| format!("{}: {err}", release.path))?;
+        let values = values(&release.path, release.format, &info)? * notes;
+        let mut release = measure(values, &info);
+        release.samples = notes;
+        footprint.add(&release);
+    }
+    Ok((footprint, first.unwrap_or_default()))
+}
+
+/// The values a file decodes to, from its header if it says
+fn values(
+    path: &str,
+    format: Option<Format>,
+    info: &Info,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    match (info.frames, info.channels) {
+        (Some(frames), Some(channels)) => Ok(frames as usize * channels),
+        _ => Ok(decode::decode(path, format)
+            .map_err(|err| format!("{path}: {err}"))?
+            .len()),
+    }
+}
+
+/// The footprint of `values` decoded from a file that holds `info`
+pub fn measure(
+    values: usize,
+    info: &Info,
+) -> Footprint {
+    let seconds = match (info.sample_rate, info.channels) {
+        (Some(rate), Some(channels)) if rate > 0 && channels > 0 => {
+            values as f64 / channels as f64 / rate as f64
+        },
+        _ => 0.0,
+    };
+    Footprint {
+        samples: 0,
+        seconds,
+        bytes: values * VALUE_BYTES,
+    }
+}
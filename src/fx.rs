@@ -0,0 +1,424 @@
+use crate::config::expand_path;
+use crate::gain::db_to_gain;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::f32::consts::{FRAC_1_SQRT_2, PI};
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::path::Path;
+use std::sync::Arc;
+
+fn default_q() -> f32 {
+    FRAC_1_SQRT_2
+}
+
+fn default_bits() -> u8 {
+    8
+}
+
+fn default_downsample() -> usize {
+    1
+}
+
+fn default_drive() -> f32 {
+    6.0
+}
+
+/// Something that changes a voice's sound as it plays.  Each voice
+/// playing a sample with effects has its own, so an effect can keep
+/// state from one block to the next
+pub trait Effect: Send {
+    /// Change a block of the voice's frames in place
+    fn process(
+        &mut self,
+        frames: &mut [f32],
+    );
+}
+
+/// What a filter lets through
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterMode {
+    Lowpass,
+    Highpass,
+    Bandpass,
+}
+
+/// An effect in a sample's chain.  `gain` is in dB.  A `filter` cuts
+/// off at `frequency` in Hz, with resonance `q`.  `bitcrush` keeps
+/// `bits` bits and holds each frame for `downsample` frames.
+/// `saturation` drives the voice `drive` dB into a soft clipper.  A
+/// `plugin` is an effect in the shared library at `path`, given
+/// `params`, loaded with `load`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase", tag = "type")]
+pub enum EffectDescr {
+    Gain {
+        db: f32,
+    },
+    Filter {
+        mode: FilterMode,
+        frequency: f32,
+        #[serde(default = "default_q")]
+        q: f32,
+    },
+    Bitcrush {
+        #[serde(default = "default_bits")]
+        bits: u8,
+        #[serde(default = "default_downsample")]
+        downsample: usize,
+    },
+    Saturation {
+        #[serde(default = "default_drive")]
+        drive: f32,
+    },
+    Plugin {
+        path: String,
+        #[serde(default)]
+        params: Value,
+        #[serde(skip)]
+        plugin: Option<Arc<Plugin>>,
+    },
+}
+
+impl EffectDescr {
+    /// A new effect for a voice, at `sample_rate`
+    pub fn effect(
+        &self,
+        sample_rate: usize,
+    ) -> Box<dyn Effect> {
+        match *self {
+            EffectDescr::Gain { db } => Box::new(Gain(db_to_gain(db))),
+            EffectDescr::Filter { mode, frequency, q } => {
+                Box::new(Filter::new(mode, frequency, q, sample_rate))
+            },
+            EffectDescr::Bitcrush { bits, downsample } => Box::new(Bitcrush {
+                step: 2.0_f32.powi(1 - bits.clamp(1, 24) as i32),
+                downsample: downsample.max(1),
+                held: 0.0,
+                count: 0,
+            }),
+            EffectDescr::Saturation { drive } => {
+                let drive = db_to_gain(drive);
+                Box::new(Saturation {
+                    drive,
+                    makeup: 1.0 / drive.tanh(),
+                })
+            },
+
+            // A plugin that has not been loaded leaves the voice alone
+            EffectDescr::Plugin { ref plugin, .. } => match plugin {
+                Some(plugin) => {
+                    Box::new(PluginEffect::new(plugin, sample_rate))
+                },
+                None => Box::new(Gain(1.0)),
+            },
+        }
+    }
+
+    /// Load a plugin's library, with `path` relative to `dir` if it is
+    /// not absolute.  Built in effects need nothing loading
+    pub fn load(
+        &mut self,
+        dir: &Path,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let EffectDescr::Plugin {
+            path,
+            params,
+            plugin,
+        } = self
+        {
+            *path = expand_path(path, dir)?.to_string_lossy().into_owned();
+            *plugin = Some(Arc::new(Plugin::load(path, params)?));
+        }
+        Ok(())
+    }
+}
+
+/// The effects on a voice, applied in order
+#[derive(Default)]
+pub struct Chain {
+    effects: Vec<Box<dyn Effect>>,
+}
+
+impl std::fmt::Debug for Chain {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter,
+    ) -> std::fmt::Result {
+        write!(f, "Chain of {} effects", self.effects.len())
+    }
+}
+
+impl Chain {
+    /// The chain for a voice playing a sample with `descrs`.  It
+    /// allocates, so is made before the voice's trigger is sent to
+    /// the Jack thread
+    pub fn new(
+        descrs: &[EffectDescr],
+        sample_rate: usize,
+    ) -> Self {
+        Self {
+            effects: descrs
+                .iter()
+                .map(|descr| descr.effect(sample_rate))
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.effects.is_empty()
+    }
+
+    /// Put the next frame through the chain.  The voices are mixed a
+    /// frame at a time, so each effect is given a block of one
+    pub fn tick(
+        &mut self,
+        x: f32,
+    ) -> f32 {
+        let mut frame = [x];
+        for effect in self.effects.iter_mut() {
+            effect.process(&mut frame);
+        }
+        frame[0]
+    }
+}
+
+struct Gain(f32);
+
+impl Effect for Gain {
+    fn process(
+        &mut self,
+        frames: &mut [f32],
+    ) {
+        for x in frames.iter_mut() {
+            *x *= self.0;
+        }
+    }
+}
+
+/// A state variable filter, in the form that stays stable as the
+/// cutoff is swept, with its two integrators
+struct Filter {
+    mode: FilterMode,
+    g: f32,
+    k: f32,
+    s1: f32,
+    s2: f32,
+}
+
+impl Filter {
+    fn new(
+        mode: FilterMode,
+        frequency: f32,
+        q: f32,
+        sample_rate: usize,
+    ) -> Self {
+        let rate = sample_rate as f32;
+        let frequency = frequency.clamp(1.0, rate * 0.49);
+        Self {
+            mode,
+            g: (PI * frequency / rate).tan(),
+            k: 1.0 / q.max(0.1),
+            s1: 0.0,
+            s2: 0.0,
+        }
+    }
+}
+
+impl Effect for Filter {
+    fn process(
+        &mut self,
+        frames: &mut [f32],
+    ) {
+        let Self { g, k, .. } = *self;
+        for x in frames.iter_mut() {
+            let high = (*x - (g + k) * self.s1 - self.s2) / (1.0 + g * (g + k));
+            let band = g * high + self.s1;
+            let low = g * band + self.s2;
+            self.s1 = g * high + band;
+            self.s2 = g * band + low;
+            *x = match self.mode {
+                FilterMode::Lowpass => low,
+                FilterMode::Highpass => high,
+                FilterMode::Bandpass => band,
+            };
+        }
+    }
+}
+
+/// Fewer bits and a lower sample rate, for a lo-fi sound.  `step` is
+/// the size of the smallest change kept
+struct Bitcrush {
+    step: f32,
+    downsample: usize,
+    held: f32,
+    count: usize,
+}
+
+impl Effect for Bitcrush {
+    fn process(
+        &mut self,
+        frames: &mut [f32],
+    ) {
+        for x in frames.iter_mut() {
+            if self.count == 0 {
+                self.held = (*x / self.step).round() * self.step;
+            }
+            self.count = (self.count + 1) % self.downsample;
+            *x = self.held;
+        }
+    }
+}
+
+/// A soft clipper, made up so a full scale frame stays at full scale
+struct Saturation {
+    drive: f32,
+    makeup: f32,
+}
+
+impl Effect for Saturation {
+    fn process(
+        &mut self,
+        frames: &mut [f32],
+    ) {
+        for x in frames.iter_mut() {
+            *x = (*x * self.drive).tanh() * self.makeup;
+        }
+    }
+}
+
+/// An effect in a shared library, which has these C functions:
+///
+/// ```c
+/// void *qzt_effect_new(const char *params, uint32_t sample_rate);
+/// void qzt_effect_process(void *effect, float *frames, size_t count);
+/// void qzt_effect_free(void *effect);
+/// ```
+///
+/// `qzt_effect_new` makes an effect for a voice, from the plugin's
+/// "params" as JSON, and returns NULL if it cannot.  It is called
+/// before the voice's trigger is sent, in the thread that plays the
+/// note: the MIDI thread, the control thread or the clock's, so it
+/// may allocate but should be quick.  `qzt_effect_process` is called
+/// in the Jack thread, once for each frame with `count` one, as the
+/// voices are mixed a frame at a time, so it must not block.
+/// `qzt_effect_free` is called as the voice ends, in the thread the
+/// Jack thread sends what it has finished with to, unless that falls
+/// far behind.  The library is never unloaded
+#[derive(Debug)]
+pub struct Plugin {
+    params: CString,
+    new: unsafe extern "C" fn(*const c_char, u32) -> *mut c_void,
+    process: unsafe extern "C" fn(*mut c_void, *mut f32, usize),
+    free: unsafe extern "C" fn(*mut c_void),
+}
+
+impl Plugin {
+    fn load(
+        path: &str,
+        params: &Value,
+    ) -> Result<Self, String> {
+        let file = CString::new(path).map_err(|err| err.to_string())?;
+
+        // SAFETY: The library is trusted as the configuration is, and
+        // is never closed so the functions stay valid
+        unsafe {
+            let library =
+                libc::dlopen(file.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL);
+            if library.is_null() {
+                return Err(dl_error());
+            }
+            let symbol = |name: &CStr| {
+                let symbol = libc::dlsym(library, name.as_ptr());
+                if symbol.is_null() {
+                    Err(format!("{path}: No {}", name.to_string_lossy()))
+                } else {
+                    Ok(symbol)
+                }
+            };
+            Ok(Self {
+                params: CString::new(params.to_string())
+                    .map_err(|err| err.to_string())?,
+                new: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(*const c_char, u32) -> *mut c_void,
+                >(symbol(c"qzt_effect_new")?),
+                process: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(*mut c_void, *mut f32, usize),
+                >(symbol(c"qzt_effect_process")?),
+                free: std::mem::transmute::<
+                    *mut c_void,
+                    unsafe extern "C" fn(*mut c_void),
+                >(symbol(c"qzt_effect_free")?),
+            })
+        }
+    }
+}
+
+/// The reason the dynamic linker last failed
+fn dl_error() -> String {
+    // SAFETY: dlerror returns NULL or a C string valid until the next
+    // call
+    unsafe {
+        let error = libc::dlerror();
+        if error.is_null() {
+            "Cannot load the library".to_string()
+        } else {
+            CStr::from_ptr(error).to_string_lossy().into_owned()
+        }
+    }
+}
+
+/// A plugin's effect for one voice
+struct PluginEffect {
+    plugin: Arc<Plugin>,
+    effect: *mut c_void,
+}
+
+// SAFETY: Each voice's effect is only used by the thread that has the
+// voice
+unsafe impl Send for PluginEffect {}
+
+impl PluginEffect {
+    fn new(
+        plugin: &Arc<Plugin>,
+        sample_rate: usize,
+    ) -> Self {
+        // SAFETY: `params` is a C string that outlives the call
+        let effect =
+            unsafe { (plugin.new)(plugin.params.as_ptr(), sample_rate as u32) };
+        Self {
+            plugin: plugin.clone(),
+            effect,
+        }
+    }
+}
+
+impl Effect for PluginEffect {
+    fn process(
+        &mut self,
+        frames: &mut [f32],
+    ) {
+        if !self.effect.is_null() {
+            // SAFETY: The plugin made `effect`, and `frames` is valid
+            // for `frames.len()` floats
+            unsafe {
+                (self.plugin.process)(
+                    self.effect,
+                    frames.as_mut_ptr(),
+                    frames.len(),
+                )
+            };
+        }
+    }
+}
+
+impl Drop for PluginEffect {
+    fn drop(&mut self) {
+        if !self.effect.is_null() {
+            // SAFETY: The plugin made `effect`, and it is freed once
+            unsafe { (self.plugin.free)(self.effect) };
+        }
+    }
+}
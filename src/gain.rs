@@ -0,0 +1,4 @@
+/// Convert decibels to a linear gain.  `-inf` is silence
+pub fn db_to_gain(db: f32) -> f32 {
+    10_f32.powf(db / 20.0)
+}
@@ -0,0 +1,562 @@
+use crate::control::{self, ControlState};
+use crate::decode;
+use crate::engine::{SampleData, TriggerEvent};
+use crate::player::Player;
+use base64::Engine as _;
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// How many events can be waiting for a WebSocket client before they
+/// are dropped for it
+pub const EVENT_QUEUE: usize = 1024;
+
+/// The longest request body taken.  Commands and velocities are a
+/// line of text
+const MAX_BODY: u64 = 4096;
+
+/// How many requests are served at once, WebSockets included.  More
+/// are answered with status 503
+const MAX_CONNECTIONS: usize = 32;
+
+/// How often the output levels are sent to WebSocket clients
+const LEVELS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The mDNS service type the API is advertised as, for companion
+/// apps to look for
+const SERVICE_TYPE: &str = "_midi-sample-qzt._tcp.local.";
+
+/// Added to the client's key to make the WebSocket handshake's answer
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The WebSocket clients, each with where its events go
+type Clients = Arc<Mutex<Vec<SyncSender<String>>>>;
+
+/// Who may use the API, and for what.  With a `token`, requests must
+/// have it, as a bearer token in the "Authorization" header or, for
+/// WebSockets from a browser, as `token` in the query.  Without one
+/// the API is read only, and only answers pages it serves itself, as
+/// any web page open in a browser on the machine can send it
+/// requests.  `read_only` refuses everything but GET, so the API can
+/// be looked at but not play anything
+pub struct Access {
+    pub token: Option<String>,
+    pub read_only: bool,
+}
+
+impl Access {
+    /// A JSON answer.  With a token any web page may read it, as only
+    /// those with the token are answered
+    fn answer(
+        &self,
+        status: u16,
+        value: Value,
+    ) -> Response<std::io::Cursor<Vec<u8>>> {
+        let response = Response::from_string(value.to_string())
+            .with_status_code(status)
+            .with_header(header("Content-Type", "application/json"));
+        match self.token {
+            Some(_) => {
+                response.with_header(header("Access-Control-Allow-Origin", "*"))
+            },
+            None => response,
+        }
+    }
+
+    /// Whether a request has the token, if one is needed.  Without a
+    /// token, only GET requests from no page or the API's own are
+    /// allowed
+    fn allows(
+        &self,
+        request: &Request,
+    ) -> bool {
+        let Some(token) = &self.token else {
+            return *request.method() == Method::Get && same_origin(request);
+        };
+        let header = request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .and_then(|h| h.value.as_str().strip_prefix("Bearer "))
+            .map(str::trim);
+        let query = request.url().split_once('?').and_then(|(_, query)| {
+            query.split('&').find_map(|p| p.strip_prefix("token="))
+        });
+        header.or(query).is_some_and(|given| same(given, token))
+    }
+}
+
+/// Whether a request comes from a page served by the API itself, or
+/// from no page at all.  Browsers say which page a request comes from
+/// in the "Origin" header, for WebSockets as well, which are not kept
+/// to their own origin as other requests are
+fn same_origin(request: &Request) -> bool {
+    let field = |name: &'static str| {
+        request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv(name))
+            .map(|h| h.value.as_str())
+    };
+    let Some(origin) = field("Origin") else {
+        return true;
+    };
+    let host = origin
+        .strip_prefix("http://")
+        .or_else(|| origin.strip_prefix("https://"));
+    host.is_some() && host == field("Host")
+}
+
+/// Compare a token given with the one needed, taking as long
+/// wherever they differ
+fn same(
+    given: &str,
+    token: &str,
+) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// What the HTTP requests need to get at
+struct Api {
+    access: Access,
+    control: Arc<ControlState>,
+    player: Arc<Player>,
+    clients: Clients,
+
+    /// Requests being served
+    connections: AtomicUsize,
+
+    /// Advertises the API while it runs
+    _mdns: Option<ServiceDaemon>,
+}
+
+/// Serve the HTTP API on `address`, such as "0.0.0.0:8080", so a web
+/// page on a tablet can control the sampler:
+///
+/// GET /status: the bank, output levels and what the audio is doing
+/// POST /command: run the control command in the body
+/// POST /notes/<note>: play a note, at the velocity in the body
+/// DELETE /notes/<note>: release a note
+/// GET /events: a WebSocket sending each trigger, the levels, and
+/// changes of bank, scene and samples
+/// GET /metrics: counts and levels for Prometheus
+///
+/// Everything else is answered with JSON.  The API is advertised by
+/// mDNS so apps on the network can find it.  `access` says who may
+/// use it, and without a token only lets it be looked at.  Beyond the loopback address it must have a token or be
+/// read only, or it is not started
+pub fn start(
+    address: &str,
+    access: Access,
+    control: Arc<ControlState>,
+    player: Arc<Player>,
+    events: Receiver<TriggerEvent>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let server = Server::http(address)?;
+    let exposed = server
+        .server_addr()
+        .to_ip()
+        .is_some_and(|address| !address.ip().is_loopback());
+    if exposed && access.token.is_none() && !access.read_only {
+        return Err("Listening beyond the loopback address needs \
+                    \"http_token\" or \"http_read_only\""
+            .into());
+    }
+    let mdns = match server.server_addr().to_ip() {
+        Some(address) if !address.ip().is_loopback() => {
+            advertise(address.port())
+                .map_err(|err| eprintln!("{err}: Advertising the HTTP API"))
+                .ok()
+        },
+        _ => None,
+    };
+    let api = Arc::new(Api {
+        access,
+        control,
+        player,
+        clients: Arc::new(Mutex::new(vec![])),
+        connections: AtomicUsize::new(0),
+        _mdns: mdns,
+    });
+
+    let clients = api.clients.clone();
+    thread::spawn(move || {
+        for event in events {
+            let SampleData {
+                note, path, name, ..
+            } = &*event.sample;
+            let time = event
+                .time
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or(0.0);
+            broadcast(
+                &clients,
+                json!({
+                    "event": "trigger",
+                    "time": time,
+                    "note": note,
+                    "velocity": event.velocity,
+                    "sample": path,
+                    "voice": event.voice,
+                    "name": name,
+                }),
+            );
+        }
+    });
+    // The levels are sent as often as they are looked at, and the
+    // bank, scene and samples when they have changed since
+    let watched = api.clone();
+    thread::spawn(move || {
+        let control = &watched.control;
+        let mut bank = control.banks.active_name().map(str::to_string);
+        let mut scene = control.scenes.active();
+        let mut samples = watched.player.samples();
+        loop {
+            thread::sleep(LEVELS_INTERVAL);
+            let levels = control.levels.peaks();
+            broadcast(
+                &watched.clients,
+                json!({ "event": "levels", "levels": levels }),
+            );
+
+            let active = control.banks.active_name().map(str::to_string);
+            if active != bank {
+                broadcast(
+                    &watched.clients,
+                    json!({ "event": "bank", "bank": active }),
+                );
+                bank = active;
+            }
+            let active = control.scenes.active();
+            if active != scene {
+                broadcast(
+                    &watched.clients,
+                    json!({ "event": "scene", "scene": active }),
+                );
+                scene = active;
+            }
+            let now = watched.player.samples();
+            for (old, new) in samples.iter().zip(now.iter()) {
+                if !Arc::ptr_eq(old, new) {
+                    broadcast(
+                        &watched.clients,
+                        json!({
+                            "event": "sample",
+                            "note": new.note,
+                            "sample": new.path,
+                            "name": new.name,
+                            "loaded": !new.data.is_empty(),
+                        }),
+                    );
+                }
+            }
+            samples = now;
+        }
+    });
+
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            if api.connections.fetch_add(1, Ordering::Relaxed)
+                >= MAX_CONNECTIONS
+            {
+                api.connections.fetch_sub(1, Ordering::Relaxed);
+                let busy = api
+                    .access
+                    .answer(503, json!({ "error": "Too many requests" }));
+                if let Err(err) = request.respond(busy) {
+                    eprintln!("HTTP: {err}");
+                }
+                continue;
+            }
+            let api = api.clone();
+            thread::spawn(move || {
+                if let Err(err) = api.serve(request) {
+                    eprintln!("HTTP: {err}");
+                }
+                api.connections.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+    });
+    eprintln!("HTTP API on {address}");
+    Ok(())
+}
+
+impl Api {
+    fn serve(
+        &self,
+        mut request: Request,
+    ) -> std::io::Result<()> {
+        let url = request.url().to_string();
+        let path: Vec<&str> =
+            url.split('?').next().unwrap_or("").split('/').collect();
+
+        // A browser asks first whether it may send the token
+        if *request.method() == Method::Options {
+            return request.respond(
+                self.access
+                    .answer(204, json!({}))
+                    .with_header(header(
+                        "Access-Control-Allow-Headers",
+                        "Authorization",
+                    ))
+                    .with_header(header(
+                        "Access-Control-Allow-Methods",
+                        "GET, POST, DELETE",
+                    )),
+            );
+        }
+        if !self.access.allows(&request) {
+            return request.respond(
+                self.access.answer(401, json!({ "error": "Token needed" })),
+            );
+        }
+        if self.access.read_only && *request.method() != Method::Get {
+            return request.respond(
+                self.access.answer(403, json!({ "error": "Read only" })),
+            );
+        }
+
+        // Only read the body once the request may be served, and only
+        // as much of it as a command needs
+        let mut body = String::new();
+        request
+            .as_reader()
+            .take(MAX_BODY + 1)
+            .read_to_string(&mut body)?;
+        if body.len() as u64 > MAX_BODY {
+            return request.respond(
+                self.access.answer(413, json!({ "error": "Body too long" })),
+            );
+        }
+        let body = body.trim();
+        let result = match (request.method(), &path[1..]) {
+            (Method::Get, ["events"]) => return self.websocket(request),
+            (Method::Get, ["metrics"]) => {
+                return request.respond(
+                    Response::from_string(self.metrics()).with_header(header(
+                        "Content-Type",
+                        "text/plain; version=0.0.4",
+                    )),
+                )
+            },
+            (Method::Get, ["status"]) => Ok(json!({
+                "bank": self.control.banks.active_name(),
+                "stats": self.control.stats.report(),
+                "levels": self.control.levels.peaks(),
+            })),
+            (Method::Post, ["command"]) => {
+                control::command(body, &self.control)
+                    .map(|data| json!({ "data": data }))
+            },
+            (method @ (Method::Post | Method::Delete), ["notes", note]) => {
+                match note.parse::<u8>().ok().filter(|n| *n < 128) {
+                    Some(note) if *method == Method::Delete => {
+                        self.player.note_off(note, 0);
+                        Ok(json!({}))
+                    },
+                    Some(note) => {
+                        match body.parse::<u8>().ok().filter(|v| *v < 128) {
+                            Some(velocity) => {
+                                self.player.note_on(note, velocity);
+                                Ok(json!({}))
+                            },
+                            None if body.is_empty() => {
+                                self.player.note_on(note, 127);
+                                Ok(json!({}))
+                            },
+                            None => Err(format!("Bad velocity: {body}")),
+                        }
+                    },
+                    None => Err(format!("Bad note: {note}")),
+                }
+            },
+            _ => {
+                return request.respond(self.access.answer(
+                    404,
+                    json!({ "error": format!("Not found: {url}") }),
+                ))
+            },
+        };
+        request.respond(match result {
+            Ok(value) => self.access.answer(200, value),
+            Err(err) => self.access.answer(400, json!({ "error": err })),
+        })
+    }
+
+    /// The metrics in the Prometheus text format
+    fn metrics(&self) -> String {
+        let stats = &self.control.stats;
+        let count = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let voices = self
+            .control
+            .voices
+            .iter()
+            .filter(|voice| voice.length.load(Ordering::Relaxed) > 0)
+            .count();
+        let mut metrics = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: f64| {
+            metrics += &format!(
+                "# HELP midi_sample_qzt_{name} {help}\n\
+                 # TYPE midi_sample_qzt_{name} {kind}\n\
+                 midi_sample_qzt_{name} {value}\n"
+            );
+        };
+        metric(
+            "triggers_total",
+            "counter",
+            "Voices started",
+            count(&stats.triggers) as f64,
+        );
+        metric("active_voices", "gauge", "Voices playing", voices as f64);
+        metric(
+            "cycles_total",
+            "counter",
+            "Jack process cycles run",
+            count(&stats.cycles) as f64,
+        );
+        metric(
+            "missed_cycles_total",
+            "counter",
+            "Cycles output as silence because the engine was busy",
+            count(&stats.missed) as f64,
+        );
+        metric(
+            "xruns_total",
+            "counter",
+            "Cycles Jack reported as late",
+            count(&stats.xruns) as f64,
+        );
+        metric(
+            "decode_seconds_total",
+            "counter",
+            "Time spent decoding sample files",
+            decode::decode_time().as_secs_f64(),
+        );
+        metrics += "# HELP midi_sample_qzt_output_level Output level, linear\n\
+                    # TYPE midi_sample_qzt_output_level gauge\n";
+        for (channel, level) in self.control.levels.peaks().iter().enumerate() {
+            metrics += &format!(
+                "midi_sample_qzt_output_level{{channel=\"{}\"}} {level}\n",
+                channel + 1
+            );
+        }
+        metrics
+    }
+
+    /// Take over the connection as a WebSocket and send it events
+    /// until it goes away
+    fn websocket(
+        &self,
+        request: Request,
+    ) -> std::io::Result<()> {
+        let Some(key) = request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Sec-WebSocket-Key"))
+            .map(|h| h.value.to_string())
+        else {
+            return request.respond(
+                self.access
+                    .answer(400, json!({ "error": "Not a WebSocket request" })),
+            );
+        };
+        let accept = base64::engine::general_purpose::STANDARD.encode(
+            sha1_smol::Sha1::from(format!("{key}{WEBSOCKET_GUID}"))
+                .digest()
+                .bytes(),
+        );
+        let response = Response::empty(101)
+            .with_header(header("Upgrade", "websocket"))
+            .with_header(header("Connection", "Upgrade"))
+            .with_header(header("Sec-WebSocket-Accept", &accept));
+        let mut stream = request.upgrade("websocket", response);
+        let (sender, receiver) = sync_channel(EVENT_QUEUE);
+        self.clients.lock().unwrap().push(sender);
+        for text in receiver {
+            stream.write_all(&text_frame(&text))?;
+            stream.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Advertise the API on `port` by mDNS, on every interface, as
+/// "MidiSampleQzt on <host>"
+fn advertise(port: u16) -> Result<ServiceDaemon, mdns_sd::Error> {
+    let mut name = [0u8; 256];
+    // SAFETY: `name` is as long as it says, and is NUL terminated
+    // by being zeroed and one byte longer than allowed for
+    let host = if unsafe {
+        libc::gethostname(name.as_mut_ptr().cast(), name.len() - 1)
+    } == 0
+    {
+        let end = name.iter().position(|b| *b == 0).unwrap_or(0);
+        String::from_utf8_lossy(&name[..end]).into_owned()
+    } else {
+        "midi-sample-qzt".to_string()
+    };
+    let mdns = ServiceDaemon::new()?;
+    let info = ServiceInfo::new(
+        SERVICE_TYPE,
+        &format!("MidiSampleQzt on {host}"),
+        &format!("{host}.local."),
+        "",
+        port,
+        &[("status", "/status"), ("events", "/events")][..],
+    )?
+    .enable_addr_auto();
+    mdns.register(info)?;
+    Ok(mdns)
+}
+
+/// Send an event to every WebSocket client, forgetting the ones that
+/// have gone.  One that has fallen behind misses it
+fn broadcast(
+    clients: &Clients,
+    event: Value,
+) {
+    let text = event.to_string();
+    clients.lock().unwrap().retain(|client| {
+        !matches!(
+            client.try_send(text.clone()),
+            Err(TrySendError::Disconnected(_))
+        )
+    });
+}
+
+/// An unmasked WebSocket text frame, as a server sends
+fn text_frame(text: &str) -> Vec<u8> {
+    let mut frame = vec![0x81];
+    match text.len() {
+        length @ 0..=125 => frame.push(length as u8),
+        length @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend((length as u16).to_be_bytes());
+        },
+        length => {
+            frame.push(127);
+            frame.extend((length as u64).to_be_bytes());
+        },
+    }
+    frame.extend(text.as_bytes());
+    frame
+}
+
+fn header(
+    field: &str,
+    value: &str,
+) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).unwrap()
+}
@@ -0,0 +1,123 @@
+use crate::message::Message;
+use std::io::Read;
+
+/// The keys, in two rows like a piano's white and black keys.  The
+/// bottom row starts on C in the octave chosen, and the top row on
+/// the C above
+const BOTTOM: &[u8] = b"zsxdcvgbhnjm,";
+const TOP: &[u8] = b"q2w3er5t6y7ui";
+
+/// The octave the bottom row starts in, with middle C (note 60) in
+/// octave 4
+const DEFAULT_OCTAVE: i32 = 3;
+
+const VELOCITY: u8 = 100;
+
+const NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// Play notes from the computer keyboard, for auditioning and mapping
+/// samples with no MIDI controller to hand.  Each note is sent to
+/// `handler` as if it came from MIDI, and the one before it is
+/// released.  "[" and "]" change the octave, and Enter or Escape
+/// stops.  The terminal is put in raw mode so keys arrive as they are
+/// pressed.  If the input is not a terminal this just waits for Enter
+pub fn play(handler: impl Fn(Message)) {
+    let Some(saved) = raw_mode() else {
+        let _ = std::io::stdin().read_line(&mut String::new());
+        return;
+    };
+    eprintln!(
+        "Play notes on the keys {} and {}, \
+         change octave with [ and ], and press Enter to exit",
+        String::from_utf8_lossy(BOTTOM),
+        String::from_utf8_lossy(TOP)
+    );
+    let mut octave = DEFAULT_OCTAVE;
+    let mut playing: Option<u8> = None;
+    let mut stdin = std::io::stdin();
+    let mut key = [0u8];
+    while let Ok(1) = stdin.read(&mut key) {
+        let offset = BOTTOM
+            .iter()
+            .position(|k| *k == key[0])
+            .or_else(|| TOP.iter().position(|k| *k == key[0]).map(|p| p + 12));
+        match (key[0], offset) {
+            (b'\r' | b'\n' | 0x1b, _) => break,
+            (b'[' | b']', _) => {
+                octave = match key[0] {
+                    b'[' => (octave - 1).max(-1),
+                    _ => (octave + 1).min(8),
+                };
+                eprintln!("Octave {octave}");
+            },
+            (_, Some(offset)) => {
+                let note = (octave + 1) * 12 + offset as i32;
+                if !(0..128).contains(&note) {
+                    continue;
+                }
+                let note = note as u8;
+                if let Some(note) = playing.take() {
+                    handler(Message::NoteOff {
+                        channel: 0,
+                        note,
+                        velocity: 0,
+                    });
+                }
+                eprintln!("Note {note} ({})", name(note));
+                handler(Message::NoteOn {
+                    channel: 0,
+                    note,
+                    velocity: VELOCITY,
+                });
+                playing = Some(note);
+            },
+            _ => (),
+        }
+    }
+    if let Some(note) = playing {
+        handler(Message::NoteOff {
+            channel: 0,
+            note,
+            velocity: 0,
+        });
+    }
+    restore(&saved);
+}
+
+/// A note's name, such as "C4" for middle C
+fn name(note: u8) -> String {
+    format!("{}{}", NAMES[note as usize % 12], note as i32 / 12 - 1)
+}
+
+/// Turn off line editing and echo on the terminal, returning how it
+/// was so it can be put back.  Output is left alone
+fn raw_mode() -> Option<libc::termios> {
+    // SAFETY: `termios` is plain data, filled in by tcgetattr before
+    // it is used
+    unsafe {
+        if libc::isatty(libc::STDIN_FILENO) == 0 {
+            return None;
+        }
+        let mut saved: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(libc::STDIN_FILENO, &mut saved) != 0 {
+            return None;
+        }
+        let mut raw = saved;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+            return None;
+        }
+        Some(saved)
+    }
+}
+
+fn restore(saved: &libc::termios) {
+    // SAFETY: `saved` came from tcgetattr
+    unsafe {
+        libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, saved);
+    }
+}
@@ -0,0 +1,68 @@
+use crate::resample::Quality;
+use crate::stretch;
+use serde::{Deserialize, Serialize};
+use std::ops::RangeInclusive;
+
+/// How a sample is moved to another note's pitch.  `resample` plays
+/// it faster or slower, so higher notes are shorter, as a tape would.
+/// `stretch` keeps its length, with a phase vocoder, for vocal and
+/// melodic one-shots that must fit the beat whatever the note
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PitchMode {
+    #[default]
+    Resample,
+    Stretch,
+}
+
+/// Playing one sample across the notes from `low` to `high`, each at
+/// its own pitch, taking the sample's note as its pitch.  With
+/// `formants`, stretched samples keep the shape of their spectrum, so
+/// a voice does not sound bigger low down and smaller high up
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct KeysDescr {
+    pub low: u8,
+    pub high: u8,
+    #[serde(default)]
+    pub mode: PitchMode,
+    #[serde(default)]
+    pub formants: bool,
+}
+
+impl KeysDescr {
+    /// The notes the sample plays on
+    pub fn notes(&self) -> RangeInclusive<u8> {
+        self.low..=self.high.min(127)
+    }
+
+    /// `data`, `semitones` from its own pitch.  Each is worked out
+    /// when the sample is loaded, so playing it costs no more than
+    /// any other sample
+    pub fn render(
+        &self,
+        data: &[f32],
+        semitones: i32,
+        quality: Quality,
+    ) -> Vec<f32> {
+        let ratio = (semitones as f32 / 12.0).exp2();
+        if semitones == 0 {
+            return data.to_vec();
+        }
+        match self.mode {
+            PitchMode::Resample => {
+                let length = (data.len() as f32 / ratio) as usize;
+                (0..length)
+                    .map(|frame| {
+                        let position = frame as f64 * ratio as f64;
+                        quality.interpolate(
+                            data,
+                            position as usize,
+                            position.fract() as f32,
+                        )
+                    })
+                    .collect()
+            },
+            PitchMode::Stretch => stretch::shift(data, ratio, self.formants),
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use crate::config::MAX_DELAY_MS;
+use crate::engine::{Command, SampleData};
+use crate::loader;
+use crate::player::NO_NOTE;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// The click, a millisecond long at half scale.  Long enough to get
+/// through an interface's filters, short enough to have a sharp start
+const CLICK: Duration = Duration::from_millis(1);
+const CLICK_GAIN: f32 = 0.5;
+
+/// How long the input is listened to for the click
+const LISTEN: Duration = Duration::from_secs(1);
+
+/// Quieter than this the click was not heard at all
+const NOISE_FLOOR: f32 = 0.01;
+
+/// How much of the input before a note is kept, to hear the pad being
+/// hit, which is before the note arrives
+const PRE_ROLL: Duration = Duration::from_millis(250);
+
+/// How long the sound is looked at to tell if something has started
+const ONSET_WINDOW: Duration = Duration::from_millis(5);
+
+/// Measures the latency from a MIDI note to the sound coming back in
+/// through a loopback cable from an output to the input.  The note
+/// clicks on the output and starts a recording of the input in the
+/// same Jack cycle, and where the click starts in the recording is
+/// the round trip through the audio interface
+pub struct Latency {
+    note: u8,
+    commands: SyncSender<Command>,
+    sample_rate: usize,
+
+    /// The click's sample
+    click: usize,
+
+    /// Set while a measurement is under way, so a second note does
+    /// not start another
+    measuring: Arc<AtomicBool>,
+}
+
+impl Latency {
+    /// Add the click as sample number `click`, after the samples
+    /// there are, and wait for `note`
+    pub fn new(
+        note: u8,
+        click: usize,
+        commands: SyncSender<Command>,
+        sample_rate: usize,
+    ) -> Self {
+        let _ = commands.send(Command::AddSample(Arc::new(click_sample(
+            note,
+            sample_rate,
+        ))));
+        eprintln!("Play note {note} to measure the latency");
+        Self {
+            note,
+            commands,
+            sample_rate,
+            click,
+            measuring: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// A note arrived.  If it is the one that clicks, measure
+    pub fn note_on(
+        &self,
+        note: u8,
+    ) {
+        if note != self.note || self.measuring.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let (done, receiver) = sync_channel(1);
+        let listen = (LISTEN.as_secs_f32() * self.sample_rate as f32) as usize;
+        let sent = self.commands.try_send(Command::Record {
+            buffer: Vec::with_capacity(listen),
+            done,
+        });
+        if sent.is_err()
+            || self
+                .commands
+                .try_send(Command::Trigger {
+                    sample: self.click,
+                    gain: 1.0,
+                    velocity: 127,
+                    effects: vec![],
+                })
+                .is_err()
+        {
+            eprintln!("Latency: Command queue full");
+            self.measuring.store(false, Ordering::SeqCst);
+            return;
+        }
+        let sample_rate = self.sample_rate;
+        let measuring = self.measuring.clone();
+        thread::spawn(move || {
+            match receiver.recv_timeout(LISTEN * 2) {
+                Ok(recording) => report(&recording, sample_rate),
+                Err(err) => eprintln!("{err}: Latency: Recording lost"),
+            }
+            measuring.store(false, Ordering::SeqCst);
+        });
+    }
+}
+
+/// Measures how long each pad takes from being hit to being heard,
+/// so the quicker pads can be held back to match the slowest.  A
+/// microphone by the pads picks up the stick, and the click each note
+/// plays comes back on the same input, through a loopback cable or
+/// the microphone.  The input is kept for a while before each note
+/// arrives, so the recording holds the hit and then the click
+pub struct Calibration {
+    commands: SyncSender<Command>,
+    sample_rate: usize,
+
+    /// The click's sample
+    click: usize,
+
+    /// Set while a hit is being measured, so the next waits
+    measuring: Arc<AtomicBool>,
+
+    /// The latencies measured for each note, in milliseconds
+    latencies: Arc<Mutex<BTreeMap<u8, Vec<f64>>>>,
+}
+
+impl Calibration {
+    /// Add the click as sample number `click`, after the samples
+    /// there are, and start keeping the input
+    pub fn new(
+        click: usize,
+        commands: SyncSender<Command>,
+        sample_rate: usize,
+    ) -> Self {
+        let _ = commands.send(Command::AddSample(Arc::new(click_sample(
+            NO_NOTE,
+            sample_rate,
+        ))));
+        let pre_roll = (PRE_ROLL.as_secs_f32() * sample_rate as f32) as usize;
+        let _ = commands.send(Command::PreRoll(vec![0.0; pre_roll]));
+        eprintln!("Hit each pad a few times, a second apart, then press enter");
+        Self {
+            commands,
+            sample_rate,
+            click,
+            measuring: Arc::new(AtomicBool::new(false)),
+            latencies: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// A pad was hit.  Click, and find how long after the hit the
+    /// click was heard
+    pub fn note_on(
+        &self,
+        note: u8,
+    ) {
+        if self.measuring.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let (done, receiver) = sync_channel(1);
+        let pre_roll =
+            (PRE_ROLL.as_secs_f32() * self.sample_rate as f32) as usize;
+        let listen = (LISTEN.as_secs_f32() * self.sample_rate as f32) as usize;
+        let sent = self.commands.try_send(Command::Record {
+            buffer: Vec::with_capacity(pre_roll + listen),
+            done,
+        });
+        if sent.is_err()
+            || self
+                .commands
+                .try_send(Command::Trigger {
+                    sample: self.click,
+                    gain: 1.0,
+                    velocity: 127,
+                    effects: vec![],
+                })
+                .is_err()
+        {
+            eprintln!("Latency: Command queue full");
+            self.measuring.store(false, Ordering::SeqCst);
+            return;
+        }
+        let sample_rate = self.sample_rate;
+        let measuring = self.measuring.clone();
+        let latencies = self.latencies.clone();
+        thread::spawn(move || {
+            match receiver.recv_timeout(LISTEN * 2) {
+                Ok(recording) => {
+                    match hit_to_click(&recording, pre_roll, sample_rate) {
+                        Ok(frames) => {
+                            let ms =
+                                frames as f64 * 1000.0 / sample_rate as f64;
+                            eprintln!("Note {note}: {ms:.2}ms");
+                            latencies
+                                .lock()
+                                .unwrap()
+                                .entry(note)
+                                .or_default()
+                                .push(ms);
+                        },
+                        Err(err) => eprintln!("Note {note}: {err}"),
+                    }
+                },
+                Err(err) => eprintln!("{err}: Latency: Recording lost"),
+            }
+            measuring.store(false, Ordering::SeqCst);
+        });
+    }
+
+    /// Write the delay that brings each note measured into time with
+    /// the slowest into `config` as its "delay_ms", replacing the
+    /// delays there were.  The file is rewritten, so its layout is lost
+    pub fn write(
+        &self,
+        config: &Path,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let latencies = self.latencies.lock().unwrap();
+        if latencies.is_empty() {
+            return Err("Latency: No hits measured".into());
+        }
+        let averages: BTreeMap<u8, f64> = latencies
+            .iter()
+            .map(|(note, ms)| (*note, ms.iter().sum::<f64>() / ms.len() as f64))
+            .collect();
+        let slowest = averages.values().copied().fold(0.0, f64::max);
+        let mut delays = Map::new();
+        for (note, average) in averages.iter() {
+            // Hundredths of a millisecond are plenty
+            let delay = ((slowest - average) * 100.0).round() / 100.0;
+            println!(
+                "Note {note}: {average:.2}ms over {} hits, delay {delay}ms",
+                latencies[note].len()
+            );
+            if delay > MAX_DELAY_MS as f64 {
+                println!("Note {note}: Held back {MAX_DELAY_MS}ms, the most");
+            }
+            delays.insert(
+                note.to_string(),
+                json!(delay.min(MAX_DELAY_MS as f64)),
+            );
+        }
+
+        loader::rewrite(config, |settings| {
+            settings.insert("delay_ms".to_string(), Value::Object(delays));
+            Ok(())
+        })?;
+        println!("Delays written into {}", config.display());
+        Ok(())
+    }
+}
+
+/// The frames from the hit, the first sound in `recording` before
+/// `split`, where the note arrived, to the click, the first sound
+/// after it that stands out from what was there before
+fn hit_to_click(
+    recording: &[f32],
+    split: usize,
+    sample_rate: usize,
+) -> Result<usize, &'static str> {
+    let window = (ONSET_WINDOW.as_secs_f32() * sample_rate as f32) as usize;
+    let (before, after) = recording.split_at(split.min(recording.len()));
+    let loudest =
+        |part: &[f32]| part.iter().fold(0.0_f32, |m, x| m.max(x.abs()));
+
+    // The start of the pre-roll is the quiet before the hit
+    let noise = loudest(&before[..window.min(before.len())]);
+    let threshold = (noise * 4.0).max(NOISE_FLOOR);
+    let hit = before
+        .iter()
+        .position(|x| x.abs() >= threshold)
+        .ok_or("No hit heard.  Is the microphone by the pads?")?;
+
+    // The click is louder than the hit was dying away
+    let tail = loudest(&before[before.len().saturating_sub(window)..]);
+    let threshold = (tail * 2.0).max(NOISE_FLOOR);
+    let click = after
+        .iter()
+        .position(|x| x.abs() >= threshold)
+        .ok_or("No click heard.  Is an output looped back to the input?")?;
+    Ok(before.len() + click - hit)
+}
+
+/// The click, on `note`
+fn click_sample(
+    note: u8,
+    sample_rate: usize,
+) -> SampleData {
+    let click = (CLICK.as_secs_f32() * sample_rate as f32) as usize;
+    SampleData {
+        path: "latency-click".to_string(),
+        data: vec![CLICK_GAIN; click.max(1)],
+        note,
+        ..Default::default()
+    }
+}
+
+/// Find the start of the click, where the input first gets to half
+/// its peak, and print how far into the recording it is
+fn report(
+    recording: &[f32],
+    sample_rate: usize,
+) {
+    let peak = recording.iter().fold(0.0_f32, |peak, x| peak.max(x.abs()));
+    if peak < NOISE_FLOOR {
+        eprintln!(
+            "Latency: No click heard.  Is an output looped back to the input?"
+        );
+        return;
+    }
+    let Some(frames) = recording.iter().position(|x| x.abs() >= peak / 2.0)
+    else {
+        return;
+    };
+    eprintln!(
+        "Latency: {frames} frames, {:.2}ms round trip, peak {:.1}dB",
+        frames as f64 * 1000.0 / sample_rate as f64,
+        20.0 * peak.log10()
+    );
+}
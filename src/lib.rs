@@ -0,0 +1,73 @@
+pub mod args;
+pub mod arpeggiator;
+pub mod audio;
+pub mod bank;
+pub mod beep;
+pub mod bench;
+pub mod bpm;
+pub mod bundle;
+pub mod bus;
+pub mod cc_map;
+pub mod chance;
+pub mod chord;
+pub mod clock;
+pub mod config;
+pub mod console;
+pub mod control;
+pub mod deck;
+pub mod decode;
+pub mod demo;
+pub mod duck;
+pub mod engine;
+pub mod eq;
+pub mod export;
+pub mod feedback;
+pub mod footprint;
+pub mod fx;
+pub mod gain;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod keyboard;
+pub mod keys;
+pub mod latency;
+pub mod link;
+pub mod lint;
+pub mod loader;
+pub mod loop_sync;
+pub mod looper;
+pub mod loudness;
+pub mod memory;
+pub mod message;
+pub mod metadata;
+pub mod midi;
+pub mod migrate;
+pub mod mirror;
+pub mod mpe;
+pub mod pan;
+pub mod peaks;
+pub mod pitch;
+pub mod player;
+pub mod realtime;
+pub mod record;
+pub mod repeat;
+pub mod report;
+pub mod resample;
+pub mod rtp_midi;
+pub mod scene;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod sequencer;
+pub mod setlist;
+pub mod sha256;
+pub mod signal;
+pub mod slice;
+pub mod smf;
+pub mod smooth;
+pub mod staging;
+pub mod state;
+pub mod stretch;
+pub mod sysex;
+pub mod template;
+pub mod test_tone;
+pub mod trigger_log;
+pub mod velocity;
@@ -0,0 +1,481 @@
+use crate::clock::Clock;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Where Link peers announce themselves and their session's timeline
+const GROUP: Ipv4Addr = Ipv4Addr::new(224, 76, 78, 75);
+const PORT: u16 = 20808;
+
+/// Starts every Link discovery message: the protocol and its version
+const PROTOCOL: &[u8] = b"_asdp_v\x01";
+
+/// The messages that carry a peer's state.  The other kind says
+/// goodbye
+const ALIVE: u8 = 1;
+const RESPONSE: u8 = 2;
+
+/// The protocol, message type, time to live, group and the peer's ID
+const HEADER: usize = 20;
+
+/// The payload entry with the session's tempo, beat and time origin
+const TIMELINE: &[u8] = b"tmln";
+
+/// The payload entry with the ID of the peer's session
+const SESSION: &[u8] = b"sess";
+
+/// The payload entry with where the peer answers pings, to measure
+/// the session's clock
+const ENDPOINT: &[u8] = b"mep4";
+
+/// Starts every measurement message, and the message types
+const MEASUREMENT: &[u8] = b"_link_v\x01";
+const PING: u8 = 1;
+const PONG: u8 = 2;
+
+/// The measurement payload entries: the time on this host a ping was
+/// sent, and the time on the session's clock a pong was sent and the
+/// one before it
+const HOST_TIME: &[u8] = b"__ht";
+const GHOST_TIME: &[u8] = b"__gt";
+const PREVIOUS_GHOST_TIME: &[u8] = b"_pgt";
+
+/// Pings sent each time the session's clock is measured, and how long
+/// to wait for each pong
+const PINGS: usize = 25;
+const PONG_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How often the session's clock is measured again, as this host's
+/// clock drifts from it
+const MEASURE_EVERY: Duration = Duration::from_secs(30);
+
+/// The biggest packet taken
+const MAX_PACKET: usize = 512;
+
+/// A Link session's timeline: its tempo, and the beat at a time on the
+/// session's clock
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Timeline {
+    /// Microseconds per beat
+    micros_per_beat: i64,
+
+    /// The beat, in millionths of a beat, at `time_origin`
+    beat_origin: i64,
+
+    /// Microseconds on the session's clock
+    time_origin: i64,
+}
+
+impl Timeline {
+    /// The tempo in beats per minute
+    fn tempo(&self) -> f32 {
+        (60e6 / self.micros_per_beat as f64) as f32
+    }
+
+    /// The beat at `time` microseconds on the session's clock
+    fn beat(
+        &self,
+        time: i64,
+    ) -> f64 {
+        self.beat_origin as f64 / 1e6
+            + (time - self.time_origin) as f64 / self.micros_per_beat as f64
+    }
+}
+
+/// What a peer announces about itself and its session
+#[derive(Debug, PartialEq)]
+struct Peer {
+    session: [u8; 8],
+    timeline: Timeline,
+    endpoint: Option<SocketAddrV4>,
+}
+
+/// What a peer answers a ping with
+#[derive(Debug, PartialEq)]
+struct Pong {
+    session: [u8; 8],
+
+    /// When this host sent the ping, on its own clock
+    host_time: i64,
+
+    /// When the peer answered it, and the pong before it, on the
+    /// session's clock
+    ghost_time: i64,
+    previous_ghost_time: Option<i64>,
+}
+
+/// Follow the Ableton Link session on the network, so things that
+/// keep time stay in step with other Link apps.  The tempo and the
+/// session's beat timeline are taken from peers' announcements, and
+/// the session's clock is measured against this host's by pinging a
+/// peer, so the clock's pulses line up with the session's beats and
+/// phase.  The sampler does not announce itself, so it cannot change
+/// the tempo.  The discovery port is shared, so other Link apps on
+/// the same host still work
+pub fn follow(clock: Arc<Clock>) -> std::io::Result<()> {
+    let socket = bind()?;
+    socket.join_multicast_v4(&GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    thread::spawn(move || {
+        // This host's clock, in microseconds since here
+        let base = Instant::now();
+        let mut packet = [0; MAX_PACKET];
+        let mut following = None;
+
+        // The session last measured, when, and its clock's offset
+        // from this host's
+        let mut measured: Option<([u8; 8], Instant, Option<f64>)> = None;
+        loop {
+            match socket.recv(&mut packet) {
+                Ok(length) => {
+                    let Some(peer) = peer(&packet[..length]) else {
+                        continue;
+                    };
+                    let tempo = peer.timeline.tempo();
+                    if following.is_none() {
+                        eprintln!("Following Link session at {tempo:.2} BPM");
+                    }
+                    if following != Some(tempo) {
+                        clock.set_tempo(tempo);
+                        following = Some(tempo);
+                    }
+
+                    let current = measured.is_some_and(|(session, at, _)| {
+                        session == peer.session && at.elapsed() < MEASURE_EVERY
+                    });
+                    if !current {
+                        let offset = peer.endpoint.and_then(|endpoint| {
+                            measure(base, endpoint, peer.session)
+                                .map_err(|err| eprintln!("{err}: Link"))
+                                .ok()
+                                .flatten()
+                        });
+                        measured = Some((peer.session, Instant::now(), offset));
+                    }
+                    if let Some((_, _, Some(offset))) = measured {
+                        let now = Instant::now();
+                        let time = micros(base, now) as f64 + offset;
+                        clock.align(peer.timeline.beat(time as i64), now);
+                    }
+                },
+                Err(err) => {
+                    eprintln!("{err}: Link");
+                    return;
+                },
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Bind the discovery port so Link apps on this host can share it
+fn bind() -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, PORT).into())?;
+    Ok(socket.into())
+}
+
+/// Microseconds from `base` to `at` on this host's clock
+fn micros(
+    base: Instant,
+    at: Instant,
+) -> i64 {
+    at.duration_since(base).as_micros() as i64
+}
+
+/// The session's clock less this host's, in microseconds, measured by
+/// pinging the peer at `endpoint` as Link peers measure each other.
+/// Each pong gives the session's time halfway between sending the
+/// ping and hearing back.  The median is taken, so a slow round trip
+/// does not throw it off.  None if the peer did not answer for
+/// `session`
+fn measure(
+    base: Instant,
+    endpoint: SocketAddrV4,
+    session: [u8; 8],
+) -> std::io::Result<Option<f64>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_read_timeout(Some(PONG_TIMEOUT))?;
+    let mut packet = [0; MAX_PACKET];
+    let mut offsets = vec![];
+    let mut previous = None;
+    for _ in 0..PINGS {
+        socket
+            .send_to(&ping(micros(base, Instant::now()), previous), endpoint)?;
+        let length = match socket.recv(&mut packet) {
+            Ok(length) => length,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut
+                ) =>
+            {
+                previous = None;
+                continue;
+            },
+            Err(err) => return Err(err),
+        };
+        let now = micros(base, Instant::now());
+        let Some(pong) = pong(&packet[..length]) else {
+            continue;
+        };
+        if pong.session != session
+            || now - pong.host_time > PONG_TIMEOUT.as_micros() as i64
+        {
+            previous = None;
+            continue;
+        }
+        offsets
+            .push(pong.ghost_time as f64 - (pong.host_time + now) as f64 / 2.0);
+        if let Some(before) = pong.previous_ghost_time {
+            offsets.push(
+                (pong.ghost_time + before) as f64 / 2.0 - pong.host_time as f64,
+            );
+        }
+        previous = Some(pong.ghost_time);
+    }
+    offsets.sort_by(f64::total_cmp);
+    Ok(offsets.get(offsets.len() / 2).copied())
+}
+
+/// A ping sent at `host_time` on this host's clock, with the session's
+/// time from the last pong
+fn ping(
+    host_time: i64,
+    previous: Option<i64>,
+) -> Vec<u8> {
+    let mut packet = MEASUREMENT.to_vec();
+    packet.push(PING);
+    let mut entry = |key: &[u8], value: i64| {
+        packet.extend_from_slice(key);
+        packet.extend_from_slice(&8u32.to_be_bytes());
+        packet.extend_from_slice(&value.to_be_bytes());
+    };
+    entry(HOST_TIME, host_time);
+    if let Some(previous) = previous {
+        entry(PREVIOUS_GHOST_TIME, previous);
+    }
+    packet
+}
+
+/// A peer's answer to a ping
+fn pong(packet: &[u8]) -> Option<Pong> {
+    let payload = packet.strip_prefix(MEASUREMENT)?.strip_prefix(&[PONG])?;
+    let time = |key| {
+        Some(i64::from_be_bytes(
+            entry(payload, key)?.get(..8)?.try_into().ok()?,
+        ))
+    };
+    Some(Pong {
+        session: entry(payload, SESSION)?.get(..8)?.try_into().ok()?,
+        host_time: time(HOST_TIME)?,
+        ghost_time: time(GHOST_TIME)?,
+        previous_ghost_time: time(PREVIOUS_GHOST_TIME),
+    })
+}
+
+/// The session, timeline and measurement endpoint in a peer's
+/// announcement
+fn peer(packet: &[u8]) -> Option<Peer> {
+    if !packet.starts_with(PROTOCOL)
+        || !matches!(packet.get(PROTOCOL.len()), Some(&(ALIVE | RESPONSE)))
+    {
+        return None;
+    }
+    let payload = packet.get(HEADER..)?;
+    let timeline = entry(payload, TIMELINE)?;
+    let value = |at: usize| {
+        Some(i64::from_be_bytes(
+            timeline.get(at..at + 8)?.try_into().ok()?,
+        ))
+    };
+    let timeline = Timeline {
+        micros_per_beat: value(0)?,
+        beat_origin: value(8)?,
+        time_origin: value(16)?,
+    };
+    if timeline.micros_per_beat <= 0 {
+        return None;
+    }
+    let endpoint = entry(payload, ENDPOINT).and_then(|value| {
+        let address: [u8; 4] = value.get(..4)?.try_into().ok()?;
+        let port = u16::from_be_bytes(value.get(4..6)?.try_into().ok()?);
+        Some(SocketAddrV4::new(address.into(), port))
+    });
+    Some(Peer {
+        session: entry(payload, SESSION)?.get(..8)?.try_into().ok()?,
+        timeline,
+        endpoint,
+    })
+}
+
+/// The value of `key` in a Link payload: entries of a four letter
+/// key, a length and a value, all big endian.  None if it is not
+/// there or the payload is cut short before it
+fn entry<'a>(
+    mut payload: &'a [u8],
+    key: &[u8],
+) -> Option<&'a [u8]> {
+    while payload.len() >= 8 {
+        let (name, rest) = payload.split_at(4);
+        let size = u32::from_be_bytes(rest[..4].try_into().ok()?) as usize;
+        let value = rest.get(4..4 + size)?;
+        if name == key {
+            return Some(value);
+        }
+        payload = &rest[4 + size..];
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An announcement of `kind` with `entries` as its payload
+    fn packet(
+        kind: u8,
+        entries: &[(&[u8], Vec<u8>)],
+    ) -> Vec<u8> {
+        let mut packet = PROTOCOL.to_vec();
+        packet.push(kind);
+        packet.resize(HEADER, 0);
+        for (key, value) in entries {
+            packet.extend_from_slice(key);
+            packet.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            packet.extend_from_slice(value);
+        }
+        packet
+    }
+
+    /// A timeline entry's value for `micros` per beat, at beat one
+    /// and a half two seconds into the session's clock
+    fn timeline(micros: i64) -> Vec<u8> {
+        let mut value = micros.to_be_bytes().to_vec();
+        value.extend_from_slice(&1_500_000i64.to_be_bytes());
+        value.extend_from_slice(&2_000_000i64.to_be_bytes());
+        value
+    }
+
+    /// An announcement in session 7 with `entries` after the session
+    fn announce(
+        kind: u8,
+        entries: &[(&[u8], Vec<u8>)],
+    ) -> Vec<u8> {
+        let mut all = vec![(SESSION, vec![7; 8])];
+        all.extend_from_slice(entries);
+        packet(kind, &all)
+    }
+
+    /// The tempo in an announcement, if it is one
+    fn tempo(packet: &[u8]) -> Option<f32> {
+        peer(packet).map(|peer| peer.timeline.tempo())
+    }
+
+    #[test]
+    fn reads_the_timeline_tempo() {
+        let alive = announce(ALIVE, &[(TIMELINE, timeline(500_000))]);
+        assert_eq!(tempo(&alive), Some(120.0));
+        let response = announce(RESPONSE, &[(TIMELINE, timeline(600_000))]);
+        assert_eq!(tempo(&response), Some(100.0));
+    }
+
+    #[test]
+    fn reads_the_session_beat_and_endpoint() {
+        let alive = announce(
+            ALIVE,
+            &[
+                (TIMELINE, timeline(500_000)),
+                (ENDPOINT, vec![192, 168, 1, 5, 0x4e, 0x20]),
+            ],
+        );
+        let peer = peer(&alive).unwrap();
+        assert_eq!(peer.session, [7; 8]);
+        assert_eq!(
+            peer.endpoint,
+            Some(SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 5), 20000))
+        );
+
+        // Half a second a beat, so two beats a second after the origin
+        assert_eq!(peer.timeline.beat(2_000_000), 1.5);
+        assert_eq!(peer.timeline.beat(3_000_000), 3.5);
+        assert_eq!(peer.timeline.beat(1_750_000), 1.0);
+    }
+
+    #[test]
+    fn skips_other_entries() {
+        let packet = announce(
+            ALIVE,
+            &[(b"stst", vec![1; 8]), (TIMELINE, timeline(500_000))],
+        );
+        assert_eq!(tempo(&packet), Some(120.0));
+    }
+
+    #[test]
+    fn ignores_other_messages() {
+        let goodbye = announce(3, &[(TIMELINE, timeline(500_000))]);
+        assert_eq!(tempo(&goodbye), None);
+        let mut other = announce(ALIVE, &[(TIMELINE, timeline(500_000))]);
+        other[0] = b'x';
+        assert_eq!(tempo(&other), None);
+        assert_eq!(tempo(&announce(ALIVE, &[])), None);
+
+        // No session
+        assert_eq!(
+            tempo(&packet(ALIVE, &[(TIMELINE, timeline(500_000))])),
+            None
+        );
+    }
+
+    #[test]
+    fn refuses_bad_timelines() {
+        assert_eq!(tempo(&announce(ALIVE, &[(TIMELINE, timeline(0))])), None);
+        assert_eq!(tempo(&announce(ALIVE, &[(TIMELINE, vec![0; 4])])), None);
+
+        // An entry longer than the packet
+        let mut short = announce(ALIVE, &[(TIMELINE, timeline(500_000))]);
+        short.truncate(short.len() - 10);
+        assert_eq!(tempo(&short), None);
+        assert_eq!(tempo(&PROTOCOL[..4]), None);
+    }
+
+    #[test]
+    fn reads_pongs() {
+        // A pong echoes the ping's entries after its own
+        let mut answer = MEASUREMENT.to_vec();
+        answer.push(PONG);
+        for (key, value) in
+            [(SESSION, [7; 8]), (GHOST_TIME, 900i64.to_be_bytes())]
+        {
+            answer.extend_from_slice(key);
+            answer.extend_from_slice(&8u32.to_be_bytes());
+            answer.extend_from_slice(&value);
+        }
+        answer
+            .extend_from_slice(&ping(100, Some(800))[MEASUREMENT.len() + 1..]);
+        assert_eq!(
+            pong(&answer),
+            Some(Pong {
+                session: [7; 8],
+                host_time: 100,
+                ghost_time: 900,
+                previous_ghost_time: Some(800),
+            })
+        );
+
+        // A ping is not a pong
+        assert_eq!(pong(&ping(100, None)), None);
+    }
+
+    #[test]
+    fn shares_the_discovery_port() {
+        let first = bind().unwrap();
+        let second = bind().unwrap();
+        assert_eq!(first.local_addr().unwrap().port(), PORT);
+        assert_eq!(second.local_addr().unwrap().port(), PORT);
+    }
+}
@@ -0,0 +1,208 @@
+use crate::config::{process_samples_json, SampleDescr};
+use crate::decode;
+use crate::slice;
+use crate::staging;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+/// A problem found in a configuration.  `kind` is one word, for
+/// scripts to match on
+struct Problem {
+    kind: &'static str,
+    note: Option<u8>,
+    bank: Option<String>,
+    path: Option<String>,
+    message: String,
+}
+
+/// Check a configuration before a show, without starting the sampler,
+/// and print what is wrong with it: notes played by more than one
+/// sample where one was not meant to layer on the other, notes the
+/// sequencer or scenes use that no sample plays, files used twice,
+/// files that are missing, samples and deck tracks alike, and samples
+/// at full scale.  With `json`
+/// each problem is a JSON object on a line of its own.  It is an error
+/// if there are any problems
+pub fn lint(
+    config: &Path,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = process_samples_json(&config.to_string_lossy(), None)?;
+    let mut problems: Vec<Problem> = vec![];
+
+    // The notes each sample plays, in its bank.  A sliced sample plays
+    // a note for each slice, so must be decoded to know which
+    let mut zones: BTreeMap<(Option<String>, u8), Vec<&SampleDescr>> =
+        BTreeMap::new();
+    let mut uses: BTreeMap<&str, usize> = BTreeMap::new();
+    for descr in config.samples_descr.iter() {
+        let paths: Vec<&str> = if descr.segments.is_empty() {
+            vec![&descr.path]
+        } else {
+            descr.segments.iter().map(|s| s.path.as_str()).collect()
+        };
+        let mut notes: Vec<u8> = match &descr.keys {
+            Some(keys) => keys.notes().collect(),
+            None => vec![descr.note],
+        };
+        for path in paths {
+            *uses.entry(path).or_default() += 1;
+            if !Path::new(path).exists() {
+                problems.push(Problem {
+                    kind: "missing",
+                    note: Some(descr.note),
+                    bank: descr.bank.clone(),
+                    path: Some(path.to_string()),
+                    message: "File is missing".to_string(),
+                });
+                continue;
+            }
+            let data = match decode::decode(path, descr.format) {
+                Ok(data) => data,
+                Err(err) => {
+                    problems.push(Problem {
+                        kind: "unreadable",
+                        note: Some(descr.note),
+                        bank: descr.bank.clone(),
+                        path: Some(path.to_string()),
+                        message: err.to_string(),
+                    });
+                    continue;
+                },
+            };
+            let level = staging::level(&data);
+            if level.peak >= staging::CLIPS {
+                problems.push(Problem {
+                    kind: "loud",
+                    note: Some(descr.note),
+                    bank: descr.bank.clone(),
+                    path: Some(path.to_string()),
+                    message: format!(
+                        "Peak {:.1} dB, is at full scale and may clip",
+                        level.peak
+                    ),
+                });
+            }
+            if let Some(descr_slice) = &descr.slice {
+                notes = (0..slice::starts(&data, descr_slice).len())
+                    .map_while(|i| descr_slice.note(descr.note, i))
+                    .collect();
+            }
+        }
+        for note in notes {
+            zones
+                .entry((descr.bank.clone(), note))
+                .or_default()
+                .push(descr);
+        }
+    }
+
+    // Deck tracks are long, so are only checked for being there and
+    // readable, not decoded
+    for track in config.decks.iter().flat_map(|decks| decks.tracks.iter()) {
+        let problem = if !Path::new(&track.path).exists() {
+            Some(("missing", "File is missing".to_string()))
+        } else {
+            decode::info(&track.path, track.format)
+                .err()
+                .map(|err| ("unreadable", err.to_string()))
+        };
+        if let Some((kind, message)) = problem {
+            problems.push(Problem {
+                kind,
+                note: Some(track.cue),
+                bank: None,
+                path: Some(track.path.clone()),
+                message,
+            });
+        }
+    }
+
+    for ((bank, note), descrs) in zones.iter() {
+        if descrs.iter().skip(1).all(|descr| descr.layer) {
+            continue;
+        }
+        let paths: Vec<&str> =
+            descrs.iter().map(|descr| descr.path.as_str()).collect();
+        problems.push(Problem {
+            kind: "overlap",
+            note: Some(*note),
+            bank: bank.clone(),
+            path: None,
+            message: format!("Played by {}, not all layered", paths.join(", ")),
+        });
+    }
+
+    // Notes the configuration plays or sets the gain of, that are not
+    // in any bank
+    let played: BTreeSet<u8> = zones.keys().map(|(_, note)| *note).collect();
+    let mut used: BTreeMap<u8, &str> = BTreeMap::new();
+    for track in config.sequencer.iter().flat_map(|s| s.tracks.iter()) {
+        used.entry(track.note).or_insert("the sequencer");
+    }
+    for scene in config.scenes.iter() {
+        for note in scene.notes.keys().chain(scene.mute.iter()) {
+            used.entry(*note).or_insert("a scene");
+        }
+    }
+    for (note, user) in used {
+        if !played.contains(&note) {
+            problems.push(Problem {
+                kind: "unused",
+                note: Some(note),
+                bank: None,
+                path: None,
+                message: format!("Used by {user} but has no sample"),
+            });
+        }
+    }
+
+    for (path, count) in uses {
+        if count > 1 {
+            problems.push(Problem {
+                kind: "duplicate",
+                note: None,
+                bank: None,
+                path: Some(path.to_string()),
+                message: format!("Used by {count} samples"),
+            });
+        }
+    }
+
+    for problem in problems.iter() {
+        if json {
+            println!(
+                "{}",
+                serde_json::json!({
+                    "kind": problem.kind,
+                    "note": problem.note,
+                    "bank": problem.bank,
+                    "path": problem.path,
+                    "message": problem.message,
+                })
+            );
+        } else {
+            let mut place = vec![];
+            if let Some(bank) = &problem.bank {
+                place.push(format!("Bank {bank}"));
+            }
+            if let Some(note) = problem.note {
+                place.push(format!("Note {note}"));
+            }
+            if let Some(path) = &problem.path {
+                place.push(path.clone());
+            }
+            println!(
+                "{}: {}: {}",
+                problem.kind,
+                place.join(": "),
+                problem.message
+            );
+        }
+    }
+    match problems.len() {
+        0 => Ok(()),
+        1 => Err("1 problem found".into()),
+        n => Err(format!("{n} problems found").into()),
+    }
+}
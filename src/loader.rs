@@ -0,0 +1,338 @@
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+/// The largest configuration file read.  Real ones are a few hundred
+/// kilobytes at most, so anything bigger is a mistake
+pub const MAX_FILE_BYTES: u64 = 16 * 1024 * 1024;
+
+/// The most samples a configuration can describe, with its includes
+pub const MAX_SAMPLES: usize = 10_000;
+
+/// How deep includes can go
+pub const MAX_INCLUDE_DEPTH: usize = 32;
+
+/// Read a configuration file's JSON.  Errors say which file, and where
+/// in it the JSON went wrong
+pub fn read(path: &Path) -> Result<Value, String> {
+    let describe = |err: String| format!("{}: {err}", path.display());
+    let file = File::open(path).map_err(|err| describe(err.to_string()))?;
+    let mut contents = vec![];
+    file.take(MAX_FILE_BYTES + 1)
+        .read_to_end(&mut contents)
+        .map_err(|err| describe(err.to_string()))?;
+    if contents.len() as u64 > MAX_FILE_BYTES {
+        return Err(describe(format!(
+            "Larger than the {} MiB a configuration can be",
+            MAX_FILE_BYTES / 1024 / 1024
+        )));
+    }
+    let json: Value = serde_json::from_slice(&contents)
+        .map_err(|err| describe(err.to_string()))?;
+    let samples = json
+        .get("samples_descr")
+        .and_then(Value::as_array)
+        .map_or(0, Vec::len);
+    if samples > MAX_SAMPLES {
+        return Err(describe(format!(
+            "{samples} samples, more than the {MAX_SAMPLES} there can be"
+        )));
+    }
+    Ok(json)
+}
+
+/// Change a configuration file's settings with `change`, and write it
+/// back.  It is written to a temporary file beside it and renamed, so
+/// a crash part way through leaves the file as it was.  The file is
+/// rewritten, so its keys are sorted and its layout lost
+pub fn rewrite(
+    path: &Path,
+    change: impl FnOnce(
+        &mut Map<String, Value>,
+    ) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut json = read(path)?;
+    let settings = json
+        .as_object_mut()
+        .ok_or(format!("{}: Not a JSON object", path.display()))?;
+    change(settings)?;
+    let mut name = path
+        .file_name()
+        .ok_or(format!("{}: Not a file", path.display()))?
+        .to_os_string();
+    name.push(".tmp");
+    let tmp = path.with_file_name(name);
+    fs::write(&tmp, serde_json::to_string_pretty(&json)? + "\n")?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Convert JSON to a configuration, or part of one.  An error starts
+/// with the path to the field at fault, such as
+/// "samples_descr[3].note", so it can be found in a long file
+pub fn from_value<T: DeserializeOwned>(json: Value) -> Result<T, String> {
+    // serde_json only says where an error is in text, so the JSON is
+    // laid out a value to a line, with the path to each line's value
+    let mut lines = vec![];
+    layout(&json, None, String::new(), false, &mut lines);
+    let text: Vec<&str> = lines.iter().map(|(line, _)| line.as_str()).collect();
+    serde_json::from_str(&text.join("\n")).map_err(|err| {
+        // Where the error is in the laid out text means nothing to
+        // whoever wrote the file
+        let message = err.to_string();
+        let message = match message.rsplit_once(" at line ") {
+            Some((message, _)) => message.to_string(),
+            None => message,
+        };
+        match lines.get(err.line().saturating_sub(1)) {
+            Some((_, path)) if !path.is_empty() => format!("{path}: {message}"),
+            _ => message,
+        }
+    })
+}
+
+/// Lay out `value`, the member `key` of an object if it is one, a line
+/// to each value and each bracket, with the path to each
+fn layout(
+    value: &Value,
+    key: Option<&str>,
+    path: String,
+    comma: bool,
+    lines: &mut Vec<(String, String)>,
+) {
+    let key =
+        key.map_or(String::new(), |key| format!("{}: ", Value::from(key)));
+    let comma = if comma { "," } else { "" };
+    match value {
+        Value::Object(members) if !members.is_empty() => {
+            lines.push((format!("{key}{{"), path.clone()));
+            for (i, (name, member)) in members.iter().enumerate() {
+                let inner = if path.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{path}.{name}")
+                };
+                layout(member, Some(name), inner, i + 1 < members.len(), lines);
+            }
+            lines.push((format!("}}{comma}"), path));
+        },
+        Value::Array(items) if !items.is_empty() => {
+            lines.push((format!("{key}["), path.clone()));
+            for (i, item) in items.iter().enumerate() {
+                let inner = format!("{path}[{i}]");
+                layout(item, None, inner, i + 1 < items.len(), lines);
+            }
+            lines.push((format!("]{comma}"), path));
+        },
+        _ => lines.push((format!("{key}{value}{comma}"), path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{process_samples_json, Config};
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Enough random cases to find the odd corner
+    const CASES: usize = 2_000;
+
+    /// A file in the temporary directory, removed when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(
+            name: &str,
+            contents: &[u8],
+        ) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("loader-{}-{name}.json", std::process::id()));
+            fs::write(&path, contents).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn load(contents: &str) -> Result<Config, String> {
+        from_value(serde_json::from_str(contents).unwrap())
+    }
+
+    #[test]
+    fn wrong_type_names_field() {
+        let err = load(
+            r#"{"samples_descr": [{"path": "a.wav", "note": 60},
+            {"path": "b.wav", "note": "C4"}]}"#,
+        )
+        .unwrap_err();
+        assert!(
+            err.starts_with("samples_descr[1].note: invalid type"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn out_of_range_names_field() {
+        let err =
+            load(r#"{"samples_descr": [{"path": "a.wav", "note": 300}]}"#)
+                .unwrap_err();
+        assert!(
+            err.starts_with("samples_descr[0].note: invalid value"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn missing_field_names_object() {
+        let err =
+            load(r#"{"samples_descr": [{"path": "a.wav"}]}"#).unwrap_err();
+        assert_eq!(err, "samples_descr[0]: missing field `note`");
+    }
+
+    #[test]
+    fn unknown_variant_names_field() {
+        let err = load(
+            r#"{"samples_descr": [{"path": "a.wav", "note": 60,
+                "quality": "best"}]}"#,
+        )
+        .unwrap_err();
+        assert!(
+            err.starts_with("samples_descr[0].quality: unknown variant"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn wrong_top_level() {
+        assert!(load("120").unwrap_err().starts_with("invalid type"));
+        assert!(load("\"tempo\"").unwrap_err().starts_with("invalid type"));
+    }
+
+    #[test]
+    fn good_config() {
+        let config = load(
+            r#"{"samples_descr": [{"path": "a.wav", "note": 60,
+                "channels": [], "name": "a \"quoted\" name"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(config.samples_descr.len(), 1);
+        assert_eq!(
+            config.samples_descr[0].name.as_deref(),
+            Some("a \"quoted\" name")
+        );
+    }
+
+    #[test]
+    fn malformed_files() {
+        for (name, contents) in [
+            ("empty", &b""[..]),
+            ("truncated", br#"{"samples_descr": [{"path": "#),
+            ("trailing", br#"{"samples_descr": []} x"#),
+            ("binary", &[0xff, 0xfe, 0x00, 0x7b][..]),
+            ("nested", &[b'['; 1000][..]),
+        ] {
+            let file = TempFile::new(name, contents);
+            let err = read(&file.0).unwrap_err();
+            assert!(err.starts_with(&file.0.display().to_string()), "{err}");
+        }
+    }
+
+    #[test]
+    fn syntax_error_has_position() {
+        let file = TempFile::new("syntax", b"{\n  \"tempo\": 120,,\n}");
+        let err = read(&file.0).unwrap_err();
+        assert!(err.ends_with("line 2 column 16"), "{err}");
+    }
+
+    #[test]
+    fn missing_file() {
+        assert!(read(Path::new("/nonexistent/config.json")).is_err());
+    }
+
+    #[test]
+    fn too_large() {
+        let mut contents = b"{\"samples_descr\": []}".to_vec();
+        contents.resize(MAX_FILE_BYTES as usize + 1, b' ');
+        let file = TempFile::new("large", &contents);
+        let err = read(&file.0).unwrap_err();
+        assert!(err.contains("Larger than"), "{err}");
+    }
+
+    #[test]
+    fn too_many_samples() {
+        let sample = r#"{"path": "a.wav", "note": 60}"#;
+        let samples = vec![sample; MAX_SAMPLES + 1].join(",");
+        let contents = format!("{{\"samples_descr\": [{samples}]}}");
+        let file = TempFile::new("many", contents.as_bytes());
+        let err = read(&file.0).unwrap_err();
+        assert!(err.contains("more than the"), "{err}");
+    }
+
+    #[test]
+    fn include_cycle() {
+        let path = std::env::temp_dir()
+            .join(format!("loader-{}-cycle.json", std::process::id()));
+        let contents = format!(
+            "{{\"include\": [{}], \"samples_descr\": []}}",
+            Value::from(path.to_string_lossy())
+        );
+        let file = TempFile::new("cycle", contents.as_bytes());
+        let err = process_samples_json(&file.0.to_string_lossy(), None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Include cycle"), "{err}");
+    }
+
+    #[test]
+    fn only_cue_outputs() {
+        let file = TempFile::new(
+            "cue",
+            br#"{"outputs": [{"name": "phones", "channels": 2,
+                "cue": true}], "samples_descr": []}"#,
+        );
+        let err = process_samples_json(&file.0.to_string_lossy(), None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("outputs: There must be an output"), "{err}");
+    }
+
+    /// Mangled versions of a good file are errors, never panics
+    #[test]
+    fn mangled() {
+        let good = br#"{"tempo": 120, "outputs": [{"name": "main",
+            "channels": 2}], "samples_descr": [{"path": "a.wav",
+            "note": 60, "pan": 30.0, "channels": [1, 2]}]}"#;
+        let mut random = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = || {
+            random ^= random << 13;
+            random ^= random >> 7;
+            random ^= random << 17;
+            random
+        };
+        for _ in 0..CASES {
+            let mut bytes = good.to_vec();
+            for _ in 0..1 + next() % 4 {
+                let at = (next() % bytes.len() as u64) as usize;
+                match next() % 3 {
+                    0 => bytes[at] = next() as u8,
+                    1 => {
+                        bytes.remove(at);
+                    },
+                    _ => {
+                        bytes.insert(at, b"{}[]\",:0-e"[(next() % 10) as usize])
+                    },
+                }
+            }
+            if let Ok(json) = serde_json::from_slice::<Value>(&bytes) {
+                let _ = from_value::<Config>(json);
+            }
+        }
+    }
+}
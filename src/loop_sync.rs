@@ -0,0 +1,223 @@
+use crate::bpm;
+use crate::clock::PPQN;
+use crate::decode::Info;
+use crate::engine::Segment;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// The tempo is measured over this many beats of the clock
+const MEASURED_BEATS: usize = 4;
+
+fn default_beats_per_bar() -> u32 {
+    4
+}
+
+fn default_crossfade_ms() -> f32 {
+    5.0
+}
+
+/// A loop that keeps time with the clock, playing faster or slower as
+/// the tempo changes, as a record on a turntable does.  It is `beats`
+/// long, or as long as its `bpm` says, and NoteOff stops it at the
+/// end of the bar of `beats_per_bar` it is playing.  Its end is
+/// crossfaded with its start over `crossfade_ms` so it goes round
+/// without a click
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct LoopSyncDescr {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub beats: Option<u32>,
+    #[serde(default = "default_beats_per_bar")]
+    pub beats_per_bar: u32,
+    #[serde(default = "default_crossfade_ms")]
+    pub crossfade_ms: f32,
+}
+
+/// The clock's tempo, measured from the time its beats take, so it
+/// is the same whether the clock is internal, MIDI or Link
+#[derive(Debug)]
+pub struct Tempo {
+    bpm: AtomicU32,
+
+    /// The last few beats: the pulse each was on and when it came
+    beats: Mutex<VecDeque<(u64, Instant)>>,
+}
+
+impl Tempo {
+    /// Start at `bpm` until the clock has been measured
+    pub fn new(bpm: f32) -> Arc<Self> {
+        Arc::new(Self {
+            bpm: AtomicU32::new(bpm.to_bits()),
+            beats: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    pub fn bpm(&self) -> f32 {
+        f32::from_bits(self.bpm.load(Ordering::Relaxed))
+    }
+
+    /// Measure the tempo on each beat of the clock
+    pub fn pulse(
+        &self,
+        pulse: u64,
+    ) {
+        if !pulse.is_multiple_of(PPQN) {
+            return;
+        }
+        let now = Instant::now();
+        let mut beats = self.beats.lock().unwrap();
+
+        // MIDI Start goes back to the first beat
+        if beats.back().is_some_and(|(last, _)| *last >= pulse) {
+            beats.clear();
+        }
+        beats.push_back((pulse, now));
+        if beats.len() > MEASURED_BEATS + 1 {
+            beats.pop_front();
+        }
+        if let Some((first, at)) = beats.front() {
+            let seconds = now.duration_since(*at).as_secs_f64();
+            if pulse > *first && seconds > 0.0 {
+                let counted = (pulse - first) as f64 / PPQN as f64;
+                let bpm = (60.0 * counted / seconds) as f32;
+                self.bpm.store(bpm.to_bits(), Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// How a loop keeps time, for the Jack thread
+#[derive(Clone, Debug)]
+pub struct LoopSync {
+    /// The loop's length in beats, if it is given, otherwise its
+    /// tempo to work it out from
+    beats: Option<u32>,
+    bpm: f32,
+    beats_per_bar: u32,
+
+    /// The rate the loop's file was made at, and the channels
+    /// interleaved in its data
+    sample_rate: usize,
+    channels: usize,
+
+    /// The samples of its data its end and start overlap, a whole
+    /// number of frames
+    crossfade: usize,
+    tempo: Arc<Tempo>,
+}
+
+impl LoopSync {
+    /// Keep to `tempo`.  `bpm` is the loop's own tempo, needed unless
+    /// the description gives its beats.  `source` is what the loop's
+    /// file holds, for its rate and channels
+    pub fn new(
+        descr: &LoopSyncDescr,
+        bpm: Option<f32>,
+        tempo: Arc<Tempo>,
+        source: Info,
+    ) -> Result<Self, String> {
+        if descr.beats.is_none() && bpm.is_none() {
+            return Err("A synced loop needs its beats or bpm".to_string());
+        }
+        let sample_rate = source
+            .sample_rate
+            .map_or(bpm::SAMPLE_RATE, |rate| rate as usize);
+        let channels = source.channels.unwrap_or(1).max(1);
+        let frames = (descr.crossfade_ms.max(0.0) / 1000.0 * sample_rate as f32)
+            as usize;
+        Ok(Self {
+            beats: descr.beats,
+            bpm: bpm.unwrap_or_default(),
+            beats_per_bar: descr.beats_per_bar.max(1),
+            sample_rate,
+            channels,
+            crossfade: frames * channels,
+            tempo,
+        })
+    }
+
+    /// The beats in `length` samples of the loop's data, as `analyze`
+    /// counts them
+    fn beats(
+        &self,
+        length: usize,
+    ) -> f64 {
+        match self.beats {
+            Some(beats) => beats as f64,
+            None => {
+                let frames = length as f64 / self.channels as f64;
+                let minutes = frames / self.sample_rate as f64 / 60.0;
+                (minutes * self.bpm as f64).round().max(1.0)
+            },
+        }
+    }
+
+    /// The loop's data with its end crossfaded into its start, and
+    /// the segments to play it: its start once, and the rest over and
+    /// over.  Each time round, the start of the loop fades in over the
+    /// end of it, so the loop goes round `crossfade` samples sooner
+    /// than it would, which its rate makes up for
+    pub fn prepare(
+        &self,
+        mut data: Vec<f32>,
+    ) -> (Vec<f32>, Vec<Segment>) {
+        let length = data.len();
+        let crossfade = self.crossfade;
+        if length < 2 * crossfade.max(1) {
+            return (data, vec![]);
+        }
+        let frames = crossfade / self.channels;
+        for i in 0..crossfade {
+            let x = ((i / self.channels) as f32 + 0.5) / frames as f32;
+            let (fade_in, fade_out) = (x.sqrt(), (1.0 - x).sqrt());
+            let tail = length - crossfade + i;
+            data[tail] = data[tail] * fade_out + data[i] * fade_in;
+        }
+        let segments = vec![
+            Segment {
+                start: 0,
+                end: crossfade,
+                looping: false,
+            },
+            Segment {
+                start: crossfade,
+                end: length,
+                looping: true,
+            },
+        ];
+        (data, segments)
+    }
+
+    /// How fast to play a loop with `length` samples of data, made
+    /// for `sample_rate`, the rate the engine plays its data at, so
+    /// each time round takes its beats at the clock's tempo
+    pub fn rate(
+        &self,
+        length: usize,
+        sample_rate: usize,
+    ) -> f32 {
+        let round = (length - self.crossfade.min(length)) as f64;
+        let seconds = self.beats(length) * 60.0 / self.tempo.bpm() as f64;
+        (round / (seconds * sample_rate as f64)) as f32
+    }
+
+    /// Where in a loop with `length` samples of data the bar playing at
+    /// `position` ends.  The last bar ends where the crossfade starts,
+    /// and in the crossfade it is time to stop
+    pub fn bar_end(
+        &self,
+        position: usize,
+        length: usize,
+    ) -> usize {
+        let end = length - self.crossfade.min(length);
+        if position >= end {
+            return position;
+        }
+        let bar =
+            length as f64 * self.beats_per_bar as f64 / self.beats(length);
+        let bars = (position as f64 / bar).floor() + 1.0;
+        ((bars * bar) as usize).min(end)
+    }
+}
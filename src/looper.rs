@@ -0,0 +1,220 @@
+use crate::clock::PPQN;
+use crate::player::Player;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+fn default_bars() -> u32 {
+    1
+}
+
+fn default_beats() -> u32 {
+    4
+}
+
+/// A looper for building grooves from pads.  Recording starts on the
+/// next bar and takes the notes played for `bars` bars of `beats`
+/// beats, which then play over and over in time with the clock.
+/// While overdubbing, notes played are added to the loop.  `cc`
+/// records a new loop (values 64 and up), `overdub_cc` turns
+/// overdubbing on (64 and up) and off, and `clear_cc` (64 and up)
+/// throws the loop away
+#[derive(Clone, Debug, Deserialize)]
+pub struct LooperDescr {
+    #[serde(default = "default_bars")]
+    pub bars: u32,
+    #[serde(default = "default_beats")]
+    pub beats: u32,
+    pub cc: u8,
+    #[serde(default)]
+    pub overdub_cc: Option<u8>,
+    #[serde(default)]
+    pub clear_cc: Option<u8>,
+}
+
+/// What the looper is doing
+#[derive(Clone, Copy, Debug)]
+enum Mode {
+    /// No loop
+    Empty,
+
+    /// Waiting for the next bar to start recording
+    Armed,
+
+    /// Recording the loop
+    Recording,
+
+    /// Playing the loop, and adding to it if overdubbing
+    Playing { overdub: bool },
+}
+
+struct Loop {
+    mode: Mode,
+
+    /// The pulse the loop started on
+    start: u64,
+
+    /// The notes in the loop: the pulse into the loop each is on, the
+    /// note and its velocity, zero for a NoteOff.  In time order
+    events: Vec<(u64, u8, u8)>,
+}
+
+pub struct Looper {
+    descr: LooperDescr,
+    player: Arc<Player>,
+
+    /// The last clock pulse
+    pulse: AtomicU64,
+    state: Mutex<Loop>,
+}
+
+impl Looper {
+    pub fn new(
+        descr: LooperDescr,
+        player: Arc<Player>,
+    ) -> Self {
+        Self {
+            descr,
+            player,
+            pulse: AtomicU64::new(0),
+            state: Mutex::new(Loop {
+                mode: Mode::Empty,
+                start: 0,
+                events: vec![],
+            }),
+        }
+    }
+
+    /// Clock pulses in a bar, and in the loop
+    fn bar(&self) -> u64 {
+        self.descr.beats.max(1) as u64 * PPQN
+    }
+
+    fn length(&self) -> u64 {
+        self.descr.bars.max(1) as u64 * self.bar()
+    }
+
+    /// Record a new loop, from the next bar
+    pub fn record(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.mode = Mode::Armed;
+        state.events.clear();
+        eprintln!("Looper armed");
+    }
+
+    /// Turn overdubbing on or off.  There must be a loop playing
+    pub fn overdub(
+        &self,
+        on: bool,
+    ) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        match state.mode {
+            Mode::Playing { overdub } => {
+                if overdub != on {
+                    eprintln!("Overdub {}", if on { "on" } else { "off" });
+                }
+                state.mode = Mode::Playing { overdub: on };
+                Ok(())
+            },
+            _ => Err("No loop playing".to_string()),
+        }
+    }
+
+    /// Throw the loop away
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.mode = Mode::Empty;
+        state.events.clear();
+        eprintln!("Looper cleared");
+    }
+
+    /// What the looper is doing, and how many notes the loop has
+    pub fn describe(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mode = match state.mode {
+            Mode::Empty => "empty",
+            Mode::Armed => "armed",
+            Mode::Recording => "recording",
+            Mode::Playing { overdub: false } => "playing",
+            Mode::Playing { overdub: true } => "overdubbing",
+        };
+        let notes = state.events.iter().filter(|e| e.2 > 0).count();
+        format!("{mode} {notes}\n")
+    }
+
+    pub fn control_change(
+        &self,
+        controller: u8,
+        value: u8,
+    ) {
+        if controller == self.descr.cc && value >= 64 {
+            self.record();
+        }
+        if Some(controller) == self.descr.overdub_cc {
+            let _ = self.overdub(value >= 64);
+        }
+        if Some(controller) == self.descr.clear_cc && value >= 64 {
+            self.clear();
+        }
+    }
+
+    /// A note played, recorded if the looper is recording or
+    /// overdubbing.  A velocity of zero is a NoteOff
+    pub fn note(
+        &self,
+        note: u8,
+        velocity: u8,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(
+            state.mode,
+            Mode::Recording | Mode::Playing { overdub: true }
+        ) {
+            return;
+        }
+        let pulse = self.pulse.load(Ordering::Relaxed);
+        let at = pulse.saturating_sub(state.start) % self.length();
+        let index = state.events.partition_point(|e| e.0 <= at);
+        state.events.insert(index, (at, note, velocity));
+    }
+
+    /// Called on each clock pulse.  Starts and stops recording on the
+    /// bar, and plays the notes that fall on the pulse
+    pub fn pulse(
+        &self,
+        pulse: u64,
+    ) {
+        self.pulse.store(pulse, Ordering::Relaxed);
+        let mut state = self.state.lock().unwrap();
+
+        // MIDI Start counts the pulses from zero again, and takes the
+        // loop back to its top
+        if pulse < state.start {
+            state.start = 0;
+        }
+        match state.mode {
+            Mode::Armed if pulse.is_multiple_of(self.bar()) => {
+                state.mode = Mode::Recording;
+                state.start = pulse;
+                eprintln!("Looper recording");
+                return;
+            },
+            Mode::Recording if pulse - state.start >= self.length() => {
+                state.mode = Mode::Playing { overdub: false };
+                eprintln!(
+                    "Looper playing {} notes",
+                    state.events.iter().filter(|e| e.2 > 0).count()
+                );
+            },
+            Mode::Playing { .. } => (),
+            _ => return,
+        }
+        let at = (pulse - state.start) % self.length();
+        for (_, note, velocity) in state.events.iter().filter(|e| e.0 == at) {
+            match velocity {
+                0 => self.player.note_off(*note, 0),
+                _ => self.player.note_on(*note, *velocity),
+            }
+        }
+    }
+}
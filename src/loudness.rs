@@ -0,0 +1,214 @@
+use crate::decode::Info;
+use crate::gain::db_to_gain;
+use crate::staging;
+use std::f64::consts::PI;
+
+/// Samples whose files do not say their rate are measured as if they
+/// were at this one
+const SAMPLE_RATE: f64 = 48000.0;
+
+/// Loudness is measured over blocks this long, in seconds, each
+/// starting a quarter of a block after the one before
+const BLOCK: f64 = 0.4;
+const HOP: f64 = 0.1;
+
+/// Blocks quieter than this, in LUFS, are left out, and then those
+/// this far below the loudness of the rest, in LU
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+
+/// A biquad filter, in direct form I
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(
+        b: [f64; 3],
+        a: [f64; 3],
+    ) -> Self {
+        Self {
+            b,
+            a,
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn tick(
+        &mut self,
+        x: f64,
+    ) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[1] * self.y[0]
+            - self.a[2] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// The K-weighting of ITU-R BS.1770 at `rate`: a high shelf for the
+/// head, then a high pass
+fn k_weighting(rate: f64) -> [Biquad; 2] {
+    let f0 = 1681.974450955533;
+    let g = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10_f64.powf(g / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+    [shelf, high_pass]
+}
+
+fn lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+/// The integrated loudness of a sample, in LUFS, as EBU R128 measures
+/// it.  `source` says the rate and channels of the interleaved data.
+/// Each channel is weighted on its own and their powers summed, all
+/// counted the same, as for the front channels.  A sample shorter
+/// than a block is measured as one block.  None for silence
+pub fn integrated(
+    data: &[f32],
+    source: Info,
+) -> Option<f32> {
+    let rate = source.sample_rate.map_or(SAMPLE_RATE, f64::from);
+    let channels = source.channels.unwrap_or(1).max(1);
+    let mut filters: Vec<[Biquad; 2]> =
+        (0..channels).map(|_| k_weighting(rate)).collect();
+    let weighted: Vec<f64> = data
+        .chunks_exact(channels)
+        .map(|frame| {
+            frame
+                .iter()
+                .zip(filters.iter_mut())
+                .map(|(x, [shelf, high_pass])| {
+                    let x = high_pass.tick(shelf.tick(*x as f64));
+                    x * x
+                })
+                .sum()
+        })
+        .collect();
+
+    let block = (BLOCK * rate) as usize;
+    let hop = (HOP * rate) as usize;
+    let powers: Vec<f64> = if weighted.len() < block {
+        vec![weighted.iter().sum::<f64>() / weighted.len().max(1) as f64]
+    } else {
+        (0..=(weighted.len() - block) / hop)
+            .map(|i| {
+                weighted[i * hop..i * hop + block].iter().sum::<f64>()
+                    / block as f64
+            })
+            .collect()
+    };
+
+    let mean = |gate: f64| {
+        let gated: Vec<f64> = powers
+            .iter()
+            .copied()
+            .filter(|power| lufs(*power) > gate)
+            .collect();
+        (!gated.is_empty())
+            .then(|| gated.iter().sum::<f64>() / gated.len() as f64)
+    };
+    let relative = lufs(mean(ABSOLUTE_GATE)?) + RELATIVE_GATE;
+    mean(relative.max(ABSOLUTE_GATE)).map(|power| lufs(power) as f32)
+}
+
+/// Turn a sample up or down to `target` LUFS, as far as its peak
+/// allows, and report what was done.  `source` says the rate and
+/// channels of the data, and `label` names it
+pub fn normalize(
+    data: &mut [f32],
+    source: Info,
+    target: f32,
+    label: &str,
+) {
+    let Some(loudness) = integrated(data, source) else {
+        return;
+    };
+    let peak = staging::level(data).peak;
+    let db = target - loudness;
+    let allowed = db.min(staging::HEADROOM - peak);
+    let gain = db_to_gain(allowed);
+    for x in data.iter_mut() {
+        *x *= gain;
+    }
+    if allowed < db {
+        eprintln!(
+            "{label}: {loudness:.1} LUFS, gain {allowed:+.1} dB, \
+             held back {:.1} dB by its peak",
+            db - allowed
+        );
+    } else {
+        eprintln!("{label}: {loudness:.1} LUFS, gain {db:+.1} dB");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ten seconds of a 1 kHz tone at 44.1 kHz, `left` and `right`
+    /// dBFS on each channel, interleaved
+    fn tone(
+        left: Option<f32>,
+        right: Option<f32>,
+    ) -> Vec<f32> {
+        let rate = 44100;
+        let amplitude = |db: Option<f32>| db.map_or(0.0, db_to_gain);
+        (0..10 * rate)
+            .flat_map(|frame| {
+                let x = (2.0 * PI * 1000.0 * frame as f64 / rate as f64).sin()
+                    as f32;
+                [x * amplitude(left), x * amplitude(right)]
+            })
+            .collect()
+    }
+
+    const STEREO: Info = Info {
+        sample_rate: Some(44100),
+        channels: Some(2),
+        frames: None,
+    };
+
+    #[test]
+    fn stereo_tone() {
+        // EBU Tech 3341's first case: -23 dBFS on both channels is
+        // -23 LUFS
+        let loudness = integrated(&tone(Some(-23.0), Some(-23.0)), STEREO);
+        assert!((loudness.unwrap() + 23.0).abs() < 0.1, "{loudness:?}");
+
+        // On one channel it is half the power
+        let loudness = integrated(&tone(Some(-23.0), None), STEREO);
+        assert!((loudness.unwrap() + 26.0).abs() < 0.1, "{loudness:?}");
+    }
+
+    #[test]
+    fn silence() {
+        assert_eq!(integrated(&tone(None, None), STEREO), None);
+    }
+}
@@ -1,288 +1,1255 @@
-use jack::{Client, ClosureProcessHandler, Control};
-use midir::{MidiInput, MidiInputConnection};
-use serde::Deserialize;
-use std::env;
-use std::fs::File;
-use std::io::Read;
-use std::path::Path;
-use std::sync::mpsc::channel;
-use std::sync::mpsc::Receiver;
-use std::sync::mpsc::Sender;
-use symphonia::core::audio::{SampleBuffer, SignalSpec};
-use symphonia::core::codecs::DecoderOptions;
-use symphonia::core::errors::Error;
-use symphonia::core::formats::{FormatOptions, Track};
-use symphonia::core::io::MediaSourceStream;
-use symphonia::core::meta::MetadataOptions;
-use symphonia::core::probe::Hint;
-
-// There need to be enough of these that there is allways one channel
-// available.  If long samples (that tie up a channel) are being
-// played in quick succession each new (long) samlpe ties up another
-// channel.  The symptom is sample playing continues after triggering
-// stops as the backlog is processed.  Nothing gets dropped.
-const NUM_RECEIVERS: usize = 300;
-
-/// Each sample is described by a path to an audio file and a MIDI
-/// note
-#[derive(Debug, Deserialize)]
-struct SampleDescr {
-    path: String,
-    note: u8,
-}
+use midi_sample_qzt::*;
 
-/// The programme is initialised with a JSON representation of this
-#[derive(Debug, Deserialize)]
-struct Config {
-    samples_descr: Vec<SampleDescr>,
-}
+use args::Subcommand;
+use arpeggiator::Arpeggiator;
+use audio::{Connections, Jack, NoServer, OutputDescr, Route};
+use bank::Banks;
+use bus::Bus;
+use cc_map::CcMap;
+use chance::Chance;
+use clock::Clock;
+use config::{process_samples_json, Config, SampleDescr};
+use control::ControlState;
+use deck::Decks;
+use decode::{Format, Info};
+use duck::Duck;
+use engine::{
+    Command, Engine, Fade, FadeTarget, SampleData, VoiceStatus, COMMAND_QUEUE,
+    NUM_VOICES,
+};
+use gain::db_to_gain;
+use loop_sync::{LoopSync, Tempo};
+use looper::Looper;
+use message::Message;
+use mirror::Mirror;
+use mpe::Mpe;
+use pan::Panner;
+use peaks::Peaks;
+use player::Player;
+use record::Recorder;
+use repeat::NoteRepeat;
+use scene::Scenes;
+use sequencer::Sequencer;
+use state::State;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::sync_channel;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use sysex::SysEx;
 
-/// Each sample is converted to a `Vec<32>` buffer and a MIDI note on
-/// start up.  When the MIDI note is received the buffer is played on
-/// the output
-struct SampleData {
-    data: Vec<f32>,
-    note: u8,
+/// Load a sample at start up.  There is no point carrying on without
+/// it
+fn decode_or_panic(
+    path: &str,
+    format: Option<Format>,
+) -> Vec<f32> {
+    match decode::decode(path, format) {
+        Ok(data) => data,
+        Err(err) => panic!("{err}: {path}"),
+    }
 }
 
-/// The configuration file  processing
-fn process_samples_json(
-    file_path: &str
-) -> Result<Vec<SampleDescr>, Box<dyn std::error::Error>> {
-    // Read the JSON file
-    let mut contents = String::new();
-    let mut file = File::open(file_path)?;
-    file.read_to_string(&mut contents)
-        .expect("Failed to read file");
-
-    // Convert JSON
-    let config: Config = serde_json::from_str(&contents)?;
-
-    Ok(config.samples_descr)
+/// What a sample's file holds, from its header.  A header that cannot
+/// be read says nothing
+fn source(
+    path: &str,
+    format: Option<Format>,
+) -> Info {
+    decode::info(path, format).unwrap_or_default()
 }
 
 fn main() {
+    if let Some(subcommand) = args::subcommand() {
+        let result = match subcommand {
+            Ok(Subcommand::Pack { config, bundle }) => {
+                bundle::pack(&config, &bundle)
+            },
+            Ok(Subcommand::Unpack { bundle, dir }) => {
+                bundle::unpack(&bundle, &dir).map(|manifest| {
+                    eprintln!("Unpacked {}", manifest.display())
+                })
+            },
+            Ok(Subcommand::ExportKit { config, dir }) => {
+                export::kit(&config, &dir)
+            },
+            Ok(Subcommand::Analyze { config }) => bpm::analyze(&config),
+            Ok(Subcommand::Lint { config, json }) => lint::lint(&config, json),
+            Ok(Subcommand::Stats { config }) => footprint::stats(&config),
+            Ok(Subcommand::CalibrateVelocity { config }) => {
+                velocity::calibrate(&config)
+            },
+            Ok(Subcommand::Hash { paths }) => {
+                paths.iter().try_for_each(|path| {
+                    let digest = sha256::file(path)
+                        .map_err(|err| format!("{}: {err}", path.display()))?;
+                    println!("{digest}  {}", path.display());
+                    Ok(())
+                })
+            },
+            Ok(Subcommand::BenchSynthetic { voices }) => {
+                bench::synthetic(voices);
+                Ok(())
+            },
+            Err(err) => Err(format!("{err}\n{}", args::USAGE).into()),
+        };
+        if let Err(err) = result {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     // Get and process command line arguments.
-    let args: Vec<String> = env::args().collect();
-    let samples_descr: Vec<SampleDescr> =
-        match process_samples_json(args[1].as_str()) {
-            Ok(sd) => sd,
-            Err(err) => panic!("{err}: Failed to process input"),
+    let args = match args::parse() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}\n{}", args::USAGE);
+            std::process::exit(1);
+        },
+    };
+    let started = Instant::now();
+
+    // A bundle is unpacked into a new temporary directory only this
+    // user can get into, removed on exit, and its manifest is the
+    // configuration.  So is the demo kit
+    let unpacked = (args.demo || args.config.ends_with(".qzt")).then(|| {
+        match tempfile::Builder::new()
+            .prefix("midi_sample_qzt-")
+            .tempdir()
+        {
+            Ok(dir) => dir,
+            Err(err) => panic!("{err}: Failed to make a temporary directory"),
+        }
+    });
+    let config = match &unpacked {
+        Some(dir) if args.demo => match demo::unpack(dir.path()) {
+            Ok(config) => config.to_string_lossy().into_owned(),
+            Err(err) => panic!("{err}: Failed to unpack the demo kit"),
+        },
+        Some(dir) => {
+            match bundle::unpack(Path::new(&args.config), dir.path()) {
+                Ok(manifest) => manifest.to_string_lossy().into_owned(),
+                Err(err) => panic!("{err}: Failed to unpack bundle"),
+            }
+        },
+        None => args.config.clone(),
+    };
+    let Config {
+        samples_descr,
+        control_socket,
+        state_file,
+        midi_input,
+        connect_input,
+        record_cc,
+        banks,
+        setlist,
+        script,
+        tempo,
+        link,
+        note_repeat,
+        sequencer,
+        arpeggiator,
+        looper,
+        decks,
+        error_beep,
+        max_voices,
+        max_voices_per_note,
+        max_play_seconds,
+        fade_curve,
+        cc_map,
+        mpe,
+        outputs,
+        realtime,
+        sysex,
+        quality: default_quality,
+        dither,
+        groups: group_descrs,
+        ducking,
+        gain,
+        http: http_address,
+        http_token,
+        http_read_only,
+        rtp_midi,
+        mirror,
+        velocity_curve,
+        delay_ms,
+        feedback,
+        scenes,
+        ..
+    } = match process_samples_json(&config, args.profile.as_deref()) {
+        Ok(config) => config,
+        Err(err) => panic!("{err}: Failed to process input"),
+    };
+
+    // The state is kept beside the user's other data unless the
+    // configuration says where
+    let state_file = state_file
+        .map(PathBuf::from)
+        .unwrap_or_else(state::default_state_file);
+
+    // Without a tempo the clock starts at the first loop's
+    let tempo = tempo.or(samples_descr.iter().find_map(|s| s.bpm));
+
+    // Without "outputs" there is one mono output, as there always
+    // used to be.  Each output's channels follow the one before's
+    let outputs = if outputs.is_empty() {
+        vec![OutputDescr {
+            name: "output".to_string(),
+            channels: 1,
+            connect: vec![],
+            speakers: vec![],
+            cue: false,
+        }]
+    } else {
+        outputs
+    };
+    let mut first_channels = vec![];
+    for output in outputs.iter() {
+        let first = first_channels
+            .last()
+            .map_or(0, |(_, first, channels, _)| first + channels);
+        let panner = (!output.speakers.is_empty()).then(|| {
+            Arc::new(Panner::new(output.speakers.iter().copied().zip(first..)))
+        });
+        first_channels.push((
+            output.name.clone(),
+            first,
+            output.channels,
+            panner,
+        ));
+    }
+    let channel_count: usize = outputs.iter().map(|o| o.channels).sum();
+
+    // Samples play on the first output that is not the cue unless they
+    // say otherwise.  The click and auditions play on the cue
+    let output_channels = |index: usize| {
+        let (_, first, count, _) = &first_channels[index];
+        (*first..first + count).collect::<Vec<usize>>()
+    };
+    let main_output =
+        outputs.iter().position(|o| !o.cue).unwrap_or_else(|| {
+            panic!("{config}: outputs: There must be an output that is not the cue")
+        });
+    let main_channels = output_channels(main_output);
+    let cue_channels = outputs.iter().position(|o| o.cue).map(output_channels);
+    let port_names: Vec<String> =
+        outputs.iter().flat_map(|o| o.port_names()).collect();
+
+    // The test tone and the latency click play instead of the samples
+    let samples_descr =
+        if args.test_tone || args.latency.is_some() || args.calibrate_latency {
+            vec![]
+        } else {
+            samples_descr
         };
 
-    // Prepare the sample buffers.  This code is from the Symphonia
-    // example
+    // Prepare the sample buffers, and the pads to light for them
     let mut sample_data: Vec<SampleData> = vec![];
-    for SampleDescr { path, note } in samples_descr {
-        // Create a media source. Note that the MediaSource trait is
-        // automatically implemented for File, among other types.
-        let file = Box::new(File::open(Path::new(path.as_str())).unwrap());
-
-        // Create the media source stream using the boxed media source from above.
-        let mss = MediaSourceStream::new(file, Default::default());
-
-        // Create a hint to help the format registry guess what format
-        // reader is appropriate. In this example we'll leave it empty.
-        let hint = Hint::new();
-
-        // Use the default options when reading and decoding.
-        let format_opts: FormatOptions = Default::default();
-        let metadata_opts: MetadataOptions = Default::default();
-        let decoder_opts: DecoderOptions = Default::default();
-
-        // Probe the media source stream for a format.
-        let probed = symphonia::default::get_probe()
-            .format(&hint, mss, &format_opts, &metadata_opts)
-            .unwrap();
 
-        // Get the format reader yielded by the probe operation.
-        let mut format = probed.format;
+    // Loops that keep time follow the clock's tempo, as measured
+    let loop_tempo = Tempo::new(tempo.unwrap_or(clock::DEFAULT_TEMPO));
 
-        // Get the default track.
-        let track: &Track = format.default_track().unwrap();
+    // Release samples are added after the levels are evened out, so
+    // key noises keep their own level
+    let mut release_data: Vec<SampleData> = vec![];
+    let mut pads: Vec<feedback::Pad> = vec![];
+    let banks = Arc::new(Banks::new(banks));
 
-        // Create a decoder for the track.
-        let mut decoder = symphonia::default::get_codecs()
-            .make(&track.codec_params, &decoder_opts)
-            .unwrap();
+    // Only the banks playing now and next in the setlist are loaded
+    // at the start, the others as the show comes to them
+    let setlist_banks: Vec<usize> = setlist
+        .iter()
+        .map(|name| match banks.index(name) {
+            Some(index) => index,
+            None => panic!("Setlist: No bank called {name}"),
+        })
+        .collect();
+    let preloaded: BTreeSet<usize> =
+        setlist_banks.iter().take(2).copied().collect();
+    let mut deferred: Vec<setlist::Deferred> = vec![];
 
-        // Store the track identifier, we'll use it to filter packets.
-        let track_id = track.id;
+    // Only the groups with buses, and the groups that duck or are
+    // ducked, matter to the engine.  The groups with buses come first
+    let mut groups: Vec<String> = vec![];
+    for descr in group_descrs.iter() {
+        if groups.contains(&descr.name) {
+            panic!("Group {}: There are two groups called that", descr.name);
+        }
+        if let Some(output) = &descr.output {
+            if !first_channels.iter().any(|(name, _, _, _)| name == output) {
+                panic!("Group {}: No output called {output}", descr.name);
+            }
+        }
+        groups.push(descr.name.clone());
+    }
+    for descr in ducking.iter() {
+        for name in [&descr.source, &descr.target] {
+            if !groups.contains(name) {
+                groups.push(name.clone());
+            }
+        }
+    }
+    for SampleDescr {
+        path,
+        segments: segment_descrs,
+        note,
+        bank,
+        slice,
+        max_voices,
+        priority,
+        mono,
+        output,
+        channels,
+        route,
+        pan,
+        format,
+        quality,
+        count_in,
+        group,
+        eq,
+        pitch_envelope,
+        color,
+        probability,
+        condition,
+        effects,
+        max_play_seconds,
+        name,
+        chord,
+        keys,
+        release_sample,
+        bpm,
+        sync,
+        ..
+    } in samples_descr
+    {
+        let quality = quality.unwrap_or(default_quality);
+        if let Some(condition) = condition {
+            if condition.hit == 0 || condition.hit > condition.every {
+                panic!(
+                    "{path}: The condition's hit is not 1 to {}",
+                    condition.every
+                );
+            }
+        }
+        let chance = Chance {
+            probability: probability.unwrap_or(1.0),
+            condition,
+        };
+        let group =
+            group.and_then(|name| groups.iter().position(|g| *g == name));
 
-        let mut sample_count = 0;
-        let mut sample_buf: Option<SampleBuffer<f32>> = None;
-        let mut data: Vec<f32> = vec![];
+        // A sample that does not give an output plays on its group's
+        let output = output.or_else(|| {
+            group
+                .and_then(|group| group_descrs.get(group))
+                .and_then(|descr| descr.output.clone())
+        });
+        let bank = bank.map(|name| match banks.index(&name) {
+            Some(index) => index,
+            None => panic!("{path}: No bank called {name}"),
+        });
 
-        loop {
-            // Get the next packet from the format reader.
-            if let Ok(packet) = format.next_packet() {
-                // If the packet does not belong to the selected track, skip it.
-                if packet.track_id() != track_id {
-                    continue;
+        // The output channels, numbered from one in the sample's
+        // output, become the engine's channels
+        let (_, first, count, panner) = match &output {
+            Some(name) => first_channels
+                .iter()
+                .find(|(n, _, _, _)| n == name)
+                .unwrap_or_else(|| panic!("{path}: No output called {name}")),
+            None => &first_channels[main_output],
+        };
+        let channels: Vec<usize> = if output.is_none() && channels.is_empty() {
+            vec![]
+        } else if channels.is_empty() {
+            (*first..first + count).collect()
+        } else {
+            channels
+                .iter()
+                .map(|channel| match channel {
+                    1.. if channel <= count => first + channel - 1,
+                    _ => panic!("{path}: No channel {channel} in its output"),
+                })
+                .collect()
+        };
+
+        // Routed to the cue, the sample plays on all its channels
+        let channels = match (route, &cue_channels) {
+            (Route::Main, _) => channels,
+            (_, None) => panic!("{path}: Routed to the cue, but there is none"),
+            (_, Some(_)) if pan.is_some() => {
+                panic!("{path}: A panned sample cannot be routed to the cue")
+            },
+            (Route::Cue, Some(cue)) => cue.clone(),
+            (Route::Both, Some(cue)) => {
+                let mut both = if channels.is_empty() {
+                    main_channels.clone()
+                } else {
+                    channels
+                };
+                for channel in cue {
+                    if !both.contains(channel) {
+                        both.push(*channel);
+                    }
                 }
+                both
+            },
+        };
 
-                // Decode the packet into audio samples, ignoring any decode errors.
-                match decoder.decode(&packet) {
-                    Ok(audio_buf) => {
-                        // The decoded audio samples may now be accessed via
-                        // the audio buffer if per-channel slices of samples
-                        // in their native decoded format is
-                        // desired. Use-cases where the samples need to be
-                        // accessed in an interleaved order or converted into
-                        // another sample format, or a byte buffer is
-                        // required, are covered by copying the audio buffer
-                        // into a sample buffer or raw sample buffer,
-                        // respectively. In the example below, we will copy
-                        // the audio buffer into a sample buffer in an
-                        // interleaved order while also converting to a f32
-                        // sample format.
-
-                        // If this is the *first* decoded packet, create a
-                        // sample buffer matching the decoded audio buffer
-                        // format.
-                        if sample_buf.is_none() {
-                            // Get the audio buffer specification.
-                            let spec: SignalSpec = *audio_buf.spec();
-
-                            // Get the capacity of the decoded buffer. Note:
-                            // This is capacity, not length!
-                            let duration = audio_buf.capacity() as u64;
-
-                            // Create the f32 sample buffer.
-                            sample_buf =
-                                Some(SampleBuffer::<f32>::new(duration, spec));
-                        }
-
-                        // Copy the decoded audio buffer into the sample
-                        // buffer in an interleaved format.
-                        if let Some(buf) = &mut sample_buf {
-                            buf.copy_interleaved_ref(audio_buf);
-
-                            // The samples may now be access via the
-                            // `samples()` function.
-                            sample_count += buf.samples().len();
-                            data.append(&mut buf.samples().to_vec());
-                        }
-                    },
-                    Err(Error::DecodeError(_)) => (),
-                    Err(_) => break,
+        // A panned sample plays round all its output's speakers
+        let pan = pan.map(|degrees| match panner {
+            Some(panner) => (degrees, panner.clone()),
+            None => panic!("{path}: Panned on an output with no speakers"),
+        });
+
+        if slice.is_some() && keys.is_some() {
+            panic!("{path}: A sample cannot be both sliced and on keys");
+        }
+        if sync.is_some()
+            && (slice.is_some() || keys.is_some() || !segment_descrs.is_empty())
+        {
+            panic!("{path}: A synced loop cannot be sliced, on keys or in segments");
+        }
+        let sync = sync.map(|descr| {
+            LoopSync::new(
+                &descr,
+                bpm,
+                loop_tempo.clone(),
+                source(&path, format),
+            )
+            .unwrap_or_else(|err| panic!("{path}: {err}"))
+        });
+
+        // A release sample plays on each note the sample does
+        if let Some(release) = release_sample {
+            let data = decode_or_panic(&release.path, release.format);
+            let release_source = source(&release.path, release.format);
+            let notes: Vec<u8> = match &keys {
+                Some(keys) => keys.notes().collect(),
+                None => vec![note],
+            };
+            for note in notes {
+                release_data.push(SampleData {
+                    path: release.path.clone(),
+                    data: data.clone(),
+                    source: release_source,
+                    note,
+                    bank,
+                    max_voices,
+                    priority,
+                    channels: channels.clone(),
+                    pan: pan.clone(),
+                    quality,
+                    group,
+                    eq,
+                    effects: effects.clone(),
+                    name: name.as_ref().map(|name| format!("{name} release")),
+                    release: Some(release.velocity),
+                    ..Default::default()
+                });
+            }
+        }
+
+        // A sliced sample is spread over notes, from its own note up
+        // unless the slices say which notes
+        if let Some(slice) = slice {
+            let data = decode_or_panic(&path, format);
+            let info = source(&path, format);
+            let starts = slice::starts(&data, &slice);
+            let ends = starts.iter().skip(1).copied().chain([data.len()]);
+            for (i, (start, end)) in starts.iter().zip(ends).enumerate() {
+                let Some(note) = slice.note(note, i) else {
+                    eprintln!(
+                        "{path}: Too many slices, {} dropped",
+                        starts.len() - i
+                    );
+                    break;
+                };
+                if let Some(color) = color {
+                    pads.push(feedback::Pad { note, bank, color });
                 }
+                sample_data.push(SampleData {
+                    path: format!("{path}#{i}"),
+                    data: data[*start..end].to_vec(),
+                    source: info,
+                    note,
+                    bank,
+                    max_voices,
+                    priority,
+                    mono,
+                    channels: channels.clone(),
+                    pan: pan.clone(),
+                    quality,
+                    count_in,
+                    group,
+                    eq,
+                    pitch_envelope,
+                    chance,
+                    effects: effects.clone(),
+                    max_play_seconds,
+                    name: name.as_ref().map(|name| format!("{name} {i}")),
+                    chord: chord.clone(),
+                    ..Default::default()
+                });
+            }
+            eprintln!("{path}: {} slices", starts.len());
+            continue;
+        }
 
-                continue;
+        // A sample on a range of keys has a copy for each, at its
+        // pitch
+        if let Some(keys) = keys {
+            let data = decode_or_panic(&path, format);
+            let info = source(&path, format);
+            for key in keys.notes() {
+                if let Some(color) = color {
+                    pads.push(feedback::Pad {
+                        note: key,
+                        bank,
+                        color,
+                    });
+                }
+                sample_data.push(SampleData {
+                    path: format!("{path}#{key}"),
+                    data: keys.render(&data, key as i32 - note as i32, quality),
+                    source: info,
+                    note: key,
+                    bank,
+                    max_voices,
+                    priority,
+                    mono,
+                    channels: channels.clone(),
+                    pan: pan.clone(),
+                    quality,
+                    count_in,
+                    group,
+                    eq,
+                    pitch_envelope,
+                    chance,
+                    effects: effects.clone(),
+                    max_play_seconds,
+                    name: name.clone(),
+                    chord: chord.clone(),
+                    ..Default::default()
+                });
             }
-            break;
+            eprintln!("{path}: {} keys", keys.notes().len());
+            continue;
         }
 
-        // Extract the file name part of the sample to output some
-        // stats.
-        let disp_path = if let Some(idx) = path.rfind('/') {
-            path.get(idx..).unwrap()
-        } else {
-            path.as_str()
+        if let Some(color) = color {
+            pads.push(feedback::Pad { note, bank, color });
+        }
+
+        // What the sample's first file holds
+        let first = segment_descrs.first().map_or(&path, |s| &s.path);
+        let info = source(first, format);
+
+        // A sample in a bank later in the setlist waits to be loaded
+        let in_setlist = bank.filter(|bank| setlist_banks.contains(bank));
+        if let Some(bank) = in_setlist {
+            deferred.push(setlist::Deferred {
+                sample: sample_data.len(),
+                bank,
+                path: path.clone(),
+                format,
+                source: info,
+                segments: segment_descrs.clone(),
+                sync: sync.clone(),
+                auto_gain: None,
+            });
+        }
+        let (data, segments) =
+            if in_setlist.is_some_and(|bank| !preloaded.contains(&bank)) {
+                (vec![], vec![])
+            } else {
+                match setlist::prepare(
+                    &path,
+                    format,
+                    &segment_descrs,
+                    sync.as_ref(),
+                ) {
+                    Ok(prepared) => prepared,
+                    Err(err) => panic!("{err}"),
+                }
+            };
+
+        // Store prepared sample, with what its first file holds
+        sample_data.push(SampleData {
+            source: info,
+            path,
+            data,
+            note,
+            bank,
+            segments,
+            max_voices,
+            priority,
+            mono,
+            channels,
+            pan,
+            quality,
+            count_in,
+            group,
+            eq,
+            pitch_envelope,
+            chance,
+            effects,
+            max_play_seconds,
+            name,
+            chord,
+            sync,
+            ..Default::default()
+        });
+    }
+
+    // Report samples that may clip or are too quiet, and even them out
+    // if asked to.  Samples loaded later are evened out to the same
+    // level
+    let auto_gain = staging::check(&mut sample_data, args.auto_gain);
+    for sample in deferred.iter_mut() {
+        sample.auto_gain = auto_gain;
+    }
+
+    // Banks with a loudness target have the last word
+    for sample in sample_data.iter_mut() {
+        if let Some(target) = sample.bank.and_then(|b| banks.target_lufs(b)) {
+            let label = sample.label().to_string();
+            loudness::normalize(
+                &mut sample.data,
+                sample.source,
+                target,
+                &label,
+            );
+        }
+    }
+    sample_data.append(&mut release_data);
+
+    if let Some(descr) = feedback {
+        feedback::start(descr, pads, banks.clone());
+    }
+
+    // In audition mode the two candidates are added after the
+    // configured samples and take over their note
+    let audition = args.audition.map(|audition| {
+        let first = sample_data.len();
+        for path in audition.paths.iter() {
+            sample_data.push(SampleData {
+                path: path.clone(),
+                data: decode_or_panic(path, None),
+                source: source(path, None),
+                note: audition.note,
+                channels: cue_channels.clone().unwrap_or_default(),
+                quality: default_quality,
+                ..Default::default()
+            });
+        }
+        eprintln!(
+            "Auditioning on note {}: A {} B {}",
+            audition.note, audition.paths[0], audition.paths[1]
+        );
+        (audition, first)
+    });
+
+    // The decks' samples come last, empty until a track is cued
+    let deck_samples = decks.as_ref().map(|_| {
+        let first = sample_data.len();
+        sample_data.extend((0..2).map(deck::slot));
+        [first, first + 1]
+    });
+
+    // The error beep is added once Jack says the rate to make it at,
+    // on its output's channels or the first output's
+    let error_beep = error_beep.map(|descr| {
+        let channels: Vec<usize> = match &descr.output {
+            Some(name) => first_channels
+                .iter()
+                .find(|(n, _, _, _)| n == name)
+                .map(|(_, first, count, _)| (*first..first + count).collect())
+                .unwrap_or_else(|| {
+                    panic!("Error beep: No output called {name}")
+                }),
+            None => vec![],
         };
-        eprintln!("{disp_path}  Total size() {sample_count}");
+        let data = descr
+            .path
+            .as_ref()
+            .map(|path| decode_or_panic(path, descr.format));
+        (data, descr.gain, channels, descr.unmapped)
+    });
+
+    let sample_data: Vec<Arc<SampleData>> =
+        sample_data.into_iter().map(Arc::new).collect();
+    eprintln!(
+        "Decoded {} samples in {:.3}s",
+        sample_data.iter().filter(|s| !s.data.is_empty()).count(),
+        decode::decode_time().as_secs_f64()
+    );
+    memory::prefault(&sample_data);
+    if args.mlock {
+        memory::lock(&sample_data);
+    }
+
+    // Prepare the channel for sending commands from the MIDI and
+    // control threads to the Jack thread
+    let (command_sender, command_receiver) =
+        sync_channel::<Command>(COMMAND_QUEUE);
+    let voices: Arc<Vec<VoiceStatus>> =
+        Arc::new((0..NUM_VOICES).map(|_| VoiceStatus::default()).collect());
+
+    // Create the Jack client and start the audio processing thread
+    let mut engine = Engine::new(
+        sample_data.clone(),
+        voices.clone(),
+        command_receiver,
+        channel_count,
+        main_channels,
+    );
+    engine.limit_voices(max_voices, max_voices_per_note);
+    engine.limit_play(max_play_seconds);
+    engine.delay_notes(delay_ms);
+    engine.banks(banks.count());
+    engine.fade_curve(fade_curve);
+    if let Some(bits) = dither {
+        engine.dither(bits);
+    }
+    let index = |name: &String| groups.iter().position(|g| g == name).unwrap();
+    engine.duck(
+        ducking
+            .iter()
+            .map(|descr| {
+                Duck::new(descr, index(&descr.source), index(&descr.target))
+            })
+            .collect(),
+        groups.len(),
+    );
+    engine.buses(
+        groups
+            .iter()
+            .map(|name| match group_descrs.iter().find(|d| d.name == *name) {
+                Some(descr) => Bus::new(descr, channel_count),
+                None => Bus::default(),
+            })
+            .collect(),
+    );
+    if let Some(trigger_log) = args.trigger_log {
+        match trigger_log::start(&trigger_log) {
+            Ok(events) => engine.log_triggers(events),
+            Err(err) => {
+                panic!("{err}: Trigger log {}", trigger_log.display())
+            },
+        }
+    }
+
+    // The HTTP API hears about triggers as they happen
+    #[cfg(feature = "http")]
+    let http_events = http_address.as_ref().map(|_| {
+        let (sender, receiver) = sync_channel(http::EVENT_QUEUE);
+        engine.log_triggers(sender);
+        receiver
+    });
+    let levels = engine.levels();
+    let jack = match Jack::start(
+        engine,
+        Connections {
+            outputs,
+            input: connect_input,
+        },
+        NoServer {
+            start_server: args.start_jack,
+            wait: args.wait_for_jack,
+        },
+    ) {
+        Ok(jack) => jack,
+        Err(err) => {
+            eprintln!(
+                "{err}: Could not connect to a Jack server.  Start one \
+                 (with `jackd -d alsa`, say), or run with --start-jack \
+                 or --wait-for-jack"
+            );
+            drop(unpacked);
+            std::process::exit(1);
+        },
+    };
+
+    if args.test_tone {
+        test_tone::start(command_sender, port_names, jack.sample_rate());
+        eprintln!("Press enter to exit...");
+        let _ = std::io::stdin().read_line(&mut String::new());
+        jack.stop();
+        return;
+    }
+    if let Some(note) = args.latency {
+        let latency = latency::Latency::new(
+            note,
+            sample_data.len(),
+            command_sender,
+            jack.sample_rate(),
+        );
+        midi::watch(midi_input, move |message: Message| {
+            if let Message::NoteOn { note, .. } = message {
+                latency.note_on(note);
+            }
+        });
+        eprintln!("Press enter to exit...");
+        let _ = std::io::stdin().read_line(&mut String::new());
+        jack.stop();
+        return;
+    }
+    if args.calibrate_latency {
+        let calibration = Arc::new(latency::Calibration::new(
+            sample_data.len(),
+            command_sender,
+            jack.sample_rate(),
+        ));
+        let hits = calibration.clone();
+        midi::watch(midi_input, move |message: Message| {
+            if let Message::NoteOn { note, .. } = message {
+                hits.note_on(note);
+            }
+        });
+        let _ = std::io::stdin().read_line(&mut String::new());
+        jack.stop();
+        if let Err(err) = calibration.write(Path::new(&config)) {
+            eprintln!("{err}");
+        }
+        return;
+    }
 
-        // Store prepared sample
-        sample_data.push(SampleData { data, note });
+    let counts_in = sample_data.iter().any(|sample| sample.count_in > 0);
+    let keeps_time = sample_data.iter().any(|sample| sample.sync.is_some());
+    let player = Arc::new(Player::new(
+        sample_data,
+        banks.clone(),
+        command_sender.clone(),
+        jack.sample_rates(),
+        audition,
+    ));
+    if !deferred.is_empty() {
+        setlist::start(
+            setlist_banks,
+            preloaded,
+            deferred,
+            player.clone(),
+            banks.clone(),
+        );
     }
 
-    // Prepare the channels for sending data from the MIDI thread to
-    // the Jack thread
-    let mut senders: Vec<Sender<f32>> = Vec::new();
-    let mut receivers: Vec<Receiver<f32>> = Vec::new();
-    for _i in 0..NUM_RECEIVERS {
-        let (sx, rx) = channel();
-        senders.push(sx.clone());
-        receivers.push(rx);
+    // Things that keep time follow the clock
+    let clock =
+        Clock::start(tempo.unwrap_or(clock::DEFAULT_TEMPO), realtime.clone());
+    if link {
+        if let Err(err) = link::follow(clock.clone()) {
+            eprintln!("{err}: Ableton Link");
+        }
+    }
+    if let Some((data, gain, channels, unmapped)) = error_beep {
+        let data = data.unwrap_or_else(|| beep::tone(jack.sample_rate()));
+        player.add_error_beep(beep::sample(data, gain, channels), unmapped);
+    }
+    if keeps_time {
+        let measured = loop_tempo.clone();
+        clock.on_pulse(move |pulse| measured.pulse(pulse));
     }
+    if counts_in {
+        player.add_click(jack.sample_rate(), cue_channels.unwrap_or_default());
+        let pulsed = player.clone();
+        clock.on_pulse(move |pulse| pulsed.pulse(pulse));
+    }
+    let note_repeat = note_repeat.map(|descr| {
+        let note_repeat = Arc::new(NoteRepeat::new(descr, player.clone()));
+        let pulsed = note_repeat.clone();
+        clock.on_pulse(move |pulse| pulsed.pulse(pulse));
+        note_repeat
+    });
 
-    // Create the Jack client
-    let (client, _status) =
-        Client::new("MidiSampleQzt", jack::ClientOptions::NO_START_SERVER)
+    let sequencer = sequencer.map(|descr| {
+        let sequencer = match Sequencer::new(descr, player.clone()) {
+            Ok(sequencer) => Arc::new(sequencer),
+            Err(err) => panic!("{err}: Sequencer"),
+        };
+        let pulsed = sequencer.clone();
+        clock.on_pulse(move |pulse| pulsed.pulse(pulse));
+        sequencer
+    });
+
+    let arpeggiator = arpeggiator.map(|descr| {
+        let arpeggiator = Arc::new(Arpeggiator::new(descr, player.clone()));
+        let pulsed = arpeggiator.clone();
+        clock.on_pulse(move |pulse| pulsed.pulse(pulse));
+        arpeggiator
+    });
+
+    let looper = looper.map(|descr| {
+        let looper = Arc::new(Looper::new(descr, player.clone()));
+        let pulsed = looper.clone();
+        clock.on_pulse(move |pulse| pulsed.pulse(pulse));
+        looper
+    });
+
+    let decks = decks.zip(deck_samples).map(|(descr, samples)| {
+        Arc::new(Decks::new(
+            descr,
+            samples,
+            player.clone(),
+            command_sender.clone(),
+        ))
+    });
+
+    let recorder = Arc::new(Recorder::new(
+        player.clone(),
+        command_sender.clone(),
+        jack.sample_rates(),
+        record_cc,
+    ));
+
+    let scenes = match Scenes::new(scenes, |name| {
+        first_channels
+            .iter()
+            .find(|(n, _, _, _)| n == name)
+            .map(|(_, first, count, _)| (*first..first + count).collect())
+    }) {
+        Ok(scenes) => scenes,
+        Err(err) => panic!("{err}"),
+    };
+
+    // Start listening for control commands
+    let control_socket = control_socket
+        .map(PathBuf::from)
+        .unwrap_or_else(control::default_control_socket);
+    let control_state = Arc::new(ControlState {
+        voices: voices.clone(),
+        sample_rate: jack.sample_rates(),
+        commands: command_sender.clone(),
+        banks: banks.clone(),
+        sequencer: sequencer.clone(),
+        looper: looper.clone(),
+        recorder: recorder.clone(),
+        stats: jack.stats(),
+        levels,
+        scenes,
+        player: player.clone(),
+        peaks: Peaks::default(),
+        groups: groups.clone(),
+        state: Mutex::new(State::default()),
+        state_file,
+        changed: AtomicBool::new(false),
+        saving: Mutex::new(()),
+        quit: AtomicBool::new(false),
+    });
+
+    // Start at the configured gain, unless the state from the last
+    // run has another.  The fades are picked up by the first Jack
+    // cycle
+    if let Some(db) = gain {
+        control_state
+            .fade(Fade {
+                target: FadeTarget::Master,
+                gain: db_to_gain(db),
+                frames: 0,
+            })
             .unwrap();
+    }
+    for (group, descr) in group_descrs.iter().enumerate() {
+        control_state
+            .fade(Fade {
+                target: FadeTarget::Group(group),
+                gain: db_to_gain(descr.gain),
+                frames: 0,
+            })
+            .unwrap();
+        control_state.mute(group, descr.mute).unwrap();
+    }
+    match state::load(&control_state.state_file) {
+        Ok(Some(saved)) => {
+            if let Err(err) = control_state.apply(&saved.snapshot(), 0) {
+                eprintln!("{err}: Restoring state");
+            }
+            if let Some(sequencer) = &sequencer {
+                for (note, steps) in saved.patterns.iter() {
+                    if let Err(err) = sequencer.set_track(*note, steps) {
+                        eprintln!("{err}: Restoring state");
+                    }
+                }
+            }
+            {
+                let mut state = control_state.state.lock().unwrap();
+                state.patterns = saved.patterns;
+                state.snapshots = saved.snapshots;
+            }
+            eprintln!(
+                "Restored state from {}",
+                control_state.state_file.display()
+            );
+        },
+        Ok(None) => (),
+        Err(err) => eprintln!(
+            "{err}: Restoring state from {}",
+            control_state.state_file.display()
+        ),
+    }
+
+    // Nothing has changed yet.  From now on changes are saved as they
+    // happen
+    control_state.state.lock().unwrap().bank =
+        banks.active_name().map(String::from);
+    control_state.changed.store(false, Ordering::Relaxed);
+    control::autosave(control_state.clone());
+
+    let mpe = mpe.map(|descr| Mpe::new(descr, command_sender.clone()));
+    let cc_map = CcMap::new(cc_map, control_state.clone(), clock.clone());
+
+    let listen_state = control_state.clone();
+    if let Err(err) = control::listen(&control_socket, move |command| {
+        control::command(command, &listen_state)
+    }) {
+        eprintln!("{err}: Control socket {}", control_socket.display());
+    }
+
+    #[cfg(feature = "http")]
+    if let (Some(address), Some(events)) = (&http_address, http_events) {
+        let access = http::Access {
+            token: http_token,
+            read_only: http_read_only,
+        };
+        if let Err(err) = http::start(
+            address,
+            access,
+            control_state.clone(),
+            player.clone(),
+            events,
+        ) {
+            eprintln!("{err}: HTTP API {address}");
+        }
+    }
+    #[cfg(not(feature = "http"))]
+    let _ = (http_token, http_read_only);
+    #[cfg(not(feature = "http"))]
+    if let Some(address) = http_address {
+        eprintln!(
+            "{address}: The HTTP API needs midi_sample_qzt built with the \"http\" feature"
+        );
+    }
+
+    // The script, if there is one, decides what is played
+    #[cfg(feature = "scripting")]
+    let script = script.map(|path| {
+        match script::Script::load(
+            std::path::Path::new(&path),
+            player.clone(),
+            control_state.clone(),
+        ) {
+            Ok(script) => script,
+            Err(err) => panic!("{err}: Script {path}"),
+        }
+    });
+    #[cfg(not(feature = "scripting"))]
+    if let Some(path) = script {
+        eprintln!(
+            "{path}: Scripts need midi_sample_qzt built with the \"scripting\" feature"
+        );
+    }
+
+    let capture = args.capture_midi.map(smf::Capture::start);
+    let capture_sender = capture.as_ref().map(|capture| capture.sender());
+
+    // Connect to the MIDI input, and keep reconnecting if it goes away
+    // Control commands can come over MIDI too
+    let sysex = sysex
+        .then(|| match SysEx::new() {
+            Ok(sysex) => Some(sysex),
+            Err(err) => {
+                eprintln!("{err}: System exclusive output");
+                None
+            },
+        })
+        .flatten();
+    let sysex_state = control_state.clone();
+    let scene_state = control_state.clone();
 
-    let mut port = client.register_port("output", jack::AudioOut);
-
-    // Activate the Jack client and start the audio processing thread
-    let as_client = client
-        .activate_async(
-            (),
-            ClosureProcessHandler::new(
-                move |_c: &Client, ps: &jack::ProcessScope| -> Control {
-                    let output = port.as_mut().unwrap().as_mut_slice(ps);
-
-                    for (_frame, sample) in output.iter_mut().enumerate() {
-                        let mut f: f32 = 0.0;
-                        for r in receivers.iter() {
-                            if let Ok(_f) = r.try_recv() {
-                                // Mixing the channels together
-                                f += _f;
-                            }
-                        }
-
-                        // Unsure if this is the thing to do.  `tanh`
-                        // is almost linear except in the extremes
-                        // where it assymptotically approaches -1 and
-                        // 1
-                        // if f > 1.0 || f < -1.0 {
-                        //     eprintln!(
-                        //         "Sample is: {f}.  Adjusting too: {}",
-                        //         f.tanh()
-                        //     );
-                        // }
-                        *sample = f.tanh();
+    let handler = Arc::new(move |message: Message| {
+        // The MIDI thread is new each time the controller connects
+        if let Some(realtime) = &realtime {
+            realtime.apply("MIDI");
+        }
+        let message = match &velocity_curve {
+            Some(curve) => curve.apply(message),
+            None => message,
+        };
+        if let Some(capture_sender) = &capture_sender {
+            capture_sender.note(&message);
+        }
+        if let Some(mpe) = &mpe {
+            mpe.message(&message);
+        }
+        match message {
+            Message::RealTime(byte) => {
+                clock.midi(byte);
+
+                // MIDI Start and Stop play and stop the sequencer
+                if let Some(sequencer) = &sequencer {
+                    match byte {
+                        0xfa => sequencer.play(true),
+                        0xfc => sequencer.play(false),
+                        _ => (),
+                    }
+                }
+            },
+
+            // Bank Select and Program Change, on any channel,
+            // choose the bank
+            Message::ControlChange {
+                controller, value, ..
+            } => {
+                banks.control_change(controller, value);
+                if let Some(note_repeat) = &note_repeat {
+                    note_repeat.control_change(controller, value);
+                }
+                if let Some(arpeggiator) = &arpeggiator {
+                    arpeggiator.control_change(controller, value);
+                }
+                if let Some(looper) = &looper {
+                    looper.control_change(controller, value);
+                }
+                if let Some(decks) = &decks {
+                    decks.control_change(controller, value);
+                }
+                recorder.control_change(controller, value);
+                cc_map.control_change(controller, value);
+            },
+
+            // A scene's program selects the scene rather than a bank
+            Message::ProgramChange { program, .. } => {
+                let scene =
+                    scene_state.scenes.program_change(program, &scene_state);
+                if !scene {
+                    banks.program_change(program)
+                }
+            },
+            Message::Exclusive(data) => {
+                if let Some(sysex) = &sysex {
+                    sysex.message(&data, |command| {
+                        control::command(command, &sysex_state)
+                    });
+                }
+            },
+
+            // Notes are taken from the first channel, or from
+            // every channel with MPE
+            Message::NoteOff {
+                channel,
+                note,
+                velocity,
+            } if channel == 0 || mpe.is_some() => {
+                if let Some(note_repeat) = &note_repeat {
+                    note_repeat.note_off(note);
+                }
+                if let Some(looper) = &looper {
+                    looper.note(note, 0);
+                }
+                if let Some(arpeggiator) = &arpeggiator {
+                    if arpeggiator.note_off(note) {
+                        return;
                     }
-                    Control::Continue
-                },
-            ),
-        )
-        .unwrap();
-
-    // Create a virtual midi port to read in data
-    let lpx_midi = MidiInput::new("MidiSampleQzt").unwrap();
-    let in_ports = lpx_midi.ports();
-    let in_port = in_ports.get(0).ok_or("no input port available").unwrap();
-
-    // // Create the channel that the buf reading closure uses to send data
-    // let (sender, receiver) = channel::<f32>();
-
-    // Index the clousre below maintains for output clients
-    let mut idx = 0;
-    let _conn_in: MidiInputConnection<()> = lpx_midi
-        .connect(
-            in_port,
-            "midi_input",
-            move |_stamp, message: &[u8], _| {
-                // let message = MidiMessage::from_bytes(message.to_vec());
-
-                if message.len() == 3 && message[0] == 144 {
-                    // All MIDI notes from LPX start with 144, for initial
-                    // noteon and noteoff
-                    let velocity = message[2];
-                    if velocity != 0 {
-                        // NoteOn
-			// eprintln!("Message: {message:?}");
-                        if let Some(sample) =
-                            sample_data.iter().find(|s| s.note == message[1])
-                        {
-			    // Get the volume as a f32 fraction
-			    let volume:f32 = message[2] as f32 / 127.0;
-                            for f in sample.data.iter() {
-                                senders.get(idx).unwrap().send(*f * volume).unwrap();
-                            }
-
-                            idx += 1;
-                            idx %= senders.len();
-                        }
+                }
+                #[cfg(feature = "scripting")]
+                if let Some(script) = &script {
+                    script.note_off(&player, note, velocity);
+                    return;
+                }
+                player.note_off(note, velocity);
+            },
+            Message::NoteOn {
+                channel,
+                note,
+                velocity,
+            } if channel == 0 || mpe.is_some() => {
+                if decks.as_ref().is_some_and(|decks| decks.cue(note)) {
+                    return;
+                }
+                recorder.note(note);
+                if let Some(note_repeat) = &note_repeat {
+                    note_repeat.note_on(note, velocity);
+                }
+                if let Some(looper) = &looper {
+                    looper.note(note, velocity);
+                }
+                if let Some(arpeggiator) = &arpeggiator {
+                    if arpeggiator.note_on(note, velocity) {
+                        return;
                     }
                 }
+                #[cfg(feature = "scripting")]
+                if let Some(script) = &script {
+                    script.note_on(&player, note, velocity);
+                    return;
+                }
+                player.note_on(note, velocity);
             },
-            (),
-        )
-        .unwrap();
-    // Wait for the user to press enter to exit
-    eprintln!("Press enter to exit...");
-    let _ = std::io::stdin().read_line(&mut String::new());
-    // Deactivate the Jack client and stop the audio processing thread
-    as_client.deactivate().unwrap();
+            _ => (),
+        }
+    });
+
+    // A mirror master sends what it plays on to the follower, and a
+    // follower plays what the master sends rather than its own MIDI
+    // until the master goes quiet
+    let mirror = mirror.and_then(|descr| {
+        let handler = handler.clone();
+        Mirror::start(&descr, move |message| handler(message))
+            .map_err(|err| eprintln!("{err}: Mirror {}", descr.address))
+            .ok()
+    });
+    let handler = Arc::new(move |message: Message| {
+        if mirror.as_ref().is_none_or(|mirror| mirror.local(&message)) {
+            handler(message)
+        }
+    });
+
+    // MIDI can come over the network too
+    if let Some(descr) = &rtp_midi {
+        let handler = handler.clone();
+        if let Err(err) =
+            rtp_midi::listen(descr, move |message| handler(message))
+        {
+            eprintln!("{err}: RTP-MIDI port {}", descr.port);
+        }
+    }
+    let keys = handler.clone();
+    midi::watch(midi_input, move |message| handler(message));
+
+    // Wait for the user to press enter to exit, playing notes from
+    // the keyboard or taking commands until then if asked to
+    if args.keyboard {
+        keyboard::play(|message| keys(message));
+    } else if args.console {
+        console::run(|message| keys(message), &control_state);
+    } else {
+        eprintln!("Press enter to exit...");
+
+        // With nothing on stdin, run headless until a signal or the
+        // "quit" command stops it
+        if let Ok(0) = std::io::stdin().read_line(&mut String::new()) {
+            eprintln!("No input: running until stopped");
+            signal::catch();
+            while !signal::stopped()
+                && !control_state.quit.load(Ordering::Relaxed)
+            {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            eprintln!("Stopping");
+        }
+    }
+    jack.stop();
+    if let Some(capture) = capture {
+        capture.finish();
+    }
+    let _ = std::fs::remove_file(&control_socket);
+    if let Err(err) = control_state.save_state() {
+        eprintln!("{err}");
+    }
+    report::report(
+        &control_state.stats,
+        started.elapsed(),
+        args.report.as_deref(),
+    );
 }
@@ -0,0 +1,66 @@
+use crate::engine::SampleData;
+use std::sync::Arc;
+
+/// Memory is faulted in a page at a time
+const PAGE: usize = 4096;
+
+/// Touch every page of the samples, so the first time each is
+/// triggered does not wait on page faults in the Jack thread
+pub fn prefault(samples: &[Arc<SampleData>]) {
+    let stride = PAGE / std::mem::size_of::<f32>();
+    let mut sum = 0.0;
+    for sample in samples {
+        for value in sample.data.iter().step_by(stride) {
+            sum += value;
+        }
+    }
+    std::hint::black_box(sum);
+}
+
+/// Lock the samples into memory so they are never paged out.  If the
+/// memory lock limit (RLIMIT_MEMLOCK) is too low for them all, as many
+/// as fit are locked and the rest are left as they are
+pub fn lock(samples: &[Arc<SampleData>]) {
+    let mut locked = 0;
+    for sample in samples {
+        let bytes = std::mem::size_of_val(sample.data.as_slice());
+        if bytes == 0 {
+            continue;
+        }
+        // SAFETY: The range is the sample's buffer, which is never
+        // freed or moved while the programme runs
+        if unsafe { libc::mlock(sample.data.as_ptr().cast(), bytes) } != 0 {
+            eprintln!(
+                "{}: Locked {locked} of {} bytes of samples into memory.  \
+                 Raise the memory lock limit (ulimit -l, currently {}) \
+                 to lock them all",
+                std::io::Error::last_os_error(),
+                samples
+                    .iter()
+                    .map(|s| std::mem::size_of_val(s.data.as_slice()))
+                    .sum::<usize>(),
+                memlock_limit()
+            );
+            return;
+        }
+        locked += bytes;
+    }
+    eprintln!("Locked {locked} bytes of samples into memory");
+}
+
+/// The memory lock limit, for reporting
+fn memlock_limit() -> String {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: `limit` is a valid rlimit for getrlimit to fill in
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } != 0 {
+        return "unknown".to_string();
+    }
+    if limit.rlim_cur == libc::RLIM_INFINITY {
+        "unlimited".to_string()
+    } else {
+        format!("{} kB", limit.rlim_cur / 1024)
+    }
+}
@@ -0,0 +1,524 @@
+/// The longest system exclusive message kept.  Longer ones are
+/// dropped
+const MAX_EXCLUSIVE: usize = 4096;
+
+/// A MIDI message.  Channels are numbered from zero.  A NoteOn with
+/// velocity zero is a NoteOff, and comes out as one
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    NoteOff {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    PolyPressure {
+        channel: u8,
+        note: u8,
+        pressure: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    ChannelPressure {
+        channel: u8,
+        pressure: u8,
+    },
+
+    /// From 0 to 16383, centred on 8192
+    PitchBend {
+        channel: u8,
+        value: u16,
+    },
+
+    /// A system real time message, such as Timing Clock (0xf8), Start
+    /// (0xfa) or Stop (0xfc)
+    RealTime(u8),
+
+    /// A system common message.  Nothing uses these
+    Common,
+
+    /// A system exclusive message, the bytes between 0xf0 and 0xf7
+    Exclusive(Vec<u8>),
+}
+
+impl Message {
+    /// The message as MIDI bytes, status first.  System common
+    /// messages are not kept, so have none
+    pub fn bytes(&self) -> Vec<u8> {
+        match *self {
+            Message::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => vec![0x80 | channel, note, velocity],
+            Message::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => vec![0x90 | channel, note, velocity],
+            Message::PolyPressure {
+                channel,
+                note,
+                pressure,
+            } => vec![0xa0 | channel, note, pressure],
+            Message::ControlChange {
+                channel,
+                controller,
+                value,
+            } => vec![0xb0 | channel, controller, value],
+            Message::ProgramChange { channel, program } => {
+                vec![0xc0 | channel, program]
+            },
+            Message::ChannelPressure { channel, pressure } => {
+                vec![0xd0 | channel, pressure]
+            },
+            Message::PitchBend { channel, value } => {
+                vec![0xe0 | channel, (value & 0x7f) as u8, (value >> 7) as u8]
+            },
+            Message::RealTime(byte) => vec![byte],
+            Message::Common => vec![],
+            Message::Exclusive(ref data) => {
+                let mut bytes = Vec::with_capacity(data.len() + 2);
+                bytes.push(0xf0);
+                bytes.extend_from_slice(data);
+                bytes.push(0xf7);
+                bytes
+            },
+        }
+    }
+}
+
+/// Turns MIDI bytes into messages.  A message can arrive in pieces,
+/// and with running status its status byte can be left out when it
+/// is the same as the last one's.  Real time messages can come in the
+/// middle of another message.  Stray data bytes, messages cut short
+/// by a new status byte, and overlong system exclusive messages are
+/// dropped
+#[derive(Debug, Default)]
+pub struct Parser {
+    /// The status of the message being read, which stays for the
+    /// next one if it is a channel message
+    status: Option<u8>,
+    data: [u8; 2],
+    count: usize,
+
+    /// The system exclusive message being read, if there is one
+    exclusive: Option<Vec<u8>>,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Call `f` with each message completed by `bytes`
+    pub fn parse(
+        &mut self,
+        bytes: &[u8],
+        mut f: impl FnMut(Message),
+    ) {
+        for byte in bytes {
+            if let Some(message) = self.byte(*byte) {
+                f(message);
+            }
+        }
+    }
+
+    fn byte(
+        &mut self,
+        byte: u8,
+    ) -> Option<Message> {
+        // Real time messages do not interrupt anything
+        if byte >= 0xf8 {
+            return Some(Message::RealTime(byte));
+        }
+        if byte >= 0x80 {
+            let exclusive = self.exclusive.take();
+            self.status = None;
+            self.count = 0;
+            match byte {
+                0xf0 => {
+                    self.exclusive = Some(vec![]);
+                    return None;
+                },
+                0xf7 => return exclusive.map(Message::Exclusive),
+                _ => (),
+            }
+            if data_length(byte) == 0 {
+                return Some(Message::Common);
+            }
+            self.status = Some(byte);
+            return None;
+        }
+        if let Some(exclusive) = &mut self.exclusive {
+            exclusive.push(byte);
+            if exclusive.len() > MAX_EXCLUSIVE {
+                // The rest of it is dropped as stray data
+                self.exclusive = None;
+            }
+            return None;
+        }
+        let status = self.status?;
+        self.data[self.count] = byte;
+        self.count += 1;
+        if self.count < data_length(status) {
+            return None;
+        }
+        self.count = 0;
+        if status >= 0xf0 {
+            // System common messages do not set running status
+            self.status = None;
+        }
+        Some(message(status, self.data))
+    }
+}
+
+/// How many data bytes follow a status byte
+pub fn data_length(status: u8) -> usize {
+    match status & 0xf0 {
+        0xc0 | 0xd0 => 1,
+        0xf0 => match status {
+            0xf1 | 0xf3 => 1,
+            0xf2 => 2,
+            _ => 0,
+        },
+        _ => 2,
+    }
+}
+
+fn message(
+    status: u8,
+    [first, second]: [u8; 2],
+) -> Message {
+    let channel = status & 0x0f;
+    match status & 0xf0 {
+        0x80 => Message::NoteOff {
+            channel,
+            note: first,
+            velocity: second,
+        },
+        0x90 if second == 0 => Message::NoteOff {
+            channel,
+            note: first,
+            velocity: 0,
+        },
+        0x90 => Message::NoteOn {
+            channel,
+            note: first,
+            velocity: second,
+        },
+        0xa0 => Message::PolyPressure {
+            channel,
+            note: first,
+            pressure: second,
+        },
+        0xb0 => Message::ControlChange {
+            channel,
+            controller: first,
+            value: second,
+        },
+        0xc0 => Message::ProgramChange {
+            channel,
+            program: first,
+        },
+        0xd0 => Message::ChannelPressure {
+            channel,
+            pressure: first,
+        },
+        0xe0 => Message::PitchBend {
+            channel,
+            value: (second as u16) << 7 | first as u16,
+        },
+        _ => Message::Common,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Enough random cases to find the odd corner
+    const CASES: usize = 10_000;
+
+    /// A xorshift generator, so failures can be reproduced
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(
+            &mut self,
+            n: u64,
+        ) -> u8 {
+            (self.next() % n) as u8
+        }
+    }
+
+    fn parse_all(
+        parser: &mut Parser,
+        bytes: &[u8],
+    ) -> Vec<Message> {
+        let mut messages = vec![];
+        parser.parse(bytes, |message| messages.push(message));
+        messages
+    }
+
+    /// A random channel message and its bytes, status first
+    fn channel_message(random: &mut Random) -> (Message, Vec<u8>) {
+        let channel = random.below(16);
+        let status = 0x80 + random.below(7) * 0x10;
+        let (first, second) = (random.below(128), random.below(128));
+        let second = if status == 0x90 {
+            second.max(1)
+        } else {
+            second
+        };
+        let bytes = match data_length(status) {
+            1 => vec![status | channel, first],
+            _ => vec![status | channel, first, second],
+        };
+        (message(status | channel, [first, second]), bytes)
+    }
+
+    #[test]
+    fn parses_messages() {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parse_all(&mut parser, &[0x91, 60, 100, 0x81, 60, 0, 0xc2, 5]),
+            [
+                Message::NoteOn {
+                    channel: 1,
+                    note: 60,
+                    velocity: 100
+                },
+                Message::NoteOff {
+                    channel: 1,
+                    note: 60,
+                    velocity: 0
+                },
+                Message::ProgramChange {
+                    channel: 2,
+                    program: 5
+                },
+            ]
+        );
+        assert_eq!(
+            parse_all(&mut parser, &[0xe0, 0x7f, 0x7f]),
+            [Message::PitchBend {
+                channel: 0,
+                value: 16383
+            }]
+        );
+    }
+
+    #[test]
+    fn running_status_and_zero_velocity() {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parse_all(&mut parser, &[0x90, 60, 100, 62, 90, 60, 0]),
+            [
+                Message::NoteOn {
+                    channel: 0,
+                    note: 60,
+                    velocity: 100
+                },
+                Message::NoteOn {
+                    channel: 0,
+                    note: 62,
+                    velocity: 90
+                },
+                Message::NoteOff {
+                    channel: 0,
+                    note: 60,
+                    velocity: 0
+                },
+            ]
+        );
+    }
+
+    /// Cheap controllers send the status once, then only data, and a
+    /// message can be split between callbacks
+    #[test]
+    fn running_status_across_calls() {
+        let mut parser = Parser::new();
+        assert_eq!(parse_all(&mut parser, &[0x90]), []);
+        assert_eq!(
+            parse_all(&mut parser, &[60, 100]),
+            [Message::NoteOn {
+                channel: 0,
+                note: 60,
+                velocity: 100
+            }]
+        );
+        assert_eq!(parse_all(&mut parser, &[60]), []);
+        assert_eq!(
+            parse_all(&mut parser, &[0]),
+            [Message::NoteOff {
+                channel: 0,
+                note: 60,
+                velocity: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn real_time_in_the_middle() {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parse_all(&mut parser, &[0x90, 60, 0xf8, 100]),
+            [
+                Message::RealTime(0xf8),
+                Message::NoteOn {
+                    channel: 0,
+                    note: 60,
+                    velocity: 100
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn system_exclusive_and_common() {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parse_all(&mut parser, &[0xf0, 1, 2, 0xf8, 3, 0xf7, 60, 100]),
+            [Message::RealTime(0xf8), Message::Exclusive(vec![1, 2, 3])]
+        );
+        let mut long = vec![0xf0];
+        long.extend([1; MAX_EXCLUSIVE + 1]);
+        long.extend([0xf7, 0xf0, 0x90, 60, 100]);
+        assert_eq!(
+            parse_all(&mut parser, &long),
+            [Message::NoteOn {
+                channel: 0,
+                note: 60,
+                velocity: 100
+            }]
+        );
+        assert_eq!(
+            parse_all(&mut parser, &[0x90, 60, 100, 0xf2, 1, 2, 62, 100]),
+            [
+                Message::NoteOn {
+                    channel: 0,
+                    note: 60,
+                    velocity: 100
+                },
+                Message::Common,
+            ]
+        );
+    }
+
+    /// Random messages, with the status left out when running status
+    /// allows and real time messages thrown in anywhere, come back
+    /// out as they went in
+    #[test]
+    fn random_messages_round_trip() {
+        let mut random = Random(0x2545f4914f6cdd1d);
+        for _ in 0..CASES {
+            let mut expected = vec![];
+            let mut bytes = vec![];
+            let mut running = None;
+            for _ in 0..random.below(8) {
+                let (message, mut encoded) = channel_message(&mut random);
+                let status = encoded[0];
+                if running == Some(status) && random.below(2) == 0 {
+                    encoded.remove(0);
+                }
+                running = Some(status);
+
+                // A real time message before the last byte comes out
+                // first
+                let real_time = random.below(4) == 0;
+                let at = random.below(encoded.len() as u64 + 1) as usize;
+                let before = real_time && at < encoded.len();
+                if real_time {
+                    encoded.insert(at, 0xf8);
+                }
+                if before {
+                    expected.push(Message::RealTime(0xf8));
+                }
+                expected.push(message);
+                if real_time && !before {
+                    expected.push(Message::RealTime(0xf8));
+                }
+                bytes.extend(encoded);
+            }
+            let mut parser = Parser::new();
+            assert_eq!(parse_all(&mut parser, &bytes), expected, "{bytes:?}");
+        }
+    }
+
+    /// However random bytes are split up, parsing them gives the same
+    /// messages, and nothing panics
+    #[test]
+    fn split_anywhere() {
+        let mut random = Random(0x9e3779b97f4a7c15);
+        for _ in 0..CASES {
+            let bytes: Vec<u8> =
+                (0..random.below(32)).map(|_| random.below(256)).collect();
+            let whole = parse_all(&mut Parser::new(), &bytes);
+            let mut parser = Parser::new();
+            let mut pieces = vec![];
+            let mut rest = bytes.as_slice();
+            while !rest.is_empty() {
+                let at = 1 + random.below(rest.len() as u64) as usize;
+                pieces.extend(parse_all(&mut parser, &rest[..at]));
+                rest = &rest[at..];
+            }
+            assert_eq!(whole, pieces, "{bytes:?}");
+        }
+    }
+
+    /// A message cut short by the next status byte is dropped, and the
+    /// message after it is parsed as usual
+    #[test]
+    fn truncated_messages_are_dropped() {
+        let mut random = Random(0xd1b54a32d192ed03);
+        for _ in 0..CASES {
+            let (_, mut truncated) = channel_message(&mut random);
+            truncated.pop();
+            let (message, bytes) = channel_message(&mut random);
+            truncated.extend(bytes);
+            assert_eq!(
+                parse_all(&mut Parser::new(), &truncated),
+                [message],
+                "{truncated:?}"
+            );
+        }
+    }
+
+    /// Messages turned back into bytes parse to the same messages
+    #[test]
+    fn bytes_round_trip() {
+        let mut random = Random(0x9e3779b97f4a7c15);
+        for _ in 0..CASES {
+            let (message, bytes) = channel_message(&mut random);
+            assert_eq!(message.bytes(), bytes, "{message:?}");
+        }
+        for message in [
+            Message::RealTime(0xfa),
+            Message::Exclusive(vec![0x7d, 1, 2]),
+        ] {
+            assert_eq!(
+                parse_all(&mut Parser::new(), &message.bytes()),
+                [message]
+            );
+        }
+    }
+}
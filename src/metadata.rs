@@ -0,0 +1,111 @@
+use crate::engine::Segment;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// What a sample editor stored in a WAV file about how it plays: the
+/// note it sounds at its own pitch, from the "inst" or "smpl" chunk,
+/// and the first loop in the "smpl" chunk.  The loop is in samples
+/// of the interleaved data, with its end one past the last one
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Metadata {
+    pub root: Option<u8>,
+    pub looped: Option<(usize, usize)>,
+}
+
+impl Metadata {
+    /// The segments for a sample `len` samples long that plays up to
+    /// the end of its loop, loops until NoteOff, and then plays the
+    /// rest.  None without a loop, or with one that does not fit
+    pub fn segments(
+        &self,
+        len: usize,
+    ) -> Option<Vec<Segment>> {
+        let (start, end) = self.looped?;
+        if start >= end || end > len {
+            return None;
+        }
+        let segment = |start, end, looping| Segment {
+            start,
+            end,
+            looping,
+        };
+        let mut segments = vec![segment(start, end, true)];
+        if start > 0 {
+            segments.insert(0, segment(0, start, false));
+        }
+        if end < len {
+            segments.push(segment(end, len, false));
+        }
+        Some(segments)
+    }
+}
+
+/// Read the metadata of a WAV file.  Anything else, or a file that
+/// cannot be read, has none
+pub fn read(path: &Path) -> Metadata {
+    File::open(path)
+        .and_then(|file| wav(&mut BufReader::new(file)))
+        .unwrap_or_default()
+}
+
+fn wav(file: &mut (impl Read + Seek)) -> std::io::Result<Metadata> {
+    let mut header = [0u8; 12];
+    file.read_exact(&mut header)?;
+    if &header[..4] != b"RIFF" || &header[8..] != b"WAVE" {
+        return Ok(Metadata::default());
+    }
+    let mut channels = 1;
+    let mut inst: Option<u8> = None;
+    let mut smpl: Option<(u8, Option<(u32, u32)>)> = None;
+    let mut chunk = [0u8; 8];
+    while file.read_exact(&mut chunk).is_ok() {
+        let size = u32::from_le_bytes(chunk[4..].try_into().unwrap()) as u64;
+        let body = match &chunk[..4] {
+            b"fmt " | b"inst" | b"smpl" => {
+                let mut body = vec![0u8; size as usize];
+                file.read_exact(&mut body)?;
+                body
+            },
+            _ => {
+                file.seek(SeekFrom::Current(size as i64))?;
+                vec![]
+            },
+        };
+        let u32_at = |at: usize| {
+            body.get(at..at + 4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        };
+        match &chunk[..4] {
+            b"fmt " if body.len() >= 4 => {
+                channels = u16::from_le_bytes([body[2], body[3]]).max(1);
+            },
+            b"inst" if !body.is_empty() => inst = Some(body[0]),
+
+            // The loops follow 36 bytes of header, 24 bytes each with
+            // the start and end at 8 and 12
+            b"smpl" => {
+                if let Some(note) = u32_at(12) {
+                    let looped = match u32_at(28) {
+                        Some(1..) => u32_at(44).zip(u32_at(48)),
+                        _ => None,
+                    };
+                    smpl = Some((note.min(127) as u8, looped));
+                }
+            },
+            _ => (),
+        }
+
+        // Chunks are padded to an even length
+        if size % 2 == 1 {
+            file.seek(SeekFrom::Current(1))?;
+        }
+    }
+    let channels = channels as usize;
+    Ok(Metadata {
+        root: inst.or(smpl.map(|(note, _)| note)).filter(|n| *n < 128),
+        looped: smpl.and_then(|(_, looped)| looped).map(|(start, end)| {
+            (start as usize * channels, (end as usize + 1) * channels)
+        }),
+    })
+}
@@ -0,0 +1,115 @@
+use crate::message::{Message, Parser};
+use midir::{MidiInput, MidiInputConnection};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How often the MIDI ports are checked for the device going away or
+/// coming back
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keep connected to a MIDI input.  `device` is (part of) the name of
+/// the port to use, or `None` for the first port.  The ports are
+/// polled so that if the controller is unplugged and plugged back in
+/// it is reconnected, and if there is no MIDI at all yet, no device
+/// or not even the system's MIDI service, the sampler runs without it
+/// until there is.  `handler` gets every MIDI message
+pub fn watch<F>(
+    device: Option<String>,
+    handler: F,
+) where
+    F: Fn(Message) + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    thread::spawn(move || {
+        let mut waiting_reported = false;
+        let watcher = loop {
+            match MidiInput::new("MidiSampleQzt watcher") {
+                Ok(watcher) => break watcher,
+                Err(err) => {
+                    if !waiting_reported {
+                        eprintln!("{err}: Waiting for MIDI");
+                        waiting_reported = true;
+                    }
+                    thread::sleep(POLL_INTERVAL);
+                },
+            }
+        };
+        let mut connection: Option<(String, MidiInputConnection<()>)> = None;
+        let mut connected_before = false;
+        loop {
+            let names: Vec<String> = watcher
+                .ports()
+                .iter()
+                .filter_map(|port| watcher.port_name(port).ok())
+                .collect();
+
+            if let Some((name, _)) = &connection {
+                if !names.contains(name) {
+                    eprintln!("MIDI input {name} disconnected");
+                    connection = None;
+                }
+            }
+
+            if connection.is_none() {
+                let wanted = names.iter().find(|name| match &device {
+                    Some(device) => name.contains(device.as_str()),
+                    None => true,
+                });
+                match wanted {
+                    Some(name) => match connect(name, handler.clone()) {
+                        Ok(conn) => {
+                            eprintln!(
+                                "{} MIDI input {name}",
+                                if connected_before {
+                                    "Reconnected to"
+                                } else {
+                                    "Connected to"
+                                }
+                            );
+                            connection = Some((name.clone(), conn));
+                            connected_before = true;
+                            waiting_reported = false;
+                        },
+                        Err(err) => eprintln!("{err}: MIDI input {name}"),
+                    },
+                    None => {
+                        if !waiting_reported {
+                            eprintln!(
+                                "Waiting for MIDI input {}",
+                                device.as_deref().unwrap_or("port")
+                            );
+                            waiting_reported = true;
+                        }
+                    },
+                }
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    });
+}
+
+/// Connect to the input port called `name`.  Each connection has its
+/// own parser, so running status carries on from one callback to the
+/// next but not from one device to the next
+fn connect<F>(
+    name: &str,
+    handler: Arc<F>,
+) -> Result<MidiInputConnection<()>, Box<dyn std::error::Error>>
+where
+    F: Fn(Message) + Send + Sync + 'static,
+{
+    let midi_input = MidiInput::new("MidiSampleQzt")?;
+    let ports = midi_input.ports();
+    let port = ports
+        .iter()
+        .find(|port| midi_input.port_name(port).ok().as_deref() == Some(name))
+        .ok_or("Port went away")?;
+    let mut parser = Parser::new();
+    Ok(midi_input.connect(
+        port,
+        "midi_input",
+        move |_stamp, bytes: &[u8], _| parser.parse(bytes, &*handler),
+        (),
+    )?)
+}
@@ -0,0 +1,75 @@
+use serde_json::{json, Map, Value};
+
+/// The version of the configuration schema.  Files without a
+/// "version" are version 0
+pub const CONFIG_VERSION: u64 = 1;
+
+type Migration = fn(&mut Map<String, Value>) -> Vec<String>;
+
+/// Each migration upgrades a configuration from the version before
+/// it, and says what it changed.  The first upgrades version 0
+const MIGRATIONS: [Migration; CONFIG_VERSION as usize] = [outputs];
+
+/// Upgrade a configuration file's JSON, and its profiles, to the
+/// current version, so files written for older versions keep working.
+/// Returns what was changed.  The file itself is left alone
+pub fn migrate(json: &mut Value) -> Result<Vec<String>, String> {
+    // Anything but an object is left for reading it to complain about
+    let Some(config) = json.as_object_mut() else {
+        return Ok(vec![]);
+    };
+    let version = match config.get("version") {
+        None => 0,
+        Some(version) => {
+            version.as_u64().ok_or(format!("Bad version: {version}"))?
+        },
+    };
+    if version > CONFIG_VERSION {
+        return Err(format!(
+            "Version {version} is newer than this programme's, {CONFIG_VERSION}"
+        ));
+    }
+    let mut changes = vec![];
+    for (from, migration) in
+        MIGRATIONS.iter().enumerate().skip(version as usize)
+    {
+        let mut changed = migration(config);
+        if let Some(profiles) =
+            config.get_mut("profiles").and_then(Value::as_object_mut)
+        {
+            for (name, profile) in profiles.iter_mut() {
+                if let Some(profile) = profile.as_object_mut() {
+                    changed.extend(
+                        migration(profile)
+                            .into_iter()
+                            .map(|change| format!("Profile {name}: {change}")),
+                    );
+                }
+            }
+        }
+        changes.extend(
+            changed.into_iter().map(|change| {
+                format!("Version {from} to {}: {change}", from + 1)
+            }),
+        );
+    }
+    config.insert("version".to_string(), CONFIG_VERSION.into());
+    Ok(changes)
+}
+
+/// Version 1: the ports the single output connected to,
+/// "connect_output", are given as an output in "outputs".  They were
+/// ignored if there were outputs
+fn outputs(config: &mut Map<String, Value>) -> Vec<String> {
+    let Some(connect) = config.remove("connect_output") else {
+        return vec![];
+    };
+    if config.contains_key("outputs") {
+        return vec!["connect_output dropped, as there are outputs".into()];
+    }
+    config.insert(
+        "outputs".to_string(),
+        json!([{ "name": "output", "connect": connect }]),
+    );
+    vec!["connect_output moved into outputs".into()]
+}
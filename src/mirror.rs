@@ -0,0 +1,188 @@
+use crate::message::{Message, Parser};
+use serde::Deserialize;
+use std::io;
+use std::net::{IpAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn default_timeout() -> u64 {
+    1000
+}
+
+/// Every packet starts with this, so stray packets are ignored
+const MAGIC: &[u8] = b"QZTM";
+
+/// How often the master says it is still there when it has nothing
+/// else to send
+const HEARTBEAT: Duration = Duration::from_millis(250);
+
+/// The biggest packet taken
+const MAX_PACKET: usize = 8192;
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Master,
+    Follower,
+}
+
+/// Two instances playing the same show, a main machine and a backup.
+/// The master sends every MIDI message it plays to the follower at
+/// `address` ("host:port"), over UDP.  The follower, listening on
+/// `address` (such as "0.0.0.0:5010"), plays them too and ignores its
+/// own MIDI, so the two play alike.  If the master is not heard from
+/// for `timeout` milliseconds the follower takes over and plays its
+/// own MIDI, until the master is heard from again.  The follower
+/// only takes packets from `master`, the master's host, which it must
+/// be given unless it only listens on the loopback address
+#[derive(Clone, Debug, Deserialize)]
+pub struct MirrorDescr {
+    pub role: Role,
+    pub address: String,
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    #[serde(default)]
+    pub master: Option<String>,
+}
+
+pub struct Mirror {
+    role: Role,
+    socket: UdpSocket,
+
+    /// For the follower, the addresses the master may send from.
+    /// Empty to take packets from anywhere, on the loopback address
+    masters: Vec<IpAddr>,
+
+    /// For the follower, whether it has taken over from the master
+    taken_over: AtomicBool,
+}
+
+impl Mirror {
+    /// Start mirroring.  A follower plays what the master sends with
+    /// `handler`
+    pub fn start<F>(
+        descr: &MirrorDescr,
+        handler: F,
+    ) -> io::Result<Arc<Self>>
+    where
+        F: Fn(Message) + Send + 'static,
+    {
+        let (socket, masters) = match descr.role {
+            Role::Master => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(&descr.address)?;
+                (socket, vec![])
+            },
+            Role::Follower => {
+                let socket = UdpSocket::bind(&descr.address)?;
+                let masters: Vec<IpAddr> = match &descr.master {
+                    Some(host) => (host.as_str(), 0)
+                        .to_socket_addrs()?
+                        .map(|address| address.ip())
+                        .collect(),
+                    None => vec![],
+                };
+                if masters.is_empty()
+                    && !socket.local_addr()?.ip().is_loopback()
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "A follower listening beyond the loopback address \
+                         needs its \"master\"",
+                    ));
+                }
+                (socket, masters)
+            },
+        };
+        let mirror = Arc::new(Self {
+            role: descr.role,
+            socket,
+            masters,
+            taken_over: AtomicBool::new(false),
+        });
+        match descr.role {
+            Role::Master => {
+                let master = mirror.clone();
+                thread::spawn(move || loop {
+                    let _ = master.socket.send(MAGIC);
+                    thread::sleep(HEARTBEAT);
+                });
+                eprintln!("Mirroring to {}", descr.address);
+            },
+            Role::Follower => {
+                let timeout = Duration::from_millis(descr.timeout);
+                let heard = Arc::new(Mutex::new(Instant::now()));
+                let follower = mirror.clone();
+                let received = heard.clone();
+                thread::spawn(move || follower.follow(&received, handler));
+                let follower = mirror.clone();
+                thread::spawn(move || loop {
+                    thread::sleep(HEARTBEAT / 2);
+                    let silent = heard.lock().unwrap().elapsed() > timeout;
+                    if silent
+                        && !follower.taken_over.swap(true, Ordering::Relaxed)
+                    {
+                        eprintln!("Mirror: Lost the master, taking over");
+                    }
+                });
+                eprintln!("Following the master on {}", descr.address);
+            },
+        }
+        Ok(mirror)
+    }
+
+    /// Receive the master's messages, noting when each packet came.
+    /// Packets from anywhere else are ignored
+    fn follow(
+        &self,
+        heard: &Mutex<Instant>,
+        handler: impl Fn(Message),
+    ) {
+        let mut parser = Parser::new();
+        let mut packet = [0u8; MAX_PACKET];
+        loop {
+            let size = match self.socket.recv_from(&mut packet) {
+                Ok((_, from))
+                    if !self.masters.is_empty()
+                        && !self.masters.contains(&from.ip()) =>
+                {
+                    continue
+                },
+                Ok((size, _)) => size,
+                Err(err) => {
+                    eprintln!("{err}: Mirror");
+                    thread::sleep(HEARTBEAT);
+                    continue;
+                },
+            };
+            let Some(bytes) = packet[..size].strip_prefix(MAGIC) else {
+                continue;
+            };
+            *heard.lock().unwrap() = Instant::now();
+            if self.taken_over.swap(false, Ordering::Relaxed) {
+                eprintln!("Mirror: The master is back, following it");
+            }
+            parser.parse(bytes, &handler);
+        }
+    }
+
+    /// Whether a message from this instance's own MIDI should play.
+    /// The master sends it on to the follower
+    pub fn local(
+        &self,
+        message: &Message,
+    ) -> bool {
+        match self.role {
+            Role::Master => {
+                let bytes = message.bytes();
+                if !bytes.is_empty() {
+                    let _ = self.socket.send(&[MAGIC, &bytes].concat());
+                }
+                true
+            },
+            Role::Follower => self.taken_over.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use crate::engine::Command;
+use crate::message::Message;
+use serde::Deserialize;
+use std::sync::mpsc::SyncSender;
+use std::sync::Mutex;
+
+fn default_bend_range() -> f32 {
+    48.0
+}
+
+/// MPE, where each note is played on a channel of its own so it can
+/// be bent and pressed on its own.  `bend_range` is the pitch bend
+/// range of the member channels in semitones
+#[derive(Clone, Debug, Deserialize)]
+pub struct MpeDescr {
+    #[serde(default = "default_bend_range")]
+    pub bend_range: f32,
+}
+
+/// The expression on a member channel, and the note it is playing
+#[derive(Clone, Copy)]
+struct Channel {
+    note: Option<u8>,
+    bend: f32,
+    pressure: f32,
+}
+
+/// Tracks which note each MPE member channel is playing, and turns
+/// the channel's pitch bend and pressure into expression for that
+/// note.  Bend changes the playback rate.  Pressure makes the note
+/// louder, up to twice as loud.  The master channel, channel 1, is
+/// left alone
+pub struct Mpe {
+    bend_range: f32,
+    commands: SyncSender<Command>,
+    channels: Mutex<[Channel; 16]>,
+}
+
+impl Mpe {
+    pub fn new(
+        descr: MpeDescr,
+        commands: SyncSender<Command>,
+    ) -> Self {
+        Self {
+            bend_range: descr.bend_range,
+            commands,
+            channels: Mutex::new(
+                [Channel {
+                    note: None,
+                    bend: 0.0,
+                    pressure: 0.0,
+                }; 16],
+            ),
+        }
+    }
+
+    pub fn message(
+        &self,
+        message: &Message,
+    ) {
+        let index = match *message {
+            Message::NoteOn { channel, .. }
+            | Message::NoteOff { channel, .. }
+            | Message::PitchBend { channel, .. }
+            | Message::ChannelPressure { channel, .. } => channel as usize,
+            _ => return,
+        };
+        if index == 0 {
+            return;
+        }
+        let mut channels = self.channels.lock().unwrap();
+        let channel = &mut channels[index];
+        match *message {
+            Message::NoteOn { note, .. } => channel.note = Some(note),
+            Message::NoteOff { note, .. } => {
+                if channel.note == Some(note) {
+                    channel.note = None;
+                }
+                return;
+            },
+            Message::PitchBend { value, .. } => {
+                let bend = value as i32 - 8192;
+                channel.bend = bend as f32 / 8192.0 * self.bend_range;
+            },
+            Message::ChannelPressure { pressure, .. } => {
+                channel.pressure = pressure as f32 / 127.0
+            },
+            _ => return,
+        }
+        if let Some(note) = channel.note {
+            let command = Command::Expression {
+                note,
+                rate: 2_f32.powf(channel.bend / 12.0),
+                gain: 1.0 + channel.pressure,
+            };
+            if let Err(err) = self.commands.try_send(command) {
+                eprintln!("{err}: Dropped MPE expression");
+            }
+        }
+    }
+}
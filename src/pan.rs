@@ -0,0 +1,67 @@
+use std::f32::consts::{FRAC_PI_2, PI, TAU};
+
+/// Pans sounds round a ring of speakers by vector base amplitude
+/// panning.  A sound between two neighbouring speakers plays on those
+/// two only, in the proportions that put it at its angle, at constant
+/// power.  Angles are in degrees clockwise from straight ahead, so a
+/// quad is -45, 45, 135 and -135
+#[derive(Debug)]
+pub struct Panner {
+    /// Each speaker's angle, in radians from 0 to 2π, and its
+    /// channel.  In order of angle
+    speakers: Vec<(f32, usize)>,
+}
+
+impl Panner {
+    /// `speakers` are the angles of the speakers on each channel
+    pub fn new(speakers: impl IntoIterator<Item = (f32, usize)>) -> Self {
+        let mut speakers: Vec<(f32, usize)> = speakers
+            .into_iter()
+            .map(|(degrees, channel)| {
+                (degrees.to_radians().rem_euclid(TAU), channel)
+            })
+            .collect();
+        speakers.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Self { speakers }
+    }
+
+    /// Call `f` with the channels and gains that put a sound at
+    /// `degrees`.  Does not allocate, it is called in the Jack thread
+    pub fn pan(
+        &self,
+        degrees: f32,
+        mut f: impl FnMut(usize, f32),
+    ) {
+        let angle = degrees.to_radians().rem_euclid(TAU);
+        let count = self.speakers.len();
+        if count == 1 {
+            f(self.speakers[0].1, 1.0);
+            return;
+        }
+        for (i, (from, from_channel)) in self.speakers.iter().enumerate() {
+            let (to, to_channel) = self.speakers[(i + 1) % count];
+            let span = (to - from).rem_euclid(TAU);
+            let offset = (angle - from).rem_euclid(TAU);
+            if offset > span {
+                continue;
+            }
+
+            // Speakers half way round or further apart, such as the
+            // back of a stereo pair, cannot be panned between by
+            // vectors.  Cross fade between them instead
+            let (from_gain, to_gain) = if span == 0.0 {
+                (1.0, 0.0)
+            } else if span < PI - 1e-3 {
+                let (a, b) = ((span - offset).sin(), offset.sin());
+                let norm = (a * a + b * b).sqrt();
+                (a / norm, b / norm)
+            } else {
+                let x = offset / span * FRAC_PI_2;
+                (x.cos(), x.sin())
+            };
+            f(*from_channel, from_gain);
+            f(to_channel, to_gain);
+            return;
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use crate::engine::SampleData;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// Peaks are worked out once for blocks of this many frames, and
+/// coarser ones are made from them
+const BLOCK: usize = 256;
+
+/// The most blocks asked for at once
+pub const MAX_BLOCKS: usize = 4096;
+
+/// The lowest and highest value in each block of a sample
+type Blocks = Arc<Vec<(f32, f32)>>;
+
+/// The lowest and highest value in each block of each sample, for
+/// clients to draw waveforms with.  Worked out the first time a
+/// sample is asked for, and again if it has been replaced by a new
+/// recording
+#[derive(Default)]
+pub struct Peaks {
+    cache: Mutex<BTreeMap<usize, (Arc<SampleData>, Blocks)>>,
+}
+
+impl Peaks {
+    /// The peaks of sample number `index`, which is `sample`, in
+    /// `blocks` blocks of equal length.  A sample shorter than that
+    /// has fewer
+    pub fn get(
+        &self,
+        index: usize,
+        sample: &Arc<SampleData>,
+        blocks: usize,
+    ) -> Vec<(f32, f32)> {
+        let fine = {
+            let mut cache = self.cache.lock().unwrap();
+            match cache.get(&index) {
+                Some((cached, fine)) if Arc::ptr_eq(cached, sample) => {
+                    fine.clone()
+                },
+                _ => {
+                    let fine = Arc::new(
+                        sample.data.chunks(BLOCK).map(peak).collect::<Vec<_>>(),
+                    );
+                    cache.insert(index, (sample.clone(), fine.clone()));
+                    fine
+                },
+            }
+        };
+        if blocks == 0 {
+            return vec![];
+        }
+
+        // Each block takes the fine blocks that start in it, or the
+        // frames themselves if the blocks are finer than that
+        let frames = sample.data.len();
+        if frames / blocks < BLOCK {
+            let per = frames.div_ceil(blocks).max(1);
+            return sample.data.chunks(per).map(peak).collect();
+        }
+        (0..blocks)
+            .map(|block| {
+                let start = block * fine.len() / blocks;
+                let end = ((block + 1) * fine.len() / blocks).max(start + 1);
+                fine[start..end.min(fine.len())]
+                    .iter()
+                    .fold((f32::MAX, f32::MIN), |(low, high), (min, max)| {
+                        (low.min(*min), high.max(*max))
+                    })
+            })
+            .collect()
+    }
+}
+
+fn peak(frames: &[f32]) -> (f32, f32) {
+    frames.iter().fold((f32::MAX, f32::MIN), |(low, high), x| {
+        (low.min(*x), high.max(*x))
+    })
+}
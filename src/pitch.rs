@@ -0,0 +1,65 @@
+use crate::smooth::{Curve, Ramp};
+use serde::{Deserialize, Serialize};
+
+/// When the envelope is this close to the sample's own pitch, in
+/// octaves, it has finished
+const SETTLED: f32 = 1e-4;
+
+/// A pitch envelope, the classic way to fatten kicks and toms: the
+/// sample starts `semitones` above (or, if negative, below) its pitch
+/// and falls back to it, most of the way in `decay` milliseconds.
+/// With a `curve` it falls along that instead, all the way in `decay`
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct PitchEnvelopeDescr {
+    pub semitones: f32,
+    pub decay: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub curve: Option<Curve>,
+}
+
+/// A pitch envelope for a voice, as it plays
+#[derive(Clone, Copy, Debug)]
+pub struct PitchEnvelope {
+    /// How far from the sample's pitch the voice is, in octaves, and
+    /// what that is multiplied by each frame
+    octaves: f32,
+    fall: f32,
+
+    /// How much of the way from `octaves` the voice has still to go,
+    /// if it falls along a curve
+    ramp: Option<Ramp>,
+}
+
+impl PitchEnvelope {
+    pub fn new(
+        descr: &PitchEnvelopeDescr,
+        sample_rate: usize,
+    ) -> Self {
+        let frames = descr.decay.max(0.0) * sample_rate as f32 / 1000.0;
+        let octaves = descr.semitones / 12.0;
+        let ramp = descr.curve.map(|curve| {
+            let mut ramp = Ramp::new(1.0);
+            ramp.set_curve(curve);
+            ramp.set(0.0, frames as usize);
+            ramp
+        });
+        Self {
+            octaves,
+            fall: (-1.0 / frames.max(1.0)).exp(),
+            ramp,
+        }
+    }
+
+    /// The playback rate for this frame.  Moves on by one frame
+    pub fn tick(&mut self) -> f32 {
+        if let Some(ramp) = &mut self.ramp {
+            return (self.octaves * ramp.tick()).exp2();
+        }
+        if self.octaves.abs() < SETTLED {
+            return 1.0;
+        }
+        let rate = self.octaves.exp2();
+        self.octaves *= self.fall;
+        rate
+    }
+}
@@ -0,0 +1,450 @@
+use crate::args::Audition;
+use crate::audio::SampleRate;
+use crate::bank::Banks;
+use crate::chance::{Chance, Dice};
+use crate::clock::PPQN;
+use crate::decode::Info;
+use crate::engine::{Command, SampleData, Segment, LIVE_SLOTS};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::SyncSender;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+
+/// The count in click: a short, falling beep
+const CLICK_FREQUENCY: f32 = 1500.0;
+const CLICK_SECONDS: f32 = 0.03;
+const CLICK_GAIN: f32 = 0.5;
+
+/// The note of samples the player plays itself, the click and the
+/// error beep.  It is no MIDI note, so no note plays them
+pub const NO_NOTE: u8 = 128;
+
+/// A sample waiting for its count in to finish
+struct CountIn {
+    sample: usize,
+    gain: f32,
+    velocity: u8,
+
+    /// Clicks still to play before the sample starts
+    clicks: u32,
+}
+
+/// Turns notes into commands for the Jack thread.  Used by the MIDI
+/// thread and anything else that plays notes
+pub struct Player {
+    /// The same samples as the Jack thread's, in the same order
+    samples: RwLock<Vec<Arc<SampleData>>>,
+
+    /// The most samples there can be, with those added while running.
+    /// The Jack thread refuses any more
+    max_samples: usize,
+    banks: Arc<Banks>,
+    commands: SyncSender<Command>,
+
+    /// The rate the voices' effects are made for
+    sample_rate: Arc<SampleRate>,
+
+    /// In audition mode, the audition and the index of the first of
+    /// its two samples, and a count of hits to decide whose turn it is
+    audition: Option<(Audition, usize)>,
+    auditioned: AtomicUsize,
+
+    /// Samples recorded live take over their note.  The index of the
+    /// recording for each note that has one
+    live: Mutex<BTreeMap<u8, usize>>,
+
+    /// The click's sample, once it has been added, and the samples
+    /// being counted in
+    click: OnceLock<usize>,
+    counting: Mutex<Vec<CountIn>>,
+
+    /// Hits on each sample left to chance, and the dice rolled for
+    /// them
+    hits: Mutex<BTreeMap<usize, u32>>,
+    dice: Mutex<Dice>,
+
+    /// Whether a note with no sample on it beeps
+    beeps_unmapped: AtomicBool,
+}
+
+impl Player {
+    pub fn new(
+        samples: Vec<Arc<SampleData>>,
+        banks: Arc<Banks>,
+        commands: SyncSender<Command>,
+        sample_rate: Arc<SampleRate>,
+        audition: Option<(Audition, usize)>,
+    ) -> Self {
+        Self {
+            max_samples: samples.len() + LIVE_SLOTS,
+            samples: RwLock::new(samples),
+            banks,
+            commands,
+            sample_rate,
+            audition,
+            auditioned: AtomicUsize::new(0),
+            live: Mutex::new(BTreeMap::new()),
+            click: OnceLock::new(),
+            counting: Mutex::new(vec![]),
+            hits: Mutex::new(BTreeMap::new()),
+            dice: Mutex::new(Dice::new()),
+            beeps_unmapped: AtomicBool::new(false),
+        }
+    }
+
+    /// Play the samples on `note`.  Every sample layered on the note
+    /// plays, if it is not in a bank or it is in the active bank.
+    /// When auditioning the candidates take turns instead, and a
+    /// recording plays alone.  A sample left to chance may not play.
+    /// A sample with a count in waits for it, and playing its note
+    /// again during the count in calls it off.  A note with no sample
+    /// on it in any bank fails, and beeps if the error beep is set to
+    /// beep for those
+    pub fn note_on(
+        &self,
+        note: u8,
+        velocity: u8,
+    ) {
+        // Get the volume as a f32 fraction
+        let volume: f32 = velocity as f32 / 127.0;
+
+        let active = self.banks.active();
+        let live = self.live.lock().unwrap().get(&note).copied();
+        let triggered: Vec<usize> = match &self.audition {
+            _ if live.is_some() => live.into_iter().collect(),
+            Some((audition, first)) if audition.note == note => {
+                let turn = self.auditioned.fetch_add(1, Ordering::Relaxed) % 2;
+                eprintln!(
+                    "Audition {}: {}",
+                    ["A", "B"][turn],
+                    audition.paths[turn]
+                );
+                vec![first + turn]
+            },
+            _ => self
+                .samples
+                .read()
+                .unwrap()
+                .iter()
+                .enumerate()
+                .filter(|(_, s)| {
+                    s.note == note
+                        && s.deck.is_none()
+                        && s.release.is_none()
+                        && (s.bank.is_none() || s.bank == active)
+                })
+                .map(|(idx, _)| idx)
+                .collect(),
+        };
+        let samples = self.samples.read().unwrap();
+
+        // A note with only release samples, or samples in other
+        // banks, is not missing one
+        if triggered.is_empty()
+            && !samples.iter().any(|s| s.note == note && s.deck.is_none())
+        {
+            if self.beeps_unmapped.load(Ordering::Relaxed) {
+                self.send(Command::Unmapped);
+            }
+            return;
+        }
+        let mut counting = self.counting.lock().unwrap();
+        for idx in triggered {
+            if !self.plays(idx, &samples[idx].chance) {
+                continue;
+            }
+            let clicks = samples[idx].count_in;
+            if clicks == 0 || self.click.get().is_none() {
+                self.trigger(&samples[idx], idx, volume, velocity);
+            } else if let Some(at) =
+                counting.iter().position(|c| c.sample == idx)
+            {
+                counting.remove(at);
+                eprintln!("{}: Count in called off", samples[idx].label());
+            } else {
+                counting.push(CountIn {
+                    sample: idx,
+                    gain: volume,
+                    velocity,
+                    clicks,
+                });
+            }
+        }
+    }
+
+    /// Whether sample number `idx` plays on this hit.  Every hit is
+    /// counted, played or not, so conditions keep to the hits
+    fn plays(
+        &self,
+        idx: usize,
+        chance: &Chance,
+    ) -> bool {
+        if chance.is_certain() {
+            return true;
+        }
+        let mut hits = self.hits.lock().unwrap();
+        let hit = hits.entry(idx).or_insert(0);
+        let plays = chance.plays(*hit, self.dice.lock().unwrap().roll());
+        *hit = hit.wrapping_add(1);
+        plays
+    }
+
+    /// Add the count in click, for samples that have a count in, on
+    /// `channels`, the cue output's if there is one.  Until it is added
+    /// they start straight away
+    pub fn add_click(
+        &self,
+        sample_rate: usize,
+        channels: Vec<usize>,
+    ) {
+        let length = (CLICK_SECONDS * sample_rate as f32) as usize;
+        let data = (0..length)
+            .map(|frame| {
+                let time = frame as f32 / sample_rate as f32;
+                let fall = 1.0 - frame as f32 / length as f32;
+                CLICK_GAIN
+                    * fall
+                    * fall
+                    * (std::f32::consts::TAU * CLICK_FREQUENCY * time).sin()
+            })
+            .collect();
+        let data = Arc::new(SampleData {
+            path: "count-in-click".to_string(),
+            data,
+            source: Info {
+                sample_rate: Some(sample_rate as u32),
+                channels: Some(1),
+                frames: Some(length as u64),
+            },
+            note: NO_NOTE,
+            channels,
+            ..Default::default()
+        });
+        let mut samples = self.samples.write().unwrap();
+        if samples.len() < self.max_samples
+            && self.click.set(samples.len()).is_ok()
+        {
+            samples.push(data.clone());
+            let _ = self.commands.send(Command::AddSample(data));
+        }
+    }
+
+    /// Add the sample the Jack thread plays when a note cannot play,
+    /// and when one with no sample on it is played if `unmapped`
+    pub fn add_error_beep(
+        &self,
+        data: SampleData,
+        unmapped: bool,
+    ) {
+        let data = Arc::new(data);
+        let mut samples = self.samples.write().unwrap();
+        if samples.len() == self.max_samples {
+            eprintln!("No room for the error beep");
+            return;
+        }
+        samples.push(data.clone());
+        self.beeps_unmapped.store(unmapped, Ordering::Relaxed);
+        let _ = self.commands.send(Command::ErrorBeep(data));
+    }
+
+    /// Count in on each beat of the clock: a click for each beat of
+    /// the count in, and then the sample on the beat after.  Samples
+    /// counting in together share one click
+    pub fn pulse(
+        &self,
+        pulse: u64,
+    ) {
+        let Some(&click) = self.click.get() else {
+            return;
+        };
+        if !pulse.is_multiple_of(PPQN) {
+            return;
+        }
+        let mut clicked = false;
+        let samples = self.samples.read().unwrap();
+        self.counting.lock().unwrap().retain_mut(|count_in| {
+            if count_in.clicks > 0 {
+                count_in.clicks -= 1;
+                if !clicked {
+                    self.trigger(&samples[click], click, 1.0, 127);
+                    clicked = true;
+                }
+                true
+            } else {
+                self.trigger(
+                    &samples[count_in.sample],
+                    count_in.sample,
+                    count_in.gain,
+                    count_in.velocity,
+                );
+                false
+            }
+        });
+    }
+
+    /// Put a recording on `note`, replacing any earlier recording
+    /// there.  It takes over from the configured samples on the note
+    pub fn record(
+        &self,
+        note: u8,
+        data: Vec<f32>,
+    ) {
+        let source = Info {
+            sample_rate: Some(self.sample_rate.current() as u32),
+            channels: Some(1),
+            frames: Some(data.len() as u64),
+        };
+        let data = Arc::new(SampleData {
+            path: format!("recording-{note}"),
+            data,
+            source,
+            note,
+            ..Default::default()
+        });
+
+        // Holding the lock while sending keeps the Jack thread's
+        // samples in step.  These commands wait for room in the queue
+        // rather than being dropped, for the same reason
+        let mut samples = self.samples.write().unwrap();
+        let mut live = self.live.lock().unwrap();
+        match live.get(&note) {
+            Some(&sample) => {
+                samples[sample] = data.clone();
+                let _ =
+                    self.commands.send(Command::ReplaceSample { sample, data });
+            },
+            None if samples.len() == self.max_samples => {
+                eprintln!("Note {note}: No room for another recording");
+            },
+            None => {
+                live.insert(note, samples.len());
+                samples.push(data.clone());
+                let _ = self.commands.send(Command::AddSample(data));
+            },
+        }
+    }
+
+    /// Give sample number `sample` new data and segments, keeping the
+    /// rest of it.  Voices playing it finish the old data.  Returns
+    /// the sample that was replaced
+    pub fn replace_data(
+        &self,
+        sample: usize,
+        data: Vec<f32>,
+        segments: Vec<Segment>,
+    ) -> Arc<SampleData> {
+        let data =
+            self.samples.read().unwrap()[sample].with_data(data, segments);
+        self.replace(sample, data)
+    }
+
+    /// Put `data` in place of sample number `sample`.  Voices playing
+    /// it play the old data to its end, without a break, and triggers
+    /// from now on play the new.  Returns the sample that was
+    /// replaced, as `replace_data` does
+    pub fn replace(
+        &self,
+        sample: usize,
+        data: SampleData,
+    ) -> Arc<SampleData> {
+        let mut samples = self.samples.write().unwrap();
+        let data = Arc::new(data);
+        let replaced = std::mem::replace(&mut samples[sample], data.clone());
+        let _ = self.commands.send(Command::ReplaceSample { sample, data });
+        replaced
+    }
+
+    /// The sample `note` plays: a recording made on it, or else the
+    /// first sample on it in the active bank
+    pub fn sample_on(
+        &self,
+        note: u8,
+    ) -> Option<usize> {
+        if let Some(&live) = self.live.lock().unwrap().get(&note) {
+            return Some(live);
+        }
+        let active = self.banks.active();
+        self.samples.read().unwrap().iter().position(|s| {
+            s.note == note
+                && s.deck.is_none()
+                && s.release.is_none()
+                && (s.bank.is_none() || s.bank == active)
+        })
+    }
+
+    /// The samples, as the Jack thread has them
+    pub fn samples(&self) -> Vec<Arc<SampleData>> {
+        self.samples.read().unwrap().clone()
+    }
+
+    /// Release `note`, and play its release samples as loud as
+    /// `velocity` says.  Zero is a NoteOff with no velocity
+    pub fn note_off(
+        &self,
+        note: u8,
+        velocity: u8,
+    ) {
+        self.send(Command::Release { note });
+        let active = self.banks.active();
+        let samples = self.samples.read().unwrap();
+        for (idx, sample) in samples.iter().enumerate() {
+            let Some(default) = sample.release else {
+                continue;
+            };
+            if sample.note != note
+                || !(sample.bank.is_none() || sample.bank == active)
+            {
+                continue;
+            }
+            let velocity = if velocity == 0 { default } else { velocity };
+            self.trigger(sample, idx, velocity as f32 / 127.0, velocity);
+        }
+    }
+
+    /// Play sample number `sample` from the top, at full gain, whatever
+    /// note it is on
+    pub fn play(
+        &self,
+        sample: usize,
+    ) {
+        let samples = self.samples.read().unwrap();
+        if let Some(data) = samples.get(sample) {
+            self.trigger(data, sample, 1.0, 127);
+        }
+    }
+
+    /// Stop the voices playing sample number `sample`
+    pub fn stop(
+        &self,
+        sample: usize,
+    ) {
+        self.send(Command::Stop { sample });
+    }
+
+    /// Start sample number `sample`, which is `data`.  The effects
+    /// for its voices are made here, so the Jack thread does not
+    /// allocate them
+    fn trigger(
+        &self,
+        data: &SampleData,
+        sample: usize,
+        gain: f32,
+        velocity: u8,
+    ) {
+        self.send(Command::Trigger {
+            sample,
+            gain,
+            velocity,
+            effects: data.voice_effects(self.sample_rate.current()),
+        });
+    }
+
+    fn send(
+        &self,
+        command: Command,
+    ) {
+        if let Err(err) = self.commands.try_send(command) {
+            eprintln!("{err}: Dropped command");
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use serde::Deserialize;
+use std::cell::Cell;
+use std::io;
+
+thread_local! {
+    /// Whether the settings have been applied to this thread
+    static APPLIED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Scheduling for the threads that keep time and take MIDI, so they
+/// are not held up on a busy system.  `priority` is a SCHED_FIFO
+/// priority, 1 to 99, and `cpus` the CPUs the threads may run on.
+/// The Jack thread is left to Jack
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RealtimeDescr {
+    #[serde(default)]
+    pub priority: Option<i32>,
+    #[serde(default)]
+    pub cpus: Vec<usize>,
+}
+
+impl RealtimeDescr {
+    /// Apply the settings to the calling thread, once.  Problems are
+    /// reported and the thread carries on as it was
+    pub fn apply(
+        &self,
+        thread: &str,
+    ) {
+        if APPLIED.replace(true) {
+            return;
+        }
+        if let Some(priority) = self.priority {
+            let param = libc::sched_param {
+                sched_priority: priority,
+            };
+            // SAFETY: `param` is a valid sched_param for the call to
+            // read
+            let err = unsafe {
+                libc::pthread_setschedparam(
+                    libc::pthread_self(),
+                    libc::SCHED_FIFO,
+                    &param,
+                )
+            };
+            if err != 0 {
+                eprintln!(
+                    "{}: {thread} thread cannot have realtime priority \
+                     {priority}.  Allow the user realtime priority, such \
+                     as with rtprio in /etc/security/limits.conf",
+                    io::Error::from_raw_os_error(err)
+                );
+            }
+        }
+        if !self.cpus.is_empty() {
+            // SAFETY: An all zero cpu_set_t is an empty set
+            let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+            for cpu in self.cpus.iter() {
+                if *cpu < libc::CPU_SETSIZE as usize {
+                    // SAFETY: The CPU is within the set
+                    unsafe { libc::CPU_SET(*cpu, &mut set) };
+                }
+            }
+            // SAFETY: `set` is a valid cpu_set_t of the size given.
+            // Zero is the calling thread
+            let result = unsafe {
+                libc::sched_setaffinity(
+                    0,
+                    std::mem::size_of::<libc::cpu_set_t>(),
+                    &set,
+                )
+            };
+            if result != 0 {
+                eprintln!(
+                    "{}: {thread} thread cannot be pinned to CPUs {:?}",
+                    io::Error::last_os_error(),
+                    self.cpus
+                );
+            }
+        }
+    }
+}
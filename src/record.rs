@@ -0,0 +1,143 @@
+use crate::audio::SampleRate;
+use crate::engine::Command;
+use crate::player::Player;
+use crate::resample::Quality;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The longest recording, in seconds.  The buffer is allocated
+/// before recording starts so the Jack thread does not have to
+const MAX_RECORDING: usize = 60;
+
+/// How long to wait for the Jack thread to hand a recording back
+const STOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Records the Jack input into a new sample on a note.  Started and
+/// stopped from the control socket, or by holding down a controller
+pub struct Recorder {
+    player: Arc<Player>,
+    commands: SyncSender<Command>,
+    sample_rate: Arc<SampleRate>,
+
+    /// The controller that records while it is held down (values 64
+    /// and up), into the last note played
+    cc: Option<u8>,
+    last_note: AtomicU8,
+
+    /// The note being recorded and where the recording comes back
+    recording: Mutex<Option<(u8, Receiver<Vec<f32>>)>>,
+}
+
+impl Recorder {
+    pub fn new(
+        player: Arc<Player>,
+        commands: SyncSender<Command>,
+        sample_rate: Arc<SampleRate>,
+        cc: Option<u8>,
+    ) -> Self {
+        Self {
+            player,
+            commands,
+            sample_rate,
+            cc,
+            last_note: AtomicU8::new(60),
+            recording: Mutex::new(None),
+        }
+    }
+
+    /// Start recording a sample for `note`
+    pub fn start(
+        &self,
+        note: u8,
+    ) -> Result<(), String> {
+        let mut recording = self.recording.lock().unwrap();
+        if let Some((note, _)) = *recording {
+            return Err(format!("Already recording note {note}"));
+        }
+        let (done, receiver) = sync_channel(1);
+        self.commands
+            .try_send(Command::Record {
+                buffer: Vec::with_capacity(
+                    MAX_RECORDING * self.sample_rate.current(),
+                ),
+                done,
+            })
+            .map_err(|err| err.to_string())?;
+        *recording = Some((note, receiver));
+        eprintln!("Recording note {note}");
+        Ok(())
+    }
+
+    /// Stop recording and put the recording on its note.  Returns the
+    /// note and the length in seconds
+    pub fn stop(&self) -> Result<(u8, f64), String> {
+        let (note, receiver) = self
+            .recording
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or("Not recording")?;
+        let _ = self.commands.try_send(Command::StopRecording);
+        let data = receiver
+            .recv_timeout(STOP_TIMEOUT)
+            .map_err(|err| format!("{err}: Recording lost"))?;
+        let seconds = data.len() as f64 / self.sample_rate.current() as f64;
+        if data.is_empty() {
+            return Err("Nothing recorded".to_string());
+        }
+
+        // Recorded at Jack's rate, and played as if at the samples'
+        let ratio = self.sample_rate.current() as f64
+            / self.sample_rate.samples() as f64;
+        let data = if ratio == 1.0 {
+            data
+        } else {
+            let length = (data.len() as f64 / ratio) as usize;
+            (0..length)
+                .map(|frame| {
+                    let position = frame as f64 * ratio;
+                    Quality::Cubic.interpolate(
+                        &data,
+                        position as usize,
+                        position.fract() as f32,
+                    )
+                })
+                .collect()
+        };
+        self.player.record(note, data);
+        eprintln!("Recorded {seconds:.3}s on note {note}");
+        Ok((note, seconds))
+    }
+
+    /// Remember the last note played, for the controller to record
+    /// into
+    pub fn note(
+        &self,
+        note: u8,
+    ) {
+        self.last_note.store(note, Ordering::Relaxed);
+    }
+
+    pub fn control_change(
+        &self,
+        controller: u8,
+        value: u8,
+    ) {
+        if Some(controller) != self.cc {
+            return;
+        }
+        let recording = self.recording.lock().unwrap().is_some();
+        let result = if value >= 64 && !recording {
+            self.start(self.last_note.load(Ordering::Relaxed))
+        } else if value < 64 && recording {
+            self.stop().map(|_| ())
+        } else {
+            Ok(())
+        };
+        if let Err(err) = result {
+            eprintln!("{err}: Recording");
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use crate::clock::{default_rate, Rate};
+use crate::player::Player;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Note repeat, like an MPC's: while it is on, holding a note
+/// retriggers it in time with the clock.  `cc` turns it on (values 64
+/// and up) and off.  `rate_cc`, if given, chooses the rate: the bottom
+/// third of its range is 1/8, the middle 1/16, and the top 1/32
+#[derive(Clone, Debug, Deserialize)]
+pub struct NoteRepeatDescr {
+    pub cc: u8,
+    #[serde(default = "default_rate")]
+    pub rate: Rate,
+    #[serde(default)]
+    pub rate_cc: Option<u8>,
+}
+
+/// Keeps track of the held notes and retriggers them on the clock
+pub struct NoteRepeat {
+    descr: NoteRepeatDescr,
+    player: Arc<Player>,
+    on: AtomicBool,
+    pulses: AtomicU64,
+
+    /// The held notes and their velocities
+    held: Mutex<BTreeMap<u8, u8>>,
+}
+
+impl NoteRepeat {
+    pub fn new(
+        descr: NoteRepeatDescr,
+        player: Arc<Player>,
+    ) -> Self {
+        Self {
+            pulses: AtomicU64::new(descr.rate.pulses()),
+            descr,
+            player,
+            on: AtomicBool::new(false),
+            held: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Turn note repeat on or off, or change the rate
+    pub fn control_change(
+        &self,
+        controller: u8,
+        value: u8,
+    ) {
+        if controller == self.descr.cc {
+            let on = value >= 64;
+            if self.on.swap(on, Ordering::Relaxed) != on {
+                eprintln!("Note repeat {}", if on { "on" } else { "off" });
+            }
+        }
+        if Some(controller) == self.descr.rate_cc {
+            let rate = match value {
+                0..=42 => Rate::Eighth,
+                43..=85 => Rate::Sixteenth,
+                _ => Rate::ThirtySecond,
+            };
+            self.pulses.store(rate.pulses(), Ordering::Relaxed);
+        }
+    }
+
+    pub fn note_on(
+        &self,
+        note: u8,
+        velocity: u8,
+    ) {
+        self.held.lock().unwrap().insert(note, velocity);
+    }
+
+    pub fn note_off(
+        &self,
+        note: u8,
+    ) {
+        self.held.lock().unwrap().remove(&note);
+    }
+
+    /// Called on each clock pulse.  Retriggers the held notes when
+    /// the pulse is on the beat for the rate
+    pub fn pulse(
+        &self,
+        pulse: u64,
+    ) {
+        if !self.on.load(Ordering::Relaxed)
+            || !pulse.is_multiple_of(self.pulses.load(Ordering::Relaxed))
+        {
+            return;
+        }
+        for (note, velocity) in self.held.lock().unwrap().iter() {
+            self.player.note_on(*note, *velocity);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use crate::audio::Stats;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Print a summary of the session on exit, for looking into what
+/// happened at a show: how long it ran, how often each note was
+/// played, the most voices at once, the xruns and the loudest the
+/// output got.  With `path` it is written there as JSON too
+pub fn report(
+    stats: &Stats,
+    uptime: Duration,
+    path: Option<&Path>,
+) {
+    let count = |n: &AtomicU64| n.load(Ordering::Relaxed);
+    let notes: Vec<(usize, u64)> = stats
+        .note_triggers
+        .iter()
+        .map(count)
+        .enumerate()
+        .filter(|(_, n)| *n > 0)
+        .collect();
+    let peak = f32::from_bits(stats.peak_level.load(Ordering::Relaxed));
+    let peak_db = 20.0 * peak.log10();
+    let seconds = uptime.as_secs();
+
+    eprintln!("Session report");
+    eprintln!(
+        "  Uptime {}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    );
+    eprintln!(
+        "  Triggers {}, failed {}, most voices at once {}",
+        count(&stats.triggers),
+        count(&stats.failed),
+        count(&stats.max_voices)
+    );
+    eprintln!(
+        "  Xruns {}, missed cycles {}",
+        count(&stats.xruns),
+        count(&stats.missed)
+    );
+    eprintln!("  Peak output {peak_db:.1} dBFS");
+    if !notes.is_empty() {
+        let notes: Vec<String> = notes
+            .iter()
+            .map(|(note, n)| format!("{note}:{n}"))
+            .collect();
+        eprintln!("  Notes played {}", notes.join(" "));
+    }
+
+    let Some(path) = path else {
+        return;
+    };
+    let report = serde_json::json!({
+        "uptime_seconds": uptime.as_secs_f64(),
+        "triggers": count(&stats.triggers),
+        "failed_triggers": count(&stats.failed),
+        "notes": notes
+            .iter()
+            .map(|(note, n)| (note.to_string(), serde_json::json!(n)))
+            .collect::<serde_json::Map<_, _>>(),
+        "max_voices": count(&stats.max_voices),
+        "xruns": count(&stats.xruns),
+        "missed_cycles": count(&stats.missed),
+        "peak_db": peak_db.is_finite().then_some(peak_db),
+    });
+    if let Err(err) = fs::write(path, format!("{report:#}\n")) {
+        eprintln!("{err}: Report {}", path.display());
+    }
+}
@@ -0,0 +1,476 @@
+use crate::message::{data_length, Message, Parser};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+fn default_port() -> u16 {
+    5004
+}
+
+fn default_name() -> String {
+    "MidiSampleQzt".to_string()
+}
+
+fn default_address() -> String {
+    "0.0.0.0".to_string()
+}
+
+/// Listen for RTP-MIDI (AppleMIDI) sessions, as iPads and Macs use
+/// for network MIDI.  The session uses `port` on `address` for
+/// control and the port after it for MIDI.  `name` is what the other
+/// end sees.  Sessions and MIDI are only taken from `hosts`, which
+/// must be given unless it only listens on the loopback address
+#[derive(Clone, Debug, Deserialize)]
+pub struct RtpMidiDescr {
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default = "default_name")]
+    pub name: String,
+    #[serde(default = "default_address")]
+    pub address: String,
+    #[serde(default)]
+    pub hosts: Vec<String>,
+}
+
+/// The AppleMIDI protocol version
+const VERSION: u32 = 2;
+
+/// The RTP payload type of MIDI
+const PAYLOAD_TYPE: u8 = 0x61;
+
+/// The biggest packet taken
+const MAX_PACKET: usize = 1500;
+
+/// The open sessions, each with a parser so running status carries
+/// on between its packets, by the other end's SSRC
+type Sessions = Arc<Mutex<HashMap<u32, Parser>>>;
+
+/// Start listening.  `handler` gets every MIDI message from every
+/// session.  Packets from anywhere but the hosts allowed are ignored
+pub fn listen<F>(
+    descr: &RtpMidiDescr,
+    handler: F,
+) -> std::io::Result<()>
+where
+    F: Fn(Message) + Send + Sync + 'static,
+{
+    let data_port = descr.port.checked_add(1).ok_or(std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("RTP-MIDI port {} has no port after it for MIDI", descr.port),
+    ))?;
+    let control = UdpSocket::bind((descr.address.as_str(), descr.port))?;
+    let mut hosts: Vec<IpAddr> = vec![];
+    for host in &descr.hosts {
+        hosts.extend(
+            (host.as_str(), 0)
+                .to_socket_addrs()?
+                .map(|address| address.ip()),
+        );
+    }
+    if hosts.is_empty() && !control.local_addr()?.ip().is_loopback() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "RTP-MIDI listening beyond the loopback address needs its \
+             \"hosts\"",
+        ));
+    }
+    let data = UdpSocket::bind((descr.address.as_str(), data_port))?;
+    let session = Arc::new(Session {
+        name: descr.name.clone(),
+        hosts,
+        ssrc: std::process::id() ^ 0x51a7_e000,
+        started: Instant::now(),
+        sessions: Arc::new(Mutex::new(HashMap::new())),
+    });
+    let handler = Arc::new(handler);
+    for (socket, is_data) in [(control, false), (data, true)] {
+        let session = session.clone();
+        let handler = handler.clone();
+        thread::spawn(move || {
+            let mut packet = [0; MAX_PACKET];
+            loop {
+                match socket.recv_from(&mut packet) {
+                    Ok((_, from))
+                        if !session.hosts.is_empty()
+                            && !session.hosts.contains(&from.ip()) =>
+                    {
+                        continue
+                    },
+                    Ok((length, from)) => {
+                        let packet = &packet[..length];
+                        let result = if packet.starts_with(&[0xff, 0xff]) {
+                            session.command(&socket, from, packet)
+                        } else if is_data {
+                            session.rtp(packet, handler.as_ref());
+                            Ok(())
+                        } else {
+                            Ok(())
+                        };
+                        if let Err(err) = result {
+                            eprintln!("{err}: RTP-MIDI {from}");
+                        }
+                    },
+                    Err(err) => {
+                        eprintln!("{err}: RTP-MIDI");
+                        return;
+                    },
+                }
+            }
+        });
+    }
+    eprintln!("RTP-MIDI session {} on port {}", descr.name, descr.port);
+    Ok(())
+}
+
+/// Our end of the sessions
+struct Session {
+    name: String,
+
+    /// The addresses sessions may come from.  Empty to take them
+    /// from anywhere, on the loopback address
+    hosts: Vec<IpAddr>,
+    ssrc: u32,
+    started: Instant,
+    sessions: Sessions,
+}
+
+impl Session {
+    /// Answer an AppleMIDI command: an invitation, which is accepted,
+    /// a clock synchronisation, or the end of a session
+    fn command(
+        &self,
+        socket: &UdpSocket,
+        from: SocketAddr,
+        packet: &[u8],
+    ) -> std::io::Result<()> {
+        let word = |at: usize| {
+            packet
+                .get(at..at + 4)
+                .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+                .unwrap_or(0)
+        };
+        match packet.get(2..4) {
+            Some(b"IN") => {
+                let name = packet.get(16..).unwrap_or(&[]);
+                let name = String::from_utf8_lossy(
+                    name.split(|b| *b == 0).next().unwrap_or(&[]),
+                );
+                let ssrc = word(12);
+                if self
+                    .sessions
+                    .lock()
+                    .unwrap()
+                    .insert(ssrc, Parser::new())
+                    .is_none()
+                {
+                    eprintln!("RTP-MIDI session from {name} at {from}");
+                }
+                let mut reply = vec![0xff, 0xff, b'O', b'K'];
+                reply.extend(VERSION.to_be_bytes());
+                reply.extend(word(8).to_be_bytes());
+                reply.extend(self.ssrc.to_be_bytes());
+                reply.extend(self.name.as_bytes());
+                reply.push(0);
+                socket.send_to(&reply, from)?;
+            },
+
+            // The other end sends its time, we answer with ours, and
+            // it works out the latency
+            Some(b"CK") if packet.len() >= 36 && packet[8] == 0 => {
+                let mut reply = packet[..36].to_vec();
+                reply[4..8].copy_from_slice(&self.ssrc.to_be_bytes());
+                reply[8] = 1;
+                reply[20..28].copy_from_slice(&self.time().to_be_bytes());
+                socket.send_to(&reply, from)?;
+            },
+            Some(b"BY") => self.end(word(12), from),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn end(
+        &self,
+        ssrc: u32,
+        from: SocketAddr,
+    ) {
+        if self.sessions.lock().unwrap().remove(&ssrc).is_some() {
+            eprintln!("RTP-MIDI session from {from} ended");
+        }
+    }
+
+    /// The time in the units of clock synchronisation, 100µs
+    fn time(&self) -> u64 {
+        (self.started.elapsed().as_micros() / 100) as u64
+    }
+
+    /// Play the MIDI commands in an RTP packet from a session with
+    /// `handler`, once the sessions are no longer locked
+    fn rtp(
+        &self,
+        packet: &[u8],
+        handler: &dyn Fn(Message),
+    ) {
+        for message in self.commands(packet) {
+            handler(message);
+        }
+    }
+
+    /// Take the MIDI commands out of an RTP packet from a session.
+    /// The recovery journal is ignored, so MIDI lost on the network
+    /// stays lost
+    fn commands(
+        &self,
+        packet: &[u8],
+    ) -> Vec<Message> {
+        let mut messages = vec![];
+        if packet.len() < 13 || packet[1] & 0x7f != PAYLOAD_TYPE {
+            return messages;
+        }
+        let ssrc =
+            u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(parser) = sessions.get_mut(&ssrc) else {
+            return messages;
+        };
+
+        // The command section header: whether the length is 12 bits
+        // (B), and whether the first command has a delta time (Z)
+        let header = packet[12];
+        let (length, start) = if header & 0x80 != 0 {
+            match packet.get(13) {
+                Some(low) => {
+                    (((header as usize & 0x0f) << 8) | *low as usize, 14)
+                },
+                None => return messages,
+            }
+        } else {
+            (header as usize & 0x0f, 13)
+        };
+        let Some(list) = packet.get(start..start + length) else {
+            return messages;
+        };
+        let mut delta = header & 0x20 != 0;
+        let mut status = None;
+        let mut at = 0;
+        while at < list.len() {
+            // Delta times come between commands, and are dropped
+            if delta {
+                while at < list.len() && list[at] & 0x80 != 0 {
+                    at += 1;
+                }
+                at += 1;
+            }
+            delta = true;
+            let Some(first) = list.get(at) else {
+                break;
+            };
+            let end = match first {
+                0xf8.. => at + 1,
+                0xf0 | 0xf7 | 0xf4 => {
+                    // System exclusive runs to its end byte
+                    match list[at + 1..]
+                        .iter()
+                        .position(|b| matches!(b, 0xf0 | 0xf4 | 0xf7))
+                    {
+                        Some(end) => at + end + 2,
+                        None => list.len(),
+                    }
+                },
+                0x80.. => {
+                    status = (*first < 0xf0).then_some(*first);
+                    at + 1 + data_length(*first)
+                },
+                _ => match status {
+                    Some(status) => at + data_length(status),
+                    None => break,
+                },
+            };
+            let command = &list[at..end.min(list.len())];
+
+            // Only whole system exclusive messages are passed on,
+            // not ones split between packets
+            let split = command[0] == 0xf7
+                || (command[0] == 0xf0 && command.last() != Some(&0xf7));
+            if !split {
+                parser.parse(command, |message| messages.push(message));
+            }
+            at = end;
+        }
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// The SSRC of the other end in the packets below
+    const SSRC: u32 = 0x5e31_d07b;
+
+    /// An RTP header from a Mac, sequence number 0x1b2a, before the
+    /// MIDI command section
+    const HEADER: [u8; 12] = [
+        0x80, 0x61, 0x1b, 0x2a, 0x00, 0x0d, 0x2f, 0x90, 0x5e, 0x31, 0xd0, 0x7b,
+    ];
+
+    /// Our end, with a session open from `SSRC`
+    fn session() -> Session {
+        let session = Session {
+            name: default_name(),
+            hosts: vec![],
+            ssrc: 1,
+            started: Instant::now(),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+        };
+        session.sessions.lock().unwrap().insert(SSRC, Parser::new());
+        session
+    }
+
+    /// The messages in `packet`, the command section after `HEADER`
+    fn messages(
+        session: &Session,
+        packet: &[u8],
+    ) -> Vec<Message> {
+        let messages = RefCell::new(vec![]);
+        let packet = [&HEADER[..], packet].concat();
+        session.rtp(&packet, &|message| messages.borrow_mut().push(message));
+        messages.into_inner()
+    }
+
+    fn note_on(
+        note: u8,
+        velocity: u8,
+    ) -> Message {
+        Message::NoteOn {
+            channel: 0,
+            note,
+            velocity,
+        }
+    }
+
+    #[test]
+    fn short_list() {
+        // The J flag is set and the journal follows, which is ignored
+        let packet = [
+            0x43, 0x90, 0x3c, 0x64, 0x20, 0x1b, 0x29, 0x07, 0x08, 0x81, 0xf1,
+        ];
+        assert_eq!(messages(&session(), &packet), [note_on(60, 100)]);
+    }
+
+    #[test]
+    fn long_list() {
+        // B: the length is 12 bits, 0x007 here
+        let packet = [0x80, 0x07, 0xb0, 0x07, 0x7f, 0x00, 0xb0, 0x0a, 0x40];
+        assert_eq!(
+            messages(&session(), &packet),
+            [
+                Message::ControlChange {
+                    channel: 0,
+                    controller: 7,
+                    value: 127
+                },
+                Message::ControlChange {
+                    channel: 0,
+                    controller: 10,
+                    value: 64
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn delta_times() {
+        // Z: the first command has a delta time too.  The second's
+        // takes two bytes
+        let packet =
+            [0x29, 0x00, 0x90, 0x3c, 0x64, 0x81, 0x10, 0x90, 0x3e, 0x50];
+        assert_eq!(
+            messages(&session(), &packet),
+            [note_on(60, 100), note_on(62, 80)]
+        );
+    }
+
+    #[test]
+    fn running_status() {
+        let packet =
+            [0x09, 0x90, 0x3c, 0x64, 0x00, 0x3e, 0x50, 0x00, 0x3c, 0x00];
+        assert_eq!(
+            messages(&session(), &packet),
+            [
+                note_on(60, 100),
+                note_on(62, 80),
+                Message::NoteOff {
+                    channel: 0,
+                    note: 60,
+                    velocity: 0
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn split_exclusive() {
+        let session = session();
+
+        // A whole message is passed on
+        let packet = [0x06, 0xf0, 0x7e, 0x7f, 0x06, 0x01, 0xf7];
+        assert_eq!(
+            messages(&session, &packet),
+            [Message::Exclusive(vec![0x7e, 0x7f, 0x06, 0x01])]
+        );
+
+        // The first part of a split message ends with 0xf0, and the
+        // last starts with 0xf7.  Neither is passed on, and the note
+        // after the first part still is
+        let packet =
+            [0x09, 0xf0, 0x43, 0x12, 0x00, 0xf0, 0x00, 0x90, 0x3c, 0x64];
+        assert_eq!(messages(&session, &packet), [note_on(60, 100)]);
+        let packet = [0x04, 0xf7, 0x01, 0x02, 0xf7];
+        assert_eq!(messages(&session, &packet), []);
+    }
+
+    #[test]
+    fn ignored() {
+        // From a session not open
+        let session = session();
+        session.sessions.lock().unwrap().clear();
+        assert_eq!(messages(&session, &[0x03, 0x90, 0x3c, 0x64]), []);
+
+        // Not MIDI, or cut short
+        let session = self::session();
+        let mut packet = [&HEADER[..], &[0x03, 0x90, 0x3c, 0x64]].concat();
+        packet[1] = 0x60;
+        session.rtp(&packet, &|message| panic!("{message:?}"));
+        assert_eq!(messages(&session, &[0x05, 0x90, 0x3c, 0x64]), []);
+        assert_eq!(messages(&session, &[0x80]), []);
+    }
+
+    #[test]
+    fn last_port() {
+        let descr = RtpMidiDescr {
+            port: u16::MAX,
+            name: default_name(),
+            address: "127.0.0.1".to_string(),
+            hosts: vec![],
+        };
+        let err = listen(&descr, |_| ()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn needs_hosts_beyond_loopback() {
+        let descr = RtpMidiDescr {
+            port: 0,
+            name: default_name(),
+            address: default_address(),
+            hosts: vec![],
+        };
+        let err = listen(&descr, |_| ()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,140 @@
+use crate::control::ControlState;
+use crate::engine::{Fade, FadeTarget};
+use crate::gain::db_to_gain;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+fn default_fade() -> usize {
+    20
+}
+
+/// A named set of gains switched together, such as "soundcheck" and
+/// "show".  `master` is the master gain, `notes` the gains of notes
+/// and `outputs` the gains of outputs, all in dB.  Notes in `mute`
+/// are silenced.  Selecting the scene fades to it over `fade`
+/// milliseconds, and Program Change `program`, if given, selects it
+#[derive(Clone, Debug, Deserialize)]
+pub struct SceneDescr {
+    pub name: String,
+    #[serde(default)]
+    pub program: Option<u8>,
+    #[serde(default)]
+    pub master: Option<f32>,
+    #[serde(default)]
+    pub notes: BTreeMap<u8, f32>,
+    #[serde(default)]
+    pub mute: Vec<u8>,
+    #[serde(default)]
+    pub outputs: BTreeMap<String, f32>,
+    #[serde(default = "default_fade")]
+    pub fade: usize,
+}
+
+/// The scenes, with their outputs turned into channels
+pub struct Scenes {
+    scenes: Vec<SceneDescr>,
+
+    /// The channels of each output a scene names
+    channels: BTreeMap<String, Vec<usize>>,
+    active: Mutex<Option<String>>,
+}
+
+impl Scenes {
+    /// `outputs` finds the channels of an output by name
+    pub fn new(
+        scenes: Vec<SceneDescr>,
+        outputs: impl Fn(&str) -> Option<Vec<usize>>,
+    ) -> Result<Self, String> {
+        let mut channels = BTreeMap::new();
+        for scene in scenes.iter() {
+            for name in scene.outputs.keys() {
+                let found = outputs(name).ok_or(format!(
+                    "Scene {}: No output called {name}",
+                    scene.name
+                ))?;
+                channels.insert(name.clone(), found);
+            }
+        }
+        Ok(Self {
+            scenes,
+            channels,
+            active: Mutex::new(None),
+        })
+    }
+
+    /// The name of the scene selected last
+    pub fn active(&self) -> Option<String> {
+        self.active.lock().unwrap().clone()
+    }
+
+    /// Fade to a scene.  Notes and outputs that another scene changes
+    /// but this one does not go back to 0dB, so each scene sounds the
+    /// same whichever came before it
+    pub fn select(
+        &self,
+        name: &str,
+        control: &ControlState,
+    ) -> Result<(), String> {
+        let scene = self
+            .scenes
+            .iter()
+            .find(|scene| scene.name == name)
+            .ok_or(format!("No scene called {name}"))?;
+        let frames = scene.fade * control.sample_rate.current() / 1000;
+        let fade = |target, gain| {
+            control.fade(Fade {
+                target,
+                gain,
+                frames,
+            })
+        };
+        if let Some(db) = scene.master {
+            fade(FadeTarget::Master, db_to_gain(db))?;
+        }
+        let notes: BTreeSet<u8> = self
+            .scenes
+            .iter()
+            .flat_map(|scene| scene.notes.keys().chain(scene.mute.iter()))
+            .copied()
+            .collect();
+        for note in notes.into_iter().filter(|note| *note < 128) {
+            let gain = if scene.mute.contains(&note) {
+                0.0
+            } else {
+                scene.notes.get(&note).map_or(1.0, |db| db_to_gain(*db))
+            };
+            fade(FadeTarget::Note(note), gain)?;
+        }
+        for (output, channels) in self.channels.iter() {
+            let gain =
+                scene.outputs.get(output).map_or(1.0, |db| db_to_gain(*db));
+            for channel in channels {
+                fade(FadeTarget::Channel(*channel), gain)?;
+            }
+        }
+        *self.active.lock().unwrap() = Some(name.to_string());
+        eprintln!("Scene {name}");
+        Ok(())
+    }
+
+    /// Select the scene for a Program Change, if there is one.  False
+    /// if no scene has that program
+    pub fn program_change(
+        &self,
+        program: u8,
+        control: &ControlState,
+    ) -> bool {
+        let Some(scene) = self
+            .scenes
+            .iter()
+            .find(|scene| scene.program == Some(program))
+        else {
+            return false;
+        };
+        if let Err(err) = self.select(&scene.name, control) {
+            eprintln!("{err}: Scene {}", scene.name);
+        }
+        true
+    }
+}
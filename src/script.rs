@@ -0,0 +1,164 @@
+use crate::control::ControlState;
+use crate::engine::{Fade, FadeTarget};
+use crate::gain::db_to_gain;
+use crate::player::Player;
+use rhai::{CallFnOptions, Dynamic, Engine, Map, Scope, AST};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How often `on_tick` is called
+const TICK: Duration = Duration::from_millis(10);
+
+/// A Rhai script with hooks called on note events:
+///
+/// * `on_note_on(note, velocity)` is called instead of playing the
+///   note.  Returning nothing (or `true`) plays the note as usual,
+///   a note number plays that note instead, an array of note numbers
+///   plays them all, and `false` plays nothing
+/// * `on_note_off(note)` is called on NoteOff.  Returning `false`
+///   ignores the NoteOff
+/// * `on_tick()` is called every ten milliseconds
+///
+/// Scripts can call `trigger(note, velocity)`, `set_gain(note, db)`
+/// and `set_master_gain(db)`.  State kept between calls goes in
+/// `this`, an object map shared by all the hooks
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    state: Mutex<(Scope<'static>, Dynamic)>,
+}
+
+impl Script {
+    pub fn load(
+        path: &Path,
+        player: Arc<Player>,
+        control: Arc<ControlState>,
+    ) -> Result<Arc<Self>, Box<dyn std::error::Error>> {
+        let mut engine = Engine::new();
+
+        let trigger_player = player.clone();
+        engine.register_fn("trigger", move |note: i64, velocity: i64| {
+            if let (Some(note), Some(velocity)) = (to_u7(note), to_u7(velocity))
+            {
+                trigger_player.note_on(note, velocity);
+            }
+        });
+        let gain_control = control.clone();
+        engine.register_fn("set_gain", move |note: i64, db: f64| {
+            if let Some(note) = to_u7(note) {
+                set_gain(&gain_control, FadeTarget::Note(note), db);
+            }
+        });
+        engine.register_fn("set_master_gain", move |db: f64| {
+            set_gain(&control, FadeTarget::Master, db);
+        });
+
+        let ast = engine.compile_file(path.to_path_buf())?;
+        let mut scope = Scope::new();
+        engine.run_ast_with_scope(&mut scope, &ast)?;
+
+        let script = Arc::new(Self {
+            engine,
+            ast,
+            state: Mutex::new((scope, Dynamic::from(Map::new()))),
+        });
+        if script.has("on_tick") {
+            let ticking = script.clone();
+            thread::spawn(move || loop {
+                thread::sleep(TICK);
+                let _ = ticking.call("on_tick", ());
+            });
+        }
+        Ok(script)
+    }
+
+    /// Called for NoteOn.  Plays what the script says to
+    pub fn note_on(
+        &self,
+        player: &Player,
+        note: u8,
+        velocity: u8,
+    ) {
+        if !self.has("on_note_on") {
+            player.note_on(note, velocity);
+            return;
+        }
+        let result = self.call("on_note_on", (note as i64, velocity as i64));
+        if result.is_unit() || result.as_bool() == Ok(true) {
+            player.note_on(note, velocity);
+        } else if let Ok(remapped) = result.as_int() {
+            if let Some(remapped) = to_u7(remapped) {
+                player.note_on(remapped, velocity);
+            }
+        } else if result.is_array() {
+            for remapped in result.into_array().unwrap_or_default() {
+                if let Some(remapped) = remapped.as_int().ok().and_then(to_u7) {
+                    player.note_on(remapped, velocity);
+                }
+            }
+        }
+    }
+
+    /// Called for NoteOff
+    pub fn note_off(
+        &self,
+        player: &Player,
+        note: u8,
+        velocity: u8,
+    ) {
+        if !self.has("on_note_off")
+            || self.call("on_note_off", (note as i64,)).as_bool() != Ok(false)
+        {
+            player.note_off(note, velocity);
+        }
+    }
+
+    fn has(
+        &self,
+        hook: &str,
+    ) -> bool {
+        self.ast.iter_functions().any(|f| f.name == hook)
+    }
+
+    /// Call a hook.  Errors are reported and treated as the hook
+    /// returning nothing
+    fn call(
+        &self,
+        hook: &str,
+        args: impl rhai::FuncArgs,
+    ) -> Dynamic {
+        let mut state = self.state.lock().unwrap();
+        let (scope, this) = &mut *state;
+        let options = CallFnOptions::new().eval_ast(false).bind_this_ptr(this);
+        match self.engine.call_fn_with_options::<Dynamic>(
+            options, scope, &self.ast, hook, args,
+        ) {
+            Ok(result) => result,
+            Err(err) => {
+                eprintln!("{err}: Script {hook}");
+                Dynamic::UNIT
+            },
+        }
+    }
+}
+
+/// A MIDI data byte from a script's integer
+fn to_u7(value: i64) -> Option<u8> {
+    u8::try_from(value).ok().filter(|v| *v < 128)
+}
+
+fn set_gain(
+    control: &ControlState,
+    target: FadeTarget,
+    db: f64,
+) {
+    if let Err(err) = control.fade(Fade {
+        target,
+        gain: db_to_gain(db as f32),
+        frames: 0,
+    }) {
+        eprintln!("{err}: Script set gain");
+    }
+}
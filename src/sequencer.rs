@@ -0,0 +1,161 @@
+use crate::clock::{default_rate, Rate};
+use crate::player::Player;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The velocity of a step written "x"
+const STEP_VELOCITY: u8 = 100;
+
+/// The velocity of an accented step, written "X"
+const ACCENT_VELOCITY: u8 = 127;
+
+/// One note's part in the sequence.  `steps` has a character per
+/// step: "x" plays the note, "X" plays it accented, and "." or "-"
+/// is a rest.  Spaces are ignored so steps can be grouped in beats.
+/// Tracks of different lengths loop independently
+#[derive(Clone, Debug, Deserialize)]
+pub struct TrackDescr {
+    pub note: u8,
+    pub steps: String,
+}
+
+/// A step sequencer that plays tracks in a loop in time with the
+/// clock.  Each step is `rate` long.  If `playing` is set it starts
+/// playing straight away
+#[derive(Clone, Debug, Deserialize)]
+pub struct SequencerDescr {
+    #[serde(default = "default_rate")]
+    pub rate: Rate,
+    #[serde(default)]
+    pub playing: bool,
+    #[serde(default)]
+    pub tracks: Vec<TrackDescr>,
+}
+
+/// A track with its steps as velocities, zero for a rest
+struct Track {
+    note: u8,
+    steps: Vec<u8>,
+}
+
+pub struct Sequencer {
+    rate: Rate,
+    player: Arc<Player>,
+    playing: AtomicBool,
+    tracks: Mutex<Vec<Track>>,
+}
+
+impl Sequencer {
+    pub fn new(
+        descr: SequencerDescr,
+        player: Arc<Player>,
+    ) -> Result<Self, String> {
+        let mut tracks = vec![];
+        for TrackDescr { note, steps } in descr.tracks {
+            let steps = parse_steps(&steps)?;
+            if steps.is_empty() {
+                return Err(format!("Note {note}: No steps"));
+            }
+            tracks.push(Track { note, steps });
+        }
+        Ok(Self {
+            rate: descr.rate,
+            player,
+            playing: AtomicBool::new(descr.playing),
+            tracks: Mutex::new(tracks),
+        })
+    }
+
+    pub fn play(
+        &self,
+        playing: bool,
+    ) {
+        self.playing.store(playing, Ordering::Relaxed);
+    }
+
+    pub fn playing(&self) -> bool {
+        self.playing.load(Ordering::Relaxed)
+    }
+
+    /// Set the steps of the track for `note`, adding the track if
+    /// there is not one.  No steps removes the track
+    pub fn set_track(
+        &self,
+        note: u8,
+        steps: &str,
+    ) -> Result<(), String> {
+        let steps = parse_steps(steps)?;
+        let mut tracks = self.tracks.lock().unwrap();
+        match tracks.iter().position(|t| t.note == note) {
+            Some(index) if steps.is_empty() => {
+                tracks.remove(index);
+            },
+            Some(index) => tracks[index].steps = steps,
+            None if steps.is_empty() => (),
+            None => tracks.push(Track { note, steps }),
+        }
+        Ok(())
+    }
+
+    /// The steps of each note's track
+    pub fn patterns(&self) -> BTreeMap<u8, String> {
+        self.tracks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|track| {
+                let steps = track
+                    .steps
+                    .iter()
+                    .map(|velocity| match *velocity {
+                        0 => '.',
+                        ACCENT_VELOCITY => 'X',
+                        _ => 'x',
+                    })
+                    .collect();
+                (track.note, steps)
+            })
+            .collect()
+    }
+
+    /// One line per track: the note and its steps
+    pub fn describe(&self) -> String {
+        self.patterns()
+            .iter()
+            .map(|(note, steps)| format!("{note} {steps}\n"))
+            .collect()
+    }
+
+    /// Called on each clock pulse.  Plays the steps that fall on it
+    pub fn pulse(
+        &self,
+        pulse: u64,
+    ) {
+        let pulses = self.rate.pulses();
+        if !self.playing() || !pulse.is_multiple_of(pulses) {
+            return;
+        }
+        let step = (pulse / pulses) as usize;
+        for track in self.tracks.lock().unwrap().iter() {
+            let velocity = track.steps[step % track.steps.len()];
+            if velocity > 0 {
+                self.player.note_on(track.note, velocity);
+            }
+        }
+    }
+}
+
+fn parse_steps(steps: &str) -> Result<Vec<u8>, String> {
+    steps
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| match c {
+            'x' => Ok(STEP_VELOCITY),
+            'X' => Ok(ACCENT_VELOCITY),
+            '.' | '-' => Ok(0),
+            _ => Err(format!("Bad step {c:?} in {steps}")),
+        })
+        .collect()
+}
@@ -0,0 +1,177 @@
+use crate::bank::Banks;
+use crate::config::SegmentDescr;
+use crate::decode::{self, Format, Info};
+use crate::engine::{SampleData, Segment};
+use crate::loop_sync::LoopSync;
+use crate::loudness;
+use crate::metadata;
+use crate::player::Player;
+use crate::staging;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the active bank is looked at
+const POLL: Duration = Duration::from_millis(50);
+
+/// A sample in a bank of the setlist, which is only loaded while its
+/// bank is playing or next up.  `sample` is its index in the samples
+pub struct Deferred {
+    pub sample: usize,
+    pub bank: usize,
+    pub path: String,
+    pub format: Option<Format>,
+
+    /// The rate and channels of its first file
+    pub source: Info,
+    pub segments: Vec<SegmentDescr>,
+    pub sync: Option<LoopSync>,
+
+    /// The RMS level, in dB, `--auto-gain` brought the samples loaded
+    /// at the start to, if it is on
+    pub auto_gain: Option<f32>,
+}
+
+impl Deferred {
+    /// Load the sample as it is at the start: prepared, evened out
+    /// with the others and brought to its bank's loudness `target`
+    fn load(
+        &self,
+        target: Option<f32>,
+    ) -> Result<(Vec<f32>, Vec<Segment>), String> {
+        let (mut data, segments) = prepare(
+            &self.path,
+            self.format,
+            &self.segments,
+            self.sync.as_ref(),
+        )?;
+        if let Some(median) = self.auto_gain {
+            staging::correct(&mut data, median, &self.path);
+        }
+        if let Some(target) = target {
+            loudness::normalize(&mut data, self.source, target, &self.path);
+        }
+        Ok((data, segments))
+    }
+}
+
+/// Decode a sample, or its segments into one buffer so they play
+/// without gaps.  A file with a loop saved in it is split into
+/// segments around the loop
+pub fn load(
+    path: &str,
+    format: Option<Format>,
+    segment_descrs: &[SegmentDescr],
+) -> Result<(Vec<f32>, Vec<Segment>), String> {
+    let decode = |path: &str| {
+        decode::decode(path, format).map_err(|err| format!("{err}: {path}"))
+    };
+    let mut data: Vec<f32> = vec![];
+    let mut segments: Vec<Segment> = vec![];
+    if segment_descrs.is_empty() {
+        data = decode(path)?;
+        segments = metadata::read(Path::new(path))
+            .segments(data.len())
+            .unwrap_or_default();
+    }
+    for SegmentDescr { path, looping, .. } in segment_descrs {
+        let start = data.len();
+        data.append(&mut decode(path)?);
+        segments.push(Segment {
+            start,
+            end: data.len(),
+            looping: *looping,
+        });
+    }
+    Ok((data, segments))
+}
+
+/// Load a sample and, if it is a loop that keeps time, crossfade it
+/// and make its segments
+pub fn prepare(
+    path: &str,
+    format: Option<Format>,
+    segment_descrs: &[SegmentDescr],
+    sync: Option<&LoopSync>,
+) -> Result<(Vec<f32>, Vec<Segment>), String> {
+    let (data, segments) = load(path, format, segment_descrs)?;
+    Ok(match sync {
+        Some(sync) => sync.prepare(data),
+        None => (data, segments),
+    })
+}
+
+/// Follow the show through `setlist`, the banks in the order they are
+/// played, keeping the active bank's samples and the next one's
+/// loaded and letting the others go.  Voices still playing a bank
+/// that is let go play to their end, as the engine keeps the data
+/// they play until they finish.  Starts with the banks in `loaded`
+/// loaded
+pub fn start(
+    setlist: Vec<usize>,
+    mut loaded: BTreeSet<usize>,
+    deferred: Vec<Deferred>,
+    player: Arc<Player>,
+    banks: Arc<Banks>,
+) {
+    thread::spawn(move || {
+        let mut position = 0;
+        loop {
+            thread::sleep(POLL);
+
+            // A bank that comes up twice is taken to be the one next
+            // in the show.  A bank not in the setlist changes nothing
+            let Some(active) = banks.active() else {
+                continue;
+            };
+            let Some(found) = setlist
+                .iter()
+                .skip(position)
+                .position(|bank| *bank == active)
+                .map(|found| found + position)
+                .or_else(|| setlist.iter().position(|bank| *bank == active))
+            else {
+                continue;
+            };
+            position = found;
+            let wanted: BTreeSet<usize> =
+                setlist.iter().skip(position).take(2).copied().collect();
+            if wanted == loaded {
+                continue;
+            }
+
+            for bank in loaded.difference(&wanted) {
+                for sample in deferred.iter().filter(|d| d.bank == *bank) {
+                    player.replace_data(sample.sample, vec![], vec![]);
+                }
+            }
+            for bank in wanted.difference(&loaded) {
+                let started = Instant::now();
+                for sample in deferred.iter().filter(|d| d.bank == *bank) {
+                    match sample.load(banks.target_lufs(*bank)) {
+                        Ok((data, segments)) => {
+                            let old = player.samples()[sample.sample].clone();
+                            player.replace(
+                                sample.sample,
+                                SampleData {
+                                    data,
+                                    segments,
+                                    ..(*old).clone()
+                                },
+                            );
+                        },
+                        Err(err) => eprintln!("{err}"),
+                    }
+                }
+                eprintln!(
+                    "Bank {} loaded in {:.3}s",
+                    banks.name(*bank),
+                    started.elapsed().as_secs_f64()
+                );
+            }
+            loaded = wanted;
+        }
+    });
+}
@@ -0,0 +1,30 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set when SIGINT or SIGTERM arrives
+static STOPPED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn stop(_signal: libc::c_int) {
+    STOPPED.store(true, Ordering::Relaxed);
+}
+
+/// Catch SIGINT and SIGTERM, so a sampler running as a service can
+/// shut down properly when it is stopped rather than being killed
+pub fn catch() {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // Storing to an atomic is safe in a signal handler
+        let handler = stop as extern "C" fn(libc::c_int);
+        if unsafe { libc::signal(signal, handler as libc::sighandler_t) }
+            == libc::SIG_ERR
+        {
+            eprintln!(
+                "{}: Catching signal {signal}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+/// Whether SIGINT or SIGTERM has arrived since `catch`
+pub fn stopped() -> bool {
+    STOPPED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+
+/// Onsets are looked for in windows of this many samples
+const WINDOW: usize = 512;
+
+/// Energy is compared with the average over this many windows before
+const HISTORY: usize = 8;
+
+/// Windows quieter than this (in dB) never start a slice
+const FLOOR_DB: f32 = -50.0;
+
+fn default_sensitivity() -> f32 {
+    6.0
+}
+
+fn default_min_length() -> usize {
+    4096
+}
+
+/// Slicing one long sample into pieces on consecutive notes, the
+/// first on the sample's note.  Slices start where the level jumps
+/// by `sensitivity` dB over the level just before, and are at least
+/// `min_length` samples long.
+///
+/// Or the slices start at `markers`, positions in the sample in
+/// increasing order, and anything before the first marker is not
+/// played.  `notes`, if given, has the note for each slice
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SliceDescr {
+    #[serde(default = "default_sensitivity")]
+    pub sensitivity: f32,
+    #[serde(default = "default_min_length")]
+    pub min_length: usize,
+    #[serde(default)]
+    pub markers: Vec<usize>,
+    #[serde(default)]
+    pub notes: Vec<u8>,
+}
+
+impl SliceDescr {
+    /// The note for slice `index` of a sample on `note`.  None if it
+    /// runs off the top of the keyboard
+    pub fn note(
+        &self,
+        note: u8,
+        index: usize,
+    ) -> Option<u8> {
+        match self.notes.get(index) {
+            Some(note) => Some(*note),
+            None => u8::try_from(index)
+                .ok()
+                .and_then(|index| note.checked_add(index)),
+        }
+        .filter(|note| *note < 128)
+    }
+}
+
+/// Where each slice of `data` starts, at the markers if there are
+/// any, otherwise at the onsets
+pub fn starts(
+    data: &[f32],
+    descr: &SliceDescr,
+) -> Vec<usize> {
+    if descr.markers.is_empty() {
+        onsets(data, descr)
+    } else {
+        descr
+            .markers
+            .iter()
+            .copied()
+            .filter(|marker| *marker < data.len())
+            .collect()
+    }
+}
+
+/// Where each slice of `data` starts.  The first slice always starts
+/// at the beginning
+fn onsets(
+    data: &[f32],
+    descr: &SliceDescr,
+) -> Vec<usize> {
+    let levels: Vec<f32> = data
+        .chunks(WINDOW)
+        .map(|window| {
+            let power =
+                window.iter().map(|s| s * s).sum::<f32>() / window.len() as f32;
+            10.0 * power.max(1e-12).log10()
+        })
+        .collect();
+
+    let mut starts = vec![0];
+    for (i, level) in levels.iter().enumerate().skip(1) {
+        let before = &levels[i.saturating_sub(HISTORY)..i];
+        let average = before.iter().sum::<f32>() / before.len() as f32;
+        let start = i * WINDOW;
+        if *level > FLOOR_DB
+            && *level - average >= descr.sensitivity
+            && start - starts[starts.len() - 1] >= descr.min_length
+        {
+            starts.push(start);
+        }
+    }
+    starts
+}
@@ -0,0 +1,154 @@
+use crate::message as midi;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+/// How many note events can be waiting for the capture thread
+const EVENT_QUEUE: usize = 1024;
+
+/// Ticks per quarter note in the file
+const DIVISION: u16 = 480;
+
+/// The tempo written to the file, in microseconds per quarter note
+/// (120 BPM).  With `DIVISION` that makes a tick just over a
+/// millisecond
+const TEMPO: u32 = 500_000;
+
+enum Message {
+    Event(Instant, [u8; 3]),
+    Finish,
+}
+
+/// Captures the note events performed and writes them to a type 0
+/// Standard MIDI File when finished, so a performance can be replayed
+/// or edited in a DAW
+pub struct Capture {
+    sender: SyncSender<Message>,
+    thread: JoinHandle<()>,
+}
+
+/// The MIDI thread's end of a `Capture`
+#[derive(Clone)]
+pub struct CaptureSender(SyncSender<Message>);
+
+impl CaptureSender {
+    /// Record a NoteOn or NoteOff, ignoring any other message
+    pub fn note(
+        &self,
+        message: &midi::Message,
+    ) {
+        let event = match *message {
+            midi::Message::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => [0x80 | channel, note, velocity],
+            midi::Message::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => [0x90 | channel, note, velocity],
+            _ => return,
+        };
+        let _ = self.0.try_send(Message::Event(Instant::now(), event));
+    }
+}
+
+impl Capture {
+    pub fn start(path: PathBuf) -> Self {
+        let (sender, receiver) = sync_channel::<Message>(EVENT_QUEUE);
+        let thread = thread::spawn(move || {
+            let mut events: Vec<(Instant, [u8; 3])> = vec![];
+            for message in receiver {
+                match message {
+                    Message::Event(time, event) => events.push((time, event)),
+                    Message::Finish => break,
+                }
+            }
+            // Events come from more than one thread, so they can arrive
+            // out of order
+            events.sort_by_key(|(time, _)| *time);
+            match write_smf(&path, &events) {
+                Ok(()) => eprintln!(
+                    "Wrote {} note events to {}",
+                    events.len(),
+                    path.display()
+                ),
+                Err(err) => eprintln!("{err}: Writing {}", path.display()),
+            }
+        });
+        Self { sender, thread }
+    }
+
+    pub fn sender(&self) -> CaptureSender {
+        CaptureSender(self.sender.clone())
+    }
+
+    /// Stop capturing and write the file
+    pub fn finish(self) {
+        let _ = self.sender.send(Message::Finish);
+        let _ = self.thread.join();
+    }
+}
+
+/// Write `events`, in time order, as a type 0 Standard MIDI File.
+/// Time starts at the first event
+fn write_smf(
+    path: &Path,
+    events: &[(Instant, [u8; 3])],
+) -> std::io::Result<()> {
+    let mut track: Vec<u8> = vec![];
+
+    // Tempo
+    write_variable_length(&mut track, 0);
+    track.extend_from_slice(&[0xff, 0x51, 0x03]);
+    track.extend_from_slice(&TEMPO.to_be_bytes()[1..]);
+
+    let micros_per_tick = TEMPO as f64 / DIVISION as f64;
+    let mut last_tick: u64 = 0;
+    if let Some((start, _)) = events.first() {
+        for (time, event) in events {
+            let micros = time.duration_since(*start).as_micros() as f64;
+            let tick = (micros / micros_per_tick).round() as u64;
+            write_variable_length(
+                &mut track,
+                tick.saturating_sub(last_tick) as u32,
+            );
+            track.extend_from_slice(event);
+            last_tick = tick;
+        }
+    }
+
+    // End of track
+    write_variable_length(&mut track, 0);
+    track.extend_from_slice(&[0xff, 0x2f, 0x00]);
+
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(b"MThd")?;
+    out.write_all(&6_u32.to_be_bytes())?;
+    out.write_all(&0_u16.to_be_bytes())?; // Format 0
+    out.write_all(&1_u16.to_be_bytes())?; // One track
+    out.write_all(&DIVISION.to_be_bytes())?;
+    out.write_all(b"MTrk")?;
+    out.write_all(&(track.len() as u32).to_be_bytes())?;
+    out.write_all(&track)?;
+    out.flush()
+}
+
+/// Delta times are written seven bits at a time, most significant
+/// first, with the top bit set on all but the last byte
+fn write_variable_length(
+    out: &mut Vec<u8>,
+    value: u32,
+) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    let mut value = value >> 7;
+    while value > 0 {
+        bytes.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
@@ -0,0 +1,153 @@
+use serde::{Deserialize, Serialize};
+use std::f32::consts::FRAC_PI_2;
+
+/// How quickly a `OnePole` follows its target: the frames it takes to
+/// get about two thirds of the way there
+const SMOOTHING: f32 = 240.0;
+
+/// Close enough to the target to stop moving
+const SETTLED: f32 = 1e-6;
+
+/// How steep an exponential fade is.  It is about 20 dB down half way
+const STEEPNESS: f32 = 5.0;
+
+/// The shape of a fade.  `linear` changes the gain evenly, which
+/// sounds abrupt on sustained material.  `equal_power` follows a
+/// quarter sine, so two voices crossfading keep the same loudness.
+/// `exponential` changes evenly in dB, near enough, fading out fast
+/// and in slowly.  `s_curve` starts and ends gently
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Curve {
+    #[default]
+    Linear,
+    EqualPower,
+    Exponential,
+    #[serde(rename = "s_curve")]
+    Smooth,
+}
+
+impl Curve {
+    /// The gain, from 0 to 1, `x` of the way through a fade in.  Also
+    /// the gain of a fade out with `x` of it left to go
+    pub fn shape(
+        &self,
+        x: f32,
+    ) -> f32 {
+        let x = x.clamp(0.0, 1.0);
+        match self {
+            Curve::Linear => x,
+            Curve::EqualPower => (x * FRAC_PI_2).sin(),
+            Curve::Exponential => (STEEPNESS * x).exp_m1() / STEEPNESS.exp_m1(),
+            Curve::Smooth => x * x * (3.0 - 2.0 * x),
+        }
+    }
+}
+
+/// A value that moves to a target over a number of frames, along its
+/// curve, so changes do not click.  It is advanced once per frame in
+/// the Jack thread.  For changes that should take a set time, such as
+/// fades
+#[derive(Clone, Copy, Debug)]
+pub struct Ramp {
+    start: f32,
+    current: f32,
+    target: f32,
+    frames: usize,
+    remaining: usize,
+    curve: Curve,
+}
+
+impl Ramp {
+    pub fn new(value: f32) -> Self {
+        Self {
+            start: value,
+            current: value,
+            target: value,
+            frames: 0,
+            remaining: 0,
+            curve: Curve::Linear,
+        }
+    }
+
+    /// The shape of the ramps to come
+    pub fn set_curve(
+        &mut self,
+        curve: Curve,
+    ) {
+        self.curve = curve;
+    }
+
+    /// Start moving towards `target`, arriving after `frames` frames
+    pub fn set(
+        &mut self,
+        target: f32,
+        frames: usize,
+    ) {
+        self.start = self.current;
+        self.target = target;
+        self.frames = frames;
+        self.remaining = frames;
+        if frames == 0 {
+            self.current = target;
+        }
+    }
+
+    /// The value for this frame.  Moves the ramp on by one frame
+    pub fn tick(&mut self) -> f32 {
+        let value = self.current;
+        if self.remaining > 0 {
+            self.remaining -= 1;
+
+            // Going down is a fade out, the curve played backwards
+            let done = 1.0 - self.remaining as f32 / self.frames as f32;
+            self.current = if self.remaining == 0 {
+                self.target
+            } else if self.target >= self.start {
+                self.start + (self.target - self.start) * self.curve.shape(done)
+            } else {
+                self.target
+                    + (self.start - self.target) * self.curve.shape(1.0 - done)
+            };
+        }
+        value
+    }
+}
+
+/// A value that follows its target with a one pole low pass filter,
+/// quickly at first and then more slowly, so a stream of small
+/// changes from a controller does not make zipper noise.  It is
+/// advanced once per frame in the Jack thread
+#[derive(Clone, Copy, Debug)]
+pub struct OnePole {
+    current: f32,
+    target: f32,
+}
+
+impl OnePole {
+    pub fn new(value: f32) -> Self {
+        Self {
+            current: value,
+            target: value,
+        }
+    }
+
+    pub fn set(
+        &mut self,
+        target: f32,
+    ) {
+        self.target = target;
+    }
+
+    /// The value for this frame.  Moves on by one frame
+    pub fn tick(&mut self) -> f32 {
+        let value = self.current;
+        if self.current != self.target {
+            self.current += (self.target - self.current) / SMOOTHING;
+            if (self.target - self.current).abs() < SETTLED {
+                self.current = self.target;
+            }
+        }
+        value
+    }
+}
@@ -0,0 +1,115 @@
+use crate::engine::SampleData;
+use crate::gain::db_to_gain;
+
+/// A sample this close to full scale, in dB, may clip once it is
+/// layered or turned up
+pub const CLIPS: f32 = -0.1;
+
+/// A sample this much quieter than the median, in dB, is reported
+const QUIET: f32 = 12.0;
+
+/// Samples turned up by `--auto-gain`, or to their bank's loudness,
+/// are kept this far below full scale, in dB
+pub const HEADROOM: f32 = -1.0;
+
+/// Samples quieter than this, in dB, are taken to be silent and left
+/// out
+const SILENT: f32 = -90.0;
+
+/// The peak and RMS levels of a sample, in dB from full scale
+pub struct Level {
+    pub peak: f32,
+    pub rms: f32,
+}
+
+pub fn level(data: &[f32]) -> Level {
+    let peak = data.iter().fold(0.0_f32, |peak, x| peak.max(x.abs()));
+    let power =
+        data.iter().map(|x| x * x).sum::<f32>() / data.len().max(1) as f32;
+    Level {
+        peak: 20.0 * peak.max(1e-9).log10(),
+        rms: 10.0 * power.max(1e-18).log10(),
+    }
+}
+
+/// Report the samples that are at full scale, so may clip, and those
+/// much quieter than the rest.  With `correct` each sample is turned
+/// up or down so its RMS level is the median, as far as its peak
+/// allows, and the gain applied is reported.  Returns the median the
+/// samples were brought to, for samples loaded later
+pub fn check(
+    samples: &mut [SampleData],
+    correct: bool,
+) -> Option<f32> {
+    let levels: Vec<Level> =
+        samples.iter().map(|sample| level(&sample.data)).collect();
+    let mut loudness: Vec<f32> = levels
+        .iter()
+        .map(|level| level.rms)
+        .filter(|rms| *rms > SILENT)
+        .collect();
+    if loudness.is_empty() {
+        return None;
+    }
+    loudness.sort_by(f32::total_cmp);
+    let median = loudness[loudness.len() / 2];
+    eprintln!(
+        "Levels: {} samples, median RMS {median:.1} dB",
+        loudness.len()
+    );
+    for (sample, mut level) in samples.iter_mut().zip(levels) {
+        if level.rms <= SILENT {
+            continue;
+        }
+        if correct {
+            let db = even_out(&mut sample.data, &level, median, &sample.path);
+            level.peak += db;
+            level.rms += db;
+        }
+        let problem = if level.peak >= CLIPS {
+            "is at full scale and may clip"
+        } else if level.rms < median - QUIET {
+            "is much quieter than the rest"
+        } else {
+            continue;
+        };
+        eprintln!(
+            "{}: Peak {:.1} dB, RMS {:.1} dB, {problem}",
+            sample.path, level.peak, level.rms
+        );
+    }
+    correct.then_some(median)
+}
+
+/// Turn a sample up or down so its RMS level is `median`, as `check`
+/// does with `--auto-gain`, and report the gain.  `label` names it
+pub fn correct(
+    data: &mut [f32],
+    median: f32,
+    label: &str,
+) {
+    let level = level(data);
+    if level.rms > SILENT {
+        even_out(data, &level, median, label);
+    }
+}
+
+/// Apply the gain that brings a sample at `level` to `median`, as far
+/// as its peak allows, returning it in dB
+fn even_out(
+    data: &mut [f32],
+    level: &Level,
+    median: f32,
+    label: &str,
+) -> f32 {
+    let db = (median - level.rms).min(HEADROOM - level.peak);
+    if db.abs() < 0.1 {
+        return 0.0;
+    }
+    let gain = db_to_gain(db);
+    for x in data.iter_mut() {
+        *x *= gain;
+    }
+    eprintln!("{label}: Gain {db:+.1} dB");
+    db
+}
@@ -0,0 +1,177 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The version of the state file written.  Files from before it was
+/// versioned are version 0
+pub const STATE_VERSION: u32 = 1;
+
+/// How many earlier state files are kept, as "state.json.1" (the
+/// newest) to "state.json.5"
+const BACKUPS: usize = 5;
+
+/// The settings changed while the programme runs.  It is saved soon
+/// after it changes, on exit, and by the `save` control command, and
+/// restored at start up so live tweaks survive a restart or a crash.
+/// Gains are linear, not dB, so silence can be represented in JSON
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct State {
+    #[serde(default)]
+    pub version: u32,
+    pub master_gain: f32,
+
+    /// Gains of notes that are not at unity
+    #[serde(default)]
+    pub note_gains: BTreeMap<u8, f32>,
+
+    /// Gains of output channels that are not at unity
+    #[serde(default)]
+    pub channel_gains: BTreeMap<usize, f32>,
+
+    /// Gains of groups' buses, by name, and the groups muted
+    #[serde(default)]
+    pub group_gains: BTreeMap<String, f32>,
+    #[serde(default)]
+    pub muted_groups: BTreeSet<String>,
+
+    /// Gains of banks that are not at unity, by name
+    #[serde(default)]
+    pub bank_gains: BTreeMap<String, f32>,
+
+    /// Pans of notes that are not straight, in degrees
+    #[serde(default)]
+    pub note_pans: BTreeMap<u8, f32>,
+
+    /// The name of the active bank
+    #[serde(default)]
+    pub bank: Option<String>,
+
+    /// The sequencer's tracks, the steps for each note
+    #[serde(default)]
+    pub patterns: BTreeMap<u8, String>,
+
+    /// Snapshots of the mixer, by name
+    #[serde(default)]
+    pub snapshots: BTreeMap<String, Snapshot>,
+}
+
+/// The mixer at a moment, its gains, mutes, pans and bank, saved by
+/// name to be recalled as a cue
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Snapshot {
+    pub master_gain: f32,
+    #[serde(default)]
+    pub note_gains: BTreeMap<u8, f32>,
+    #[serde(default)]
+    pub channel_gains: BTreeMap<usize, f32>,
+    #[serde(default)]
+    pub group_gains: BTreeMap<String, f32>,
+    #[serde(default)]
+    pub muted_groups: BTreeSet<String>,
+    #[serde(default)]
+    pub bank_gains: BTreeMap<String, f32>,
+    #[serde(default)]
+    pub note_pans: BTreeMap<u8, f32>,
+    #[serde(default)]
+    pub bank: Option<String>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            version: STATE_VERSION,
+            master_gain: 1.0,
+            note_gains: BTreeMap::new(),
+            channel_gains: BTreeMap::new(),
+            group_gains: BTreeMap::new(),
+            muted_groups: BTreeSet::new(),
+            bank_gains: BTreeMap::new(),
+            note_pans: BTreeMap::new(),
+            bank: None,
+            patterns: BTreeMap::new(),
+            snapshots: BTreeMap::new(),
+        }
+    }
+}
+
+impl State {
+    /// The mixer as it is
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            master_gain: self.master_gain,
+            note_gains: self.note_gains.clone(),
+            channel_gains: self.channel_gains.clone(),
+            group_gains: self.group_gains.clone(),
+            muted_groups: self.muted_groups.clone(),
+            bank_gains: self.bank_gains.clone(),
+            note_pans: self.note_pans.clone(),
+            bank: self.bank.clone(),
+        }
+    }
+}
+
+/// Where the state is kept if the configuration does not say
+pub fn default_state_file() -> PathBuf {
+    env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os("HOME")
+                .map(|home| PathBuf::from(home).join(".local/state"))
+        })
+        .unwrap_or_else(env::temp_dir)
+        .join("midi_sample_qzt")
+        .join("state.json")
+}
+
+/// Read the saved state.  A missing file is not an error, there is
+/// just nothing to restore
+pub fn load(path: &Path) -> Result<Option<State>, Box<dyn std::error::Error>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(None)
+        },
+        Err(err) => return Err(err.into()),
+    };
+    let state: State = serde_json::from_str(&contents)?;
+    if state.version > STATE_VERSION {
+        eprintln!(
+            "{}: State version {} is newer than {STATE_VERSION}, some may be lost",
+            path.display(),
+            state.version
+        );
+    }
+    Ok(Some(state))
+}
+
+/// Write the state.  It is written to a temporary file and renamed
+/// so a crash part way through does not lose the old state.  The old
+/// state is kept as a backup, as are the `BACKUPS` before it
+pub fn save(
+    path: &Path,
+    state: &State,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    let state = State {
+        version: STATE_VERSION,
+        ..state.clone()
+    };
+    fs::write(&tmp, serde_json::to_string_pretty(&state)?)?;
+
+    let backup = |n: usize| path.with_extension(format!("json.{n}"));
+    for n in (1..BACKUPS).rev() {
+        if backup(n).exists() {
+            fs::rename(backup(n), backup(n + 1))?;
+        }
+    }
+    if path.exists() {
+        fs::copy(path, backup(1))?;
+    }
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
@@ -0,0 +1,217 @@
+use crate::resample::Quality;
+use std::f64::consts::TAU;
+
+/// The phase vocoder's frame, in frames, and how many frames overlap
+/// each point, so it moves on by an eighth of a frame at a time
+const FRAME: usize = 2048;
+const OVERLAP: usize = 8;
+
+/// The spectral envelope kept when formants are preserved is the
+/// magnitude averaged over this many bins either side
+const ENVELOPE_BINS: usize = 8;
+
+/// A complex number, as the FFT works on them
+#[derive(Clone, Copy, Default)]
+struct Complex {
+    re: f64,
+    im: f64,
+}
+
+/// An in place radix-2 FFT.  `buffer`'s length is a power of two.
+/// The inverse is not scaled
+fn fft(
+    buffer: &mut [Complex],
+    inverse: bool,
+) {
+    let n = buffer.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            buffer.swap(i, j);
+        }
+    }
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut length = 2;
+    while length <= n {
+        let angle = sign * TAU / length as f64;
+        let twiddles: Vec<(f64, f64)> = (0..length / 2)
+            .map(|k| (angle * k as f64).sin_cos())
+            .collect();
+        for start in (0..n).step_by(length) {
+            for (k, &(sin, cos)) in twiddles.iter().enumerate() {
+                let a = buffer[start + k];
+                let b = buffer[start + k + length / 2];
+                let b = Complex {
+                    re: b.re * cos - b.im * sin,
+                    im: b.re * sin + b.im * cos,
+                };
+                buffer[start + k] = Complex {
+                    re: a.re + b.re,
+                    im: a.im + b.im,
+                };
+                buffer[start + k + length / 2] = Complex {
+                    re: a.re - b.re,
+                    im: a.im - b.im,
+                };
+            }
+        }
+        length <<= 1;
+    }
+}
+
+/// Wrap a phase into -π to π
+fn wrap(phase: f64) -> f64 {
+    phase - TAU * (phase / TAU).round()
+}
+
+/// The magnitudes averaged over nearby bins, the shape formants give
+/// a spectrum
+fn envelope(magnitudes: &[f64]) -> Vec<f64> {
+    (0..magnitudes.len())
+        .map(|k| {
+            let low = k.saturating_sub(ENVELOPE_BINS);
+            let high = (k + ENVELOPE_BINS + 1).min(magnitudes.len());
+            magnitudes[low..high].iter().sum::<f64>() / (high - low) as f64
+        })
+        .collect()
+}
+
+/// `data` with its pitch moved by `ratio`, two for an octave up,
+/// keeping its length.  A phase vocoder stretches it by `ratio`,
+/// and it is then played back faster or slower to its length.  With
+/// `formants` the shape of the spectrum stays where it was, so a
+/// voice sounds like the same person singing another note rather
+/// than a bigger or smaller one
+pub fn shift(
+    data: &[f32],
+    ratio: f32,
+    formants: bool,
+) -> Vec<f32> {
+    let ratio = ratio as f64;
+    let analysis = FRAME / OVERLAP;
+    let synthesis = ((analysis as f64 * ratio).round() as usize).max(1);
+    let stretch = synthesis as f64 / analysis as f64;
+    let bins = FRAME / 2 + 1;
+    let window: Vec<f64> = (0..FRAME)
+        .map(|i| 0.5 - 0.5 * (TAU * i as f64 / FRAME as f64).cos())
+        .collect();
+
+    // Hann windows, applied going in and coming out, overlapping
+    // `synthesis` frames apart add up to this
+    let gain = 3.0 / 8.0 * FRAME as f64 / synthesis as f64;
+
+    // The data is padded at the start so its start is covered as
+    // fully as the rest
+    let padding = FRAME - analysis;
+    let frames = (data.len() + padding).div_ceil(analysis) + 1;
+    let mut stretched = vec![0.0_f64; frames * synthesis + FRAME];
+    let mut last_phase = vec![0.0; bins];
+    let mut sum_phase = vec![0.0; bins];
+    let mut buffer = vec![Complex::default(); FRAME];
+    for frame in 0..frames {
+        for (i, (value, w)) in buffer.iter_mut().zip(window.iter()).enumerate()
+        {
+            let x = (frame * analysis + i)
+                .checked_sub(padding)
+                .and_then(|at| data.get(at))
+                .copied()
+                .unwrap_or(0.0);
+            *value = Complex {
+                re: x as f64 * w,
+                im: 0.0,
+            };
+        }
+        fft(&mut buffer, false);
+
+        let mut magnitudes: Vec<f64> = buffer[..bins]
+            .iter()
+            .map(|c| (c.re * c.re + c.im * c.im).sqrt())
+            .collect();
+        let phases: Vec<f64> =
+            buffer[..bins].iter().map(|c| c.im.atan2(c.re)).collect();
+
+        // The peaks' phases move on by their true frequency, from how
+        // far their phase has turned, over the synthesis hop.  The
+        // bins round a peak keep their phase relative to it, so a
+        // sinusoid spread over several bins stays in one piece
+        let peaks: Vec<usize> = (0..bins)
+            .filter(|&k| {
+                (k == 0 || magnitudes[k] > magnitudes[k - 1])
+                    && (k == bins - 1 || magnitudes[k] >= magnitudes[k + 1])
+            })
+            .collect();
+        let mut synthesised = vec![0.0; bins];
+        for (i, &peak) in peaks.iter().enumerate() {
+            let bin = TAU * peak as f64 / FRAME as f64;
+            let delta =
+                wrap(phases[peak] - last_phase[peak] - bin * analysis as f64);
+            let frequency = bin + delta / analysis as f64;
+            let phase = sum_phase[peak] + frequency * synthesis as f64;
+            let low = match i {
+                0 => 0,
+                _ => (peaks[i - 1] + peak).div_ceil(2),
+            };
+            let high = peaks
+                .get(i + 1)
+                .map_or(bins, |next| (peak + next).div_ceil(2));
+            for k in low..high {
+                synthesised[k] = wrap(phase + phases[k] - phases[peak]);
+            }
+        }
+        last_phase = phases;
+        sum_phase = synthesised;
+
+        // Played back faster or slower, the spectrum moves by the
+        // ratio, so it is given the envelope it will need there
+        if formants {
+            let envelope = envelope(&magnitudes);
+            for (k, magnitude) in magnitudes.iter_mut().enumerate() {
+                let to = ((k as f64 * stretch).round() as usize).min(bins - 1);
+                if envelope[k] > 0.0 {
+                    *magnitude *= envelope[to] / envelope[k];
+                }
+            }
+        }
+
+        for k in 0..bins {
+            let (sin, cos) = sum_phase[k].sin_cos();
+            let value = Complex {
+                re: magnitudes[k] * cos,
+                im: magnitudes[k] * sin,
+            };
+            buffer[k] = value;
+            if k > 0 && k < FRAME / 2 {
+                buffer[FRAME - k] = Complex {
+                    re: value.re,
+                    im: -value.im,
+                };
+            }
+        }
+        fft(&mut buffer, true);
+        let at = frame * synthesis;
+        for (i, (value, w)) in buffer.iter().zip(window.iter()).enumerate() {
+            stretched[at + i] += value.re * w / (FRAME as f64 * gain);
+        }
+    }
+
+    // Each frame's middle is where it was, stretched, so the data's
+    // first frame is `padding` frames in, less half a frame, stretched
+    let stretched: Vec<f32> = stretched.iter().map(|x| *x as f32).collect();
+    let middle = (FRAME / 2) as f64;
+    (0..data.len())
+        .map(|i| {
+            let position = ((padding + i) as f64 - middle) * stretch + middle;
+            Quality::Cubic.interpolate(
+                &stretched,
+                position as usize,
+                position.fract() as f32,
+            )
+        })
+        .collect()
+}
@@ -0,0 +1,75 @@
+use midir::os::unix::VirtualOutput;
+use midir::{MidiOutput, MidiOutputConnection};
+use std::sync::Mutex;
+
+/// The manufacturer ID set aside for non-commercial use
+const MANUFACTURER: u8 = 0x7d;
+
+/// Follows the manufacturer ID, to tell these messages from others
+/// using the non-commercial ID
+const TAG: &[u8] = b"QZ";
+
+/// What a message is: a command, or an answer that succeeded or
+/// failed
+const COMMAND: u8 = b'C';
+const OK: u8 = b'O';
+const ERROR: u8 = b'E';
+
+/// Control commands over MIDI, for when there is no network or
+/// socket to use.  A command is the text of a control socket command
+/// sent as a system exclusive message:
+///
+/// F0 7D 'Q' 'Z' 'C' <command> F7
+///
+/// and the answer comes back on the "sysex" MIDI output port, as
+/// F0 7D 'Q' 'Z' 'O' <data> F7, or 'E' and the error.  Text is ASCII,
+/// other characters are sent as '?'
+pub struct SysEx {
+    output: Mutex<MidiOutputConnection>,
+}
+
+impl SysEx {
+    /// Create the output port answers go out on
+    pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
+        let output = MidiOutput::new("MidiSampleQzt")?
+            .create_virtual("sysex")
+            .map_err(|err| err.to_string())?;
+        Ok(Self {
+            output: Mutex::new(output),
+        })
+    }
+
+    /// Take a system exclusive message.  If it is a command, `handle`
+    /// runs it and the answer is sent back.  Anything else is ignored
+    pub fn message(
+        &self,
+        data: &[u8],
+        handle: impl FnOnce(&str) -> Result<String, String>,
+    ) {
+        let Some(command) = data
+            .strip_prefix(&[MANUFACTURER])
+            .and_then(|data| data.strip_prefix(TAG))
+            .and_then(|data| data.strip_prefix(&[COMMAND]))
+        else {
+            return;
+        };
+        let (kind, answer) = match handle(&String::from_utf8_lossy(command)) {
+            Ok(data) => (OK, data),
+            Err(err) => (ERROR, err),
+        };
+        let mut reply = vec![0xf0, MANUFACTURER];
+        reply.extend_from_slice(TAG);
+        reply.push(kind);
+        reply.extend(answer.chars().map(|c| {
+            if c.is_ascii() {
+                c as u8
+            } else {
+                b'?'
+            }
+        }));
+        reply.push(0xf7);
+        if let Err(err) = self.output.lock().unwrap().send(&reply) {
+            eprintln!("{err}: Sending system exclusive answer");
+        }
+    }
+}
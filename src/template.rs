@@ -0,0 +1,158 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+/// General MIDI percussion, from note 35
+const GM_DRUMS: [&str; 47] = [
+    "acoustic_bass_drum",
+    "bass_drum",
+    "side_stick",
+    "acoustic_snare",
+    "hand_clap",
+    "electric_snare",
+    "low_floor_tom",
+    "closed_hi_hat",
+    "high_floor_tom",
+    "pedal_hi_hat",
+    "low_tom",
+    "open_hi_hat",
+    "low_mid_tom",
+    "hi_mid_tom",
+    "crash_cymbal_1",
+    "high_tom",
+    "ride_cymbal_1",
+    "chinese_cymbal",
+    "ride_bell",
+    "tambourine",
+    "splash_cymbal",
+    "cowbell",
+    "crash_cymbal_2",
+    "vibraslap",
+    "ride_cymbal_2",
+    "hi_bongo",
+    "low_bongo",
+    "mute_hi_conga",
+    "open_hi_conga",
+    "low_conga",
+    "high_timbale",
+    "low_timbale",
+    "high_agogo",
+    "low_agogo",
+    "cabasa",
+    "maracas",
+    "short_whistle",
+    "long_whistle",
+    "short_guiro",
+    "long_guiro",
+    "claves",
+    "hi_wood_block",
+    "low_wood_block",
+    "mute_cuica",
+    "open_cuica",
+    "mute_triangle",
+    "open_triangle",
+];
+const GM_FIRST: u8 = 35;
+
+/// The notes of an MPC's pads in its four banks, A to D, pad 1 at the
+/// bottom left
+const MPC_BANKS: [[u8; 16]; 4] = [
+    [
+        37, 36, 42, 82, 40, 38, 46, 44, 48, 47, 45, 43, 49, 55, 51, 53,
+    ],
+    [
+        54, 69, 81, 80, 65, 66, 76, 77, 56, 62, 63, 64, 73, 74, 71, 39,
+    ],
+    [
+        52, 57, 58, 59, 60, 61, 67, 68, 70, 72, 75, 78, 79, 35, 41, 50,
+    ],
+    [
+        83, 84, 85, 86, 87, 88, 89, 90, 91, 92, 93, 94, 95, 96, 97, 98,
+    ],
+];
+
+/// A familiar layout of notes, with names for its pads, so samples
+/// can be given a pad rather than a note, or none at all and take
+/// the next free pad
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Template {
+    /// General MIDI drums, with pads named as "acoustic_snare"
+    GmDrums,
+
+    /// The Launchpad X's 8x8 grid in programmer mode, with pads named
+    /// by row and column from the bottom left, "11" to "88"
+    LaunchpadX,
+
+    /// An MPC's 16 pads in banks A to D, named "A01" to "D16"
+    Mpc,
+}
+
+impl Template {
+    /// The pads, in the order samples are given them
+    fn pads(self) -> Vec<(String, u8)> {
+        match self {
+            Template::GmDrums => GM_DRUMS
+                .iter()
+                .zip(GM_FIRST..)
+                .map(|(name, note)| (name.to_string(), note))
+                .collect(),
+            Template::LaunchpadX => (1..=8)
+                .flat_map(|row| {
+                    (1..=8).map(move |column| {
+                        let note = row * 10 + column;
+                        (note.to_string(), note)
+                    })
+                })
+                .collect(),
+            Template::Mpc => MPC_BANKS
+                .iter()
+                .zip('A'..)
+                .flat_map(|(notes, bank)| {
+                    notes.iter().enumerate().map(move |(pad, note)| {
+                        (format!("{bank}{:02}", pad + 1), *note)
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    /// Give each sample in a configuration file's JSON a note: the
+    /// note of its "pad" if it has one, or if it has neither a pad
+    /// nor a note, the next pad no other sample has
+    pub fn assign(
+        self,
+        samples: &mut [Value],
+    ) -> Result<(), String> {
+        let pads = self.pads();
+        let mut taken: Vec<u64> = samples
+            .iter()
+            .filter_map(|sample| sample.get("note")?.as_u64())
+            .collect();
+        for sample in samples.iter_mut() {
+            let Some(sample) = sample.as_object_mut() else {
+                continue;
+            };
+            let note = match (sample.get("note"), sample.get("pad")) {
+                (Some(_), Some(_)) => {
+                    return Err("A sample has both a note and a pad".into())
+                },
+                (Some(_), None) => continue,
+                (None, Some(pad)) => {
+                    let pad = pad.as_str().unwrap_or_default();
+                    pads.iter()
+                        .find(|(name, _)| name.eq_ignore_ascii_case(pad))
+                        .map(|(_, note)| *note)
+                        .ok_or(format!("No pad called {pad} in {self:?}"))?
+                },
+                (None, None) => pads
+                    .iter()
+                    .map(|(_, note)| *note)
+                    .find(|note| !taken.contains(&(*note as u64)))
+                    .ok_or(format!("No pads left in {self:?}"))?,
+            };
+            taken.push(note as u64);
+            sample.insert("note".to_string(), note.into());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+use crate::engine::{Command, SampleData};
+use std::f32::consts::TAU;
+use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// The tone's pitch in Hz and gain (-12 dB)
+const FREQUENCY: f32 = 440.0;
+const GAIN: f32 = 0.25;
+
+/// Each beep and the gap after it, and the pause between channels
+const BEEP: Duration = Duration::from_millis(150);
+const PAUSE: Duration = Duration::from_secs(1);
+
+/// How long the beeps fade in and out, so they do not click
+const FADE: Duration = Duration::from_millis(5);
+
+/// Play a test tone on each output channel in turn, over and over, to
+/// check which speaker each channel reaches.  Channel n beeps n times
+/// so it can be told from the others without looking.  `ports` are
+/// the names of the channels' ports, in order
+pub fn start(
+    commands: SyncSender<Command>,
+    ports: Vec<String>,
+    sample_rate: usize,
+) {
+    for (channel, port) in ports.iter().enumerate() {
+        let _ = commands.send(Command::AddSample(Arc::new(SampleData {
+            path: format!("test-tone-{port}"),
+            data: beeps(channel + 1, sample_rate),
+            channels: vec![channel],
+            ..Default::default()
+        })));
+    }
+    thread::spawn(move || loop {
+        for (channel, port) in ports.iter().enumerate() {
+            eprintln!("Test tone: channel {} ({port})", channel + 1);
+            let _ = commands.send(Command::Trigger {
+                sample: channel,
+                gain: 1.0,
+                velocity: 127,
+                effects: vec![],
+            });
+            thread::sleep(BEEP * 2 * (channel as u32 + 1) + PAUSE);
+        }
+    });
+}
+
+/// `count` beeps, each followed by a gap as long as itself
+fn beeps(
+    count: usize,
+    sample_rate: usize,
+) -> Vec<f32> {
+    let beep = (BEEP.as_secs_f32() * sample_rate as f32) as usize;
+    let fade = (FADE.as_secs_f32() * sample_rate as f32) as usize;
+    let mut data = vec![];
+    for _ in 0..count {
+        data.extend((0..beep).map(|i| {
+            let envelope = (i.min(beep - i) as f32 / fade as f32).min(1.0);
+            let phase = TAU * FREQUENCY * i as f32 / sample_rate as f32;
+            phase.sin() * envelope * GAIN
+        }));
+        data.resize(data.len() + beep, 0.0);
+    }
+    data
+}
@@ -0,0 +1,79 @@
+use crate::engine::{SampleData, TriggerEvent};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread;
+use std::time::UNIX_EPOCH;
+
+/// How many trigger events can be waiting to be written
+const EVENT_QUEUE: usize = 1024;
+
+/// Start a thread writing trigger events to `path`.  A file ending
+/// in ".json" or ".jsonl" gets one JSON object per line, anything
+/// else gets CSV.  Each event is flushed as it is written so the log
+/// survives a crash.  Times are seconds since the Unix epoch
+pub fn start(path: &Path) -> std::io::Result<SyncSender<TriggerEvent>> {
+    let json = matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("json") | Some("jsonl")
+    );
+    let mut out = BufWriter::new(File::create(path)?);
+    if !json {
+        writeln!(out, "time,note,velocity,sample,voice,name")?;
+        out.flush()?;
+    }
+    let (sender, receiver) = sync_channel::<TriggerEvent>(EVENT_QUEUE);
+    thread::spawn(move || {
+        for event in receiver {
+            let SampleData {
+                note,
+                path: sample,
+                name,
+                ..
+            } = &*event.sample;
+            let time = event
+                .time
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or(0.0);
+            let result = if json {
+                writeln!(
+                    out,
+                    "{}",
+                    serde_json::json!({
+                        "time": time,
+                        "note": note,
+                        "velocity": event.velocity,
+                        "sample": sample,
+                        "voice": event.voice,
+                        "name": name,
+                    })
+                )
+            } else {
+                writeln!(
+                    out,
+                    "{time:.6},{note},{},{},{},{}",
+                    event.velocity,
+                    csv_field(sample),
+                    event.voice,
+                    csv_field(name.as_deref().unwrap_or(""))
+                )
+            };
+            if let Err(err) = result.and_then(|_| out.flush()) {
+                eprintln!("{err}: Writing trigger log");
+                return;
+            }
+        }
+    });
+    Ok(sender)
+}
+
+/// Quote a CSV field if it needs it
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
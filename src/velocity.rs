@@ -0,0 +1,129 @@
+use crate::config::process_samples_json;
+use crate::loader;
+use crate::message::Message;
+use crate::midi;
+use serde::Deserialize;
+use serde_json::json;
+use std::path::Path;
+use std::sync::mpsc::sync_channel;
+
+/// How many hits are taken at each strength when calibrating
+const HITS: usize = 5;
+
+/// The strengths asked for when calibrating, and the velocities they
+/// should come out as
+const STRENGTHS: [(&str, f32); 3] =
+    [("softly", 40.0), ("moderately", 80.0), ("hard", 127.0)];
+
+/// The range the curve's bend is kept in
+const GAMMA: (f32, f32) = (0.2, 5.0);
+
+/// Evens out how a controller's pads respond.  Velocities from 1 up
+/// to `max` are spread over 1 to 127, bent by `gamma`: over one a
+/// pad has to be hit harder to get loud, under one less hard.  `max`
+/// and above give 127.  Set by `calibrate-velocity`
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct VelocityCurve {
+    pub max: u8,
+    pub gamma: f32,
+}
+
+impl VelocityCurve {
+    /// The message with its NoteOn velocity on the curve
+    pub fn apply(
+        &self,
+        message: Message,
+    ) -> Message {
+        match message {
+            Message::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => Message::NoteOn {
+                channel,
+                note,
+                velocity: self.velocity(velocity),
+            },
+            message => message,
+        }
+    }
+
+    fn velocity(
+        &self,
+        velocity: u8,
+    ) -> u8 {
+        let x = (velocity as f32 / self.max.max(1) as f32).min(1.0);
+        (127.0 * x.powf(self.gamma)).round().clamp(1.0, 127.0) as u8
+    }
+
+    /// The curve that takes the average soft, moderate and hard hits
+    /// closest to the velocities they should be.  The hard hits set
+    /// `max`, and the bend is fitted to the others
+    fn fit(averages: [f32; 3]) -> Result<Self, String> {
+        if averages.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(format!(
+                "The hits did not get harder: {averages:.0?}.  Try again"
+            ));
+        }
+        let max = averages[2].round().clamp(1.0, 127.0);
+
+        // The bend that fits best, as logarithms, is a least squares
+        // fit of a line through the origin
+        let (mut xy, mut xx) = (0.0, 0.0);
+        for (average, (_, target)) in averages.iter().zip(STRENGTHS).take(2) {
+            let x = (average / max).ln();
+            let y = (target / 127.0).ln();
+            xy += x * y;
+            xx += x * x;
+        }
+        Ok(Self {
+            max: max as u8,
+            gamma: (xy / xx).clamp(GAMMA.0, GAMMA.1),
+        })
+    }
+}
+
+/// Work out a velocity curve for the controller on the configuration's
+/// MIDI input, from hits at three strengths, and write it into the
+/// configuration file as "velocity_curve".  The file is rewritten, so
+/// its layout changes
+pub fn calibrate(config: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let midi_input =
+        process_samples_json(&config.to_string_lossy(), None)?.midi_input;
+    let (sender, receiver) = sync_channel::<u8>(64);
+    midi::watch(midi_input, move |message| {
+        if let Message::NoteOn { velocity, .. } = message {
+            let _ = sender.try_send(velocity);
+        }
+    });
+    let mut averages = [0.0; 3];
+    for ((strength, _), average) in STRENGTHS.iter().zip(averages.iter_mut()) {
+        eprintln!("Hit the pads {strength}, {HITS} times");
+        let mut total = 0;
+        for hit in 1..=HITS {
+            let velocity = receiver.recv()?;
+            eprintln!("{hit}: {velocity}");
+            total += velocity as usize;
+        }
+        *average = total as f32 / HITS as f32;
+    }
+    let curve = VelocityCurve::fit(averages)?;
+    for ((strength, _), average) in STRENGTHS.iter().zip(averages) {
+        eprintln!(
+            "{strength}: {average:.0} becomes {}",
+            curve.velocity(average.round() as u8)
+        );
+    }
+
+    // More figures than this would be noise
+    let gamma = (curve.gamma as f64 * 100.0).round() / 100.0;
+    loader::rewrite(config, |settings| {
+        settings.insert(
+            "velocity_curve".to_string(),
+            json!({ "max": curve.max, "gamma": gamma }),
+        );
+        Ok(())
+    })?;
+    println!("max {} gamma {gamma}", curve.max);
+    Ok(())
+}